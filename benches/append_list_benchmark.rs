@@ -0,0 +1,26 @@
+extern crate criterion;
+extern crate sdag;
+
+use criterion::*;
+use sdag::utils::AppendListExt;
+
+const LIST_LEN: usize = 1_000;
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let list = (0..LIST_LEN).collect::<AppendListExt<_>>();
+
+    c.bench_function("append list collect and reverse", |b| {
+        b.iter(|| {
+            let mut values = list.iter().collect::<Vec<_>>();
+            values.reverse();
+            values.iter().map(|v| **v).sum::<usize>()
+        })
+    });
+
+    c.bench_function("append list iter_rev", |b| {
+        b.iter(|| list.iter_rev().map(|v| *v).sum::<usize>())
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+extern crate criterion;
+extern crate sdag;
+
+use criterion::*;
+use sdag::business::GlobalState;
+use sdag::joint::{Joint, JointProperty, Level};
+use sdag::kv_store::KV_STORE;
+use sdag::spec::{Payload, UnitBuilder};
+
+const CHAIN_LEN: usize = 10_000;
+
+// save a chain of stable joints, one joint for each mci
+fn save_stable_chain() {
+    let mut parent = "genesis".to_owned();
+    for mci in 0..CHAIN_LEN {
+        let unit = UnitBuilder::new()
+            .message("text", Payload::Text(mci.to_string()))
+            .parent(&parent)
+            .build()
+            .expect("build unit error");
+        let joint = Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit,
+        };
+        let property = JointProperty {
+            mci: Level::from(mci),
+            sub_mci: Level::from(0),
+            is_stable: true,
+            ..Default::default()
+        };
+
+        parent = joint.unit.unit.clone();
+        KV_STORE
+            .save_joint(&parent, &joint)
+            .expect("save joint error");
+        KV_STORE
+            .save_joint_property(&parent, &property)
+            .expect("save property error");
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    save_stable_chain();
+    c.bench_function("global state rebuild from genesis", |b| {
+        b.iter(|| GlobalState::rebuild_from_genesis().expect("rebuild error"))
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = criterion_benchmark
+}
+criterion_main!(benches);
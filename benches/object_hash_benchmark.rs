@@ -0,0 +1,51 @@
+extern crate criterion;
+extern crate sdag;
+extern crate sdag_object_base;
+extern crate serde_json;
+
+use criterion::*;
+use sdag::spec::{self, Payload};
+use sdag_object_base::object_hash;
+
+static PAYLOAD: &str = r#"{
+        "inputs":[
+            {
+                "message_index":1,
+                "output_index":41,
+                "unit":"Gz0nOu5Utp3WtCZwlfG5+TbqRMGvF8fDsAVWh9BJc7Q="
+            }
+        ],
+        "outputs":[
+            {
+                "address":"LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE",
+                "amount":999499
+            }
+        ]
+    }"#;
+
+const PAYLOAD_NUM: usize = 10_000;
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let payload: Payload = serde_json::from_str(PAYLOAD).expect("string to payload error");
+    let payloads = vec![payload; PAYLOAD_NUM];
+    c.bench_function("hash 10000 identical payloads", move |b| {
+        b.iter(|| {
+            for payload in &payloads {
+                object_hash::get_base64_hash(payload).expect("hash payload error");
+            }
+        })
+    });
+
+    let payload: Payload = serde_json::from_str(PAYLOAD).expect("string to payload error");
+    let payloads = vec![payload; PAYLOAD_NUM];
+    c.bench_function("cached hash 10000 identical payloads", move |b| {
+        b.iter(|| {
+            for payload in &payloads {
+                spec::get_base64_hash(payload).expect("hash payload error");
+            }
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
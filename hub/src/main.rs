@@ -1,7 +1,6 @@
 #[macro_use]
 extern crate log;
 extern crate chrono;
-extern crate env_logger;
 #[macro_use]
 extern crate sdag;
 #[macro_use]
@@ -9,23 +8,48 @@ extern crate may;
 extern crate may_signal;
 extern crate num_cpus;
 extern crate serde_json;
+extern crate tracing_subscriber;
 
 mod timer;
+use std::time::Duration;
+
 use sdag::error::Result;
 use sdag::*;
 
+// RUST_LOG overrides the configured log level
+fn log_filter(level: log::LevelFilter) -> tracing_subscriber::EnvFilter {
+    use tracing_subscriber::EnvFilter;
+
+    EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(level.to_string().to_lowercase()))
+        .add_directive("pagecache=error".parse().expect("invalid log directive"))
+}
+
 fn log_init() {
     // TODO: need to implement async logs
     let log_lvl = sdag::config::get_log_level();
-    let mut builder = env_logger::Builder::from_default_env();
 
-    builder.filter_module("pagecache", log::LevelFilter::Error);
-    builder.filter(None, log_lvl).init();
+    // the log records are bridged to the tracing subscriber
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(log_filter(log_lvl))
+        .with_filter_reloading();
+    let filter_handle = builder.reload_handle();
+    builder.init();
+    // the level is controlled by the max level, so that it could be changed by config reload
+    log::set_max_level(log_lvl);
+
+    use config::ConfigChangeEvent;
+    use utils::event::Event;
+    ConfigChangeEvent::add_handler(move |e| {
+        if let Some(level) = e.diff.log_level {
+            t!(filter_handle.reload(log_filter(level)));
+        }
+    });
 
     info!("log init done!");
 }
 
-fn start_ws_server() -> Result<::may::coroutine::JoinHandle<()>> {
+fn start_ws_server() -> Result<network::ServerHandle> {
     use network::hub::WSS;
     use network::WsServer;
 
@@ -59,13 +83,16 @@ fn network_cleanup() {
 fn register_event_handlers() {
     // use main_chain::MciStableEvent;
     use utils::event::Event;
-    use validation::NewJointEvent;
 
     // MciStableEvent::add_handler(|v| t!(network::hub::notify_watchers_about_stable_joints(v.mci)));
-    NewJointEvent::add_handler(|e| network::hub::WSS.broadcast_joint(e.joint.clone()));
+    network::hub::register_push_handlers();
 
     use notify_watcher::NotifyEvent;
-    NotifyEvent::add_handler(|e| notify_watcher::notify_watchers(e.joint.clone()));
+    // notify the watchers over the network, don't block the finalization
+    NotifyEvent::add_async_handler(|e| notify_watcher::notify_watchers(e.joint.clone()));
+
+    use config::ConfigChangeEvent;
+    ConfigChangeEvent::add_async_handler(|e| network::hub::on_config_changed(&e.diff));
 }
 
 // the hub server logic that run in coroutine context
@@ -91,11 +118,18 @@ fn main() -> Result<()> {
         .set_stack_size(stack_size)
         .set_workers(workers);
 
+    network::hub::record_start_time();
     log_init();
     config::show_config();
+    // keep watching the config file until exit
+    let _config_watch = config::watch_config(&config::get_config_path()?)?;
 
     kv_store::KV_STORE.rebuild_from_kv()?;
 
+    let watchdog_timeout = Duration::from_secs(config::get_watchdog_timeout_secs());
+    main_chain::start_watchdog(watchdog_timeout);
+    business::start_watchdog(watchdog_timeout);
+
     // uncomment it to test read joint from db
     go!(run_hub_server)
         .join()
@@ -103,6 +137,8 @@ fn main() -> Result<()> {
     // wait user input a ctrl_c to exit
     may_signal::ctrl_c().recv().unwrap();
 
+    // let the in-flight joints finish before closing the kv store
+    utils::COROUTINE_REGISTRY.wait_all(Duration::from_secs(5));
     kv_store::KV_STORE.finish()?;
 
     // close all the connections
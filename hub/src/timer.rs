@@ -48,6 +48,13 @@ pub fn start_global_timers() {
         coroutine::sleep(Duration::from_secs(1));
     });
 
+    // remove the expired uri payloads
+    go!(move || loop {
+        coroutine::sleep(Duration::from_secs(3600));
+        info!("cleanup expired payloads");
+        t!(sdag::business::payload_store::PAYLOAD_STORE.cleanup());
+    });
+
     if !cfg!(feature = "kv_store_none") {
         go!(move || loop {
             coroutine::sleep(Duration::from_secs(60));
@@ -72,6 +72,20 @@ where
     Ok(serializer.output.join("\u{0000}"))
 }
 
+// the parts that to_string joins with "\u{0000}", so that the caller can
+// hash the serialized content without building the whole string
+pub fn to_string_parts<T>(value: &T) -> Result<Vec<String>>
+where
+    T: Serialize + ?Sized,
+{
+    let mut serializer = Serializer {
+        output: Vec::new(),
+        size: 0,
+    };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
 // get object size (special with sdag)
 #[allow(dead_code)]
 pub fn obj_size<T>(value: &T) -> Result<usize>
@@ -2,11 +2,15 @@ use base32;
 use base64;
 use bit_vec::BitVec;
 use failure::Error;
-use obj_ser::to_string;
+use obj_ser::{to_string, to_string_parts};
 use rand::{self, Rng};
 use ripemd160::Ripemd160;
 use serde::ser::Serialize;
 use sha2::{Digest, Sha256};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -19,6 +23,76 @@ where
     )))
 }
 
+/// the base64 sha256 hash of raw data
+pub fn get_base64_data_hash(data: &[u8]) -> String {
+    base64::encode(&Sha256::digest(data))
+}
+
+/// cache the base64 hash of immutable objects
+/// the key is the 64-bit fingerprint together with the length of the serialized string
+pub struct HashCache {
+    inner: Mutex<HashCacheInner>,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct HashCacheInner {
+    hashes: HashMap<(u64, usize), String>,
+    // insert order, the oldest would be removed first
+    keys: VecDeque<(u64, usize)>,
+}
+
+impl HashCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        HashCache {
+            inner: Mutex::new(HashCacheInner::default()),
+            capacity,
+        }
+    }
+
+    /// return the base64 hash and whether it hits the cache
+    pub fn get_base64_hash<T>(&self, object: &T) -> Result<(String, bool)>
+    where
+        T: Serialize + ?Sized,
+    {
+        // the parts are not joined, a hit neither builds the string nor hashes it
+        let parts = to_string_parts(object)?;
+        let mut hasher = DefaultHasher::new();
+        let mut len = parts.len().saturating_sub(1);
+        for part in &parts {
+            part.hash(&mut hasher);
+            len += part.len();
+        }
+        let key = (hasher.finish(), len);
+
+        if let Some(hash) = self.inner.lock().unwrap().hashes.get(&key) {
+            return Ok((hash.clone(), true));
+        }
+
+        // same as hashing the parts joined by "\u{0000}"
+        let mut sha256 = Sha256::new();
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                sha256.input(b"\0");
+            }
+            sha256.input(part.as_bytes());
+        }
+        let hash = base64::encode(&sha256.result());
+
+        let mut inner = self.inner.lock().unwrap();
+        if self.capacity > 0 && inner.hashes.insert(key, hash.clone()).is_none() {
+            inner.keys.push_back(key);
+            while inner.keys.len() > self.capacity {
+                if let Some(old) = inner.keys.pop_front() {
+                    inner.hashes.remove(&old);
+                }
+            }
+        }
+
+        Ok((hash, false))
+    }
+}
+
 pub fn get_chash<T>(object: &T) -> Result<String>
 where
     T: Serialize,
@@ -238,6 +312,26 @@ fn test_payload() {
     assert_eq!(get_base64_hash(&payload).unwrap(), expected);
 }
 
+#[test]
+fn test_hash_cache() {
+    let cache = HashCache::with_capacity(2);
+    let mut payload = vec!["a".to_owned(), "b".to_owned()];
+
+    let (hash, is_hit) = cache.get_base64_hash(&payload).unwrap();
+    assert!(!is_hit);
+    assert_eq!(hash, get_base64_hash(&payload).unwrap());
+
+    let (cached_hash, is_hit) = cache.get_base64_hash(&payload).unwrap();
+    assert!(is_hit);
+    assert_eq!(cached_hash, hash);
+
+    payload.push("c".to_owned());
+    let (new_hash, is_hit) = cache.get_base64_hash(&payload).unwrap();
+    assert!(!is_hit);
+    assert_ne!(new_hash, hash);
+    assert_eq!(new_hash, get_base64_hash(&payload).unwrap());
+}
+
 #[test]
 fn test_chash160() {
     let data = "A0mQdZvy+bGpIu/yBSNt7eB4mTZUQiM173bIQTOQRz3U";
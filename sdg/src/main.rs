@@ -8,6 +8,8 @@ extern crate clap;
 extern crate failure;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 
 extern crate chrono;
 extern crate env_logger;
@@ -15,7 +17,6 @@ extern crate sdag;
 extern crate sdag_object_base;
 extern crate sdag_wallet_base;
 extern crate serde;
-extern crate serde_json;
 
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -31,6 +32,7 @@ use sdag::joint::{Joint, JointSequence};
 use sdag::network::wallet::WalletConn;
 use sdag::statistics::{LastConnStat, StatsPerPeriod};
 use sdag::try_go;
+use sdag::utils::event::HandlerGuard;
 use sdag::validation;
 use sdag::wallet_info::{WalletInfo, MY_WALLET};
 use sdag_object_base::object_hash;
@@ -122,66 +124,85 @@ fn info(ws: &Arc<WalletConn>, wallet_info: &WalletInfo, is_json: bool) -> Result
     Ok(())
 }
 
-fn net_state(ws: &Arc<WalletConn>) -> Result<()> {
-    let net_state = ws.get_net_state()?;
-    println!("{}", serde_json::to_string_pretty(&net_state)?);
-    Ok(())
-}
+fn address_stats(ws: &Arc<WalletConn>, address: &str) -> Result<()> {
+    let stats = ws.get_address_stats(address)?;
 
-fn print_stats_matrix(stat: LastConnStat) {
-    println!("|           |  RX_GOOD |  RX_BAD  |    TX    |");
-    println!("|-----------|----------|----------|----------|");
+    let mci = |mci: sdag::joint::Level| {
+        if mci.is_valid() {
+            mci.value().to_string()
+        } else {
+            "-".to_owned()
+        }
+    };
 
+    println!("address        : {}", address);
     println!(
-        "| LAST_SEC  | {:>8} | {:>8} | {:>8} |",
-        stat.sec.rx_good, stat.sec.rx_bad, stat.sec.tx_total
+        "balance        : {:.6} MN",
+        stats.balance as f64 / 1_000_000.0
     );
-
     println!(
-        "| LAST_MIN  | {:>8} | {:>8} | {:>8} |",
-        stat.min.rx_good, stat.min.rx_bad, stat.min.tx_total
+        "total_received : {:.6} MN",
+        stats.total_received as f64 / 1_000_000.0
     );
-
     println!(
-        "| LAST_HOUR | {:>8} | {:>8} | {:>8} |",
-        stat.hour.rx_good, stat.hour.rx_bad, stat.hour.tx_total
+        "total_sent     : {:.6} MN",
+        stats.total_sent as f64 / 1_000_000.0
     );
+    println!("tx_count       : {}", stats.tx_count);
+    println!("first_seen_mci : {}", mci(stats.first_seen_mci));
+    println!("last_seen_mci  : {}", mci(stats.last_seen_mci));
+    Ok(())
+}
 
-    println!(
-        "| LAST_DAY  | {:>8} | {:>8} | {:>8} |",
-        stat.day.rx_good, stat.day.rx_bad, stat.day.tx_total
-    );
+fn net_state(ws: &Arc<WalletConn>) -> Result<()> {
+    let net_state = ws.get_net_state()?;
+    println!("{}", serde_json::to_string_pretty(&net_state)?);
+    Ok(())
 }
 
-fn calc_overall_stats(stats: &HashMap<String, LastConnStat>) -> LastConnStat {
-    let mut total_sec = StatsPerPeriod::default();
-    let mut total_min = StatsPerPeriod::default();
-    let mut total_hour = StatsPerPeriod::default();
-    let mut total_day = StatsPerPeriod::default();
+// show the window duration in the biggest unit, like 1d, 1h, 1m, 1s
+fn format_window(secs: u64) -> String {
+    if secs % 86400 == 0 {
+        format!("{}d", secs / 86400)
+    } else if secs % 3600 == 0 {
+        format!("{}h", secs / 3600)
+    } else if secs % 60 == 0 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
 
-    for stat in stats.values() {
-        total_sec.rx_good += stat.sec.rx_good;
-        total_sec.rx_bad += stat.sec.rx_bad;
-        total_sec.tx_total += stat.sec.tx_total;
+fn print_stats_matrix(stat: LastConnStat) {
+    println!("|           |  RX_GOOD |  RX_BAD  |    TX    |");
+    println!("|-----------|----------|----------|----------|");
 
-        total_min.rx_good += stat.min.rx_good;
-        total_min.rx_bad += stat.min.rx_bad;
-        total_min.tx_total += stat.min.tx_total;
+    for (window, stat) in stat.windows {
+        println!(
+            "| {:<9} | {:>8} | {:>8} | {:>8} |",
+            format!("LAST_{}", format_window(window)),
+            stat.rx_good,
+            stat.rx_bad,
+            stat.tx_total
+        );
+    }
+}
 
-        total_hour.rx_good += stat.hour.rx_good;
-        total_hour.rx_bad += stat.hour.rx_bad;
-        total_hour.tx_total += stat.hour.tx_total;
+fn calc_overall_stats(stats: &HashMap<String, LastConnStat>) -> LastConnStat {
+    let mut windows: Vec<(u64, StatsPerPeriod)> = Vec::new();
 
-        total_day.rx_good += stat.day.rx_good;
-        total_day.rx_bad += stat.day.rx_bad;
-        total_day.tx_total += stat.day.tx_total;
+    for stat in stats.values() {
+        for &(window, ref period) in &stat.windows {
+            match windows.iter_mut().find(|w| w.0 == window) {
+                Some(w) => w.1.add(period),
+                None => windows.push((window, *period)),
+            }
+        }
     }
+    windows.sort_by_key(|w| w.0);
 
     LastConnStat {
-        sec: total_sec,
-        min: total_min,
-        hour: total_hour,
-        day: total_day,
+        windows,
         ..Default::default()
     }
 }
@@ -218,6 +239,60 @@ fn net_state_info(ws: &Arc<WalletConn>) -> Result<()> {
     Ok(())
 }
 
+fn print_utxos(title: &str, utxos: &[sdag::light::UtxoEntry]) {
+    println!("\n{}:", title);
+    for utxo in utxos {
+        let mci = match utxo.mci {
+            Some(mci) => mci.to_string(),
+            None => "-".to_owned(),
+        };
+        println!(
+            "  {} {:>14.6} MN  mci={}",
+            utxo.unit,
+            utxo.amount as f64 / 1_000_000.0,
+            mci
+        );
+    }
+
+    if utxos.is_empty() {
+        println!("  none");
+    }
+}
+
+// the amount is the received amount of the address, or the total sent amount
+fn print_live_joint(joint: &Joint, address: Option<&str>) {
+    use sdag::spec::Payload;
+
+    let unit = &joint.unit;
+    let from = &unit.authors[0].address;
+    let mut amount: i64 = 0;
+    for msg in &unit.messages {
+        if let Some(Payload::Payment(ref payment)) = msg.payload {
+            // the changes are not counted
+            for output in payment.outputs.iter().filter(|o| &o.address != from) {
+                match address {
+                    Some(address) if output.address == address => amount += output.amount as i64,
+                    Some(address) if from == address => amount -= output.amount as i64,
+                    Some(_) => {}
+                    None => amount += output.amount as i64,
+                }
+            }
+        }
+    }
+
+    let mci = match unit.main_chain_index {
+        Some(mci) => mci.to_string(),
+        None => "-".to_owned(),
+    };
+    println!(
+        "#{} {} {:+.6} MN from {}",
+        mci,
+        &unit.unit[..8],
+        amount as f64 / 1_000_000.0,
+        &from[..8]
+    );
+}
+
 fn show_history(
     ws: &Arc<WalletConn>,
     address: &str,
@@ -273,19 +348,21 @@ fn show_history(
     Ok(())
 }
 
+// where to print the dry run joint
+enum DryRun<'a> {
+    Stdout,
+    File(&'a str),
+}
+
 fn send_payment(
     ws: &Arc<WalletConn>,
     text: Option<&str>,
     address_amount: Vec<(String, f64)>,
     wallet_info: &WalletInfo,
+    wait_stable: Option<Duration>,
+    send_all: bool,
+    dry_run: Option<DryRun>,
 ) -> Result<()> {
-    let text_message = match text {
-        Some(msg) => Some(sdag::composer::create_text_message(msg)?),
-        None => None,
-    };
-
-    let light_props = ws.get_light_props(&wallet_info._00_address)?;
-
     let outputs = address_amount
         .iter()
         .map(|(address, amount)| sdag::spec::Output {
@@ -294,39 +371,60 @@ fn send_payment(
         })
         .collect::<Vec<_>>();
 
-    let total_amount = outputs.iter().fold(0, |acc, x| acc + x.amount);
-
-    let inputs: sdag::light::InputsResponse = ws.get_inputs_from_hub(
-        &wallet_info._00_address,
-        total_amount + 1000, // we need another 1000 sdg (usually 431 + 197)
-        false,               // is_spend_all
-        &light_props.last_ball_unit,
-    )?;
-
-    let compose_info = sdag::composer::ComposeInfo {
-        paid_address: wallet_info._00_address.clone(),
-        change_address: wallet_info._00_address.clone(),
-        outputs,
-        text_message,
-        inputs,
-        transaction_amount: total_amount,
-        light_props,
-        pubk: wallet_info._00_address_pubk.to_base64_key(),
-    };
-
-    let joint = sdag::composer::compose_joint(compose_info, wallet_info)?;
-
-    if let Err(e) = ws.post_joint(&joint) {
-        eprintln!("post_joint err={}", e);
-        return Err(e);
+    warn_unconfirmed_inputs(ws, &wallet_info._00_address);
+
+    // the joint is the same as the posted one, so raw_post could post it later
+    if let Some(dry_run) = dry_run {
+        let joint = sdag::composer::compose_payment(ws, wallet_info, outputs, text, send_all)?;
+        match dry_run {
+            DryRun::Stdout => println!("{}", serde_json::to_string_pretty(&joint)?),
+            DryRun::File(output) => {
+                let file = ::std::fs::File::create(output)?;
+                serde_json::to_writer_pretty(&file, &joint)?;
+                println!(
+                    "saved unit {} to {}, headers_commission = {}, payload_commission = {}",
+                    joint.unit.unit,
+                    output,
+                    joint.unit.headers_commission.unwrap_or(0),
+                    joint.unit.payload_commission.unwrap_or(0)
+                );
+            }
+        }
+        return Ok(());
     }
 
+    let unit = match wait_stable {
+        Some(timeout) if !send_all => {
+            match sdag::composer::pay_and_wait(ws, wallet_info, outputs, text, timeout) {
+                Ok((unit, mci)) => {
+                    println!("STABLE: mci = {}", mci.value());
+                    unit
+                }
+                Err(e) => {
+                    eprintln!("pay_and_wait err={}", e);
+                    return Err(e);
+                }
+            }
+        }
+        Some(timeout) => {
+            let joint = sdag::composer::compose_payment(ws, wallet_info, outputs, text, true)?;
+            let mci = ws.post_joint_and_wait_stable(&joint, timeout)?;
+            println!("STABLE: mci = {}", mci.value());
+            joint.unit.unit
+        }
+        None => post_payment(ws, text, outputs, wallet_info, send_all)?,
+    };
+
     println!("FROM  : {}", wallet_info._00_address);
     println!("TO    : ");
     for (address, amount) in address_amount {
-        println!("      address : {}, amount : {}", address, amount);
+        if send_all {
+            println!("      address : {}, amount : all", address);
+        } else {
+            println!("      address : {}, amount : {}", address, amount);
+        }
     }
-    println!("UNIT  : {}", joint.unit.unit);
+    println!("UNIT  : {}", unit);
 
     if text.is_some() {
         println!("TEXT  : {}", text.unwrap_or(""));
@@ -342,6 +440,43 @@ fn send_payment(
     Ok(())
 }
 
+// the utxos spent by the pending joints can't be used until they are stable
+fn warn_unconfirmed_inputs(ws: &WalletConn, address: &str) {
+    let inputs = match ws.get_unconfirmed_inputs(address) {
+        Ok(inputs) => inputs,
+        // the old hubs don't support it, just skip the warning
+        Err(_) => return,
+    };
+    if inputs.is_empty() {
+        return;
+    }
+
+    let amount: u64 = inputs.iter().map(|k| k.amount).sum();
+    eprintln!(
+        "WARNING: {} utxos ({}) are spent by pending joints, they can't be spent until stable",
+        inputs.len(),
+        amount as f64 / 1_000_000.0
+    );
+}
+
+// compose and post the payment, return the unit hash
+fn post_payment(
+    ws: &Arc<WalletConn>,
+    text: Option<&str>,
+    outputs: Vec<sdag::spec::Output>,
+    wallet_info: &WalletInfo,
+    send_all: bool,
+) -> Result<String> {
+    let joint = sdag::composer::compose_payment(ws, wallet_info, outputs, text, send_all)?;
+
+    if let Err(e) = ws.post_joint(&joint) {
+        eprintln!("post_joint err={}", e);
+        return Err(e);
+    }
+
+    Ok(joint.unit.unit)
+}
+
 fn verify_joints(joints: Vec<Joint>, last_mci: usize) -> Result<()> {
     if joints.is_empty() {
         return Ok(());
@@ -353,7 +488,7 @@ fn verify_joints(joints: Vec<Joint>, last_mci: usize) -> Result<()> {
     let now = Instant::now();
     let sem = Arc::new(Semphore::new(0));
     let total_joints = joints.len();
-    register_event_handlers(last_mci, sem.clone());
+    let _handler_guard = register_event_handlers(last_mci, sem.clone());
     for joint in joints {
         try_go!(move || {
             // check content_hash or unit_hash first!
@@ -391,16 +526,16 @@ fn verify_joints(joints: Vec<Joint>, last_mci: usize) -> Result<()> {
     Ok(())
 }
 
-// register global event handlers
-fn register_event_handlers(last_mci: usize, sem: Arc<Semphore>) {
+// register global event handlers, they are removed when the guard is dropped
+fn register_event_handlers(last_mci: usize, sem: Arc<Semphore>) -> HandlerGuard {
     use sdag::main_chain::MciStableEvent;
     use sdag::utils::event::Event;
 
-    MciStableEvent::add_handler(move |v| {
+    MciStableEvent::add_handler_guard(move |v| {
         if v.mci.value() == last_mci {
             sem.post();
         }
-    });
+    })
 }
 
 fn main() -> Result<()> {
@@ -431,6 +566,16 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    // multisig command
+    if let Some(multisig) = m.subcommand_matches("multisig") {
+        return handle_subcommand_multisig(multisig);
+    }
+
+    // db command, works on the local database
+    if let Some(db) = m.subcommand_matches("db") {
+        return handle_subcommand_db(db);
+    }
+
     let settings = sdag::config::get_settings();
     let ws = connect_to_remote(&settings.hub_url)?;
 
@@ -453,6 +598,9 @@ fn main() -> Result<()> {
 
     //info
     if let Some(info_args) = m.subcommand_matches("info") {
+        if info_args.values_of("stats").is_some() {
+            return address_stats(&ws, &wallet_info._00_address);
+        }
         let is_json = info_args.values_of("j").is_some();
         return info(&ws, wallet_info, is_json);
     }
@@ -512,13 +660,56 @@ fn main() -> Result<()> {
             }
         }
 
+        // the amount is decided by the spendable outputs
+        let send_all = send.is_present("all");
+        if let Some(address) = send.value_of("all") {
+            if !object_hash::is_chash_valid(address) {
+                eprintln!("invalid address, please check");
+                return Ok(());
+            }
+            address_amount.push((address.to_string(), 0.0));
+        }
+
         let text = send.value_of("text");
+        let wait_stable = if send.values_of("wait-stable").is_some() {
+            Some(Duration::from_secs(value_t!(
+                send.value_of("timeout"),
+                u64
+            )?))
+        } else {
+            None
+        };
+
+        let dry_run = if send.is_present("dry-run") {
+            Some(match send.value_of("output") {
+                Some(output) => DryRun::File(output),
+                None => DryRun::Stdout,
+            })
+        } else {
+            None
+        };
 
-        return send_payment(&ws, text, address_amount, wallet_info);
+        return send_payment(
+            &ws,
+            text,
+            address_amount,
+            wallet_info,
+            wait_stable,
+            send_all,
+            dry_run,
+        );
     }
 
     //balance
-    if m.subcommand_matches("balance").is_some() {
+    if let Some(balance) = m.subcommand_matches("balance") {
+        if balance.is_present("utxos") {
+            let details = ws.get_balance_details(&wallet_info._00_address)?;
+            println!("balance: {:.6}", details.balance as f64 / 1_000_000.0);
+            print_utxos("stable utxos", &details.stable_utxos);
+            print_utxos("pending utxos", &details.pending_utxos);
+            return Ok(());
+        }
+
         println!(
             "{:.6}",
             ws.get_balance(&wallet_info._00_address)? as f64 / 1_000_000.0
@@ -542,6 +733,10 @@ fn main() -> Result<()> {
         return handle_subcommand_unit(unit_args, &ws);
     }
 
+    if let Some(debug) = m.subcommand_matches("debug") {
+        return handle_subcommand_debug(debug, &ws);
+    }
+
     if let Some(dump_args) = m.subcommand_matches("dump") {
         let is_verbose = dump_args.values_of("verbose").is_some();
 
@@ -589,14 +784,210 @@ fn main() -> Result<()> {
                 .map(|v| v.to_string())
                 .collect::<Vec<String>>();
             ws.add_watcher(&addr)?;
+            return Ok(());
         }
 
-        return Ok(());
+        let address = watch.value_of("address").map(|s| s.to_owned());
+        let filter = address.clone();
+        let _handle = ws.subscribe_live(address, move |joint| {
+            print_live_joint(&joint, filter.as_ref().map(|s| s.as_str()))
+        })?;
+
+        // keep printing until Ctrl-C
+        loop {
+            may::coroutine::sleep(Duration::from_secs(1));
+        }
+    }
+
+    Ok(())
+}
+
+fn read_joint_file(file: &str) -> Result<Joint> {
+    let file = ::std::fs::File::open(file).context(format!("failed to open {}", file))?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+fn write_joint_file(file: &str, joint: &Joint) -> Result<()> {
+    let file = ::std::fs::File::create(file).context(format!("failed to create {}", file))?;
+    serde_json::to_writer_pretty(file, joint)?;
+    Ok(())
+}
+
+// the sig options of a multisig definition
+fn get_multisig_options(definition: &serde_json::Value) -> Result<&Vec<serde_json::Value>> {
+    let options = match definition[0].as_str() {
+        Some("and") => definition[1].as_array(),
+        Some("r of set") => definition[1]["set"].as_array(),
+        _ => None,
+    };
+
+    options.ok_or_else(|| format_err!("not a multisig definition: {}", definition))
+}
+
+fn multisig_create(pubkeys: Vec<&str>, required: usize) -> Result<()> {
+    if pubkeys.len() < 2 {
+        bail!("multisig address needs at least 2 pubkeys");
+    }
+    if required == 0 || required > pubkeys.len() {
+        bail!("required must be in [1, {}]", pubkeys.len());
+    }
+
+    let options = pubkeys
+        .iter()
+        .map(|pubkey| json!(["sig", { "pubkey": pubkey }]))
+        .collect::<Vec<_>>();
+
+    let definition = if required == pubkeys.len() {
+        json!(["and", options])
+    } else {
+        json!(["r of set", { "required": required, "set": options }])
+    };
+    let address = object_hash::get_chash(&definition)?;
+
+    println!("DEFINITION : {}", definition);
+    println!("ADDRESS    : {}", address);
+    Ok(())
+}
+
+fn multisig_sign(
+    joint_file: &str,
+    key_index: usize,
+    output: &str,
+    wallet_info: &WalletInfo,
+) -> Result<()> {
+    let mut joint = read_joint_file(joint_file)?;
+    let hash = joint.unit.calc_unit_hash_to_sign();
+    let pubkey = wallet_info._00_address_pubk.to_base64_key();
+
+    let author = joint
+        .unit
+        .authors
+        .get_mut(0)
+        .ok_or_else(|| format_err!("no author in joint"))?;
+
+    {
+        let options = get_multisig_options(&author.definition)?;
+        let option = options
+            .get(key_index)
+            .ok_or_else(|| format_err!("invalid key index {}", key_index))?;
+        if option[1]["pubkey"].as_str() != Some(pubkey.as_str()) {
+            bail!("the key at index {} is not the wallet pubkey", key_index);
+        }
+    }
+
+    let sig = sdag_wallet_base::sign(&hash, &wallet_info._00_address_prvk)?;
+    author.authentifiers.clear();
+    author.authentifiers.insert(format!("r.{}", key_index), sig);
+
+    write_joint_file(output, &joint)?;
+    println!("partial signed joint saved to {}", output);
+    Ok(())
+}
+
+fn multisig_combine(partial1: &str, partial2: &str, output: &str) -> Result<()> {
+    let mut joint = read_joint_file(partial1)?;
+    let other = read_joint_file(partial2)?;
+
+    let hash = joint.unit.calc_unit_hash_to_sign();
+    if hash != other.unit.calc_unit_hash_to_sign() {
+        bail!("the partial signed joints are not the same unit");
     }
 
+    {
+        let author = joint
+            .unit
+            .authors
+            .get_mut(0)
+            .ok_or_else(|| format_err!("no author in joint"))?;
+        for (path, sig) in &other.unit.authors[0].authentifiers {
+            author.authentifiers.insert(path.clone(), sig.clone());
+        }
+
+        validation::validate_authentifiers(
+            &serde_json::Value::Null,
+            &author.definition,
+            &hash,
+            &author.authentifiers,
+        )?;
+    }
+
+    joint.unit.unit = joint.unit.calc_unit_hash();
+    write_joint_file(output, &joint)?;
+    println!("UNIT  : {}", joint.unit.unit);
+    println!("final joint saved to {}", output);
     Ok(())
 }
 
+fn handle_subcommand_multisig(multisig: &clap::ArgMatches) -> Result<()> {
+    if let Some(create) = multisig.subcommand_matches("create") {
+        let pubkeys = create.values_of("pubkeys").unwrap().collect::<Vec<_>>();
+        let required = value_t!(create.value_of("required"), usize).unwrap_or_else(|e| e.exit());
+        return multisig_create(pubkeys, required);
+    }
+
+    if let Some(sign) = multisig.subcommand_matches("sign") {
+        let key_index = value_t!(sign.value_of("key-index"), usize).unwrap_or_else(|e| e.exit());
+        return multisig_sign(
+            sign.value_of("joint").unwrap(),
+            key_index,
+            sign.value_of("output").unwrap(),
+            &MY_WALLET,
+        );
+    }
+
+    if let Some(combine) = multisig.subcommand_matches("combine") {
+        return multisig_combine(
+            combine.value_of("partial1").unwrap(),
+            combine.value_of("partial2").unwrap(),
+            combine.value_of("output").unwrap(),
+        );
+    }
+
+    bail!("invalid argument value")
+}
+
+fn handle_subcommand_db(db: &clap::ArgMatches) -> Result<()> {
+    use sdag::kv_store::KV_STORE;
+    use std::path::Path;
+
+    if let Some(export) = db.subcommand_matches("export") {
+        let output = export.value_of("output").unwrap();
+        let count = KV_STORE.export_to_jsonl(Path::new(output))?;
+        println!("exported {} entries to {}", count, output);
+        return Ok(());
+    }
+
+    if let Some(import) = db.subcommand_matches("import") {
+        let file = import.value_of("file").unwrap();
+        let force = import.is_present("force");
+        let count = KV_STORE.import_from_jsonl(Path::new(file), force)?;
+        // finish() would overwrite the imported business snapshot with the empty state
+        KV_STORE.flush()?;
+        println!("imported {} entries from {}", count, file);
+        return Ok(());
+    }
+
+    bail!("invalid argument value")
+}
+
+fn handle_subcommand_debug(debug: &clap::ArgMatches, ws: &Arc<WalletConn>) -> Result<()> {
+    if let Some(export) = debug.subcommand_matches("export-state") {
+        let output = export.value_of("output").unwrap();
+        let state = ws.export_business_state()?;
+        let file = ::std::fs::File::create(output)?;
+        serde_json::to_writer_pretty(&file, &state)?;
+        println!(
+            "exported {} utxos at mci {} to {}",
+            state.utxo_count,
+            state.snapshot_mci.value(),
+            output
+        );
+        return Ok(());
+    }
+
+    bail!("invalid argument value")
+}
+
 #[inline]
 fn print_unit_hash_list(list: Vec<String>, item_type: &str) {
     for (index, hash) in list.iter().enumerate() {
@@ -695,6 +1086,15 @@ fn handle_subcommand_unit(unit_args: &clap::ArgMatches, ws: &Arc<WalletConn>) ->
         return Ok(());
     }
 
+    // show the joints that double spend with a specified unit hash
+    if let Some(hash) = unit_args.value_of("conflicts") {
+        print_unit_hash_list(
+            ws.get_double_spends(hash)?,
+            &format!("{}'s conflicts", hash),
+        );
+        return Ok(());
+    }
+
     if let Some(unit) = unit_args.value_of("text") {
         let text = ws.get_text(unit)?;
         serde_json::to_writer_pretty(std::io::stdout(), &text)?;
@@ -702,5 +1102,29 @@ fn handle_subcommand_unit(unit_args: &clap::ArgMatches, ws: &Arc<WalletConn>) ->
         return Ok(());
     }
 
+    // show the ancestors chain along the best parent
+    if let Some(unit) = unit_args.value_of("ancestors") {
+        let depth = value_t!(unit_args.value_of("depth"), usize)?;
+        let chain = ws.get_ancestors_chain(unit, depth)?;
+        for (i, (joint, property)) in chain.iter().enumerate() {
+            let author = joint
+                .unit
+                .authors
+                .get(0)
+                .map(|a| a.address.as_str())
+                .unwrap_or("");
+            println!(
+                "{:indent$}{} level={:?} mci={:?} author={}",
+                "",
+                &joint.unit.unit[..::std::cmp::min(12, joint.unit.unit.len())],
+                property.level,
+                property.mci,
+                author,
+                indent = i * 2
+            );
+        }
+        return Ok(());
+    }
+
     bail!("invalid argument value")
 }
@@ -0,0 +1,276 @@
+use std::cmp;
+use std::collections::HashMap as StdHashMap;
+
+use hashbrown::HashMap;
+use joint::Level;
+use spec::{Payload, Unit};
+
+/// the accumulated statistics of an address, only stable joints are counted
+/// the earned commissions are not included
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AddressStats {
+    pub balance: u64,
+    pub total_received: u64,
+    pub total_sent: u64,
+    pub tx_count: u64,
+    pub first_seen_mci: Level,
+    pub last_seen_mci: Level,
+}
+
+impl AddressStats {
+    fn touch(&mut self, mci: Level) {
+        self.tx_count += 1;
+        if !self.first_seen_mci.is_valid() {
+            self.first_seen_mci = mci;
+        }
+        self.last_seen_mci = mci;
+    }
+}
+
+#[derive(Default)]
+pub struct AddressStatsCache {
+    stats: HashMap<String, AddressStats>,
+}
+
+impl AddressStatsCache {
+    pub fn from_map(stats: StdHashMap<String, AddressStats>) -> Self {
+        AddressStatsCache {
+            stats: stats.into_iter().collect(),
+        }
+    }
+
+    pub fn get(&self, address: &str) -> Option<AddressStats> {
+        self.stats.get(address).cloned()
+    }
+
+    pub fn to_map(&self) -> StdHashMap<String, AddressStats> {
+        self.stats
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// update the stats of the addresses involved in the stable unit
+    /// inputs are the owner and amount of the spent inputs, only multi-author units need them
+    /// return the updated stats
+    pub fn apply_unit(
+        &mut self,
+        unit: &Unit,
+        mci: Level,
+        inputs: &[(String, u64)],
+    ) -> Vec<(String, AddressStats)> {
+        let authors = unit
+            .authors
+            .iter()
+            .map(|a| a.address.as_str())
+            .collect::<Vec<_>>();
+        // genesis issues the outputs, it has no inputs
+        let is_genesis = unit.is_genesis_unit();
+
+        // outputs to the authors are changes, which are neither received nor sent
+        let mut received = HashMap::<&str, u64>::new();
+        let mut changes = HashMap::<&str, u64>::new();
+        for msg in &unit.messages {
+            if let Some(Payload::Payment(ref payment)) = msg.payload {
+                for output in &payment.outputs {
+                    let address = output.address.as_str();
+                    if !is_genesis && authors.contains(&address) {
+                        *changes.entry(address).or_insert(0) += output.amount;
+                    } else {
+                        *received.entry(address).or_insert(0) += output.amount;
+                    }
+                }
+            }
+        }
+
+        let commission = u64::from(unit.headers_commission.unwrap_or(0))
+            + u64::from(unit.payload_commission.unwrap_or(0));
+        // <author, sent, paid>, the paid amount includes the commission
+        let mut outflows = Vec::with_capacity(authors.len());
+        if is_genesis || authors.len() == 1 {
+            let sent = if is_genesis {
+                0
+            } else {
+                received.values().sum::<u64>()
+            };
+            outflows.push((authors[0], sent, sent + commission));
+        } else {
+            // each author pays its inputs minus its changes, a negative one is received
+            // the commission is taken from the paying authors in order
+            let mut commission_left = commission;
+            for &author in &authors {
+                let paid = inputs
+                    .iter()
+                    .filter(|(owner, _)| owner == author)
+                    .map(|(_, amount)| amount)
+                    .sum::<u64>();
+                let change = changes.get(author).cloned().unwrap_or(0);
+                if paid < change {
+                    *received.entry(author).or_insert(0) += change - paid;
+                    outflows.push((author, 0, 0));
+                    continue;
+                }
+                let paid = paid - change;
+                let fee = cmp::min(paid, commission_left);
+                commission_left -= fee;
+                outflows.push((author, paid - fee, paid));
+            }
+        }
+
+        for (author, sent, paid) in outflows {
+            let stats = self
+                .stats
+                .entry(author.to_owned())
+                .or_insert_with(AddressStats::default);
+            stats.total_sent += sent;
+            stats.balance = stats.balance.saturating_sub(paid);
+            stats.touch(mci);
+        }
+
+        let mut updated = Vec::with_capacity(received.len() + authors.len());
+        for (address, amount) in received {
+            let stats = self
+                .stats
+                .entry(address.to_owned())
+                .or_insert_with(AddressStats::default);
+            stats.total_received += amount;
+            stats.balance += amount;
+            // the authors are already counted
+            if !authors.contains(&address) {
+                stats.touch(mci);
+                updated.push((address.to_owned(), stats.clone()));
+            }
+        }
+
+        for author in authors {
+            updated.push((author.to_owned(), self.stats[author].clone()));
+        }
+        updated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use spec::{Author, Message, Output, Payment};
+    use std::collections::HashMap as StdHashMap;
+
+    const ADDRESS_A: &str = "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI";
+    const ADDRESS_B: &str = "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE";
+    const ADDRESS_C: &str = "JERTY5XNENMHYQW7NVBXUB5CU3IDODA3";
+
+    // pay the amount and send the changes back
+    fn payment_unit(from: &str, to: &str, amount: u64) -> Unit {
+        let payment = Payment {
+            address: None,
+            asset: None,
+            definition_chash: None,
+            denomination: None,
+            inputs: Vec::new(),
+            outputs: vec![
+                Output {
+                    address: from.to_owned(),
+                    amount: 1_000,
+                },
+                Output {
+                    address: to.to_owned(),
+                    amount,
+                },
+            ],
+        };
+
+        let mut unit = Unit::default();
+        unit.parent_units.push("parent".to_owned());
+        unit.authors.push(Author {
+            address: from.to_owned(),
+            authentifiers: StdHashMap::new(),
+            definition: Value::Null,
+        });
+        unit.messages.push(Message {
+            app: "payment".to_owned(),
+            payload: Some(Payload::Payment(payment)),
+            ..Default::default()
+        });
+        unit
+    }
+
+    #[test]
+    fn test_address_stats() {
+        let mut cache = AddressStatsCache::default();
+        let mut mci = 1;
+
+        for _ in 0..5 {
+            cache.apply_unit(
+                &payment_unit(ADDRESS_B, ADDRESS_A, 1_000_000),
+                Level::new(mci),
+                &[],
+            );
+            mci += 1;
+        }
+        for _ in 0..3 {
+            cache.apply_unit(
+                &payment_unit(ADDRESS_A, ADDRESS_B, 500_000),
+                Level::new(mci),
+                &[],
+            );
+            mci += 1;
+        }
+
+        let stats = cache.get(ADDRESS_A).unwrap();
+        assert_eq!(stats.total_received, 5_000_000);
+        assert_eq!(stats.total_sent, 1_500_000);
+        assert_eq!(stats.tx_count, 8);
+        assert_eq!(stats.balance, 3_500_000);
+        assert_eq!(stats.first_seen_mci, Level::new(1));
+        assert_eq!(stats.last_seen_mci, Level::new(8));
+
+        assert!(cache.get("UNKNOWN").is_none());
+    }
+
+    #[test]
+    fn test_multi_author_stats() {
+        let mut cache = AddressStatsCache::default();
+        cache.apply_unit(
+            &payment_unit(ADDRESS_C, ADDRESS_A, 1_000_000),
+            Level::new(1),
+            &[],
+        );
+        cache.apply_unit(
+            &payment_unit(ADDRESS_C, ADDRESS_B, 1_000_000),
+            Level::new(2),
+            &[],
+        );
+
+        // A pays 500_000 and B pays 400_000, C receives 600_000 and the commission is 100
+        let mut unit = payment_unit(ADDRESS_A, ADDRESS_C, 600_000);
+        unit.headers_commission = Some(100);
+        unit.authors.push(unit.authors[0].clone());
+        unit.authors[1].address = ADDRESS_B.to_owned();
+        if let Some(Payload::Payment(ref mut payment)) = unit.messages[0].payload {
+            payment.outputs.push(Output {
+                address: ADDRESS_B.to_owned(),
+                amount: 298_900,
+            });
+        }
+        let inputs = [
+            (ADDRESS_A.to_owned(), 500_000),
+            (ADDRESS_B.to_owned(), 400_000),
+        ];
+        let updated = cache.apply_unit(&unit, Level::new(3), &inputs);
+        assert_eq!(updated.len(), 3);
+
+        let stats_a = cache.get(ADDRESS_A).unwrap();
+        assert_eq!(stats_a.total_sent, 498_900);
+        assert_eq!(stats_a.balance, 501_000);
+        assert_eq!(stats_a.tx_count, 2);
+
+        let stats_b = cache.get(ADDRESS_B).unwrap();
+        assert_eq!(stats_b.total_sent, 101_100);
+        assert_eq!(stats_b.balance, 898_900);
+        assert_eq!(stats_b.tx_count, 2);
+        assert_eq!(stats_b.last_seen_mci, Level::new(3));
+
+        assert_eq!(cache.get(ADDRESS_C).unwrap().total_received, 600_000);
+    }
+}
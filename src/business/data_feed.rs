@@ -1,12 +1,22 @@
 use super::SubBusiness;
-use cache::JointData;
+use cache::{JointData, SDAG_CACHE};
 use config;
 use error::Result;
+use hashbrown::HashMap;
+use joint::Level;
+use kv_store::KV_STORE;
+use main_chain;
+use serde_json::Value;
 use spec::{Message, Payload};
 
-#[derive(Default)]
+/// the latest stable feed value <(address, feed_name), (value, unit, mci)>
+pub type DataFeedCache = HashMap<(String, String), (Value, String, Level)>;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct TimerCache {
     cur_time: u64,
+    #[serde(with = "super::utxo::map_entries")]
+    feeds: DataFeedCache,
 }
 
 impl SubBusiness for TimerCache {
@@ -25,10 +35,32 @@ impl SubBusiness for TimerCache {
         Ok(())
     }
 
-    fn apply_message(&mut self, _joint: &JointData, _message_idx: usize) -> Result<()> {
+    fn apply_message(&mut self, joint: &JointData, message_idx: usize) -> Result<()> {
         // TODO: update the current time
         self.cur_time = crate::time::now();
-        unimplemented!()
+
+        // only stable feeds are saved, the temp state has no mci yet
+        let mci = joint.get_mci();
+        if !mci.is_valid() {
+            return Ok(());
+        }
+
+        let message = &joint.unit.messages[message_idx];
+        if let Some(Payload::Other(ref v)) = message.payload {
+            if let Some(map) = v.as_object() {
+                for author in &joint.unit.authors {
+                    for (feed_name, value) in map {
+                        KV_STORE.save_feed_value(&author.address, feed_name, mci, value)?;
+                        self.feeds.insert(
+                            (author.address.clone(), feed_name.clone()),
+                            (value.clone(), joint.unit.unit.clone(), mci),
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
     fn revert_message(&mut self, _joint: &JointData, _message_idx: usize) -> Result<()> {
@@ -36,6 +68,75 @@ impl SubBusiness for TimerCache {
     }
 }
 
+impl TimerCache {
+    /// get the latest stable value of the feed posted by the address
+    pub fn get_latest_feed(
+        &self,
+        address: &str,
+        feed_name: &str,
+    ) -> Option<&(Value, String, Level)> {
+        self.feeds.get(&(address.to_owned(), feed_name.to_owned()))
+    }
+
+    /// cache the latest feed value that is read back from kv
+    pub fn cache_latest_feed(
+        &mut self,
+        address: &str,
+        feed_name: &str,
+        feed: (Value, String, Level),
+    ) {
+        self.feeds
+            .entry((address.to_owned(), feed_name.to_owned()))
+            .or_insert(feed);
+    }
+
+    /// read the latest stable value of the feed from kv, with the unit that posted it
+    pub fn read_latest_feed(
+        address: &str,
+        feed_name: &str,
+    ) -> Result<Option<(Value, String, Level)>> {
+        let last_mci = main_chain::get_last_stable_mci();
+        let (mci, value) = match KV_STORE
+            .read_feed_range(address, feed_name, Level::ZERO, last_mci)?
+            .pop()
+        {
+            Some(feed) => feed,
+            None => return Ok(None),
+        };
+
+        // kv only keeps the mci, find the unit of the address that posted the feed
+        for joint in SDAG_CACHE.get_joints_by_mci(mci)? {
+            let joint = joint.read()?;
+            if !joint.unit.authors.iter().any(|a| a.address == address) {
+                continue;
+            }
+            let has_feed = joint.unit.messages.iter().any(|m| match m.payload {
+                Some(Payload::Other(ref v)) if m.app == "data_feed" => v.get(feed_name).is_some(),
+                _ => false,
+            });
+            if has_feed {
+                return Ok(Some((value, joint.unit.unit.clone(), mci)));
+            }
+        }
+        bail!(
+            "no unit of {} posted feed {} at mci {}",
+            address,
+            feed_name,
+            mci.value()
+        )
+    }
+
+    /// get the feed values posted by the address in the mci range [from_mci, to_mci]
+    pub fn get_feed_history(
+        feed_name: &str,
+        address: &str,
+        from_mci: Level,
+        to_mci: Level,
+    ) -> Result<Vec<(Level, Value)>> {
+        KV_STORE.read_feed_range(address, feed_name, from_mci, to_mci)
+    }
+}
+
 fn validate_datafeed(message: &Message) -> Result<()> {
     match message.payload.as_ref() {
         Some(Payload::Other(ref v)) => {
@@ -70,3 +171,51 @@ fn validate_datafeed(message: &Message) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use joint::Joint;
+    use spec::UnitBuilder;
+
+    const ADDRESS_A: &str = "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI";
+    const ADDRESS_B: &str = "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE";
+
+    #[test]
+    fn test_latest_data_feed() {
+        let feed_joint = |feeds: Value, mci: usize| {
+            let unit = UnitBuilder::new()
+                .author(ADDRESS_A, Default::default(), Default::default())
+                .message("data_feed", Payload::Other(feeds))
+                .parent("genesis")
+                .build()
+                .unwrap();
+            let joint_data = JointData::from_joint(
+                Joint {
+                    ball: None,
+                    skiplist_units: Vec::new(),
+                    unit,
+                },
+                None,
+            );
+            joint_data.set_mci(Level::new(mci));
+            joint_data
+        };
+
+        let mut data_feed = TimerCache::default();
+        let first = feed_joint(json!({"BTC_USD": 6000, "ETH_USD": 200}), 3);
+        let second = feed_joint(json!({"BTC_USD": 6100}), 5);
+        data_feed.apply_message(&first, 0).unwrap();
+        data_feed.apply_message(&second, 0).unwrap();
+
+        let (value, unit, mci) = data_feed.get_latest_feed(ADDRESS_A, "BTC_USD").unwrap();
+        assert_eq!(value, &json!(6100));
+        assert_eq!(unit, &second.unit.unit);
+        assert_eq!(*mci, Level::new(5));
+
+        let (value, unit, _) = data_feed.get_latest_feed(ADDRESS_A, "ETH_USD").unwrap();
+        assert_eq!(value, &json!(200));
+        assert_eq!(unit, &first.unit.unit);
+        assert!(data_feed.get_latest_feed(ADDRESS_B, "BTC_USD").is_none());
+    }
+}
@@ -1,23 +1,38 @@
-mod data_feed;
+mod address_stats;
+pub mod data_feed;
+pub mod payload_store;
+mod state_export;
 pub mod text;
 mod utxo;
 
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use self::utxo::{UtxoData, UtxoKey};
+pub use self::address_stats::AddressStats;
+pub use self::state_export::{BusinessStateExport, UtxoExport};
+pub use self::utxo::{UtxoCache, UtxoKey};
+
+use self::utxo::UtxoData;
 use cache::{CachedJoint, JointData, SDAG_CACHE};
 use config;
 use error::Result;
 use hashbrown::HashMap;
-use joint::{JointSequence, Level};
+use joint::{Joint, JointSequence, Level};
+use kv_store::{self, KV_STORE};
 use may::coroutine::JoinHandle;
-use may::sync::{mpsc, RwLock};
+use may::sync::{mpsc, Mutex, RwLock};
 use rcu_cell::RcuReader;
 use sdag_object_base::object_hash;
+use serde_json::Value;
 use spec::*;
+use statistics;
+use utils::{OnceOption, Watchdog};
 
 lazy_static! {
     pub static ref BUSINESS_WORKER: BusinessWorker = BusinessWorker::default();
+    static ref BUSINESS_WATCHDOG: OnceOption<Watchdog> = OnceOption::new();
     pub static ref BUSINESS_CACHE: BusinessCache =
         BusinessCache::rebuild_from_db().expect("failed to rebuild business state");
 }
@@ -47,90 +62,90 @@ pub trait SubBusiness {
 //---------------------------------------------------------------------------------------
 pub struct BusinessWorker {
     tx: mpsc::Sender<RcuReader<JointData>>,
+    // number of joints that are still in the queue
+    queue_len: Arc<AtomicUsize>,
     _handler: JoinHandle<()>,
 }
 
 impl Default for BusinessWorker {
     fn default() -> Self {
         let (tx, rx) = mpsc::channel();
+        let queue_len = Arc::new(AtomicUsize::new(0));
 
-        let _handler = start_business_worker(rx);
+        let _handler = start_business_worker(rx, queue_len.clone());
 
-        BusinessWorker { tx, _handler }
+        BusinessWorker {
+            tx,
+            queue_len,
+            _handler,
+        }
     }
 }
 
 impl BusinessWorker {
     // the main chain logic would call this API to push stable joint in order
     pub fn push_stable_joint(&self, joint: RcuReader<JointData>) -> Result<()> {
+        self.queue_len.fetch_add(1, Ordering::Relaxed);
         self.tx.send(joint)?;
         Ok(())
     }
+
+    /// get the number of stable joints that wait to be processed
+    pub fn get_queue_len(&self) -> usize {
+        self.queue_len.load(Ordering::Relaxed)
+    }
+}
+
+/// monitor the business worker, report it if it stalled longer than timeout
+pub fn start_watchdog(timeout: Duration) {
+    if BUSINESS_WATCHDOG
+        .set(Watchdog::start("business", timeout))
+        .is_some()
+    {
+        warn!("business watchdog already started");
+    }
 }
 
 // this would start the global thread to process the stable joints
-fn start_business_worker(rx: mpsc::Receiver<RcuReader<JointData>>) -> JoinHandle<()> {
+fn start_business_worker(
+    rx: mpsc::Receiver<RcuReader<JointData>>,
+    queue_len: Arc<AtomicUsize>,
+) -> JoinHandle<()> {
     go!(move || {
         while let Ok(joint) = rx.recv() {
-            // TODO: spend the commissions first
-            // if not enough we should set a special state and skip business validate and apply
-            // and the final_stage would clear the content
-
-            // TODO: add state transfer table
-
-            match BUSINESS_CACHE.validate_stable_joint(&joint) {
-                Ok(_) => {
-                    match joint.get_sequence() {
-                        JointSequence::NonserialBad | JointSequence::TempBad => {
-                            // apply the message to temp business state
-                            let mut temp_business_state =
-                                BUSINESS_CACHE.temp_business_state.write().unwrap();
-                            for i in 0..joint.unit.messages.len() {
-                                if let Err(e) = temp_business_state.apply_message(&joint, i) {
-                                    warn!("apply temp state failed, err = {}", e);
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
+            // pong the watchdog when the joint is done
+            let _g = BUSINESS_WATCHDOG.get().map(Watchdog::guard);
+
+            {
+                // the state is exported only between the joints
+                let mut progress = BUSINESS_CACHE.progress.lock().unwrap();
+                let mci = joint.get_mci();
+
+                if BUSINESS_CACHE.is_restored(mci) {
+                    // the joints before the restored snapshot are already applied
+                    restore_joint_sequence(&joint);
+                } else {
+                    process_stable_joint(&joint);
+                }
 
-                    if let Err(e) = BUSINESS_CACHE.apply_stable_joint(&joint) {
-                        // apply joint failed which should never happen
-                        // but we have to save it as a bad joint
-                        // we hope that the global state is still correct
-                        // like transactions
-                        error!(
-                            "apply_joint failed, unit = {}, err = {}",
-                            joint.unit.unit, e
-                        );
-                        joint.set_sequence(JointSequence::FinalBad);
+                // the main chain joint is the last stable joint of its mci
+                if joint.is_on_main_chain() {
+                    if !progress.applied_mci.is_valid() || mci > progress.applied_mci {
+                        progress.applied_mci = mci;
                     }
+                    progress.partial = false;
 
-                    if joint.get_sequence() != JointSequence::Good {
-                        joint.set_sequence(JointSequence::Good);
-                    }
-                }
-                Err(e) => {
-                    error!(
-                        "validate_joint failed, unit = {}, err = {}",
-                        joint.unit.unit, e
-                    );
-                    if let JointSequence::Good = joint.get_sequence() {
-                        let mut temp_business_state =
-                            BUSINESS_CACHE.temp_business_state.write().unwrap();
-                        for i in 0..joint.unit.messages.len() {
-                            if let Ok(true) = BUSINESS_CACHE.stable_utxo_contains(&joint, i) {
-                                if let Err(e) = temp_business_state.revert_message(&joint, i) {
-                                    error!("revert temp state failed, err = {}", e);
-                                }
-                            }
-                        }
+                    if BUSINESS_CACHE.should_save_snapshot(mci) {
+                        t!(BUSINESS_CACHE.save_snapshot(mci));
                     }
-
-                    joint.set_sequence(JointSequence::FinalBad);
+                } else {
+                    progress.partial = true;
                 }
             }
 
+            // decrease after applied, so that an empty queue means the state is up to date
+            queue_len.fetch_sub(1, Ordering::Relaxed);
+
             // FIXME: the joint may not exist due to purge temp-bad
             let joint = t_c!(SDAG_CACHE.get_joint(&joint.unit.unit));
             t_c!(::finalization::FINALIZATION_WORKER.push_final_joint(joint));
@@ -140,6 +155,76 @@ fn start_business_worker(rx: mpsc::Receiver<RcuReader<JointData>>) -> JoinHandle
     })
 }
 
+// validate and apply the stable joint, the bad joint is marked as final bad
+fn process_stable_joint(joint: &JointData) {
+    // TODO: spend the commissions first
+    // if not enough we should set a special state and skip business validate and apply
+    // and the final_stage would clear the content
+
+    // TODO: add state transfer table
+
+    match BUSINESS_CACHE.validate_stable_joint(joint) {
+        Ok(_) => {
+            match joint.get_sequence() {
+                JointSequence::NonserialBad | JointSequence::TempBad => {
+                    // apply the message to temp business state
+                    let mut temp_business_state =
+                        BUSINESS_CACHE.temp_business_state.write().unwrap();
+                    for i in 0..joint.unit.messages.len() {
+                        if let Err(e) = temp_business_state.apply_message(joint, i) {
+                            warn!("apply temp state failed, err = {}", e);
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if let Err(e) = BUSINESS_CACHE.apply_stable_joint(joint) {
+                // apply joint failed which should never happen
+                // but we have to save it as a bad joint
+                // we hope that the global state is still correct
+                // like transactions
+                error!(
+                    "apply_joint failed, unit = {}, err = {}",
+                    joint.unit.unit, e
+                );
+                joint.set_sequence(JointSequence::FinalBad);
+            }
+
+            if joint.get_sequence() != JointSequence::Good {
+                joint.set_sequence(JointSequence::Good);
+            }
+        }
+        Err(e) => {
+            error!(
+                "validate_joint failed, unit = {}, err = {}",
+                joint.unit.unit, e
+            );
+            if let JointSequence::Good = joint.get_sequence() {
+                let mut temp_business_state = BUSINESS_CACHE.temp_business_state.write().unwrap();
+                for i in 0..joint.unit.messages.len() {
+                    if let Ok(true) = BUSINESS_CACHE.stable_utxo_contains(joint, i) {
+                        if let Err(e) = temp_business_state.revert_message(joint, i) {
+                            error!("revert temp state failed, err = {}", e);
+                        }
+                    }
+                }
+            }
+
+            joint.set_sequence(JointSequence::FinalBad);
+        }
+    }
+}
+
+// use the sequence saved before the restart, the bad joints are kept bad
+fn restore_joint_sequence(joint: &JointData) {
+    let sequence = match KV_STORE.read_joint_property(&joint.unit.unit) {
+        Ok(ref property) if property.sequence == JointSequence::FinalBad => JointSequence::FinalBad,
+        _ => JointSequence::Good,
+    };
+    joint.set_sequence(sequence);
+}
+
 //---------------------------------------------------------------------------------------
 // GlobalState
 //---------------------------------------------------------------------------------------
@@ -299,11 +384,34 @@ impl GlobalState {
         }
     }
 
-    /// rebuild from database
-    /// TODO: rebuild from database
-    /// NOTE: need also update global state and temp business state
-    pub fn rebuild_from_db() -> Result<Self> {
-        Ok(GlobalState::default())
+    /// rebuild the stable states by replaying the stable joints saved in KV
+    /// the joints are read one by one, the joint graph is not loaded into memory
+    pub fn rebuild_from_genesis() -> Result<Self> {
+        let mut units = Vec::new();
+        for (key, _) in KV_STORE.iter_prefix(kv_store::MCI_INDEX_PREFIX.as_bytes())? {
+            let unit = kv_store::parse_mci_index_unit(&key)?;
+            let property = KV_STORE.read_joint_property(&unit)?;
+            units.push((property.mci.value(), property.sub_mci.value(), unit));
+        }
+        // the joints of the same mci are ordered by sub_mci
+        units.sort();
+
+        let joints = units
+            .into_iter()
+            .map(|(_, _, unit)| KV_STORE.read_joint(&unit));
+        GlobalState::replay_stable_joints(joints)
+    }
+
+    // the joints must be in the stable order
+    fn replay_stable_joints<I>(joints: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = Result<Joint>>,
+    {
+        let state = GlobalState::default();
+        for joint in joints {
+            state.update_global_state(&JointData::from_joint(joint?, None));
+        }
+        Ok(state)
     }
 }
 
@@ -335,13 +443,26 @@ impl BusinessState {
 
         match message.payload {
             Some(Payload::Payment(ref payment)) => {
-                for Input {
-                    unit,
-                    output_index,
-                    message_index,
-                    ..
-                } in &payment.inputs
-                {
+                for input in &payment.inputs {
+                    // the commission inputs spend the synthetic utxos of the earner
+                    if let Some(key) = utxo::get_commission_input_key(input) {
+                        let (address, utxo_key) = key?;
+                        let is_found = self
+                            .utxo
+                            .get_utxos_by_address(&address)
+                            .map_or(false, |outputs| outputs.contains_key(&utxo_key));
+                        if !is_found {
+                            return Ok(false);
+                        }
+                        continue;
+                    }
+
+                    let Input {
+                        unit,
+                        output_index,
+                        message_index,
+                        ..
+                    } = input;
                     let unit = unit.clone().unwrap();
                     let output_index = output_index.unwrap() as usize;
                     let message_index = message_index.unwrap() as usize;
@@ -435,6 +556,20 @@ pub struct BusinessCache {
     pub global_state: GlobalState,
     business_state: RwLock<BusinessState>,
     temp_business_state: RwLock<BusinessState>,
+    address_stats: RwLock<address_stats::AddressStatsCache>,
+    // the stable joints until the mci are included in the restored snapshot
+    restored_mci: Level,
+    // locked by the worker when applying a stable joint
+    progress: Mutex<ApplyProgress>,
+}
+
+// the business state is consistent only when all the joints of an mci are applied
+#[derive(Default)]
+struct ApplyProgress {
+    // all the stable joints until the mci are applied
+    applied_mci: Level,
+    // some stable joints after the applied mci are applied
+    partial: bool,
 }
 
 impl BusinessCache {
@@ -445,6 +580,78 @@ impl BusinessCache {
             .utxo_contains(joint, msg_index)
     }
 
+    /// get the stable and pending utxos of the address
+    pub fn get_balance_details(&self, address: &str) -> ::light::BalanceDetails {
+        // lock the temp state before the stable state, like get_inputs_for_amount
+        let temp_state = self.temp_business_state.read().unwrap();
+        let stable_state = self.business_state.read().unwrap();
+        utxo::get_balance_details(&stable_state.utxo, &temp_state.utxo, address)
+    }
+
+    /// get the stable utxos of the address that are already spent by unstable joints
+    pub fn get_pending_spends(&self, address: &str) -> Vec<UtxoKey> {
+        let temp_state = self.temp_business_state.read().unwrap();
+        let stable_state = self.business_state.read().unwrap();
+        utxo::get_pending_spends(&stable_state.utxo, &temp_state.utxo, address)
+    }
+
+    /// get the stable balance of the address at a past stable mci
+    pub fn get_balance_at_mci(&self, address: &str, mci: Level) -> Result<u64> {
+        let last_stable_mci = ::main_chain::get_last_stable_mci();
+        ensure!(
+            mci <= last_stable_mci,
+            "mci {:?} is not stable, last stable mci is {:?}",
+            mci,
+            last_stable_mci
+        );
+
+        Ok(UtxoCache::clone_at_mci(mci)?.get_balance(address))
+    }
+
+    /// get the statistics of the address from stable joints
+    pub fn get_address_stats(&self, address: &str) -> Result<AddressStats> {
+        if let Some(stats) = self.address_stats.read().unwrap().get(address) {
+            return Ok(stats);
+        }
+
+        // the stats are not rebuilt in memory yet
+        Ok(KV_STORE.read_address_stats(address)?.unwrap_or_default())
+    }
+
+    /// get the latest stable value of the feed posted by the address
+    /// the value is returned with the unit and the mci where it was set
+    pub fn get_data_feed(&self, address: &str, feed_name: &str) -> Result<Option<Value>> {
+        let latest = self
+            .business_state
+            .read()
+            .unwrap()
+            .data_feed
+            .get_latest_feed(address, feed_name)
+            .cloned();
+
+        // the cached feeds are lost on restart without a snapshot, read them from kv
+        let (value, unit, mci) = match latest {
+            Some(feed) => feed,
+            None => match data_feed::TimerCache::read_latest_feed(address, feed_name)? {
+                Some(feed) => {
+                    self.business_state
+                        .write()
+                        .unwrap()
+                        .data_feed
+                        .cache_latest_feed(address, feed_name, feed.clone());
+                    feed
+                }
+                None => return Ok(None),
+            },
+        };
+
+        Ok(Some(json!({
+            "value": value,
+            "unit": unit,
+            "mci": mci.value(),
+        })))
+    }
+
     /// select unspent outputs from temp output
     /// determine if units related with selected outputs is stable
     /// if no, calculate unstable outputs' amount
@@ -457,7 +664,17 @@ impl BusinessCache {
         last_stable_unit: &str,
     ) -> Result<(Vec<Input>, u64)> {
         let last_ball_joint = SDAG_CACHE.get_joint(last_stable_unit)?.read()?;
+        self.select_inputs(paying_address, required_amount, send_all, &last_ball_joint)
+    }
 
+    // the spendable utxos must be stable and before the last ball
+    fn select_inputs(
+        &self,
+        paying_address: &str,
+        required_amount: u64,
+        send_all: bool,
+        last_ball_joint: &JointData,
+    ) -> Result<(Vec<Input>, u64)> {
         let temp_state = self.temp_business_state.read().unwrap();
         let temp_outputs = temp_state.get_utxos_by_address(paying_address)?;
 
@@ -472,8 +689,34 @@ impl BusinessCache {
                 continue;
             }
 
+            // the header commission is spent by the mci that earned it
+            if let Some(mci) = v.header_commission_mci() {
+                if mci > last_ball_joint.get_mci() {
+                    continue;
+                }
+
+                total_amount += v.amount;
+                inputs.push(Input {
+                    kind: Some(utxo::COMMISSION.to_owned()),
+                    from_main_chain_index: Some(mci.value() as u32),
+                    output_index: Some(v.output_index as u32),
+                    address: Some(paying_address.to_owned()),
+                    amount: Some(v.amount),
+                    ..Default::default()
+                });
+
+                if !send_all && total_amount >= required_amount {
+                    break;
+                }
+                continue;
+            }
+
+            // the payload commission is spent by the unit that paid it
+            let commission_source = v.payload_commission_source();
+            let input_unit = commission_source.unwrap_or(&v.unit);
+
             // input unit must before last ball
-            let input_joint = SDAG_CACHE.get_joint(&v.unit)?.read()?;
+            let input_joint = SDAG_CACHE.get_joint(input_unit)?.read()?;
             let is_include = *input_joint <= *last_ball_joint;
             if !is_include {
                 // info!(
@@ -484,11 +727,20 @@ impl BusinessCache {
             }
 
             total_amount += v.amount;
-            inputs.push(Input {
-                unit: Some(v.unit.clone()),
-                message_index: Some(v.message_index as u32),
-                output_index: Some(v.output_index as u32),
-                ..Default::default()
+            inputs.push(match commission_source {
+                Some(unit) => Input {
+                    kind: Some(utxo::COMMISSION_EARNING.to_owned()),
+                    unit: Some(unit.to_owned()),
+                    address: Some(paying_address.to_owned()),
+                    amount: Some(v.amount),
+                    ..Default::default()
+                },
+                None => Input {
+                    unit: Some(v.unit.clone()),
+                    message_index: Some(v.message_index as u32),
+                    output_index: Some(v.output_index as u32),
+                    ..Default::default()
+                },
             });
 
             if !send_all && total_amount >= required_amount {
@@ -527,11 +779,49 @@ impl BusinessCache {
         Ok(business_cache)
     }
 
-    /// rebuild from database
-    /// TODO: rebuild from database
-    /// NOTE: need also update global state and temp business state
+    /// restore the state from the latest snapshot, the later joints are applied again
+    /// without the snapshot, the state is rebuilt by the joints replayed from kv
     pub fn rebuild_from_db() -> Result<Self> {
-        Ok(BusinessCache::default())
+        let mut business_cache = BusinessCache::default();
+        let snapshot = match KV_STORE.read_business_snapshot()? {
+            Some(snapshot) => snapshot,
+            None => {
+                info!("no business snapshot found, replay the stable joints");
+                return Ok(business_cache);
+            }
+        };
+
+        let snapshot_mci = snapshot.snapshot_mci;
+        business_cache.import_state(snapshot)?;
+        business_cache.restored_mci = snapshot_mci;
+        business_cache.progress.lock().unwrap().applied_mci = snapshot_mci;
+        Ok(business_cache)
+    }
+
+    /// if the stable joint of the mci is already included in the restored snapshot
+    pub fn is_restored(&self, mci: Level) -> bool {
+        self.restored_mci.is_valid() && mci <= self.restored_mci
+    }
+
+    // the state of the mci is not restored from the snapshot
+    fn should_save_snapshot(&self, mci: Level) -> bool {
+        let interval = config::get_business_snapshot_interval();
+        interval > 0
+            && mci.is_valid()
+            && mci.value() > 0
+            && mci.value() % interval == 0
+            && !self.is_restored(mci)
+    }
+
+    // replace the saved snapshot, the same one is also saved on shutdown
+    // the utxos are also kept in a periodic snapshot for the historical balances
+    // all the stable joints until the mci must be applied
+    fn save_snapshot(&self, mci: Level) -> Result<()> {
+        KV_STORE.save_business_snapshot(&self.export_state_at(mci))?;
+        let utxo = self.business_state.read().unwrap().utxo.clone();
+        KV_STORE.save_utxo_snapshot(mci, &utxo)?;
+        info!("saved business snapshot at mci {}", mci.value());
+        Ok(())
     }
 
     /// validate if contains last stable self unit
@@ -557,14 +847,16 @@ impl BusinessCache {
     /// validate unstable joint with no global order
     pub fn validate_unstable_joint(&self, cached_joint: CachedJoint) -> Result<JointSequence> {
         let joint = cached_joint.read()?;
+        // the temp state lock also serializes the serial check of the same author
+        let mut g = self.temp_business_state.write().unwrap();
+
         // global check
-        let state = validate_unstable_joint_serial(cached_joint)?;
+        let state = validate_unstable_joint_serial(&self.global_state, &joint)?;
         if state != JointSequence::Good {
             return Ok(state);
         }
 
         // for each message do business related validation
-        let mut g = self.temp_business_state.write().unwrap();
         for i in 0..joint.unit.messages.len() {
             let state = g.validate_message(&joint, i);
             if let Err(e) = state {
@@ -572,7 +864,13 @@ impl BusinessCache {
                     "validate_unstable_joint, unit = {}, err = {}",
                     joint.unit.unit, e
                 );
-                // now we only support one message for a unit
+                // a temp-bad joint must leave nothing in the temp state so that it can be
+                // purged directly, only the payments are kept in the temp state
+                for j in (0..i).rev() {
+                    if joint.unit.messages[j].app == "payment" {
+                        g.revert_message(&joint, j)?;
+                    }
+                }
                 return Ok(JointSequence::TempBad);
             } else {
                 // unordered validate pass, apply it
@@ -580,12 +878,34 @@ impl BusinessCache {
             }
         }
 
+        // only a good joint is the last unstable self joint, the temp-bad one is purged
+        let addr = &joint.unit.authors[0].address;
+        self.global_state
+            .update_last_unstable_self_joint(addr, &joint.unit.unit);
         Ok(JointSequence::Good)
     }
 
+    // the commissions are earned when the joint is stable, they are also saved
+    // to the temp state, so that the unstable joints could spend them
+    fn apply_commissions(
+        &self,
+        joint: &JointData,
+        headers_commission_shares: &[(String, u64)],
+        payload_commission_shares: &[(String, u64)],
+    ) -> Result<()> {
+        for state in &[&self.business_state, &self.temp_business_state] {
+            let mut g = state.write().unwrap();
+            g.utxo
+                .apply_headers_commission(joint, headers_commission_shares)?;
+            g.utxo
+                .apply_payload_commission(joint, payload_commission_shares)?;
+        }
+        Ok(())
+    }
+
     /// validate stable joint with global order
     fn validate_stable_joint(&self, joint: &JointData) -> Result<()> {
-        info!("validate_stable_joint, unit={}", joint.unit.unit);
+        ::tracing::info!(unit_hash = %joint.unit.unit, "validate_stable_joint");
         // TODO: check if enough commission here
         // for each message do business related validation
         if joint.get_sequence() == JointSequence::FinalBad {
@@ -639,17 +959,48 @@ impl BusinessCache {
 
     /// apply changes, save the new state
     fn apply_stable_joint(&self, joint: &JointData) -> Result<()> {
+        ::tracing::debug!(unit_hash = %joint.unit.unit, "apply_stable_joint");
         // TODO: deduce the commission
 
+        // look up everything before any state is changed
+        let headers_commission_shares = utxo::get_headers_commission_shares(joint)?;
+        let payload_commission_shares = utxo::get_payload_commission_shares(joint)?;
+
         self.update_joint_balance_props(joint)?;
 
         // update global state {last_stable_self_joint, related_joints}
         self.global_state.update_global_state(joint);
 
-        let mut business_state = self.business_state.write().unwrap();
+        {
+            let mut business_state = self.business_state.write().unwrap();
 
-        for i in 0..joint.unit.messages.len() {
-            business_state.apply_message(joint, i)?;
+            for i in 0..joint.unit.messages.len() {
+                business_state.apply_message(joint, i)?;
+            }
+        }
+
+        self.apply_commissions(
+            joint,
+            &headers_commission_shares,
+            &payload_commission_shares,
+        )?;
+        if !payload_commission_shares.is_empty() {
+            statistics::increase_counter("sdag_payload_commission_distributed_total");
+        }
+
+        // only the multi-author units split the spending by the input owners
+        let spent_inputs = if joint.unit.authors.len() > 1 {
+            utxo::get_spent_inputs(&joint.unit)?
+        } else {
+            Vec::new()
+        };
+        let updated_stats = self.address_stats.write().unwrap().apply_unit(
+            &joint.unit,
+            joint.get_mci(),
+            &spent_inputs,
+        );
+        for (address, stats) in updated_stats {
+            KV_STORE.save_address_stats(&address, &stats)?;
         }
 
         Ok(())
@@ -721,7 +1072,7 @@ fn validate_message_payload(message: &Message) -> Result<()> {
         bail!("no inline payload");
     }
 
-    let payload_hash = object_hash::get_base64_hash(message.payload.as_ref().unwrap())?;
+    let payload_hash = get_base64_hash(message.payload.as_ref().unwrap())?;
     if payload_hash != message.payload_hash {
         bail!(
             "wrong payload hash: expected {}, got {}",
@@ -749,16 +1100,16 @@ fn validate_message_format(msg: &Message) -> Result<()> {
     Ok(())
 }
 
-fn validate_unstable_joint_serial(joint: CachedJoint) -> Result<JointSequence> {
-    let joint_data = joint.read()?;
+// the joint must include the last unstable self joint of the author
+fn validate_unstable_joint_serial(
+    global_state: &GlobalState,
+    joint_data: &RcuReader<JointData>,
+) -> Result<JointSequence> {
     let addr = &joint_data.unit.authors[0].address;
 
-    if let Some(unit) = BUSINESS_CACHE
-        .global_state
-        .get_last_unstable_self_joint(addr)
-    {
+    if let Some(unit) = global_state.get_last_unstable_self_joint(addr) {
         let last_unstable_joint = SDAG_CACHE.get_joint(&unit)?.read()?;
-        let is_include = last_unstable_joint <= joint_data;
+        let is_include = last_unstable_joint <= *joint_data;
         if !is_include {
             warn!(
                 "joint [{}] detect non serial with unit [{}]",
@@ -767,9 +1118,254 @@ fn validate_unstable_joint_serial(joint: CachedJoint) -> Result<JointSequence> {
             return Ok(JointSequence::NonserialBad);
         }
     }
-    BUSINESS_CACHE
-        .global_state
-        .update_last_unstable_self_joint(addr, &joint_data.unit.unit);
 
     Ok(JointSequence::Good)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::{CachedData, SDagCache};
+    use composer::{self, ComposeInfo};
+    use light::{InputsResponse, LightProps};
+    use rcu_cell::RcuCell;
+    use sdag_wallet_base::Base64KeyExt;
+    use wallet_info::WalletInfo;
+
+    const ADDRESS_A: &str = "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI";
+    const ADDRESS_B: &str = "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE";
+    const ADDRESS_C: &str = "JNA6YWLKFQG7PFF6F32KTXBUAHRAFSET";
+
+    // pay the amount and send the changes back
+    fn payment_joint(parent: &str, from: &str, to: &str) -> Joint {
+        let payment = Payment {
+            address: None,
+            asset: None,
+            definition_chash: None,
+            denomination: None,
+            inputs: Vec::new(),
+            outputs: vec![
+                Output {
+                    address: from.to_owned(),
+                    amount: 1_000,
+                },
+                Output {
+                    address: to.to_owned(),
+                    amount: 500,
+                },
+            ],
+        };
+        let unit = UnitBuilder::new()
+            .author(from, Default::default(), Default::default())
+            .message("payment", Payload::Payment(payment))
+            .parent(parent)
+            .build()
+            .unwrap();
+        Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit,
+        }
+    }
+
+    #[test]
+    fn test_replay_stable_joints() {
+        // one joint for each mci
+        let payments = [
+            (ADDRESS_A, ADDRESS_B),
+            (ADDRESS_B, ADDRESS_C),
+            (ADDRESS_C, ADDRESS_A),
+            (ADDRESS_A, ADDRESS_C),
+            (ADDRESS_B, ADDRESS_A),
+        ];
+        let mut parent = "genesis".to_owned();
+        let mut joints = Vec::new();
+        for (from, to) in payments.iter() {
+            let joint = payment_joint(&parent, from, to);
+            parent = joint.unit.unit.clone();
+            joints.push(joint);
+        }
+        let units = joints
+            .iter()
+            .map(|j| j.unit.unit.clone())
+            .collect::<Vec<_>>();
+
+        let state = GlobalState::replay_stable_joints(joints.into_iter().map(Ok)).unwrap();
+
+        let last_self = |address| state.get_last_stable_self_joint(address);
+        assert_eq!(last_self(ADDRESS_A), Some(units[3].clone()));
+        assert_eq!(last_self(ADDRESS_B), Some(units[4].clone()));
+        assert_eq!(last_self(ADDRESS_C), Some(units[2].clone()));
+
+        // the related joints are cleared when the address sends a joint
+        assert_eq!(state.get_related_joints(ADDRESS_A), vec![units[4].clone()]);
+        assert!(state.get_related_joints(ADDRESS_B).is_empty());
+        assert_eq!(state.get_related_joints(ADDRESS_C), vec![units[3].clone()]);
+    }
+
+    #[test]
+    // the earner joint gives all its headers commission to the address
+    fn earn_headers_commission(cache: &BusinessCache, address: &str) -> JointData {
+        let mut joint = payment_joint("genesis", ADDRESS_A, ADDRESS_B);
+        joint.unit.headers_commission = Some(100_000);
+        joint.unit.earned_headers_commission_recipients = vec![HeaderCommissionShare {
+            address: address.to_owned(),
+            earned_headers_commission_share: 100,
+        }];
+        let earner = JointData::from_joint(joint, None);
+        earner.set_mci(Level::from(HEADERS_COMMISSION_UPGRADE_MCI));
+        earner.set_sub_mci(Level::new(0));
+
+        let shares = utxo::get_headers_commission_shares(&earner).unwrap();
+        cache.apply_commissions(&earner, &shares, &[]).unwrap();
+        earner
+    }
+
+    // pay 1000 from the inputs of the wallet
+    fn spender_joint(wallet: &WalletInfo, inputs: Vec<Input>, amount: u64) -> Joint {
+        let hash = "-".repeat(config::HASH_LENGTH);
+        let info = ComposeInfo {
+            paid_address: wallet._00_address.clone(),
+            change_address: wallet._00_address.clone(),
+            outputs: vec![Output {
+                address: ADDRESS_B.to_owned(),
+                amount: 1_000,
+            }],
+            inputs: InputsResponse { inputs, amount },
+            transaction_amount: 1_000,
+            text_message: None,
+            light_props: LightProps {
+                last_ball: hash.clone(),
+                last_ball_unit: hash.clone(),
+                parent_units: vec![hash.clone()],
+                witness_list_unit: hash,
+                has_definition: false,
+            },
+            pubk: wallet._00_address_pubk.to_base64_key(),
+            send_all: false,
+        };
+        composer::compose_joint(info, wallet).unwrap()
+    }
+
+    fn new_cached_joint(joint_data: JointData) -> CachedJoint {
+        let key = Arc::new(joint_data.unit.unit.clone());
+        CachedData::new(key, RcuCell::new(Some(joint_data)))
+    }
+
+    #[test]
+    fn test_spend_headers_commission() {
+        // a new wallet to not conflict with the other tests
+        let wallet = WalletInfo::from_mnemonic("").unwrap();
+        let address = wallet._00_address.clone();
+        let cache = BusinessCache::default();
+        let earner = earn_headers_commission(&cache, &address);
+
+        let (inputs, amount) = cache
+            .select_inputs(&address, 1_000, false, &earner)
+            .unwrap();
+        assert_eq!(amount, 100_000);
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].kind, Some(utxo::COMMISSION.to_owned()));
+
+        let spender = JointData::from_joint(spender_joint(&wallet, inputs, amount), None);
+        assert_eq!(
+            cache
+                .validate_unstable_joint(new_cached_joint(spender))
+                .unwrap(),
+            JointSequence::Good
+        );
+
+        // the share is spent in the temp state
+        assert!(cache
+            .select_inputs(&address, 1_000, false, &earner)
+            .is_err());
+    }
+
+    #[test]
+    fn test_purge_temp_bad_payment() {
+        let wallet = WalletInfo::from_mnemonic("").unwrap();
+        let address = wallet._00_address.clone();
+        let cache = BusinessCache::default();
+        let earner = earn_headers_commission(&cache, &address);
+        let (inputs, amount) = cache
+            .select_inputs(&address, 1_000, false, &earner)
+            .unwrap();
+
+        // the second payment spends the share again, the first one is reverted
+        let mut joint = spender_joint(&wallet, inputs, amount);
+        let payment = joint.unit.messages[0].clone();
+        joint.unit.messages.push(payment);
+        let joint_data = JointData::from_joint(joint, None);
+        joint_data.set_level(Level::new(1));
+        let create_time = joint_data.get_create_time();
+        let cached_joint = new_cached_joint(joint_data);
+
+        let state = cache.validate_unstable_joint(cached_joint.clone()).unwrap();
+        assert_eq!(state, JointSequence::TempBad);
+        cached_joint.read().unwrap().set_sequence(state);
+        assert!(cache
+            .global_state
+            .get_last_unstable_self_joint(&address)
+            .is_none());
+
+        // the temp-bad joint times out and is purged
+        let sdag = SDagCache::default();
+        sdag.normalize_joint(cached_joint);
+        assert_eq!(sdag.get_all_free_joints().len(), 1);
+        sdag.purge_old_temp_bad_free_joints(create_time + 100, 100)
+            .unwrap();
+        assert!(sdag.get_all_free_joints().is_empty());
+
+        // nothing is left in the temp state, the share could still be spent
+        let (inputs, amount) = cache
+            .select_inputs(&address, 1_000, false, &earner)
+            .unwrap();
+        assert_eq!(amount, 100_000);
+        assert_eq!(inputs[0].kind, Some(utxo::COMMISSION.to_owned()));
+    }
+
+    #[test]
+    fn test_search_text() {
+        let text_joint = |text: &str, mci: Level| {
+            let unit = UnitBuilder::new()
+                .author(ADDRESS_C, Default::default(), Default::default())
+                .message("text", Payload::Text(text.to_owned()))
+                .parent("genesis")
+                .build()
+                .unwrap();
+            let joint_data = JointData::from_joint(
+                Joint {
+                    ball: None,
+                    skiplist_units: Vec::new(),
+                    unit,
+                },
+                None,
+            );
+            joint_data.set_mci(mci);
+            joint_data
+        };
+
+        let mut texts = text::TextCache::default();
+        let second = text_joint("the second searchable text", Level::new(8));
+        let first = text_joint("the first searchable text", Level::new(7));
+        let unstable = text_joint("an unstable searchable text", Level::INVALID);
+        for joint in &[&second, &first, &unstable] {
+            texts.apply_message(joint, 0).unwrap();
+        }
+
+        // substring match in the order of being stable
+        let found = text::TextCache::search("searchable te", 10).unwrap();
+        let units = found.iter().map(|(u, _)| u.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            units,
+            vec![first.unit.unit.as_str(), second.unit.unit.as_str()]
+        );
+        assert_eq!(found[0].1, "the first searchable text");
+
+        let found = text::TextCache::search("searchable te", 1).unwrap();
+        assert_eq!(found[0].0, first.unit.unit);
+        assert!(text::TextCache::search("not in any text", 10)
+            .unwrap()
+            .is_empty());
+    }
+}
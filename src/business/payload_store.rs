@@ -0,0 +1,121 @@
+//! content addressed store of the payloads that are too big to be inline
+//!
+//! the payload is saved as `{path}/sha256_{hash}.bin` and referred by the uri
+//! `sha256:{base64_hash}`, so the data could always be verified by its uri
+//!
+//! the store is local to the node, other nodes may or may not have the data,
+//! so it's never consulted by the validation or the business state. the joints
+//! always carry inline payloads, the store only keeps the data that clients
+//! exchange off the dag
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use config;
+use error::Result;
+use sdag_object_base::object_hash;
+
+const URI_PREFIX: &str = "sha256:";
+// the local copies are removed after 30 days
+const PAYLOAD_TTL_SECS: u64 = 30 * 24 * 3600;
+
+lazy_static! {
+    pub static ref PAYLOAD_STORE: PayloadStore =
+        PayloadStore::new(config::get_payload_store_path());
+}
+
+//---------------------------------------------------------------------------------------
+// PayloadStore
+//---------------------------------------------------------------------------------------
+pub struct PayloadStore {
+    path: PathBuf,
+}
+
+impl PayloadStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        PayloadStore { path: path.into() }
+    }
+
+    /// save the data and return its uri
+    pub fn store(&self, data: &[u8]) -> Result<String> {
+        fs::create_dir_all(&self.path)?;
+        let hash = object_hash::get_base64_data_hash(data);
+        fs::write(self.get_file_path(&hash), data)?;
+        Ok(format!("{}{}", URI_PREFIX, hash))
+    }
+
+    /// read the data of the uri, the data hash is verified
+    pub fn fetch(&self, uri: &str) -> Result<Vec<u8>> {
+        if !uri.starts_with(URI_PREFIX) {
+            bail!("unsupported payload uri {}", uri);
+        }
+        let hash = &uri[URI_PREFIX.len()..];
+
+        let data = fs::read(self.get_file_path(hash))?;
+        if object_hash::get_base64_data_hash(&data) != hash {
+            bail!("payload data not match the uri {}", uri);
+        }
+        Ok(data)
+    }
+
+    /// remove the payloads that are saved before the TTL, return the removed number
+    pub fn cleanup(&self) -> Result<usize> {
+        if !self.path.exists() {
+            return Ok(0);
+        }
+
+        let ttl = Duration::from_secs(PAYLOAD_TTL_SECS);
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            // the modified time may be in the future, just keep it
+            if now
+                .duration_since(modified)
+                .map(|d| d > ttl)
+                .unwrap_or(false)
+            {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // base64 contains '/' which is not allowed in file names
+    fn get_file_path(&self, hash: &str) -> PathBuf {
+        let name = hash.replace('/', "_").replace('+', "-");
+        self.path.join(format!("sha256_{}.bin", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn temp_store() -> PayloadStore {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("sdag_payloads_{}_{}", ::std::process::id(), nanos));
+        PayloadStore::new(path)
+    }
+
+    #[test]
+    fn test_store_fetch() {
+        let store = temp_store();
+        let data = b"some big payload";
+        let uri = store.store(data).unwrap();
+        assert!(uri.starts_with("sha256:"));
+        assert_eq!(store.fetch(&uri).unwrap(), data.to_vec());
+
+        assert!(store.fetch("sha256:unknown").is_err());
+        assert!(store.fetch("http://unknown").is_err());
+        fs::remove_dir_all(&store.path).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+//! export the stable business state, so that a new node could import it without
+//! replaying the whole chain
+//!
+//! all the stable sub states are exported, the temp state is rebuilt the same as the
+//! stable utxos on import
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap as StdHashMap;
+use std::time::Duration;
+
+use super::address_stats::AddressStatsCache;
+use super::data_feed::TimerCache;
+use super::text::TextCache;
+use super::utxo::{
+    HeadersCommissionOutputKey, PayloadCommissionOutputKey, UtxoCache, UtxoData, UtxoKey,
+};
+use super::{AddressStats, BusinessCache, GlobalState, BUSINESS_WORKER};
+use error::Result;
+use failure::ResultExt;
+use hashbrown::HashMap;
+use joint::Level;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UtxoExport {
+    pub address: String,
+    pub unit: String,
+    pub message_index: u32,
+    pub output_index: u32,
+    pub amount: u64,
+    pub mci: Level,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BusinessStateExport {
+    // all the stable joints until the mci are applied to the state
+    pub snapshot_mci: Level,
+    pub utxo_count: u64,
+    pub utxos: Vec<UtxoExport>,
+    // the commissions are not spent yet
+    pub payload_commission_output: Vec<(PayloadCommissionOutputKey, usize)>,
+    pub headers_commission_output: Vec<(HeadersCommissionOutputKey, usize)>,
+    pub text: TextCache,
+    pub data_feed: TimerCache,
+    pub address_stats: StdHashMap<String, AddressStats>,
+    // the global state can't be derived from the utxos
+    #[serde(default)]
+    pub last_stable_self_joints: StdHashMap<String, String>,
+    #[serde(default)]
+    pub related_joints: StdHashMap<String, Vec<String>>,
+}
+
+impl BusinessCache {
+    /// export the stable business state at the mci the worker applied
+    /// wait until all the stable joints of the mci are applied
+    pub fn export_state(&self, timeout: Duration) -> Result<BusinessStateExport> {
+        let export = RefCell::new(None);
+        ::utils::wait_cond(Some(timeout), || {
+            // the worker can't apply joints when the progress is locked
+            let progress = self.progress.lock().unwrap();
+            if progress.partial {
+                return false;
+            }
+            *export.borrow_mut() = Some(self.export_state_at(progress.applied_mci));
+            true
+        })
+        .context("wait business worker apply the whole mci timeout")?;
+        Ok(export.into_inner().expect("state exported"))
+    }
+
+    /// export the state after all the queued stable joints are applied
+    /// used to save the snapshot on shutdown
+    pub fn export_applied_state(&self, timeout: Duration) -> Result<BusinessStateExport> {
+        ::utils::wait_cond(Some(timeout), || BUSINESS_WORKER.get_queue_len() == 0)
+            .context("wait business worker idle timeout")?;
+        self.export_state(timeout)
+    }
+
+    pub(super) fn export_state_at(&self, snapshot_mci: Level) -> BusinessStateExport {
+        let business_state = self.business_state.read().unwrap();
+        let mut utxos = Vec::new();
+        for (address, outputs) in &business_state.utxo.output {
+            for (key, data) in outputs {
+                utxos.push(UtxoExport {
+                    address: address.clone(),
+                    unit: key.unit.clone(),
+                    message_index: key.message_index as u32,
+                    output_index: key.output_index as u32,
+                    amount: key.amount,
+                    mci: data.mci,
+                });
+            }
+        }
+
+        let global_state = &self.global_state;
+        BusinessStateExport {
+            snapshot_mci,
+            utxo_count: utxos.len() as u64,
+            utxos,
+            payload_commission_output: to_entries(&business_state.utxo.payload_commission_output),
+            headers_commission_output: to_entries(&business_state.utxo.headers_commission_output),
+            text: business_state.text.clone(),
+            data_feed: business_state.data_feed.clone(),
+            address_stats: self.address_stats.read().unwrap().to_map(),
+            last_stable_self_joints: to_std_map(
+                &*global_state.last_stable_self_joint.read().unwrap(),
+            ),
+            related_joints: to_std_map(&*global_state.related_joints.read().unwrap()),
+        }
+    }
+
+    /// replace all the stable sub states with the exported ones
+    /// the imported state is kept in memory only
+    pub fn import_state(&self, export: BusinessStateExport) -> Result<()> {
+        ensure!(
+            export.utxo_count == export.utxos.len() as u64,
+            "utxo count mismatch, expect {}, found {}",
+            export.utxo_count,
+            export.utxos.len()
+        );
+
+        let utxo = export.to_utxo_cache();
+        {
+            let mut temp_business_state = self.temp_business_state.write().unwrap();
+            let mut business_state = self.business_state.write().unwrap();
+            // the texts and feeds are only saved when stable, the temp state has none
+            temp_business_state.utxo = utxo.clone();
+            business_state.utxo = utxo;
+            business_state.text = export.text;
+            business_state.data_feed = export.data_feed;
+        }
+
+        *self.address_stats.write().unwrap() = AddressStatsCache::from_map(export.address_stats);
+
+        self.global_state
+            .import_state(export.last_stable_self_joints, export.related_joints);
+
+        info!(
+            "imported business state at mci {}",
+            export.snapshot_mci.value()
+        );
+        Ok(())
+    }
+}
+
+impl BusinessStateExport {
+    /// rebuild the stable utxo cache from the exported utxos and commissions
+    pub(super) fn to_utxo_cache(&self) -> UtxoCache {
+        let mut output = HashMap::new();
+        for u in &self.utxos {
+            let key = UtxoKey {
+                unit: u.unit.clone(),
+                output_index: u.output_index as usize,
+                message_index: u.message_index as usize,
+                amount: u.amount,
+            };
+            // the sub_mci is only used to create the header commission keys
+            let data = UtxoData {
+                mci: u.mci,
+                sub_mci: Level::ZERO,
+            };
+            output
+                .entry(u.address.clone())
+                .or_insert_with(BTreeMap::new)
+                .insert(key, data);
+        }
+
+        UtxoCache {
+            output,
+            payload_commission_output: self.payload_commission_output.iter().cloned().collect(),
+            headers_commission_output: self.headers_commission_output.iter().cloned().collect(),
+        }
+    }
+}
+
+impl GlobalState {
+    fn import_state(
+        &self,
+        last_stable_self_joints: StdHashMap<String, String>,
+        related_joints: StdHashMap<String, Vec<String>>,
+    ) {
+        *self.last_stable_self_joint.write().unwrap() =
+            last_stable_self_joints.into_iter().collect();
+        *self.related_joints.write().unwrap() = related_joints.into_iter().collect();
+        self.last_unstable_self_joint.write().unwrap().clear();
+    }
+}
+
+fn to_std_map<V: Clone>(map: &HashMap<String, V>) -> StdHashMap<String, V> {
+    map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+fn to_entries<K: Clone + Eq + ::std::hash::Hash, V: Clone>(map: &HashMap<K, V>) -> Vec<(K, V)> {
+    map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn add_utxo(cache: &BusinessCache, address: &str, unit: &str, amount: u64, mci: usize) {
+        let key = UtxoKey {
+            unit: unit.to_owned(),
+            output_index: 0,
+            message_index: 0,
+            amount,
+        };
+        let data = UtxoData {
+            mci: Level::new(mci),
+            sub_mci: Level::ZERO,
+        };
+        cache
+            .business_state
+            .write()
+            .unwrap()
+            .utxo
+            .output
+            .entry(address.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .insert(key, data);
+    }
+
+    fn get_utxos(cache: &BusinessCache, address: &str) -> Vec<(UtxoKey, usize)> {
+        let business_state = cache.business_state.read().unwrap();
+        business_state
+            .get_utxos_by_address(address)
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.mci.value()))
+            .collect()
+    }
+
+    #[test]
+    fn test_export_import_state() {
+        let cache = BusinessCache::default();
+        for mci in 1..=10 {
+            add_utxo(&cache, "A", &format!("unit_{}", mci), mci as u64 * 100, mci);
+            if mci % 2 == 0 {
+                add_utxo(&cache, "B", &format!("unit_{}", mci), 50, mci);
+            }
+        }
+        cache
+            .global_state
+            .last_stable_self_joint
+            .write()
+            .unwrap()
+            .insert("A".to_owned(), "unit_10".to_owned());
+        let commission_key = PayloadCommissionOutputKey {
+            mci: Level::new(10),
+            address: "A".to_owned(),
+        };
+        cache
+            .business_state
+            .write()
+            .unwrap()
+            .utxo
+            .payload_commission_output
+            .insert(commission_key.clone(), 30);
+
+        let export = cache.export_state_at(Level::new(10));
+        assert_eq!(export.utxo_count, 15);
+        let json = serde_json::to_string(&export).unwrap();
+
+        // import into an empty cache
+        let imported = BusinessCache::default();
+        imported
+            .import_state(serde_json::from_str(&json).unwrap())
+            .unwrap();
+
+        for address in &["A", "B"] {
+            assert_eq!(get_utxos(&imported, address), get_utxos(&cache, address));
+        }
+        assert!(imported
+            .temp_business_state
+            .read()
+            .unwrap()
+            .get_utxos_by_address("B")
+            .is_ok());
+        assert_eq!(
+            imported.global_state.get_last_stable_self_joint("A"),
+            Some("unit_10".to_owned())
+        );
+        assert_eq!(
+            imported
+                .business_state
+                .read()
+                .unwrap()
+                .utxo
+                .payload_commission_output
+                .get(&commission_key),
+            Some(&30)
+        );
+
+        let mut export = export;
+        export.utxo_count += 1;
+        assert!(imported.import_state(export).is_err());
+    }
+
+    #[test]
+    fn test_export_state_at_applied_mci() {
+        let cache = BusinessCache::default();
+        {
+            let mut progress = cache.progress.lock().unwrap();
+            progress.applied_mci = Level::new(10);
+            // the joints of mci 11 are being applied
+            progress.partial = true;
+        }
+        assert!(cache.export_state(Duration::from_millis(10)).is_err());
+
+        cache.progress.lock().unwrap().partial = false;
+        let export = cache.export_state(Duration::from_millis(10)).unwrap();
+        assert_eq!(export.snapshot_mci, Level::new(10));
+    }
+}
@@ -1,11 +1,13 @@
 use super::SubBusiness;
 use cache::JointData;
 use error::Result;
+use kv_store::KV_STORE;
 use light;
 use spec::{Message, Payload};
 
-#[derive(Default)]
-pub struct TextCache;
+/// the stable texts are saved in the kv store in the order of being stable
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TextCache {}
 
 impl SubBusiness for TextCache {
     fn validate_message_basic(message: &Message) -> Result<()> {
@@ -24,7 +26,15 @@ impl SubBusiness for TextCache {
         Ok(())
     }
 
-    fn apply_message(&mut self, _joint: &JointData, _message_idx: usize) -> Result<()> {
+    fn apply_message(&mut self, joint: &JointData, message_idx: usize) -> Result<()> {
+        // only stable texts are saved, the temp state has no mci yet
+        if !joint.get_mci().is_valid() {
+            return Ok(());
+        }
+
+        if let Some(Payload::Text(ref text)) = joint.unit.messages[message_idx].payload {
+            KV_STORE.save_text(&joint.unit.unit, joint.get_mci(), text)?;
+        }
         Ok(())
     }
 
@@ -33,6 +43,25 @@ impl SubBusiness for TextCache {
     }
 }
 
+impl TextCache {
+    /// return the first `limit` <unit_hash, text> whose text contains the pattern,
+    /// all the stable texts are scanned in the order of being stable
+    pub fn search(pattern: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        KV_STORE.search_texts(|text| text.contains(pattern), limit)
+    }
+}
+
+/// get the text payload of the unit, none if the unit has no inline text
+pub fn get_text_payload(unit: &str) -> Result<Option<String>> {
+    use cache::SDAG_CACHE;
+    let joint = SDAG_CACHE.get_joint(unit)?.read()?;
+
+    Ok(joint.unit.messages.iter().find_map(|m| match m.payload {
+        Some(Payload::Text(ref text)) => Some(text.clone()),
+        _ => None,
+    }))
+}
+
 pub fn get_text(unit: &str) -> Result<light::Text> {
     use cache::SDAG_CACHE;
     let joint = SDAG_CACHE.get_joint(unit)?.read()?;
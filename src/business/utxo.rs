@@ -6,24 +6,205 @@ use error::Result;
 use failure::ResultExt;
 use hashbrown::{hash_map::Entry, HashMap, HashSet};
 use joint::{JointSequence, Level};
+use kv_store::KV_STORE;
+use light::{BalanceDetails, UtxoEntry};
+use may::sync::Mutex;
+use my_witness::MY_WITNESSES;
 use sdag_object_base::object_hash;
 use spec::*;
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 
+/// the input kind that spends the earned payload commission
+pub const COMMISSION_EARNING: &str = "commission_earning";
+/// the input kind that spends the earned headers commission share of a witness
+pub const COMMISSION: &str = "commission";
+
+lazy_static! {
+    // the commission witnesses of the latest mci, shared by all the joints of the mci
+    static ref COMMISSION_WITNESSES: Mutex<Option<(Level, Vec<String>)>> = Mutex::new(None);
+}
+
 //---------------------------------------------------------------------------------------
 // UtxoCache
 //---------------------------------------------------------------------------------------
-#[derive(Default)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct UtxoCache {
     //record money that address can spend
+    #[serde(with = "output_entries")]
     pub output: HashMap<String, BTreeMap<UtxoKey, UtxoData>>,
-    // save payload commission earnings  <Key, Amount> NOT USED YET
+    // accumulated payload commission earnings of each mci <Key, Amount>
+    #[serde(with = "map_entries")]
     pub payload_commission_output: HashMap<PayloadCommissionOutputKey, usize>,
-    // save header commission earnings <Key, Amount> NOT USED YET
+    // accumulated header commission earnings of each mci <Key, Amount>
+    #[serde(with = "map_entries")]
     pub headers_commission_output: HashMap<HeadersCommissionOutputKey, usize>,
 }
 
+// the maps with struct keys can't be json objects, they are saved as entry lists
+pub(super) mod map_entries {
+    use hashbrown::HashMap;
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::{Serialize, Serializer};
+    use std::hash::Hash;
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize + Eq + Hash,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let entries = Vec::<(K, V)>::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+mod output_entries {
+    use super::{UtxoData, UtxoKey};
+    use hashbrown::HashMap;
+    use serde::de::{Deserialize, Deserializer};
+    use serde::ser::Serializer;
+    use std::collections::BTreeMap;
+
+    type Output = HashMap<String, BTreeMap<UtxoKey, UtxoData>>;
+
+    pub fn serialize<S: Serializer>(output: &Output, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            output
+                .iter()
+                .map(|(address, utxos)| (address, utxos.iter().collect::<Vec<_>>())),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Output, D::Error> {
+        let entries = Vec::<(String, Vec<(UtxoKey, UtxoData)>)>::deserialize(deserializer)?;
+        Ok(entries
+            .into_iter()
+            .map(|(address, utxos)| (address, utxos.into_iter().collect()))
+            .collect())
+    }
+}
+
+impl UtxoCache {
+    /// rebuild the stable utxos at the mci, replayed from the nearest snapshot that
+    /// is not after the mci, or from genesis if there is no such snapshot
+    /// the periodic utxo snapshots are kept, the business snapshot may be a nearer one
+    /// at most MAX_REPLAY_MCIS mcis are replayed
+    pub fn clone_at_mci(mci: Level) -> Result<UtxoCache> {
+        let utxo_snapshot_mci = KV_STORE
+            .read_utxo_snapshot_mcis()?
+            .into_iter()
+            .filter(|snapshot_mci| *snapshot_mci <= mci)
+            .last();
+        let business_snapshot = KV_STORE.read_business_snapshot()?.filter(|s| {
+            s.snapshot_mci <= mci && utxo_snapshot_mci.map_or(true, |m| s.snapshot_mci > m)
+        });
+
+        let mut next_mci = match (&business_snapshot, utxo_snapshot_mci) {
+            (Some(s), _) => s.snapshot_mci + 1,
+            (None, Some(m)) => m + 1,
+            (None, None) => Level::new(0),
+        };
+        // the replay loads all the joints of the mcis, check it before loading the snapshot
+        check_replay_range(next_mci, mci, config::MAX_REPLAY_MCIS)?;
+
+        let mut utxo = match (business_snapshot, utxo_snapshot_mci) {
+            (Some(s), _) => s.to_utxo_cache(),
+            (None, Some(m)) => KV_STORE
+                .read_utxo_snapshot(m)?
+                .ok_or_else(|| format_err!("utxo snapshot of mci {} not found", m.value()))?,
+            (None, None) => UtxoCache::default(),
+        };
+
+        while next_mci <= mci {
+            for joint in SDAG_CACHE.get_joints_by_mci(next_mci)? {
+                let joint = joint.read()?;
+                if joint.get_sequence() == JointSequence::Good {
+                    utxo.apply_stable_joint(&joint)?;
+                }
+            }
+            next_mci += 1;
+        }
+
+        Ok(utxo)
+    }
+
+    /// the stable balance of the address, sum of all its utxos
+    pub fn get_balance(&self, address: &str) -> u64 {
+        self.get_utxos_by_address(address)
+            .map(|utxos| utxos.keys().map(|k| k.amount).sum())
+            .unwrap_or(0)
+    }
+
+    // the utxo part of BusinessCache::apply_stable_joint
+    fn apply_stable_joint(&mut self, joint: &JointData) -> Result<()> {
+        let headers_commission_shares = get_headers_commission_shares(joint)?;
+        let payload_commission_shares = get_payload_commission_shares(joint)?;
+
+        for (i, message) in joint.unit.messages.iter().enumerate() {
+            if message.app == "payment" {
+                self.apply_message(joint, i)?;
+            }
+        }
+
+        self.apply_headers_commission(joint, &headers_commission_shares)?;
+        self.apply_payload_commission(joint, &payload_commission_shares)
+    }
+}
+
+// the mcis from start_mci to end_mci must be no more than max_replay
+fn check_replay_range(start_mci: Level, end_mci: Level, max_replay: usize) -> Result<()> {
+    ensure!(
+        end_mci.value() < start_mci.value() + max_replay,
+        "mci {} is not covered by the business snapshot, replay from {} is too long",
+        end_mci.value(),
+        start_mci.value()
+    );
+    Ok(())
+}
+
+/// the owner and amount of all the spent inputs of the unit, the issue inputs are skipped
+pub(super) fn get_spent_inputs(unit: &Unit) -> Result<Vec<(String, u64)>> {
+    let mut spent = Vec::new();
+    for message in &unit.messages {
+        let payment = match message.payload {
+            Some(Payload::Payment(ref payment)) => payment,
+            _ => continue,
+        };
+        for input in &payment.inputs {
+            if input.kind.as_ref().map(|k| k.as_str()) == Some("issue") {
+                continue;
+            }
+
+            if let Some(key) = get_commission_input_key(input) {
+                let (address, utxo_key) = key?;
+                spent.push((address, utxo_key.amount));
+                continue;
+            }
+
+            let output = get_output_by_unit(
+                input
+                    .unit
+                    .as_ref()
+                    .ok_or_else(|| format_err!("input has no unit"))?,
+                input.output_index.unwrap_or(0) as usize,
+                input.message_index.unwrap_or(0) as usize,
+            )?;
+            spent.push((output.address, output.amount));
+        }
+    }
+    Ok(spent)
+}
+
 pub(super) fn get_output_by_unit(
     unit: &str,
     output_index: usize,
@@ -82,8 +263,15 @@ impl UtxoCache {
 
                 // recovery output that have already spent
                 for input in &payment.inputs {
+                    if let Some(key) = get_commission_input_key(input) {
+                        let (address, utxo_key) = key?;
+                        self.insert_output(address, utxo_key, utxo_value)?;
+                        continue;
+                    }
+
+                    // the spent output belongs to the input unit, not the reverted one
                     let output = get_output_by_unit(
-                        unit,
+                        input.unit.as_ref().unwrap(),
                         input.output_index.unwrap() as usize,
                         input.message_index.unwrap() as usize,
                     )?;
@@ -128,9 +316,14 @@ impl UtxoCache {
 
     fn decrease_output(&mut self, inputs: &[Input]) -> Result<()> {
         for input in inputs.iter() {
-            match input.kind {
-                Some(ref kind) if kind == "issue" => continue,
-                _ => {}
+            if input.kind.as_ref().map(|k| k.as_str()) == Some("issue") {
+                continue;
+            }
+
+            if let Some(key) = get_commission_input_key(input) {
+                let (address, utxo_key) = key?;
+                self.remove_output(address, &utxo_key)?;
+                continue;
             }
 
             let unit = input.unit.as_ref().unwrap();
@@ -385,36 +578,173 @@ impl UtxoCache {
 
         Ok(input.amount.unwrap())
     }
+
+    fn verify_commission_earning_of_input(
+        &self,
+        input: &Input,
+        author_addresses: &[&String],
+        input_keys: &mut HashSet<String>,
+    ) -> Result<u64> {
+        if input.message_index.is_some()
+            || input.output_index.is_some()
+            || input.serial_number.is_some()
+            || input.from_main_chain_index.is_some()
+            || input.to_main_chain_index.is_some()
+        {
+            bail!("unknown fields in commission earning input")
+        }
+
+        match input.unit {
+            Some(ref unit) if unit.len() == config::HASH_LENGTH => {}
+            _ => bail!("wrong unit in commission earning input"),
+        }
+
+        let (address, utxo_key) = commission_earning_key(input)?;
+        if !author_addresses.contains(&&address) {
+            bail!("commission earning address {} is not an author", address)
+        }
+
+        // duplication detection
+        let input_key = format!("commission-{}", utxo_key.unit);
+        if input_keys.contains(&input_key) {
+            bail!("input {} already used", input_key)
+        }
+        input_keys.insert(input_key);
+
+        let is_found = self
+            .output
+            .get(&address)
+            .map_or(false, |outputs| outputs.contains_key(&utxo_key));
+        if !is_found {
+            bail!("commission earning {} not found", utxo_key.unit)
+        }
+
+        Ok(utxo_key.amount)
+    }
+
+    fn verify_commission_of_input(
+        &self,
+        input: &Input,
+        author_addresses: &[&String],
+        input_keys: &mut HashSet<String>,
+    ) -> Result<u64> {
+        if input.unit.is_some()
+            || input.message_index.is_some()
+            || input.serial_number.is_some()
+            || input.to_main_chain_index.is_some()
+        {
+            bail!("unknown fields in commission input")
+        }
+
+        let (address, utxo_key) = headers_commission_key(input)?;
+        if !author_addresses.contains(&&address) {
+            bail!("commission address {} is not an author", address)
+        }
+
+        // duplication detection
+        let input_key = format!("{}-{}", utxo_key.unit, utxo_key.output_index);
+        if input_keys.contains(&input_key) {
+            bail!("input {} already used", input_key)
+        }
+        input_keys.insert(input_key);
+
+        let is_found = self
+            .output
+            .get(&address)
+            .map_or(false, |outputs| outputs.contains_key(&utxo_key));
+        if !is_found {
+            bail!("headers commission {} not found", utxo_key.unit)
+        }
+
+        Ok(utxo_key.amount)
+    }
 }
 
 impl UtxoCache {
-    // TODO: impl spend header commission #57
-    // TODO: impl spend header commission #58
     // TODO: refine Payment structure
     // Note: in future we would use account model to record one usize balance for each address
     // thus we don't need to save that in this big table
-    #[allow(dead_code)]
-    fn save_payload_commission(
+    fn save_payload_commission(&mut self, address: String, mci: Level, amount: usize) {
+        // there could be many joints in the same mci, accumulate the earnings
+        let key = PayloadCommissionOutputKey { mci, address };
+        *self.payload_commission_output.entry(key).or_insert(0) += amount;
+    }
+
+    fn save_header_commission(&mut self, address: String, mci: Level, amount: usize) {
+        // there could be many joints in the same mci, accumulate the earnings
+        let key = HeadersCommissionOutputKey { mci, address };
+        *self.headers_commission_output.entry(key).or_insert(0) += amount;
+    }
+
+    /// save the headers commission shares of a stable joint as utxos of the earners,
+    /// the shares are from `get_headers_commission_shares`
+    pub fn apply_headers_commission(
         &mut self,
-        address: String,
-        mci: Level,
-        amount: usize,
+        joint: &JointData,
+        shares: &[(String, u64)],
     ) -> Result<()> {
-        let key = PayloadCommissionOutputKey { mci, address };
-        if self.payload_commission_output.get(&key).is_some() {
-            bail!("already have key={:?} in payload commission output", key);
+        let mci = joint.get_mci();
+        let utxo_value = UtxoData {
+            mci,
+            sub_mci: joint.get_sub_mci(),
+        };
+        self.increase_header_commission_output(mci, shares, utxo_value)
+    }
+
+    /// save the payload commission shares of a stable joint as utxos of the earners,
+    /// the shares are from `get_payload_commission_shares`
+    pub fn apply_payload_commission(
+        &mut self,
+        joint: &JointData,
+        shares: &[(String, u64)],
+    ) -> Result<()> {
+        if shares.is_empty() {
+            return Ok(());
         }
-        self.payload_commission_output.insert(key, amount);
+
+        let utxo_value = UtxoData {
+            mci: joint.get_mci(),
+            sub_mci: joint.get_sub_mci(),
+        };
+        for (address, amount) in shares {
+            if *amount == 0 {
+                continue;
+            }
+
+            let output = Output {
+                address: address.clone(),
+                amount: *amount,
+            };
+            let commission_unit = UtxoKey::payload_commission_unit(&joint.unit.unit, address);
+            self.increase_output(&commission_unit, &[output], 0, utxo_value)?;
+            self.save_payload_commission(address.clone(), utxo_value.mci, *amount as usize);
+        }
+
         Ok(())
     }
 
-    #[allow(dead_code)]
-    fn save_header_commission(&mut self, address: String, mci: Level, amount: usize) -> Result<()> {
-        let key = HeadersCommissionOutputKey { mci, address };
-        if self.headers_commission_output.get(&key).is_some() {
-            bail!("already have key={:?} in headers commission output", key);
+    fn increase_header_commission_output(
+        &mut self,
+        mci: Level,
+        shares: &[(String, u64)],
+        utxo_value: UtxoData,
+    ) -> Result<()> {
+        for (address, amount) in shares {
+            if *amount == 0 {
+                continue;
+            }
+
+            // the synthetic key has no real unit, use sub_mci to separate joints of the same mci
+            let utxo_key = UtxoKey {
+                unit: UtxoKey::header_commission_unit(mci, address),
+                output_index: utxo_value.sub_mci.value(),
+                message_index: 0,
+                amount: *amount,
+            };
+            self.insert_output(address.clone(), utxo_key, utxo_value)?;
+            self.save_header_commission(address.clone(), mci, *amount as usize);
         }
-        self.headers_commission_output.insert(key, amount);
+
         Ok(())
     }
 
@@ -481,6 +811,21 @@ impl UtxoCache {
 
                     total_input += amount;
                 }
+
+                COMMISSION_EARNING => {
+                    let amount = self.verify_commission_earning_of_input(
+                        input,
+                        &author_addresses,
+                        &mut input_keys,
+                    )?;
+                    total_input += amount;
+                }
+
+                COMMISSION => {
+                    let amount =
+                        self.verify_commission_of_input(input, &author_addresses, &mut input_keys)?;
+                    total_input += amount;
+                }
                 _ => unimplemented!(),
             }
         }
@@ -529,7 +874,14 @@ impl SubBusiness for UtxoCache {
         match message.payload {
             Some(Payload::Payment(ref payment)) => {
                 for input in &payment.inputs {
-                    if input.kind.as_ref().unwrap_or(&"transfer".to_string()) == "transfer" {
+                    let kind = input.kind.as_ref().map_or("transfer", |k| k.as_str());
+                    if kind == COMMISSION {
+                        // the commission is earned at its mci, which must be stable
+                        let mci = input.from_main_chain_index.unwrap_or(0) as usize;
+                        if Level::new(mci) > last_ball.get_mci() {
+                            bail!("commission mci must be before last ball")
+                        }
+                    } else if kind == "transfer" || kind == COMMISSION_EARNING {
                         let src_joint =
                             SDAG_CACHE.get_joint(input.unit.as_ref().unwrap())?.read()?;
 
@@ -580,10 +932,73 @@ impl SubBusiness for UtxoCache {
     }
 }
 
+// a pending utxo is one present in temp state but not in stable state
+pub(super) fn get_balance_details(
+    stable: &UtxoCache,
+    temp: &UtxoCache,
+    address: &str,
+) -> BalanceDetails {
+    let mut details = BalanceDetails::default();
+    let stable_outputs = stable.get_utxos_by_address(address);
+
+    if let Some(outputs) = stable_outputs {
+        for (k, v) in outputs {
+            details.balance += k.amount;
+            details.stable_utxos.push(to_utxo_entry(k, v, true));
+        }
+    }
+
+    if let Some(outputs) = temp.get_utxos_by_address(address) {
+        for (k, v) in outputs {
+            if stable_outputs.map_or(false, |o| o.contains_key(k)) {
+                continue;
+            }
+            details.pending_utxos.push(to_utxo_entry(k, v, false));
+        }
+    }
+
+    details
+}
+
+// the unstable joints are applied to the temp state, so the spent utxos are
+// those still in the stable state but already removed from the temp state
+pub(super) fn get_pending_spends(
+    stable: &UtxoCache,
+    temp: &UtxoCache,
+    address: &str,
+) -> Vec<UtxoKey> {
+    let stable_outputs = match stable.get_utxos_by_address(address) {
+        Some(outputs) => outputs,
+        None => return Vec::new(),
+    };
+    let temp_outputs = temp.get_utxos_by_address(address);
+
+    stable_outputs
+        .keys()
+        .filter(|k| !temp_outputs.map_or(false, |o| o.contains_key(k)))
+        .cloned()
+        .collect()
+}
+
+fn to_utxo_entry(key: &UtxoKey, data: &UtxoData, is_stable: bool) -> UtxoEntry {
+    let mci = if data.mci.is_valid() {
+        Some(data.mci.value() as u32)
+    } else {
+        None
+    };
+
+    UtxoEntry {
+        unit: key.unit.clone(),
+        amount: key.amount,
+        mci,
+        is_stable,
+    }
+}
+
 //---------------------------------------------------------------------------------------
 // UtxoKey
 //---------------------------------------------------------------------------------------
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct UtxoKey {
     pub unit: String,
     pub output_index: usize,
@@ -591,6 +1006,39 @@ pub struct UtxoKey {
     pub amount: u64,
 }
 
+impl UtxoKey {
+    const HEADER_COMMISSION: &'static str = ":header_commission:";
+    const PAYLOAD_COMMISSION: &'static str = "payload_commission:";
+
+    fn header_commission_unit(mci: Level, address: &str) -> String {
+        format!("{}{}{}", mci.value(), UtxoKey::HEADER_COMMISSION, address)
+    }
+
+    fn payload_commission_unit(unit: &str, address: &str) -> String {
+        format!("{}{}:{}", UtxoKey::PAYLOAD_COMMISSION, unit, address)
+    }
+
+    /// header commission output is not from a real unit
+    pub fn is_header_commission(&self) -> bool {
+        self.unit.contains(UtxoKey::HEADER_COMMISSION)
+    }
+
+    /// return the mci that earned the header commission, if it's a header commission
+    pub fn header_commission_mci(&self) -> Option<Level> {
+        let end = self.unit.find(UtxoKey::HEADER_COMMISSION)?;
+        self.unit[..end].parse().ok().map(Level::new)
+    }
+
+    /// return the unit that paid the payload commission, if it's a payload commission
+    pub fn payload_commission_source(&self) -> Option<&str> {
+        if !self.unit.starts_with(UtxoKey::PAYLOAD_COMMISSION) {
+            return None;
+        }
+        let rest = &self.unit[UtxoKey::PAYLOAD_COMMISSION.len()..];
+        rest.rfind(':').map(|i| &rest[..i])
+    }
+}
+
 impl Ord for UtxoKey {
     fn cmp(&self, other: &UtxoKey) -> Ordering {
         match Ord::cmp(&self.amount, &other.amount) {
@@ -618,7 +1066,7 @@ impl PartialOrd for UtxoKey {
 //---------------------------------------------------------------------------------------
 // UtxoData
 //---------------------------------------------------------------------------------------
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, Serialize, Deserialize)]
 pub struct UtxoData {
     pub mci: Level,
     pub sub_mci: Level,
@@ -627,7 +1075,7 @@ pub struct UtxoData {
 //---------------------------------------------------------------------------------------
 // HeadersCommissionOutputKey
 //---------------------------------------------------------------------------------------
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct HeadersCommissionOutputKey {
     pub mci: Level,
     pub address: String,
@@ -636,7 +1084,7 @@ pub struct HeadersCommissionOutputKey {
 //---------------------------------------------------------------------------------------
 // PayloadCommissionOutputKey
 //---------------------------------------------------------------------------------------
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PayloadCommissionOutputKey {
     pub mci: Level,
     pub address: String,
@@ -646,6 +1094,162 @@ pub struct PayloadCommissionOutputKey {
 // Global functions
 //---------------------------------------------------------------------------------------
 
+// the key of the payload commission spent by a commission earning input
+fn commission_earning_key(input: &Input) -> Result<(String, UtxoKey)> {
+    let (unit, address, amount) = match (&input.unit, &input.address, input.amount) {
+        (Some(unit), Some(address), Some(amount)) => (unit, address, amount),
+        _ => bail!("commission earning input needs unit, address and amount"),
+    };
+
+    let utxo_key = UtxoKey {
+        unit: UtxoKey::payload_commission_unit(unit, address),
+        output_index: 0,
+        message_index: 0,
+        amount,
+    };
+    Ok((address.clone(), utxo_key))
+}
+
+// the key of the headers commission share spent by a commission input,
+// the share is found by the mci and the sub_mci (the output_index) that earned it
+fn headers_commission_key(input: &Input) -> Result<(String, UtxoKey)> {
+    let from_mci = input.from_main_chain_index;
+    let (mci, sub_mci, address, amount) =
+        match (from_mci, input.output_index, &input.address, input.amount) {
+            (Some(mci), Some(sub_mci), Some(address), Some(amount)) => {
+                (mci, sub_mci, address, amount)
+            }
+            _ => bail!("commission input needs mci, output_index, address and amount"),
+        };
+
+    let utxo_key = UtxoKey {
+        unit: UtxoKey::header_commission_unit(Level::new(mci as usize), address),
+        output_index: sub_mci as usize,
+        message_index: 0,
+        amount,
+    };
+    Ok((address.clone(), utxo_key))
+}
+
+/// the synthetic utxo spent by a commission input, none for the other input kinds
+pub fn get_commission_input_key(input: &Input) -> Option<Result<(String, UtxoKey)>> {
+    match input.kind.as_ref().map(|k| k.as_str()) {
+        Some(COMMISSION_EARNING) => Some(commission_earning_key(input)),
+        Some(COMMISSION) => Some(headers_commission_key(input)),
+        _ => None,
+    }
+}
+
+/// the headers commission of a stable joint goes to its recipients if any,
+/// or equally to the witnesses that posted units in the last 6 mci
+pub(super) fn get_headers_commission_shares(joint: &JointData) -> Result<Vec<(String, u64)>> {
+    let mci = joint.get_mci();
+    let amount = u64::from(joint.unit.headers_commission.unwrap_or(0));
+    if amount == 0 || mci < Level::from(HEADERS_COMMISSION_UPGRADE_MCI) {
+        return Ok(Vec::new());
+    }
+
+    let recipients = &joint.unit.earned_headers_commission_recipients;
+    let witnesses = if recipients.is_empty() {
+        get_commission_witnesses(mci)?
+    } else {
+        Vec::new()
+    };
+    Ok(split_headers_commission(amount, recipients, &witnesses))
+}
+
+/// the payload commission of a stable joint goes to the authors of the main chain unit
+/// of the same mci, by its headers commission recipients if any
+pub(super) fn get_payload_commission_shares(joint: &JointData) -> Result<Vec<(String, u64)>> {
+    let mci = joint.get_mci();
+    let amount = u64::from(joint.unit.payload_commission.unwrap_or(0));
+    if amount == 0 || mci < Level::from(PAYLOAD_COMMISSION_UPGRADE_MCI) {
+        return Ok(Vec::new());
+    }
+
+    let mc_unit_hash = SDAG_CACHE
+        .get_mc_unit_hash(mci)?
+        .ok_or_else(|| format_err!("no main chain unit at mci {:?}", mci))?;
+    let mc_joint = SDAG_CACHE.get_joint(&mc_unit_hash)?.read()?;
+    Ok(split_payload_commission(amount, &mc_joint.unit))
+}
+
+fn split_payload_commission(amount: u64, mc_unit: &Unit) -> Vec<(String, u64)> {
+    let mc_authors = mc_unit
+        .authors
+        .iter()
+        .map(|a| a.address.clone())
+        .collect::<Vec<_>>();
+    split_headers_commission(
+        amount,
+        &mc_unit.earned_headers_commission_recipients,
+        &mc_authors,
+    )
+}
+
+/// witnesses that posted units in the last 6 mci before the joint mci
+fn get_commission_witnesses(mci: Level) -> Result<Vec<String>> {
+    // the previous mcis are stable, the witnesses of the mci never change
+    let mut cached = COMMISSION_WITNESSES.lock().unwrap();
+    match *cached {
+        Some((cached_mci, ref witnesses)) if cached_mci == mci => return Ok(witnesses.clone()),
+        _ => {}
+    }
+
+    let witnesses = load_commission_witnesses(mci)?;
+    *cached = Some((mci, witnesses.clone()));
+    Ok(witnesses)
+}
+
+fn load_commission_witnesses(mci: Level) -> Result<Vec<String>> {
+    let mut witnesses = Vec::new();
+    for i in 1..=6 {
+        let prev_mci = match mci.checked_sub(i) {
+            Some(prev_mci) => prev_mci,
+            None => break,
+        };
+
+        for joint in SDAG_CACHE.get_joints_by_mci(prev_mci)? {
+            let joint_data = joint.read()?;
+            for author in &joint_data.unit.authors {
+                if MY_WITNESSES.contains(&author.address) && !witnesses.contains(&author.address) {
+                    witnesses.push(author.address.clone());
+                }
+            }
+        }
+    }
+
+    witnesses.sort();
+    Ok(witnesses)
+}
+
+// split the headers commission by the recipients shares, or equally among the witnesses
+// the remainder of the integer division goes to the first one
+fn split_headers_commission(
+    amount: u64,
+    recipients: &[HeaderCommissionShare],
+    witnesses: &[String],
+) -> Vec<(String, u64)> {
+    let mut shares = if !recipients.is_empty() {
+        recipients
+            .iter()
+            .map(|r| {
+                let share = u64::from(r.earned_headers_commission_share);
+                (r.address.clone(), amount * share / 100)
+            })
+            .collect::<Vec<_>>()
+    } else if !witnesses.is_empty() {
+        let each = amount / witnesses.len() as u64;
+        witnesses.iter().map(|w| (w.clone(), each)).collect()
+    } else {
+        return Vec::new();
+    };
+
+    let total: u64 = shares.iter().map(|s| s.1).sum();
+    shares[0].1 += amount - total;
+    shares
+}
+
 fn validate_payment_format(message: &Message) -> Result<()> {
     if message.payload_location != "inline" {
         bail!("payment location must be inline");
@@ -683,3 +1287,367 @@ fn validate_payment_format(message: &Message) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use joint::Joint;
+    use serde_json;
+
+    fn share(address: &str, share: u32) -> HeaderCommissionShare {
+        HeaderCommissionShare {
+            address: address.to_owned(),
+            earned_headers_commission_share: share,
+        }
+    }
+
+    fn get_balance(utxo: &UtxoCache, address: &str) -> u64 {
+        utxo.get_utxos_by_address(address)
+            .map(|outputs| outputs.keys().map(|k| k.amount).sum())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn test_check_replay_range() {
+        // the mci is the snapshot mci, nothing to replay
+        assert!(check_replay_range(Level::new(11), Level::new(10), 5).is_ok());
+        // replay mci 11..=15
+        assert!(check_replay_range(Level::new(11), Level::new(15), 5).is_ok());
+        assert!(check_replay_range(Level::new(11), Level::new(16), 5).is_err());
+        // no snapshot covers the mci, don't replay all the way from genesis
+        assert!(check_replay_range(Level::new(0), Level::new(4), 5).is_ok());
+        assert!(check_replay_range(Level::new(0), Level::new(100_000), 5).is_err());
+    }
+
+    #[test]
+    fn test_single_author_commission() {
+        let shares = split_headers_commission(344, &[share("A", 100)], &[]);
+        assert_eq!(shares, vec![("A".to_owned(), 344)]);
+    }
+
+    #[test]
+    fn test_multi_author_commission() {
+        let recipients = [share("A", 60), share("B", 40)];
+        let shares = split_headers_commission(345, &recipients, &[]);
+        assert_eq!(shares, vec![("A".to_owned(), 207), ("B".to_owned(), 138)]);
+
+        let shares = split_headers_commission(1000, &recipients, &[]);
+        assert_eq!(shares, vec![("A".to_owned(), 600), ("B".to_owned(), 400)]);
+    }
+
+    #[test]
+    fn test_witness_commission_balance() {
+        let witnesses = vec!["W1".to_owned(), "W2".to_owned(), "W3".to_owned()];
+        let shares = split_headers_commission(100, &[], &witnesses);
+        assert_eq!(shares.iter().map(|s| s.1).sum::<u64>(), 100);
+
+        let mut utxo = UtxoCache::default();
+        for sub_mci in 0..2 {
+            let utxo_value = UtxoData {
+                mci: Level::new(10),
+                sub_mci: Level::new(sub_mci),
+            };
+            utxo.increase_header_commission_output(Level::new(10), &shares, utxo_value)
+                .unwrap();
+        }
+
+        assert_eq!(get_balance(&utxo, "W1"), 68);
+        assert_eq!(get_balance(&utxo, "W2"), 66);
+        assert_eq!(get_balance(&utxo, "W3"), 66);
+
+        let key = HeadersCommissionOutputKey {
+            mci: Level::new(10),
+            address: "W1".to_owned(),
+        };
+        assert_eq!(utxo.headers_commission_output[&key], 68);
+        assert!(utxo.output["W1"].keys().all(|k| k.is_header_commission()));
+        assert!(utxo.output["W1"]
+            .keys()
+            .all(|k| k.header_commission_mci() == Some(Level::new(10))));
+    }
+
+    #[test]
+    fn test_spend_headers_commission() {
+        let witnesses = vec![ADDRESS_W1.to_owned(), ADDRESS_W2.to_owned()];
+        let shares = split_headers_commission(100, &[], &witnesses);
+        let utxo_value = UtxoData {
+            mci: Level::new(3),
+            sub_mci: Level::new(1),
+        };
+        let mut utxo = UtxoCache::default();
+        utxo.increase_header_commission_output(Level::new(3), &shares, utxo_value)
+            .unwrap();
+        let earned = get_balance(&utxo, ADDRESS_W1);
+        assert!(earned > 0);
+
+        let input = Input {
+            kind: Some(COMMISSION.to_owned()),
+            from_main_chain_index: Some(3),
+            output_index: Some(1),
+            address: Some(ADDRESS_W1.to_owned()),
+            amount: Some(earned),
+            ..Default::default()
+        };
+        let spender = spender_joint(ADDRESS_W1, &input);
+        let earner = ADDRESS_W1.to_owned();
+        let amount = utxo
+            .verify_input(&[input.clone()], vec![&earner], &spender.unit)
+            .unwrap();
+        assert_eq!(amount, earned);
+
+        // only the witness could spend its share
+        let other = ADDRESS_W2.to_owned();
+        assert!(utxo
+            .verify_input(&[input.clone()], vec![&other], &spender.unit)
+            .is_err());
+
+        // the share of another mci is not found
+        let mut wrong_mci = input.clone();
+        wrong_mci.from_main_chain_index = Some(4);
+        assert!(utxo
+            .verify_input(&[wrong_mci], vec![&earner], &spender.unit)
+            .is_err());
+
+        assert_spent(&mut utxo, &spender, input);
+    }
+
+    const ADDRESS_A: &str = "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI";
+    const ADDRESS_W1: &str = "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE";
+    const ADDRESS_W2: &str = "JNA6YWLKFQG7PFF6F32KTXBUAHRAFSET";
+
+    // the earner spends the input to ADDRESS_A
+    fn spender_joint(earner: &str, input: &Input) -> JointData {
+        let outputs = vec![Output {
+            address: ADDRESS_A.to_owned(),
+            amount: input.amount.unwrap(),
+        }];
+        stable_joint(&[earner], payment(vec![input.clone()], outputs), 6)
+    }
+
+    // the input is spent by applying the spending joint
+    fn assert_spent(utxo: &mut UtxoCache, spender: &JointData, input: Input) {
+        let earner = input.address.clone().unwrap();
+        utxo.apply_message(spender, 0).unwrap();
+        assert_eq!(get_balance(utxo, &earner), 0);
+        assert!(utxo
+            .verify_input(&[input], vec![&earner], &spender.unit)
+            .is_err());
+    }
+
+    fn payment(inputs: Vec<Input>, outputs: Vec<Output>) -> Payload {
+        Payload::Payment(Payment {
+            address: None,
+            asset: None,
+            definition_chash: None,
+            denomination: None,
+            inputs,
+            outputs,
+        })
+    }
+
+    // a joint of the authors that is stable at the mci
+    fn stable_joint(authors: &[&str], payload: Payload, mci: usize) -> JointData {
+        let mut builder = UnitBuilder::new().parent("genesis");
+        for address in authors {
+            builder = builder.author(address, Default::default(), Default::default());
+        }
+        let unit = builder.message("payment", payload).build().unwrap();
+        let joint_data = JointData::from_joint(
+            Joint {
+                ball: None,
+                skiplist_units: Vec::new(),
+                unit,
+            },
+            None,
+        );
+        joint_data.set_mci(Level::new(mci));
+        joint_data.set_sub_mci(Level::new(0));
+        joint_data
+    }
+
+    // the main chain joint and a payment joint that are stable at mci 5
+    fn commission_joints() -> (JointData, JointData) {
+        let outputs = vec![Output {
+            address: ADDRESS_A.to_owned(),
+            amount: 1_000,
+        }];
+        let mc_joint = stable_joint(
+            &[ADDRESS_W1, ADDRESS_W2],
+            payment(Vec::new(), outputs.clone()),
+            5,
+        );
+        let joint = stable_joint(&[ADDRESS_A], payment(Vec::new(), outputs), 5);
+        (mc_joint, joint)
+    }
+
+    // apply the stable joint like UtxoCache::apply_stable_joint with the given main chain joint
+    fn apply_stable_joint(utxo: &mut UtxoCache, joint: &JointData, mc_joint: &JointData) {
+        let amount = u64::from(joint.unit.payload_commission.unwrap());
+        let shares = split_payload_commission(amount, &mc_joint.unit);
+        utxo.apply_message(joint, 0).unwrap();
+        utxo.apply_payload_commission(joint, &shares).unwrap();
+    }
+
+    #[test]
+    fn test_apply_payload_commission() {
+        let (mc_joint, joint) = commission_joints();
+        let amount = u64::from(joint.unit.payload_commission.unwrap());
+        assert!(amount > 0);
+
+        let mut utxo = UtxoCache::default();
+        apply_stable_joint(&mut utxo, &joint, &mc_joint);
+
+        // the payment itself and the commission split between the main chain unit authors
+        assert_eq!(get_balance(&utxo, ADDRESS_A), 1_000);
+        let w1 = get_balance(&utxo, ADDRESS_W1);
+        let w2 = get_balance(&utxo, ADDRESS_W2);
+        assert_eq!(w1 + w2, amount);
+        assert!(w1 >= w2 && w1 - w2 <= 1);
+
+        let key = utxo.output[ADDRESS_W1].keys().next().unwrap();
+        assert_eq!(
+            key.unit,
+            format!("payload_commission:{}:{}", joint.unit.unit, ADDRESS_W1)
+        );
+        assert_eq!(
+            key.payload_commission_source(),
+            Some(joint.unit.unit.as_str())
+        );
+
+        let key = PayloadCommissionOutputKey {
+            mci: Level::new(5),
+            address: ADDRESS_W2.to_owned(),
+        };
+        assert_eq!(utxo.payload_commission_output[&key], w2 as usize);
+    }
+
+    #[test]
+    fn test_commission_before_upgrade() {
+        // nothing is looked up or distributed before the upgrade mci
+        let (_, joint) = commission_joints();
+        assert!(joint.unit.headers_commission.unwrap() > 0);
+        assert!(get_headers_commission_shares(&joint).unwrap().is_empty());
+        assert!(get_payload_commission_shares(&joint).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_utxo_cache_serde() {
+        let (mc_joint, joint) = commission_joints();
+        let mut utxo = UtxoCache::default();
+        apply_stable_joint(&mut utxo, &joint, &mc_joint);
+        let shares = split_headers_commission(100, &[share(ADDRESS_W1, 100)], &[]);
+        let utxo_value = UtxoData {
+            mci: Level::new(5),
+            sub_mci: Level::new(0),
+        };
+        utxo.increase_header_commission_output(Level::new(5), &shares, utxo_value)
+            .unwrap();
+
+        let json = serde_json::to_string(&utxo).unwrap();
+        let restored: UtxoCache = serde_json::from_str(&json).unwrap();
+        for address in &[ADDRESS_A, ADDRESS_W1, ADDRESS_W2] {
+            assert_eq!(get_balance(&restored, address), get_balance(&utxo, address));
+            assert_eq!(
+                restored.output[*address].keys().collect::<Vec<_>>(),
+                utxo.output[*address].keys().collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(
+            restored.payload_commission_output,
+            utxo.payload_commission_output
+        );
+        assert_eq!(
+            restored.headers_commission_output,
+            utxo.headers_commission_output
+        );
+    }
+
+    #[test]
+    fn test_spend_payload_commission() {
+        let (mc_joint, joint) = commission_joints();
+        let mut utxo = UtxoCache::default();
+        apply_stable_joint(&mut utxo, &joint, &mc_joint);
+        let earned = get_balance(&utxo, ADDRESS_W1);
+
+        let input = Input {
+            kind: Some(COMMISSION_EARNING.to_owned()),
+            unit: Some(joint.unit.unit.clone()),
+            address: Some(ADDRESS_W1.to_owned()),
+            amount: Some(earned),
+            ..Default::default()
+        };
+        let spender = spender_joint(ADDRESS_W1, &input);
+        let earner = ADDRESS_W1.to_owned();
+        let amount = utxo
+            .verify_input(&[input.clone()], vec![&earner], &spender.unit)
+            .unwrap();
+        assert_eq!(amount, earned);
+
+        // only the earner could spend it
+        let other = ADDRESS_A.to_owned();
+        assert!(utxo
+            .verify_input(&[input.clone()], vec![&other], &spender.unit)
+            .is_err());
+
+        // the wrong amount doesn't match the earning
+        let mut wrong_amount = input.clone();
+        wrong_amount.amount = Some(earned + 1);
+        assert!(utxo
+            .verify_input(&[wrong_amount], vec![&earner], &spender.unit)
+            .is_err());
+
+        assert_spent(&mut utxo, &spender, input);
+    }
+
+    #[test]
+    fn test_balance_details() {
+        fn add_utxo(utxo: &mut UtxoCache, unit: &str, amount: u64, mci: Level) {
+            let key = UtxoKey {
+                unit: unit.to_owned(),
+                output_index: 0,
+                message_index: 0,
+                amount,
+            };
+            let value = UtxoData {
+                mci,
+                sub_mci: Level::new(0),
+            };
+            utxo.output
+                .entry("A".to_owned())
+                .or_insert_with(BTreeMap::new)
+                .insert(key, value);
+        }
+
+        let mut stable = UtxoCache::default();
+        let mut temp = UtxoCache::default();
+        for (unit, amount, mci) in &[("U1", 100, 1), ("U2", 200, 2)] {
+            add_utxo(&mut stable, unit, *amount, Level::new(*mci));
+            add_utxo(&mut temp, unit, *amount, Level::new(*mci));
+        }
+        add_utxo(&mut temp, "U3", 300, Level::INVALID);
+
+        let details = get_balance_details(&stable, &temp, "A");
+        assert_eq!(details.stable_utxos.len(), 2);
+        assert_eq!(details.pending_utxos.len(), 1);
+        assert_eq!(details.balance, 300);
+        assert!(details.stable_utxos.iter().all(|u| u.is_stable));
+        assert_eq!(details.pending_utxos[0].unit, "U3");
+        assert_eq!(details.pending_utxos[0].mci, None);
+
+        let details = get_balance_details(&stable, &temp, "B");
+        assert_eq!(details.balance, 0);
+        assert!(details.stable_utxos.is_empty() && details.pending_utxos.is_empty());
+
+        // U1 is spent by an unstable joint
+        assert!(get_pending_spends(&stable, &temp, "A").is_empty());
+        let spent = UtxoKey {
+            unit: "U1".to_owned(),
+            output_index: 0,
+            message_index: 0,
+            amount: 100,
+        };
+        temp.output.get_mut("A").unwrap().remove(&spent);
+        assert_eq!(get_pending_spends(&stable, &temp, "A"), vec![spent]);
+        assert!(get_pending_spends(&stable, &temp, "B").is_empty());
+    }
+}
@@ -1,12 +1,63 @@
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
 use std::sync::Arc;
 
 use cache::{CachedData, CachedJoint, HashKey, JointData};
 use error::Result;
 use hashbrown::{HashMap, HashSet};
+use joint::{Joint, JointProperty, Level};
 use kv_store::LoadFromKv;
 use rcu_cell::RcuCell;
 
+//---------------------------------------------------------------------------------------
+// LevelIndex
+//---------------------------------------------------------------------------------------
+/// the units indexed by level or dag depth, only the unit hashes are kept
+#[derive(Default)]
+struct LevelIndex {
+    // <level, units>
+    units: BTreeMap<usize, Vec<Arc<String>>>,
+    // <unit, level> so that a unit is removed without scanning all the levels
+    levels: HashMap<HashKey, usize>,
+}
+
+impl LevelIndex {
+    fn insert(&mut self, key: &Arc<String>, level: usize) {
+        if self.levels.contains_key(key.as_str()) {
+            return;
+        }
+        self.levels.insert(HashKey(key.clone()), level);
+        self.units
+            .entry(level)
+            .or_insert_with(Vec::new)
+            .push(key.clone());
+    }
+
+    fn remove(&mut self, key: &str) {
+        let level = match self.levels.remove(key) {
+            Some(level) => level,
+            None => return,
+        };
+        let is_empty = match self.units.get_mut(&level) {
+            Some(units) => {
+                units.retain(|u| u.as_str() != key);
+                units.is_empty()
+            }
+            None => false,
+        };
+        if is_empty {
+            self.units.remove(&level);
+        }
+    }
+
+    fn range(&self, min: usize, max: usize) -> Vec<Arc<String>> {
+        self.units
+            .range(min..=max)
+            .flat_map(|(_, units)| units.iter().cloned())
+            .collect()
+    }
+}
+
 //---------------------------------------------------------------------------------------
 // SDagCacheInner
 //---------------------------------------------------------------------------------------
@@ -23,12 +74,76 @@ pub struct SDagCacheInner {
     missing_parents: HashMap<String, Vec<CachedJoint>>,
     // known bad joints: unit_hash, error message
     known_bad_joints: HashMap<String, String>,
+    // min-heap of the stable joints <mci, unit>, the smallest mci is evicted first
+    stable_joints: BinaryHeap<Reverse<(usize, Arc<String>)>>,
+    // the normal joints indexed by level
+    level_index: LevelIndex,
+    // the normal joints indexed by dag depth
+    depth_index: LevelIndex,
 }
 
 impl SDagCacheInner {
+    /// rebuild the normal joints graph from the snapshot data
+    /// parents that are not in the snapshot would be loaded from kv store on demand
+    pub fn from_snapshot(joints: Vec<(Joint, JointProperty)>) -> Self {
+        let mut inner = SDagCacheInner::default();
+
+        // create all the entries first, so that the refs can be resolved in any order
+        let mut children = HashMap::<String, Vec<String>>::new();
+        for (joint, _) in &joints {
+            inner.add_empty_joint(&joint.unit.unit);
+            for parent in &joint.unit.parent_units {
+                children
+                    .entry(parent.clone())
+                    .or_insert_with(Vec::new)
+                    .push(joint.unit.unit.clone());
+            }
+        }
+
+        for (joint, props) in joints {
+            let parents = joint
+                .unit
+                .parent_units
+                .iter()
+                .map(|key| inner.add_empty_joint(key))
+                .collect();
+            let children = children
+                .remove(&joint.unit.unit)
+                .unwrap_or_default()
+                .iter()
+                .map(|key| inner.add_empty_joint(key))
+                .collect::<Vec<_>>();
+            let best_parent = if props.best_parent_unit.is_empty() {
+                None
+            } else {
+                Some(inner.add_empty_joint(&props.best_parent_unit))
+            };
+
+            let is_free = children.is_empty();
+            let cached_joint = inner.add_empty_joint(&joint.unit.unit);
+            cached_joint.set(JointData::from_snapshot(
+                joint,
+                props,
+                parents,
+                children,
+                best_parent,
+            ));
+            inner.index_joint(&cached_joint.key, &cached_joint.raw_read());
+
+            if is_free {
+                inner
+                    .free_joints
+                    .insert(HashKey(cached_joint.key.clone()), cached_joint);
+            }
+        }
+
+        inner
+    }
+
     /// insert a valid joint into the cache
     pub fn add_normal_joint(&mut self, hash_key: HashKey, data: JointData) -> CachedJoint {
         let key = hash_key.0.clone();
+        self.index_joint(&key, &data);
         self.normal_joints
             .entry(hash_key)
             .or_insert_with(|| CachedData::new(key, RcuCell::new(Some(data))))
@@ -37,15 +152,31 @@ impl SDagCacheInner {
 
     /// insert a valid joint into the cache
     pub fn add_unhandled_joint(&mut self, hash_key: HashKey, data: JointData) -> CachedJoint {
+        if let Some(joint) = self.unhandled_joints.get(&hash_key) {
+            return joint.clone();
+        }
+
+        if let Some(peer_id) = data.get_peer_id() {
+            ::network::hub::increase_pending_from_peer(&peer_id);
+        }
         let key = hash_key.0.clone();
-        self.unhandled_joints
-            .entry(hash_key)
-            .or_insert_with(|| CachedData::new(key, RcuCell::new(Some(data))))
-            .clone()
+        let joint = CachedData::new(key, RcuCell::new(Some(data)));
+        self.unhandled_joints.insert(hash_key, joint.clone());
+        joint
+    }
+
+    /// remove a joint from unhandled and update the pending count of its source peer
+    fn remove_unhandled_joint(&mut self, key: &str) -> Option<CachedJoint> {
+        let joint = self.unhandled_joints.remove(key)?;
+        if let Some(peer_id) = joint.raw_read().get_peer_id() {
+            ::network::hub::decrease_pending_from_peer(&peer_id);
+        }
+        Some(joint)
     }
 
     /// remove a joint entry from cache
     pub fn del_joint(&mut self, key: &str) -> Option<(HashKey, CachedJoint)> {
+        self.unindex_joint(key);
         self.normal_joints.remove_entry(key)
     }
 
@@ -54,10 +185,18 @@ impl SDagCacheInner {
         self.normal_joints.get(key).cloned()
     }
 
+    pub fn get_unhandled_joint(&self, key: &str) -> Option<CachedJoint> {
+        self.unhandled_joints.get(key).cloned()
+    }
+
     pub fn get_num_of_normal_joints(&self) -> usize {
         self.normal_joints.len()
     }
 
+    pub fn get_normal_joints(&self) -> Vec<CachedJoint> {
+        self.normal_joints.values().cloned().collect()
+    }
+
     /// add empty joint into the cache
     /// this is used when there are some (parents) refs that need to create
     pub fn add_empty_joint(&mut self, key: &str) -> CachedJoint {
@@ -148,7 +287,8 @@ impl SDagCacheInner {
 
     /// move a joint from unhandled to normal
     pub fn transfer_joint_to_normal(&mut self, joint: CachedJoint) {
-        self.unhandled_joints.remove(joint.key.as_str());
+        self.remove_unhandled_joint(joint.key.as_str());
+        self.index_joint(&joint.key, &joint.raw_read());
         self.normal_joints
             .entry(HashKey(joint.key.clone()))
             .or_insert(joint);
@@ -199,15 +339,17 @@ impl SDagCacheInner {
     }
 
     /// remove the bad parent and all it's desendants in unhandled
-    pub fn purge_bad_joint(&mut self, key: Arc<String>, err: String) {
+    /// return all the units marked as known bad
+    pub fn purge_bad_joint(&mut self, key: Arc<String>, err: String) -> Vec<Arc<String>> {
         let mut stack = vec![key.to_owned()];
         let mut error = Some(err);
+        let mut purged = Vec::new();
 
         // recursively remove the bad joint along the child of the graph
         // we use deep search without a revisited hashmap
         while let Some(key) = stack.pop() {
             // remove from unhandled
-            if let Some(joint) = self.unhandled_joints.remove(&*key) {
+            if let Some(joint) = self.remove_unhandled_joint(&*key) {
                 warn!("purge bad unit = {}", key);
                 for parent in joint.raw_read().parents.iter() {
                     // dec normal joint unhandled refs
@@ -232,7 +374,9 @@ impl SDagCacheInner {
             let err = error.take().unwrap_or_else(|| String::from("bad parent"));
             error!("add known bad joint = {}, err={}", key, err);
             self.known_bad_joints.entry(key.to_string()).or_insert(err);
+            purged.push(key);
         }
+        purged
     }
 
     /// add a missing parent dependent
@@ -265,7 +409,7 @@ impl SDagCacheInner {
         // we use deep search without a revisited hashmap
         while let Some(key) = stack.pop() {
             // remove from unhandled
-            if let Some(joint) = self.unhandled_joints.remove(&*key) {
+            if let Some(joint) = self.remove_unhandled_joint(&*key) {
                 warn!("purge unhandled unit = {}", key);
                 for parent in joint.raw_read().parents.iter() {
                     // dec normal joint unhandled refs
@@ -289,7 +433,7 @@ impl SDagCacheInner {
     }
 
     // purge unhandled joints that are old enough
-    // now: is the current time in ms
+    // now: is the current monotonic time in ms
     // timeout: is the timeout value in ms
     pub fn purge_old_unhandled_joints(&mut self, now: u64, timeout: u64) {
         let mut old_joints = Vec::new();
@@ -319,10 +463,15 @@ impl SDagCacheInner {
         while let Some(ref joint) = stack.pop() {
             warn!("purge temp-bad free unit = {}", joint);
             self.free_joints.remove(joint);
-            let cached_joint = self
-                .normal_joints
-                .remove(joint)
-                .expect("purge_free_joint not found");
+            self.unindex_joint(joint);
+            let cached_joint = match self.normal_joints.remove(joint) {
+                Some(j) => j,
+                None => {
+                    // already purged by another path
+                    warn!("purge_free_joint not found, unit = {}", joint);
+                    continue;
+                }
+            };
 
             let joint = cached_joint.raw_read();
 
@@ -355,7 +504,7 @@ impl SDagCacheInner {
     }
 
     // purge temp-bad free joints that are old enough
-    // now: is the current time in ms
+    // now: is the current monotonic time in ms
     // timeout: is the timeout value in ms
     pub fn purge_old_temp_bad_free_joints(&mut self, now: u64, timeout: u64) -> Result<()> {
         // collect those bad joints
@@ -369,7 +518,7 @@ impl SDagCacheInner {
                     return None;
                 }
 
-                if now - joint.get_create_time() < timeout {
+                if now.saturating_sub(joint.get_create_time()) < timeout {
                     return None;
                 }
 
@@ -405,6 +554,91 @@ impl SDagCacheInner {
         Ok(())
     }
 
+    /// record a stable joint that could be evicted from memory
+    pub fn add_stable_joint(&mut self, key: Arc<String>, mci: Level) {
+        self.stable_joints.push(Reverse((mci.value(), key)));
+    }
+
+    /// if the normal joints exceed the memory limit, 0 means unlimited
+    pub fn should_evict(&self, max_joints: usize) -> bool {
+        max_joints > 0 && self.normal_joints.len() > max_joints
+    }
+
+    /// take the stable joints with the smallest mci that could be evicted to get below the limit
+    /// the free joints and the joints that still have in-memory children are skipped
+    pub fn take_evictable_joints(&mut self, max_joints: usize) -> Vec<(Level, CachedJoint)> {
+        if !self.should_evict(max_joints) {
+            return Vec::new();
+        }
+
+        let excess = self.normal_joints.len() - max_joints;
+        let mut evictable = Vec::new();
+        let mut skipped = Vec::new();
+        while evictable.len() < excess {
+            let Reverse((mci, key)) = match self.stable_joints.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            let joint = match self.normal_joints.get(key.as_str()) {
+                Some(joint) => joint.clone(),
+                // already purged or evicted
+                None => continue,
+            };
+
+            if self.free_joints.contains_key(key.as_str()) || has_loaded_children(&joint) {
+                skipped.push(Reverse((mci, key)));
+                continue;
+            }
+            evictable.push((Level::new(mci), joint));
+        }
+
+        self.stable_joints.extend(skipped);
+        evictable
+    }
+
+    /// drop the evicted joints from memory, they must be saved to kv already
+    /// return the number of evicted joints
+    pub fn remove_evicted_joints(&mut self, joints: Vec<(Level, CachedJoint)>) -> usize {
+        let mut evicted = 0;
+        for (mci, joint) in joints {
+            // a child may be added while saving
+            if has_loaded_children(&joint) {
+                self.add_stable_joint(joint.key.clone(), mci);
+                continue;
+            }
+
+            joint.clear();
+            self.normal_joints.remove(joint.key.as_str());
+            self.unindex_joint(joint.key.as_str());
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// get the units of the normal joints whose level is within [min, max], ordered by level
+    pub fn get_units_by_level(&self, min: usize, max: usize) -> Vec<Arc<String>> {
+        self.level_index.range(min, max)
+    }
+
+    /// get the units of the normal joints at the dag depth
+    pub fn get_units_at_depth(&self, depth: usize) -> Vec<Arc<String>> {
+        self.depth_index.range(depth, depth)
+    }
+
+    // index the joint by level and dag depth, skip the joint whose level is not calculated
+    fn index_joint(&mut self, key: &Arc<String>, data: &JointData) {
+        if let Ok(depth) = data.dag_depth_from_genesis() {
+            self.level_index.insert(key, data.get_level().value());
+            self.depth_index.insert(key, depth);
+        }
+    }
+
+    fn unindex_joint(&mut self, key: &str) {
+        self.level_index.remove(key);
+        self.depth_index.remove(key);
+    }
+
     pub fn get_normal_joints_len(&self) -> usize {
         self.normal_joints.len()
     }
@@ -442,3 +676,8 @@ impl SDagCacheInner {
         );
     }
 }
+
+// the joint is still referenced by its children in memory
+fn has_loaded_children(joint: &CachedJoint) -> bool {
+    !joint.is_empty() && joint.raw_read().children.iter().any(|c| !c.is_empty())
+}
@@ -2,7 +2,7 @@ use std::cmp;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use cache::{CachedJoint, SDAG_CACHE};
+use cache::{count_cone, CachedJoint, SDAG_CACHE};
 use error::Result;
 use failure::ResultExt;
 use hashbrown::HashSet;
@@ -10,6 +10,7 @@ use joint::{Joint, JointProperty, JointSequence, Level};
 use kv_store::{LoadFromKv, KV_STORE};
 use may::sync::{RwLock, SyncFlag};
 use rcu_cell::RcuReader;
+use spec::{CONE_SIZE_UPGRADE_MCI, MAX_CONE_SIZE};
 use utils::{AppendList, AppendListExt, Once};
 
 //---------------------------------------------------------------------------------------
@@ -23,6 +24,7 @@ pub struct UnitProps {
     pub limci: Level,
     pub sub_mci: Level,
     pub wl: Level,
+    pub dag_depth: usize,
     pub is_stable: bool,
     pub sequence: JointSequence,
 }
@@ -145,6 +147,15 @@ pub struct JointData {
     props: RwLock<JointProperty>,
     should_reclaim: AtomicBool,
     max_stable_unit: Once<CachedJoint>,
+    // peers that already got the joint, per message kind
+    broadcast_to: RwLock<HashSet<(BroadcastKind, Arc<String>)>>,
+}
+
+/// the kind of message a joint is broadcast in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BroadcastKind {
+    Joint,
+    StableJoint,
 }
 
 // impl the property access
@@ -159,6 +170,7 @@ impl JointData {
             limci: props.limci,
             sub_mci: props.sub_mci,
             wl: props.wl,
+            dag_depth: Self::dag_depth_of(&props),
             is_stable: props.is_stable,
             sequence: props.sequence,
         }
@@ -176,6 +188,26 @@ impl JointData {
         self.props.write().unwrap().level = level;
     }
 
+    /// the hops of the longest path from genesis, available once the level is calculated
+    pub fn dag_depth_from_genesis(&self) -> Result<usize> {
+        let props = self.props.read().unwrap();
+        ensure!(
+            props.level.is_valid(),
+            "level of unit {} is not calculated",
+            self.unit.unit
+        );
+        Ok(Self::dag_depth_of(&props))
+    }
+
+    // joint saved before the dag depth is introduced has no depth,
+    // which always equals the level as both count the longest path from genesis
+    fn dag_depth_of(props: &JointProperty) -> usize {
+        if props.dag_depth == 0 && props.level.is_valid() {
+            return props.level.value();
+        }
+        props.dag_depth
+    }
+
     pub fn get_limci(&self) -> Level {
         self.props.read().unwrap().limci
     }
@@ -376,6 +408,18 @@ impl JointData {
         self.peer_id.clone()
     }
 
+    /// record that the peer has got the joint, return false if already recorded
+    pub fn mark_broadcast_to(&self, kind: BroadcastKind, peer_id: Arc<String>) -> bool {
+        self.broadcast_to.write().unwrap().insert((kind, peer_id))
+    }
+
+    pub fn is_broadcast_to(&self, kind: BroadcastKind, peer_id: &Arc<String>) -> bool {
+        self.broadcast_to
+            .read()
+            .unwrap()
+            .contains(&(kind, peer_id.clone()))
+    }
+
     pub fn set_stable_prev_self_unit(&self, unit: String) {
         self.props.write().unwrap().prev_stable_self_unit = Some(unit);
     }
@@ -416,26 +460,6 @@ impl JointData {
     pub fn is_post(&self) -> bool {
         self.is_post.load(Ordering::Relaxed)
     }
-
-    // get the max stabel unit, calc if necessary
-    pub fn get_max_stable_unit(&self) -> Result<RcuReader<JointData>> {
-        if self.is_min_wl_increased() {
-            if !self.max_stable_unit.is_completed() {
-                let joint = SDAG_CACHE.get_joint(&self.unit.unit)?.read()?;
-                self.max_stable_unit.call_once(move || {
-                    ::main_chain::calc_max_stable_unit(joint)
-                        .expect("failed to calc the max stable unit")
-                });
-            }
-
-            return Ok(self.max_stable_unit.get().read()?);
-        }
-
-        bail!(
-            "only min_wl increased unit has max_stable_unit, unit={}",
-            self.unit.unit
-        );
-    }
 }
 
 impl JointData {
@@ -454,6 +478,20 @@ impl JointData {
         Ok(())
     }
 
+    /// max parent dag depth + 1, the genesis is 0
+    pub fn calc_dag_depth(&self) -> Result<usize> {
+        let mut depth = 0;
+        for parent in self.parents.iter() {
+            let parent_depth = parent
+                .read()
+                .context("calc_dag_depth")?
+                .dag_depth_from_genesis()?;
+            depth = cmp::max(depth, parent_depth + 1);
+        }
+        self.props.write().unwrap().dag_depth = depth;
+        Ok(depth)
+    }
+
     fn calc_best_parent(&self) -> Result<()> {
         use main_chain::find_best_joint;
         if let Some(best_parent) = find_best_joint(self.parents.iter())? {
@@ -487,8 +525,12 @@ impl JointData {
     /// cacl and update the basic joint property after all parents got ready
     pub fn cacl_static_props(&self) -> Result<()> {
         self.calc_level()?;
+        self.calc_dag_depth()?;
         self.calc_best_parent()?;
         self.calc_witnessed_level()?;
+        self.calc_cone_size()?;
+        // the last ball may not be stable yet, then it's cached in validation
+        self.get_last_ball_mci()?;
 
         info!(
             "After Calc unit = {} static props: {:?}",
@@ -531,6 +573,28 @@ impl JointData {
         }
     }
 
+    /// return the mci of the last ball joint, the value is cached once the last ball
+    /// is stable, return an invalid level if the last ball is not stable yet
+    pub fn get_last_ball_mci(&self) -> Result<Level> {
+        // genesis is the last ball of itself
+        if self.unit.last_ball_unit.is_none() {
+            return Ok(Level::ZERO);
+        }
+
+        let last_ball_mci = self.props.read().unwrap().last_ball_mci;
+        if last_ball_mci.is_valid() {
+            return Ok(last_ball_mci);
+        }
+
+        let last_ball_joint = self.get_last_ball_joint()?;
+        if !last_ball_joint.is_stable() {
+            return Ok(Level::INVALID);
+        }
+        let last_ball_mci = last_ball_joint.get_mci();
+        self.props.write().unwrap().last_ball_mci = last_ball_mci;
+        Ok(last_ball_mci)
+    }
+
     pub fn clear_content(&self, content_hash: String) {
         // clear the content is somehow safe here, because we no longer read the messages
         let content_hash_ptr = &self.joint.unit.content_hash as *const _ as *mut String;
@@ -555,27 +619,88 @@ impl JointData {
         }
     }
 
+    /// derive the cone size from the parents, all the parents must be ready
+    /// the cone of a single parent has no overlap, and a cone is full once any parent's is
+    /// only the joints with a few ancestors need to count the cone
+    pub fn calc_cone_size(&self) -> Result<usize> {
+        let mut parent_sizes = Vec::with_capacity(self.parents.len());
+        for parent in self.parents.iter() {
+            parent_sizes.push(parent.read()?.get_cone_size()?);
+        }
+
+        let size = match parent_sizes.as_slice() {
+            [] => 1,
+            [size] => cmp::min(size + 1, MAX_CONE_SIZE + 1),
+            sizes if sizes.iter().any(|s| *s > MAX_CONE_SIZE) => MAX_CONE_SIZE + 1,
+            _ => self.count_cone_size()?,
+        };
+        self.props.write().unwrap().cone_size = size;
+        Ok(size)
+    }
+
+    // count the ancestors (include self), stop if the count exceeds MAX_CONE_SIZE
+    fn count_cone_size(&self) -> Result<usize> {
+        count_cone(self.unit.unit.clone(), MAX_CONE_SIZE, |key| {
+            let parents = if key == &self.unit.unit {
+                self.parents.iter().map(|p| p.key.to_string()).collect()
+            } else {
+                let joint = SDAG_CACHE.get_joint(key)?.read()?;
+                joint.parents.iter().map(|p| p.key.to_string()).collect()
+            };
+            Ok(parents)
+        })
+    }
+
+    /// get the cone size that limited by MAX_CONE_SIZE
+    pub fn get_cone_size(&self) -> Result<usize> {
+        let size = self.props.read().unwrap().cone_size;
+        if size != 0 {
+            return Ok(size);
+        }
+
+        // joint saved before the cone size is introduced, count it without recursion
+        let size = self.count_cone_size()?;
+        self.props.write().unwrap().cone_size = size;
+        Ok(size)
+    }
+
     /// return true if self is more precedence than other
-    pub fn is_precedence_than(&self, other: &Self) -> bool {
+    pub fn is_precedence_than(&self, other: &Self) -> Result<bool> {
+        // the last balls are stable, so all the nodes agree on applying the cone size
+        let upgrade_mci = Level::from(CONE_SIZE_UPGRADE_MCI);
+        let cone_sizes = if self.get_last_ball_mci()? >= upgrade_mci
+            && other.get_last_ball_mci()? >= upgrade_mci
+        {
+            Some((self.get_cone_size()?, other.get_cone_size()?))
+        } else {
+            None
+        };
+
         let self_prop = self.props.read().unwrap();
         let other_prop = other.props.read().unwrap();
 
         if self_prop.wl != other_prop.wl {
-            return self_prop.wl > other_prop.wl;
+            return Ok(self_prop.wl > other_prop.wl);
         }
 
         // we select witness unit is more weighted
         let self_wit = self.unit.is_authored_by_witness();
         let other_wit = other.unit.is_authored_by_witness();
         if self_wit != other_wit {
-            return self_wit;
+            return Ok(self_wit);
         }
 
         if self_prop.level != other_prop.level {
-            return self_prop.level < other_prop.level;
+            return Ok(self_prop.level < other_prop.level);
+        }
+
+        if let Some((self_cone, other_cone)) = cone_sizes {
+            if self_cone != other_cone {
+                return Ok(self_cone > other_cone);
+            }
         }
 
-        self.unit.unit < other.unit.unit
+        Ok(self.unit.unit < other.unit.unit)
     }
 
     pub fn delete_from_kv<T: ::std::borrow::Borrow<String>>(&self, key: &T) -> Result<()> {
@@ -599,13 +724,94 @@ impl JointData {
             children: Default::default(),
             props: Default::default(),
             valid_parent_num: Default::default(),
-            create_time: crate::time::now(),
+            create_time: crate::time::monotonic_now(),
             unhandled_refs: Default::default(),
             stable_flag: Default::default(),
             is_post: Default::default(),
             should_reclaim: Default::default(),
             max_stable_unit: Default::default(),
+            broadcast_to: Default::default(),
+        }
+    }
+
+    /// rebuild the joint data from a snapshot, the refs are resolved by the cache
+    pub fn from_snapshot(
+        joint: Joint,
+        props: JointProperty,
+        parents: Vec<CachedJoint>,
+        children: Vec<CachedJoint>,
+        best_parent: Option<CachedJoint>,
+    ) -> Self {
+        let valid_parent_num = parents.len();
+
+        let stable_flag = SyncFlag::new();
+        if props.is_stable {
+            stable_flag.fire();
+        }
+
+        JointData {
+            joint,
+            parents: parents.into_iter().collect(),
+            children: children.into_iter().collect(),
+            best_parent: best_parent.into_iter().collect(),
+            stable_flag,
+            create_time: crate::time::monotonic_now(),
+            props: RwLock::new(props),
+            valid_parent_num: AtomicUsize::new(valid_parent_num),
+            unhandled_refs: AtomicUsize::new(0),
+            is_post: Default::default(),
+            peer_id: None,
+            should_reclaim: AtomicBool::new(false),
+            max_stable_unit: Once::default(),
+            broadcast_to: Default::default(),
+        }
+    }
+}
+
+// the max stable unit
+impl JointData {
+    /// get the max stable unit, calc if necessary
+    /// only the joint that makes the min_wl increased has the max stable unit
+    /// which must be checked by `is_min_wl_increased()` before calling
+    pub fn get_max_stable_unit(&self) -> Result<RcuReader<JointData>> {
+        if !self.is_min_wl_increased() {
+            bail!(
+                "only min_wl increased unit has max_stable_unit, unit={}",
+                self.unit.unit
+            );
+        }
+
+        if !self.max_stable_unit.is_completed() {
+            let max_stable_unit = self.calc_max_stable_unit()?;
+            self.max_stable_unit.call_once(move || max_stable_unit);
+        }
+
+        Ok(self.max_stable_unit.get().read()?)
+    }
+
+    // the last joint of the main chain from self to min_wl after it's updated to
+    // intersect with the main chains of all the other witnesses
+    fn calc_max_stable_unit(&self) -> Result<CachedJoint> {
+        use main_chain::{
+            build_unstable_main_chain_from_joint_to_min_wl, get_all_alt_witness_units,
+            update_mc_to_intersect,
+        };
+
+        let joint = SDAG_CACHE.get_joint(&self.unit.unit)?.read()?;
+        let min_wl = self.get_min_wl();
+        let mut mc_joints = build_unstable_main_chain_from_joint_to_min_wl(&joint, min_wl)?;
+        let alt_witnesses = get_all_alt_witness_units(&joint, &mc_joints, min_wl)?;
+        // if we already visit the witness, we can skip it
+        let mut visited = HashSet::new();
+        for joint in alt_witnesses {
+            if visited.insert(Arc::new(joint.unit.unit.to_owned())) {
+                update_mc_to_intersect(&mut mc_joints, &mut visited, joint)?;
+            }
         }
+        let max_stable_unit = mc_joints
+            .pop()
+            .ok_or_else(|| format_err!("calc max stable unit failed, unit={}", self.unit.unit))?;
+        SDAG_CACHE.get_joint(&max_stable_unit.unit.unit)
     }
 }
 
@@ -654,7 +860,7 @@ impl LoadFromKv<String> for JointData {
             children,
             best_parent,
             stable_flag,
-            create_time: crate::time::now(),
+            create_time: crate::time::monotonic_now(),
             props: RwLock::new(props),
             valid_parent_num: AtomicUsize::new(valid_parent_num),
             unhandled_refs: AtomicUsize::new(0),
@@ -662,6 +868,7 @@ impl LoadFromKv<String> for JointData {
             peer_id: None,
             should_reclaim: AtomicBool::new(false),
             max_stable_unit: Once::default(),
+            broadcast_to: Default::default(),
         })
     }
 
@@ -2,23 +2,32 @@ mod cache_data;
 mod cache_impl;
 mod joint_data;
 
+use std::cmp;
 use std::collections::VecDeque;
+use std::hash::Hash;
 use std::sync::Arc;
+use std::time::Duration;
 
+use business::{BusinessStateExport, BUSINESS_CACHE};
 use config;
 use error::Result;
 use hashbrown::{HashMap, HashSet};
-use joint::{Joint, Level};
+use joint::{Joint, JointProperty, Level};
 use kv_store::{LoadFromKv, KV_STORE};
-use may::sync::RwLock;
+use light::{self, JointProof};
+use main_chain;
+use may::sync::{RwLock, Semphore};
+use sdag_object_base::object_hash;
 use serde_json::Value;
 use smallvec::SmallVec;
+use spec::{Payload, Unit, MAX_CONE_SIZE};
 use statistics;
+use utils::BloomFilter;
 use validation;
 
 pub use self::{
     cache_data::{CachedData, HashKey},
-    joint_data::{JointData, UnitProps},
+    joint_data::{BroadcastKind, JointData, UnitProps},
 };
 
 lazy_static! {
@@ -42,6 +51,95 @@ impl CachedJoint {
     }
 }
 
+//---------------------------------------------------------------------------------------
+// CacheSnapshot
+//---------------------------------------------------------------------------------------
+/// serializable copy of the cache content, used to checkpoint test setups
+/// note that the props skipped by serde (balance, related units...) are not saved to file
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    pub joints: Vec<(Joint, JointProperty)>,
+    pub mc_units: Vec<(Level, String)>,
+    pub ball_units: Vec<(String, String)>,
+    pub hash_tree_balls: Vec<(String, String)>,
+    pub definitions: Vec<(String, (String, Value))>,
+}
+
+//---------------------------------------------------------------------------------------
+// CheckpointBundle
+//---------------------------------------------------------------------------------------
+/// the stable state at a main chain index, a fresh node could import it and catchup
+/// from there instead of replaying all the joints from genesis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointBundle {
+    pub mci: Level,
+    // the main chain joint at mci, it becomes the last stable joint after import
+    pub mc_joint: (Joint, JointProperty),
+    pub mc_units: Vec<(Level, String)>,
+    pub definitions: Vec<(String, (String, Value))>,
+    // the utxos, address stats and global state
+    pub business_state: BusinessStateExport,
+    // proves the ball of the main chain joint from the genesis
+    pub proof: JointProof,
+}
+
+//---------------------------------------------------------------------------------------
+// KnownUnits
+//---------------------------------------------------------------------------------------
+// units that ever entered the joints cache, including the unhandled and bad ones
+struct KnownUnits(BloomFilter);
+
+impl Default for KnownUnits {
+    fn default() -> Self {
+        KnownUnits(BloomFilter::with_rate(
+            config::KNOWN_UNITS_FILTER_SIZE,
+            config::KNOWN_UNITS_FILTER_FP_RATE,
+        ))
+    }
+}
+
+//---------------------------------------------------------------------------------------
+// InputIndex
+//---------------------------------------------------------------------------------------
+// spent outputs of the unhandled joints, indexed both ways so that a unit
+// is removed without scanning all the inputs
+#[derive(Default)]
+struct InputIndex {
+    // <input_key, units>
+    units: HashMap<String, Vec<Arc<String>>>,
+    // <unit, input_keys>
+    inputs: HashMap<String, Vec<String>>,
+}
+
+impl InputIndex {
+    fn add(&mut self, key: &Arc<String>, inputs: Vec<String>) {
+        for input in &inputs {
+            let units = self.units.entry(input.clone()).or_insert_with(Vec::new);
+            units.push(key.clone());
+        }
+        self.inputs.insert(key.to_string(), inputs);
+    }
+
+    fn remove(&mut self, key: &str) {
+        let inputs = match self.inputs.remove(key) {
+            Some(inputs) => inputs,
+            None => return,
+        };
+        for input in inputs {
+            let is_empty = match self.units.get_mut(&input) {
+                Some(units) => {
+                    units.retain(|u| u.as_str() != key);
+                    units.is_empty()
+                }
+                None => false,
+            };
+            if is_empty {
+                self.units.remove(&input);
+            }
+        }
+    }
+}
+
 //---------------------------------------------------------------------------------------
 // SDagCache
 //---------------------------------------------------------------------------------------
@@ -57,12 +155,19 @@ pub struct SDagCache {
     ball_units: RwLock<HashMap<String, String>>,
     // definitions<address, (unit_hash, definition)>
     definitions: RwLock<HashMap<String, (String, Value)>>,
+    // <threshold, sem> posted when the hash tree balls drop below the threshold
+    hash_tree_watermarks: RwLock<Vec<(usize, Arc<Semphore>)>>,
+    // spent outputs of the unhandled joints
+    input_index: RwLock<InputIndex>,
+    // checked before locking the joints, every key added to joints must be inserted here
+    known_units: KnownUnits,
 }
 
 impl SDagCache {
     /// insert a valid joint into the cache
     /// the joint data can be from internet or load from kv store
     fn insert_joint(&self, hash_key: HashKey, data: JointData) -> CachedJoint {
+        self.known_units.0.insert(hash_key.0.as_str());
         self.joints
             .write()
             .unwrap()
@@ -95,7 +200,10 @@ impl SDagCache {
     fn get_joint_or_none(&self, key: &str) -> CachedJoint {
         let mut g = self.joints.write().unwrap();
         match g.get_joint(key) {
-            None => g.add_empty_joint(key),
+            None => {
+                self.known_units.0.insert(key);
+                g.add_empty_joint(key)
+            }
             Some(j) => j,
         }
     }
@@ -174,6 +282,11 @@ impl SDagCache {
         self.joints.read().unwrap().get_num_of_unhandled_joints()
     }
 
+    /// get the number of joints that are received but not handled yet
+    pub fn get_pending_count(&self) -> usize {
+        self.get_num_of_unhandled_joints() + ::business::BUSINESS_WORKER.get_queue_len()
+    }
+
     pub fn get_num_of_normal_joints(&self) -> usize {
         self.joints.read().unwrap().get_num_of_normal_joints()
     }
@@ -212,21 +325,22 @@ impl SDagCache {
 
     /// check if the joint is new, only new joint will be handled
     pub fn check_new_joint(&self, joint: &str) -> Result<()> {
-        let cache = self.joints.read().unwrap();
-        if cache.get_joint(joint).is_some() {
-            bail!("joint is already known in cache, unit = {}", joint);
-        }
+        // the joint never entered the cache, no need to lock the joints
+        if self.known_units.0.may_contain(joint) {
+            let cache = self.joints.read().unwrap();
+            if cache.get_joint(joint).is_some() {
+                bail!("joint is already known in cache, unit = {}", joint);
+            }
 
-        if cache.is_known_unhandled_joint(joint) {
-            bail!("joint is already known unhandled, unit = {}", joint);
-        }
+            if cache.is_known_unhandled_joint(joint) {
+                bail!("joint is already known unhandled, unit = {}", joint);
+            }
 
-        if cache.is_known_bad_joint(joint) {
-            bail!("joint is known bad, unit = {}", joint);
+            if cache.is_known_bad_joint(joint) {
+                bail!("joint is known bad, unit = {}", joint);
+            }
         }
 
-        drop(cache);
-
         if KV_STORE.is_joint_exist(joint)? {
             bail!("joint is already known in kv, unit = {}", joint);
         }
@@ -234,11 +348,16 @@ impl SDagCache {
         Ok(())
     }
 
-    /// purge a bad joint
+    /// purge a bad joint and the unhandled joints that depend on it
     pub fn purge_bad_joint(&self, key: Arc<String>, err: String) {
-        let mut g = self.joints.write().unwrap();
-        // then we need to purge all the child that depends on it
-        g.purge_bad_joint(key, err);
+        self.known_units.0.insert(key.as_str());
+        // an unhandled joint is linked to its unhandled parents only by the missing
+        // parents, so the purge walks exactly the unhandled subtree of the bad joint.
+        // the children links only exist between normal joints, which are never purged
+        let purged = self.joints.write().unwrap().purge_bad_joint(key, err);
+        for unit in purged {
+            self.remove_from_input_index(&unit);
+        }
     }
 
     /// add a new joint into the unhandled memory cache
@@ -254,6 +373,10 @@ impl SDagCache {
             // need to record as known bad joint
             self.purge_bad_joint(key.0, e.to_string());
             let peer_id = peer_id.unwrap_or_else(|| Arc::new(String::from("unknown")));
+            // only a malformed joint is for sure the fault of the peer
+            if validation::validate_joint_format(&joint_data).is_err() {
+                ::network::hub::update_peer_reputation(&peer_id, false);
+            }
             statistics::increase_stats(peer_id, true, false);
             bail!("base validation failed, err={}", e);
         }
@@ -284,6 +407,7 @@ impl SDagCache {
             match g.get_joint(parent) {
                 None => {
                     if KV_STORE.is_joint_exist(parent)? {
+                        self.known_units.0.insert(parent.as_str());
                         let j = g.add_empty_joint(parent);
                         valid_parents.push(j);
                     } else {
@@ -300,6 +424,8 @@ impl SDagCache {
             joint_data.add_parent(valid_parent);
         }
 
+        self.add_to_input_index(&key.0, &joint_data.unit);
+        self.known_units.0.insert(key.0.as_str());
         let cached_joint = g.add_unhandled_joint(key, joint_data);
 
         // add the missing parent
@@ -312,9 +438,55 @@ impl SDagCache {
 
     /// normalize a joint: move the joint from unhandled to normal
     pub fn normalize_joint(&self, joint: CachedJoint) {
-        let mut g = self.joints.write().unwrap();
-        g.transfer_joint_to_normal(joint.clone());
-        g.update_parent_and_child(joint);
+        // the free joints are checked directly for double spends
+        self.remove_from_input_index(&joint.key);
+        {
+            let mut g = self.joints.write().unwrap();
+            g.transfer_joint_to_normal(joint.clone());
+            g.update_parent_and_child(joint);
+        }
+
+        self.evict_stable_joints();
+    }
+
+    /// evict the oldest stable joints when exceeding the limit
+    /// the joints are saved to kv without holding the joints lock
+    fn evict_stable_joints(&self) {
+        // the evicted joints could never be loaded back
+        if !::kv_store::is_persistent() {
+            return;
+        }
+
+        let max_joints = config::get_max_memory_joints();
+        let joints = self
+            .joints
+            .write()
+            .unwrap()
+            .take_evictable_joints(max_joints);
+        if joints.is_empty() {
+            return;
+        }
+
+        let mut saved = Vec::with_capacity(joints.len());
+        for (mci, joint) in joints {
+            // the reclaimed joint is already saved
+            if !joint.is_empty() {
+                if let Err(e) = joint.save_to_db() {
+                    error!("failed to evict unit={}, err={}", joint.key, e);
+                    self.add_stable_joint(joint.key.clone(), mci);
+                    continue;
+                }
+            }
+            saved.push((mci, joint));
+        }
+
+        let n = self.joints.write().unwrap().remove_evicted_joints(saved);
+        debug!("evicted {} stable joints from memory", n);
+    }
+
+    /// the stable joint could be evicted from memory when exceeding the limit
+    pub fn add_stable_joint(&self, key: Arc<String>, mci: Level) {
+        self.joints.write().unwrap().add_stable_joint(key, mci);
     }
 
     /// judge if a ball is exit in the hash tree ball
@@ -354,7 +526,28 @@ impl SDagCache {
     /// remove the ball entry in hash tree balls when a joint got stable
     pub fn del_hash_tree_ball(&self, ball: &str) -> Option<String> {
         let mut g = self.hash_tree_balls.write().unwrap();
-        g.remove(ball)
+        let unit = g.remove(ball);
+        if unit.is_some() {
+            // only notify when the len just crossed below the threshold
+            let len = g.len();
+            for (threshold, sem) in self.hash_tree_watermarks.read().unwrap().iter() {
+                if len + 1 == *threshold {
+                    sem.post();
+                }
+            }
+        }
+        unit
+    }
+
+    /// the sem is posted each time the hash tree balls drop below the threshold
+    pub fn register_hash_tree_low_watermark(&self, threshold: usize, sem: Arc<Semphore>) {
+        let mut g = self.hash_tree_watermarks.write().unwrap();
+        g.push((threshold, sem));
+    }
+
+    pub fn unregister_hash_tree_low_watermark(&self, sem: &Arc<Semphore>) {
+        let mut g = self.hash_tree_watermarks.write().unwrap();
+        g.retain(|(_, s)| !Arc::ptr_eq(s, sem));
     }
 
     /// clear all the  hash tree balls
@@ -417,6 +610,36 @@ impl SDagCache {
         Ok(joints.into_iter().map(|v| v.1).collect())
     }
 
+    /// get all normal joints in memory whose level is within [min, max], ordered by level
+    /// the evicted joints are not indexed
+    pub fn get_joints_by_level(&self, min: usize, max: usize) -> Result<Vec<CachedJoint>> {
+        let (min, max) = (cmp::min(min, max), cmp::max(min, max));
+        let units = self.joints.read().unwrap().get_units_by_level(min, max);
+
+        let mut joints = Vec::with_capacity(units.len());
+        for unit in units {
+            match self.get_joint(&unit) {
+                Ok(joint) => joints.push(joint),
+                Err(e) => debug!("skip level indexed unit={}, err={}", unit, e),
+            }
+        }
+        Ok(joints)
+    }
+
+    /// get all normal joints at the dag depth from genesis
+    pub fn get_joints_at_depth(&self, depth: usize) -> Result<Vec<CachedJoint>> {
+        let units = self.joints.read().unwrap().get_units_at_depth(depth);
+
+        let mut joints = Vec::with_capacity(units.len());
+        for unit in units {
+            match self.get_joint(&unit) {
+                Ok(joint) => joints.push(joint),
+                Err(e) => debug!("skip depth indexed unit={}, err={}", unit, e),
+            }
+        }
+        Ok(joints)
+    }
+
     /// get all missing joints
     pub fn get_missing_joints(&self) -> Vec<String> {
         let g = self.joints.read().unwrap();
@@ -469,7 +692,7 @@ impl SDagCache {
     }
 
     // purge unhandled joints that are old enough
-    // now: is the current time in ms
+    // now: is the current monotonic time in ms
     // timeout: is the timeout value in ms
     pub fn purge_old_unhandled_joints(&self, now: u64, timeout: u64) {
         self.joints
@@ -479,9 +702,11 @@ impl SDagCache {
     }
 
     // purge temp-bad free joints that are old enough
-    // now: is the current time in ms
+    // now: is the current monotonic time in ms
     // timeout: is the timeout value in ms
+    // a temp-bad joint leaves nothing in the temp business state, it's safe to just drop it
     pub fn purge_old_temp_bad_free_joints(&self, now: u64, timeout: u64) -> Result<()> {
+        // check and purge under the same lock, a joint may get a child in between
         self.joints
             .write()
             .unwrap()
@@ -500,4 +725,992 @@ impl SDagCache {
     pub fn run_gc(&self) {
         self.joints.read().unwrap().run_gc()
     }
+
+    /// get the number of ancestors (include itself) of a joint
+    /// the count is capped at max_count + 1, max_count is limited by MAX_CONE_SIZE
+    pub fn get_joint_cone_size(&self, key: &str, max_count: usize) -> Result<usize> {
+        let max_count = cmp::min(max_count, MAX_CONE_SIZE);
+        let size = self.get_joint(key)?.read()?.get_cone_size()?;
+        Ok(cmp::min(size, max_count + 1))
+    }
+
+    /// BFS get the descendants of a joint, immediate children first
+    /// the joint itself is not included, at most max_depth * 100 hashes are returned
+    pub fn get_joint_children_recursive(&self, key: &str, max_depth: usize) -> Result<Vec<String>> {
+        collect_descendants(key.to_owned(), max_depth, max_depth * 100, |key| {
+            let joint = match self.get_known_joint(key) {
+                Some(j) => j,
+                None => self.get_joint(key)?,
+            };
+            let joint = joint.read()?;
+            Ok(joint.children.iter().map(|c| c.key.to_string()).collect())
+        })
+    }
+
+    /// BFS get the ancestors of a joint within depth hops, immediate parents first
+    /// the stable ancestors are returned but not walked through, the joint itself is not
+    /// included, at most depth * 100 joints are returned
+    pub fn get_joint_ancestors(&self, unit: &str, depth: usize) -> Result<Vec<CachedJoint>> {
+        let units = collect_descendants(unit.to_owned(), depth, depth * 100, |key| {
+            let joint = self.get_joint(key)?.read()?;
+            if key != unit && joint.is_stable() {
+                return Ok(Vec::new());
+            }
+            Ok(joint.parents.iter().map(|p| p.key.to_string()).collect())
+        })?;
+
+        units.iter().map(|unit| self.get_joint(unit)).collect()
+    }
+
+    /// take a snapshot of the normal joints and the cached indexes
+    pub fn snapshot(&self) -> Result<CacheSnapshot> {
+        // reading a reclaimed joint needs the write lock, so don't hold the guard here
+        let cached_joints = self.joints.read().unwrap().get_normal_joints();
+
+        let mut joints = Vec::with_capacity(cached_joints.len());
+        for cached_joint in cached_joints {
+            let joint_data = cached_joint.read()?;
+            let props = joint_data.get_all_props().read().unwrap().clone();
+            joints.push((Joint::clone(&joint_data), props));
+        }
+        // keep the output stable for file checkpoints
+        joints.sort_by(|a, b| {
+            (a.1.level.value(), &a.0.unit.unit).cmp(&(b.1.level.value(), &b.0.unit.unit))
+        });
+
+        // read the indexes under all their locks, so they are from the same moment
+        let mc_units = self.mc_units.read().unwrap();
+        let ball_units = self.ball_units.read().unwrap();
+        let hash_tree_balls = self.hash_tree_balls.read().unwrap();
+        let definitions = self.definitions.read().unwrap();
+
+        let mut mc_units = mc_units
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect::<Vec<_>>();
+        mc_units.sort_by_key(|v| v.0.value());
+
+        Ok(CacheSnapshot {
+            joints,
+            mc_units,
+            ball_units: clone_map(&ball_units),
+            hash_tree_balls: clone_map(&hash_tree_balls),
+            definitions: clone_map(&definitions),
+        })
+    }
+
+    /// replace the whole cache content with the snapshot
+    /// the unhandled, missing and known bad joints are all dropped
+    pub fn restore(&self, snapshot: CacheSnapshot) {
+        let CacheSnapshot {
+            joints,
+            mc_units,
+            ball_units,
+            hash_tree_balls,
+            definitions,
+        } = snapshot;
+
+        for (joint, props) in &joints {
+            // the parents are also added to the joints as refs
+            self.known_units.0.insert(joint.unit.unit.as_str());
+            for parent in &joint.unit.parent_units {
+                self.known_units.0.insert(parent.as_str());
+            }
+            self.known_units.0.insert(props.best_parent_unit.as_str());
+        }
+
+        let inner = cache_impl::SDagCacheInner::from_snapshot(joints);
+
+        // replace everything under all the locks, readers never see a mixed state
+        let mut joints_g = self.joints.write().unwrap();
+        let mut mc_units_g = self.mc_units.write().unwrap();
+        let mut ball_units_g = self.ball_units.write().unwrap();
+        let mut hash_tree_balls_g = self.hash_tree_balls.write().unwrap();
+        let mut definitions_g = self.definitions.write().unwrap();
+        *joints_g = inner;
+        *mc_units_g = mc_units.into_iter().collect();
+        *ball_units_g = ball_units.into_iter().collect();
+        *hash_tree_balls_g = hash_tree_balls.into_iter().collect();
+        *definitions_g = definitions.into_iter().collect();
+    }
+
+    /// export the checkpoint at the last stable mci
+    /// the business state can't be rolled back, so the older mci is not supported
+    pub fn export_checkpoint(&self, mci: Level) -> Result<CheckpointBundle> {
+        let last_stable_mci = main_chain::get_last_stable_mci();
+        ensure!(
+            mci == last_stable_mci,
+            "only the last stable mci {} could be checkpointed, request {}",
+            last_stable_mci.value(),
+            mci.value()
+        );
+
+        let business_state = BUSINESS_CACHE.export_applied_state(Duration::from_secs(5))?;
+        ensure!(
+            business_state.snapshot_mci == mci,
+            "the last stable mci changed while exporting the checkpoint"
+        );
+
+        let mc_unit = self
+            .get_mc_unit_hash(mci)?
+            .ok_or_else(|| format_err!("no main chain unit at mci {}", mci.value()))?;
+        let joint_data = self.get_joint(&mc_unit)?.read()?;
+        let props = joint_data.get_all_props().read().unwrap().clone();
+
+        let mut mc_units = self
+            .mc_units
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(k, _)| **k <= mci)
+            .map(|(k, v)| (*k, v.clone()))
+            .collect::<Vec<_>>();
+        mc_units.sort_by_key(|v| v.0.value());
+
+        Ok(CheckpointBundle {
+            mci,
+            mc_joint: (Joint::clone(&joint_data), props),
+            mc_units,
+            definitions: clone_map(&self.definitions.read().unwrap()),
+            business_state,
+            proof: light::prepare_joint_proof(&mc_unit, mci)?,
+        })
+    }
+
+    /// load the checkpoint into a fresh node and set the last stable joint
+    /// the imported state is kept in memory only
+    pub fn import_checkpoint(&self, bundle: CheckpointBundle) -> Result<()> {
+        ensure!(
+            self.get_num_of_normal_joints() == 0,
+            "checkpoint could only be imported by a fresh node"
+        );
+        let genesis_ball =
+            object_hash::calc_ball_hash(&config::get_genesis_unit(), &[], &[], false);
+        check_checkpoint(&bundle, &genesis_ball)?;
+
+        let CheckpointBundle {
+            mci,
+            mc_joint: (joint, props),
+            mc_units,
+            definitions,
+            business_state,
+            ..
+        } = bundle;
+
+        let unit = joint.unit.unit.clone();
+        let ball = joint.ball.clone().unwrap_or_default();
+        self.restore(CacheSnapshot {
+            joints: vec![(joint, props)],
+            mc_units,
+            ball_units: vec![(ball, unit.clone())],
+            hash_tree_balls: Vec::new(),
+            definitions,
+        });
+        main_chain::set_last_stable_joint(self.get_joint(&unit)?.read()?);
+        BUSINESS_CACHE.import_state(business_state)?;
+
+        info!("imported checkpoint at mci {}, unit={}", mci.value(), unit);
+        Ok(())
+    }
+
+    /// get the free and unhandled joints that spend the same output with the unit
+    pub fn get_double_spend_joints(&self, unit: &str) -> Result<Vec<String>> {
+        let joint = match self.get_known_joint(unit) {
+            Some(j) => j,
+            None => self.get_joint(unit)?,
+        };
+        let inputs = get_input_keys(&joint.read()?.unit);
+
+        let mut conflicts = HashSet::new();
+        {
+            let g = self.input_index.read().unwrap();
+            for input in &inputs {
+                if let Some(units) = g.units.get(input) {
+                    conflicts.extend(units.iter().map(|u| u.to_string()));
+                }
+            }
+        }
+        // the descendants of a bad joint are purged without updating the index
+        {
+            let g = self.joints.read().unwrap();
+            conflicts.retain(|u| g.is_known_unhandled_joint(u));
+        }
+
+        for free_joint in self.get_all_free_joints() {
+            let free_joint = free_joint.read()?;
+            if get_input_keys(&free_joint.unit)
+                .iter()
+                .any(|k| inputs.contains(k))
+            {
+                conflicts.insert(free_joint.unit.unit.clone());
+            }
+        }
+
+        conflicts.remove(unit);
+        let mut conflicts = conflicts.into_iter().collect::<Vec<_>>();
+        conflicts.sort();
+        Ok(conflicts)
+    }
+
+    fn add_to_input_index(&self, key: &Arc<String>, unit: &Unit) {
+        let inputs = get_input_keys(unit);
+        if !inputs.is_empty() {
+            self.input_index.write().unwrap().add(key, inputs);
+        }
+    }
+
+    fn remove_from_input_index(&self, key: &str) {
+        self.input_index.write().unwrap().remove(key);
+    }
+
+    // get a joint from the normal or unhandled joints
+    fn get_known_joint(&self, key: &str) -> Option<CachedJoint> {
+        let g = self.joints.read().unwrap();
+        g.get_joint(key).or_else(|| g.get_unhandled_joint(key))
+    }
+}
+
+//---------------------------------------------------------------------------------------
+// Global functions
+//---------------------------------------------------------------------------------------
+// the spent outputs of the unit, like `source_unit:message_index:output_index`
+fn get_input_keys(unit: &Unit) -> Vec<String> {
+    let mut keys = Vec::new();
+    for msg in &unit.messages {
+        if let Some(Payload::Payment(ref payment)) = msg.payload {
+            for input in &payment.inputs {
+                if let Some(ref src_unit) = input.unit {
+                    keys.push(format!(
+                        "{}:{}:{}",
+                        src_unit,
+                        input.message_index.unwrap_or(0),
+                        input.output_index.unwrap_or(0)
+                    ));
+                }
+            }
+        }
+    }
+    keys
+}
+
+// the checkpoint joint must be a stable main chain joint at the checkpoint mci
+// and its ball is proved from the genesis ball
+// the business state can't be verified, so the checkpoint should be from a trusted source
+fn check_checkpoint(bundle: &CheckpointBundle, genesis_ball: &str) -> Result<()> {
+    let (ref joint, ref props) = bundle.mc_joint;
+    validation::validate_unit_hash(&joint.unit)?;
+    ensure!(
+        props.is_stable && props.mci == bundle.mci && joint.ball.is_some(),
+        "checkpoint joint {} is not stable at mci {}",
+        joint.unit.unit,
+        bundle.mci.value()
+    );
+    ensure!(
+        bundle
+            .mc_units
+            .iter()
+            .any(|(mci, unit)| *mci == bundle.mci && *unit == joint.unit.unit),
+        "checkpoint joint {} is not on the main chain",
+        joint.unit.unit
+    );
+    ensure!(
+        bundle.business_state.snapshot_mci == bundle.mci,
+        "checkpoint business state is not at mci {}",
+        bundle.mci.value()
+    );
+
+    let proof = &bundle.proof;
+    light::verify_joint_proof(proof, genesis_ball)?;
+    ensure!(
+        proof.unit == joint.unit.unit
+            && proof.reference_mci == bundle.mci
+            && proof.get_reference_ball() == joint.ball.as_ref().map(|b| b.as_str()),
+        "checkpoint joint {} is not proved",
+        joint.unit.unit
+    );
+    // the proved main chain units must agree with the bundle
+    for step in &proof.main_chain_proof.steps {
+        ensure!(
+            bundle
+                .mc_units
+                .iter()
+                .any(|(mci, unit)| *mci == step.mci && *unit == step.unit),
+            "main chain unit {} at mci {} is not in the checkpoint",
+            step.unit,
+            step.mci.value()
+        );
+    }
+    Ok(())
+}
+
+fn clone_map<V: Clone>(map: &HashMap<String, V>) -> Vec<(String, V)> {
+    let mut v = map
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+    v.sort_by(|a, b| a.0.cmp(&b.0));
+    v
+}
+
+/// BFS collect the descendants of the start node in order, not include the start node
+/// stop when max_depth is reached or the number of descendants reaches max_count
+fn collect_descendants<K, F>(
+    start: K,
+    max_depth: usize,
+    max_count: usize,
+    mut get_children: F,
+) -> Result<Vec<K>>
+where
+    K: Eq + Hash + Clone,
+    F: FnMut(&K) -> Result<Vec<K>>,
+{
+    let mut descendants = Vec::new();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(start.clone());
+    queue.push_back((start, 0));
+
+    while let Some((node, depth)) = queue.pop_front() {
+        if depth >= max_depth {
+            continue;
+        }
+
+        for child in get_children(&node)? {
+            if visited.insert(child.clone()) {
+                if descendants.len() >= max_count {
+                    return Ok(descendants);
+                }
+                descendants.push(child.clone());
+                queue.push_back((child, depth + 1));
+            }
+        }
+    }
+
+    Ok(descendants)
+}
+
+/// BFS count the start node and all its ancestors
+/// return early once the count exceeds max_count
+fn count_cone<K, F>(start: K, max_count: usize, mut get_parents: F) -> Result<usize>
+where
+    K: Eq + Hash + Clone,
+    F: FnMut(&K) -> Result<Vec<K>>,
+{
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(start.clone());
+    queue.push_back(start);
+
+    while let Some(node) = queue.pop_front() {
+        for parent in get_parents(&node)? {
+            if visited.insert(parent.clone()) {
+                if visited.len() > max_count {
+                    return Ok(visited.len());
+                }
+                queue.push_back(parent);
+            }
+        }
+    }
+
+    Ok(visited.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use light::{LinkProof, LinkProofStep};
+
+    // node i has node 2i+1 and 2i+2 as parents, node 0 is the root
+    fn tree_parents(node: &usize) -> Result<Vec<usize>> {
+        let total = 31;
+        Ok([2 * node + 1, 2 * node + 2]
+            .iter()
+            .cloned()
+            .filter(|n| *n < total)
+            .collect())
+    }
+
+    #[test]
+    fn test_cone_size() {
+        assert_eq!(count_cone(0, 1000, tree_parents).unwrap(), 31);
+        // leaf
+        assert_eq!(count_cone(30, 1000, tree_parents).unwrap(), 1);
+        // the node has two levels of ancestors
+        assert_eq!(count_cone(3, 1000, tree_parents).unwrap(), 7);
+        // stop early
+        assert_eq!(count_cone(0, 10, tree_parents).unwrap(), 11);
+    }
+
+    #[test]
+    fn test_calc_cone_size() {
+        let cache = SDagCache::default();
+        let genesis = add_mc_joint(&cache, None, 0, "genesis");
+        let unit_1 = add_mc_joint(&cache, Some(&genesis), 1, "1");
+        let unit_2 = add_mc_joint(&cache, Some(&unit_1), 2, "2");
+        for (unit, size) in &[(&genesis, 1), (&unit_1, 2), (&unit_2, 3)] {
+            let joint = cache.try_get_joint(unit).unwrap().raw_read();
+            assert_eq!(joint.calc_cone_size().unwrap(), *size);
+        }
+        // the count is capped by the caller
+        assert_eq!(cache.get_joint_cone_size(&unit_2, 1).unwrap(), 2);
+        assert_eq!(cache.get_joint_cone_size(&unit_2, 100).unwrap(), 3);
+
+        // the cone is full once any parent's cone is full
+        let full = cache.try_get_joint(&unit_1).unwrap();
+        full.raw_read().get_all_props().write().unwrap().cone_size = MAX_CONE_SIZE + 1;
+        let joint_data = JointData::from_joint(new_text_joint(Some(&unit_2), "3"), None);
+        joint_data.add_parent(cache.try_get_joint(&unit_2).unwrap());
+        joint_data.add_parent(full);
+        assert_eq!(joint_data.calc_cone_size().unwrap(), MAX_CONE_SIZE + 1);
+    }
+
+    #[test]
+    fn test_cone_size_precedence() {
+        use spec::CONE_SIZE_UPGRADE_MCI;
+
+        let new_joint = |text: &str| {
+            let mut joint = new_text_joint(Some("genesis"), text);
+            joint.unit.last_ball_unit = Some(String::from("last_ball"));
+            JointData::from_joint(joint, None)
+        };
+        let set_props = |joint: &JointData, cone_size: usize, last_ball_mci: usize| {
+            let mut props = joint.get_all_props().write().unwrap();
+            props.level = Level::new(1);
+            props.wl = Level::new(0);
+            props.cone_size = cone_size;
+            props.last_ball_mci = Level::from(last_ball_mci);
+        };
+
+        // the small cone wins by the unit hash before the upgrade
+        let (a, b) = (new_joint("a"), new_joint("b"));
+        let (small, big) = if a.unit.unit < b.unit.unit {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        set_props(&small, 3, CONE_SIZE_UPGRADE_MCI - 1);
+        set_props(&big, 5, CONE_SIZE_UPGRADE_MCI - 1);
+        assert!(small.is_precedence_than(&big).unwrap());
+
+        // both last balls must be after the upgrade
+        set_props(&big, 5, CONE_SIZE_UPGRADE_MCI);
+        assert!(small.is_precedence_than(&big).unwrap());
+        set_props(&small, 3, CONE_SIZE_UPGRADE_MCI);
+        assert!(big.is_precedence_than(&small).unwrap());
+    }
+
+    // root 0 -> 2 children -> 4 grandchildren
+    fn tree_children(node: &usize) -> Result<Vec<usize>> {
+        let total = 7;
+        Ok([2 * node + 1, 2 * node + 2]
+            .iter()
+            .cloned()
+            .filter(|n| *n < total)
+            .collect())
+    }
+
+    #[test]
+    fn test_children_recursive() {
+        let children = collect_descendants(0, 1, 100, tree_children).unwrap();
+        assert_eq!(children, vec![1, 2]);
+
+        let children = collect_descendants(0, 2, 200, tree_children).unwrap();
+        assert_eq!(children, vec![1, 2, 3, 4, 5, 6]);
+        assert!(!children.contains(&0));
+
+        // deeper than the tree
+        let children = collect_descendants(0, 10, 1000, tree_children).unwrap();
+        assert_eq!(children.len(), 6);
+
+        // capped by max_count
+        let children = collect_descendants(0, 2, 3, tree_children).unwrap();
+        assert_eq!(children, vec![1, 2, 3]);
+    }
+
+    // the joint hash is decided by the text
+    fn new_text_joint(parent: Option<&str>, text: &str) -> Joint {
+        use spec::{Payload, UnitBuilder};
+
+        let mut builder = UnitBuilder::new().message("text", Payload::Text(text.to_owned()));
+        if let Some(parent) = parent {
+            builder = builder.parent(parent);
+        }
+        Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit: builder.build().unwrap(),
+        }
+    }
+
+    // add a good stable joint on the main chain
+    fn add_mc_joint(cache: &SDagCache, parent: Option<&str>, mci: usize, text: &str) -> String {
+        use joint::JointSequence;
+
+        let joint = new_text_joint(parent, text);
+        let key = joint.unit.unit.clone();
+
+        let joint_data = JointData::from_joint(joint, None);
+        if let Some(parent) = parent {
+            let parent = cache.try_get_joint(parent).unwrap();
+            parent.raw_read().inc_unhandled_refs();
+            joint_data.add_parent(parent.clone());
+            joint_data.set_best_parent(parent);
+        }
+        joint_data.set_level(Level::new(mci));
+        joint_data.calc_dag_depth().unwrap();
+        joint_data.set_mci(Level::new(mci));
+        joint_data.set_limci(Level::new(mci));
+        joint_data.set_sequence(JointSequence::Good);
+        joint_data.set_stable();
+
+        let cached_joint = cache.insert_joint(HashKey::new(&key), joint_data);
+        cache.normalize_joint(cached_joint);
+        cache
+            .set_mc_unit_hash(Level::new(mci), key.clone())
+            .unwrap();
+        key
+    }
+
+    #[test]
+    fn test_check_checkpoint() {
+        let cache = SDagCache::default();
+        let genesis = add_mc_joint(&cache, None, 0, "genesis");
+        let unit = add_mc_joint(&cache, Some(&genesis), 1, "1");
+
+        let joint_data = cache.try_get_joint(&unit).unwrap().raw_read();
+        let genesis_step =
+            LinkProofStep::new(genesis.clone(), Level::new(0), vec![], vec![], false);
+        let genesis_ball = genesis_step.ball.clone();
+        let unit_step = LinkProofStep::new(
+            unit.clone(),
+            Level::new(1),
+            vec![genesis_ball.clone()],
+            vec![],
+            false,
+        );
+        let mut joint = Joint::clone(&joint_data);
+        joint.ball = Some(unit_step.ball.clone());
+        let props = joint_data.get_all_props().read().unwrap().clone();
+        let business_state: BusinessStateExport = serde_json::from_value(json!({
+            "snapshot_mci": 1,
+            "utxo_count": 0,
+            "utxos": [],
+            "payload_commission_output": [],
+            "headers_commission_output": [],
+            "text": {"texts": []},
+            "data_feed": {"cur_time": 0, "feeds": []},
+            "address_stats": {},
+        }))
+        .unwrap();
+
+        let bundle = CheckpointBundle {
+            mci: Level::new(1),
+            mc_joint: (joint, props),
+            mc_units: vec![
+                (Level::new(0), genesis.clone()),
+                (Level::new(1), unit.clone()),
+            ],
+            definitions: Vec::new(),
+            business_state,
+            proof: JointProof {
+                unit: unit.clone(),
+                reference_mci: Level::new(1),
+                link_proof: LinkProof {
+                    unit: unit.clone(),
+                    steps: vec![unit_step.clone()],
+                },
+                main_chain_proof: LinkProof {
+                    unit: genesis.clone(),
+                    steps: vec![genesis_step, unit_step],
+                },
+            },
+        };
+        let json = serde_json::to_string(&bundle).unwrap();
+        let bundle: CheckpointBundle = serde_json::from_str(&json).unwrap();
+        assert!(check_checkpoint(&bundle, &genesis_ball).is_ok());
+
+        // the joint is not the main chain unit of the mci
+        let mut wrong = bundle.clone();
+        wrong.mc_units[1].1 = genesis.clone();
+        assert!(check_checkpoint(&wrong, &genesis_ball).is_err());
+
+        // the business state is not at the mci
+        let mut wrong = bundle.clone();
+        wrong.business_state.snapshot_mci = Level::new(0);
+        assert!(check_checkpoint(&wrong, &genesis_ball).is_err());
+
+        // the joint is modified
+        let mut wrong = bundle.clone();
+        wrong.mc_joint.0.unit.timestamp = Some(1);
+        assert!(check_checkpoint(&wrong, &genesis_ball).is_err());
+
+        // the ball is not proved
+        let mut wrong = bundle.clone();
+        wrong.mc_joint.0.ball = Some("ball".to_owned());
+        assert!(check_checkpoint(&wrong, &genesis_ball).is_err());
+
+        // proved from another genesis
+        assert!(check_checkpoint(&bundle, &unit).is_err());
+
+        // the joint has no ball
+        let mut wrong = bundle;
+        wrong.mc_joint.0.ball = None;
+        assert!(check_checkpoint(&wrong, &genesis_ball).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let cache = SDagCache::default();
+        let address = "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI".to_owned();
+
+        // genesis and 3 more mci
+        let mut units = vec![add_mc_joint(&cache, None, 0, "genesis")];
+        for mci in 1..4 {
+            let parent = units[mci - 1].clone();
+            units.push(add_mc_joint(&cache, Some(&parent), mci, &mci.to_string()));
+        }
+        cache.insert_definition(
+            address.clone(),
+            units[0].clone(),
+            json!(["sig", {"pubkey": "A"}]),
+        );
+        for (i, unit) in units.iter().enumerate() {
+            let joint = cache.try_get_joint(unit).unwrap();
+            joint.raw_read().get_all_props().write().unwrap().cone_size = i + 1;
+        }
+
+        // checkpoint through the serialized form
+        let snapshot = cache.snapshot().unwrap();
+        assert_eq!(snapshot.joints.len(), 4);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: CacheSnapshot = serde_json::from_str(&json).unwrap();
+
+        // modify the cache
+        let unit_4 = add_mc_joint(&cache, Some(&units[3]), 4, "4");
+        let unit_5 = add_mc_joint(&cache, Some(&unit_4), 5, "5");
+        cache
+            .set_mc_unit_hash(Level::new(3), unit_5.clone())
+            .unwrap();
+        cache.insert_definition(
+            address.clone(),
+            unit_4.clone(),
+            json!(["sig", {"pubkey": "B"}]),
+        );
+        assert_eq!(cache.get_joints_len(), 6);
+
+        cache.restore(snapshot);
+        assert_eq!(cache.get_joints_len(), 4);
+        assert_eq!(
+            cache.get_mc_unit_hash(Level::new(3)).unwrap(),
+            Some(units[3].clone())
+        );
+        assert_eq!(
+            cache.get_definition(&address),
+            Some((units[0].clone(), json!(["sig", {"pubkey": "A"}])))
+        );
+        assert!(cache.try_get_joint(&unit_4).is_none());
+        assert!(cache.try_get_joint(&unit_5).is_none());
+
+        // the graph is rebuilt
+        let last = cache.try_get_joint(&units[3]).unwrap();
+        let last = last.raw_read();
+        assert!(last.is_free());
+        assert!(last.is_stable());
+        assert_eq!(last.get_mci(), Level::new(3));
+        assert_eq!(last.get_cone_size().unwrap(), 4);
+        assert_eq!(*last.get_best_parent().key, units[2]);
+        let free_joints = cache.get_all_free_joints();
+        assert_eq!(free_joints.len(), 1);
+        assert_eq!(*free_joints[0].key, units[3]);
+
+        let genesis = cache.try_get_joint(&units[0]).unwrap();
+        let children = genesis
+            .raw_read()
+            .children
+            .iter()
+            .map(|c| c.key.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(children, vec![units[1].clone()]);
+    }
+
+    #[test]
+    fn test_check_known_joint() {
+        let cache = SDagCache::default();
+        let genesis = add_mc_joint(&cache, None, 0, "genesis");
+        let unit = add_mc_joint(&cache, Some(&genesis), 1, "1");
+        assert!(cache.known_units.0.may_contain(unit.as_str()));
+        assert!(cache.check_new_joint(&unit).is_err());
+
+        // the restored joints are also known
+        let snapshot = cache.snapshot().unwrap();
+        let restored = SDagCache::default();
+        restored.restore(snapshot);
+        assert!(restored.known_units.0.may_contain(genesis.as_str()));
+        assert!(restored.check_new_joint(&genesis).is_err());
+    }
+
+    #[test]
+    fn test_joints_by_level() {
+        let cache = SDagCache::default();
+        let mut units = vec![add_mc_joint(&cache, None, 0, "genesis")];
+        for i in 1..5 {
+            let unit = add_mc_joint(&cache, Some(&units[i - 1]), i, &i.to_string());
+            units.push(unit);
+        }
+
+        let keys = |joints: Vec<CachedJoint>| {
+            joints
+                .into_iter()
+                .map(|j| j.key.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(cache.get_joints_by_level(1, 3).unwrap()), &units[1..4]);
+        assert_eq!(keys(cache.get_joints_by_level(3, 1).unwrap()), &units[1..4]);
+        assert_eq!(keys(cache.get_joints_by_level(4, 10).unwrap()), &units[4..]);
+        assert!(cache.get_joints_by_level(5, 10).unwrap().is_empty());
+        assert_eq!(keys(cache.get_joints_at_depth(2).unwrap()), &units[2..3]);
+        assert!(cache.get_joints_at_depth(5).unwrap().is_empty());
+        let last = cache.get_joint(&units[4]).unwrap().read().unwrap();
+        assert_eq!(last.get_all_props().read().unwrap().dag_depth, 4);
+        assert_eq!(last.get_props().dag_depth, 4);
+
+        // the stable parent is the boundary
+        assert_eq!(
+            keys(cache.get_joint_ancestors(&units[4], 3).unwrap()),
+            &units[3..4]
+        );
+        assert!(cache.get_joint_ancestors(&units[4], 0).unwrap().is_empty());
+
+        // the index is rebuilt from the restored joints
+        let snapshot = cache.snapshot().unwrap();
+        let restored = SDagCache::default();
+        restored.restore(snapshot);
+        assert_eq!(keys(restored.get_joints_by_level(0, 4).unwrap()), units);
+        assert_eq!(keys(restored.get_joints_at_depth(3).unwrap()), &units[3..4]);
+    }
+
+    #[test]
+    fn test_evict_stable_joints() {
+        let cache = SDagCache::default();
+        let mut units = vec![add_mc_joint(&cache, None, 0, "genesis")];
+        for i in 1..3 {
+            let unit = add_mc_joint(&cache, Some(&units[i - 1]), i, &i.to_string());
+            units.push(unit);
+        }
+        for (mci, unit) in units.iter().enumerate().rev() {
+            cache.add_stable_joint(Arc::new(unit.clone()), Level::new(mci));
+        }
+
+        let mut g = cache.joints.write().unwrap();
+        assert!(!g.should_evict(0));
+        assert!(g.should_evict(2));
+
+        let keys = |joints: &[(Level, CachedJoint)]| {
+            joints
+                .iter()
+                .map(|(_, j)| j.key.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // the free tip is never evicted, the others are referenced by their children
+        assert!(g.take_evictable_joints(2).is_empty());
+
+        // the skipped joints are still queued, the child is reclaimed by gc
+        g.get_joint(&units[1]).unwrap().clear();
+        let joints = g.take_evictable_joints(2);
+        assert_eq!(keys(&joints), vec![units[0].clone()]);
+        // the joints are saved to kv here without holding the lock
+        assert_eq!(g.remove_evicted_joints(joints), 1);
+        assert!(g.get_joint(&units[0]).is_none());
+        assert_eq!(g.get_num_of_normal_joints(), 2);
+        assert!(!g.should_evict(2));
+
+        // only the reclaimed child is evicted, the free tip is kept
+        let joints = g.take_evictable_joints(1);
+        assert_eq!(keys(&joints), vec![units[1].clone()]);
+        assert_eq!(g.remove_evicted_joints(joints), 1);
+        let free_joints = g.get_all_free_joints();
+        assert_eq!(free_joints.len(), 1);
+        assert_eq!(*free_joints[0].key, units[2]);
+    }
+
+    #[test]
+    fn test_purge_old_temp_bad_free_joints() {
+        use joint::JointSequence;
+
+        let cache = SDagCache::default();
+        let genesis = add_mc_joint(&cache, None, 0, "genesis");
+
+        let joint = new_text_joint(Some(&genesis), "temp-bad");
+        let key = joint.unit.unit.clone();
+        let joint_data = JointData::from_joint(joint, None);
+        joint_data.add_parent(cache.try_get_joint(&genesis).unwrap());
+        joint_data.set_sequence(JointSequence::TempBad);
+        joint_data.set_level(Level::new(1));
+        let create_time = joint_data.get_create_time();
+        let cached_joint = cache.insert_joint(HashKey::new(&key), joint_data);
+        cache.normalize_joint(cached_joint);
+        let free_units = || {
+            cache
+                .get_all_free_joints()
+                .iter()
+                .map(|j| j.key.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(free_units(), vec![key.clone()]);
+        let level_units = || {
+            cache
+                .get_joints_by_level(0, 10)
+                .unwrap()
+                .iter()
+                .map(|j| j.key.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(level_units(), vec![genesis.clone(), key.clone()]);
+
+        // not timed out yet
+        cache
+            .purge_old_temp_bad_free_joints(create_time + 99, 100)
+            .unwrap();
+        assert!(cache.try_get_joint(&key).is_some());
+
+        // timed out, the parent becomes free again
+        cache
+            .purge_old_temp_bad_free_joints(create_time + 100, 100)
+            .unwrap();
+        assert!(cache.try_get_joint(&key).is_none());
+        assert_eq!(free_units(), vec![genesis.clone()]);
+        // the purged joint is removed from the level index
+        assert_eq!(level_units(), vec![genesis]);
+
+        // purging a joint that is already gone is not fatal
+        cache.purge_free_joint(&key).unwrap();
+    }
+
+    #[test]
+    fn test_hash_tree_low_watermark() {
+        use may::coroutine;
+        use std::time::Duration;
+
+        let cache = Arc::new(SDagCache::default());
+        for i in 0..1100 {
+            cache.add_hash_tree_ball(format!("ball_{}", i), format!("unit_{}", i));
+        }
+
+        let sem = Arc::new(Semphore::new(0));
+        cache.register_hash_tree_low_watermark(1000, sem.clone());
+
+        let waiter = {
+            let sem = sem.clone();
+            go!(move || assert!(sem.wait_timeout(Duration::from_secs(5))))
+        };
+        let remover = {
+            let cache = cache.clone();
+            go!(move || for i in 0..101 {
+                cache.del_hash_tree_ball(&format!("ball_{}", i));
+                coroutine::yield_now();
+            })
+        };
+
+        remover.join().unwrap();
+        waiter.join().unwrap();
+        assert_eq!(cache.get_hash_tree_ball_len(), 999);
+        // posted exactly once and consumed by the waiter
+        assert_eq!(sem.get_value(), 0);
+
+        // removing more balls doesn't cross the threshold again
+        cache.del_hash_tree_ball("ball_101");
+        assert_eq!(sem.get_value(), 0);
+
+        cache.unregister_hash_tree_low_watermark(&sem);
+        assert!(cache.hash_tree_watermarks.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_double_spend_joints() {
+        use spec::{Input, Payment, UnitBuilder};
+
+        fn payment_joint(src_unit: &str, output_index: u32) -> Joint {
+            let payment = Payment {
+                address: None,
+                asset: None,
+                definition_chash: None,
+                denomination: None,
+                inputs: vec![Input {
+                    unit: Some(src_unit.to_owned()),
+                    message_index: Some(0),
+                    output_index: Some(output_index),
+                    ..Default::default()
+                }],
+                outputs: Vec::new(),
+            };
+            Joint {
+                ball: None,
+                skiplist_units: Vec::new(),
+                unit: UnitBuilder::new()
+                    .message("payment", Payload::Payment(payment))
+                    .parent("missing_parent")
+                    .build()
+                    .unwrap(),
+            }
+        }
+
+        let cache = SDagCache::default();
+        let mut units = Vec::new();
+        for joint in vec![
+            payment_joint("utxo_unit", 0),
+            payment_joint("utxo_unit", 0),
+            payment_joint("utxo_unit", 1),
+        ] {
+            let key = HashKey::new(&joint.unit.unit);
+            units.push(joint.unit.unit.clone());
+            cache.add_to_input_index(&key.0, &joint.unit);
+            let joint_data = JointData::from_joint(joint, None);
+            cache
+                .joints
+                .write()
+                .unwrap()
+                .add_unhandled_joint(key, joint_data);
+        }
+
+        let conflicts = cache.get_double_spend_joints(&units[0]).unwrap();
+        assert_eq!(conflicts, vec![units[1].clone()]);
+        let conflicts = cache.get_double_spend_joints(&units[1]).unwrap();
+        assert_eq!(conflicts, vec![units[0].clone()]);
+        assert!(cache.get_double_spend_joints(&units[2]).unwrap().is_empty());
+
+        // the purged joint is removed from the index
+        cache.purge_bad_joint(Arc::new(units[1].clone()), "double spend".to_owned());
+        assert!(cache.get_double_spend_joints(&units[0]).unwrap().is_empty());
+        let g = cache.input_index.read().unwrap();
+        assert!(!g.inputs.contains_key(&units[1]));
+        assert_eq!(g.units.values().map(|u| u.len()).sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn test_purge_bad_joint_subtree() {
+        let cache = SDagCache::default();
+        // bad <- child <- grandchild, the other joint waits for another missing parent
+        let add_unhandled = |parent: &str, name: &str| {
+            let joint = new_text_joint(Some(parent), name);
+            let key = HashKey::new(&joint.unit.unit);
+            let mut g = cache.joints.write().unwrap();
+            let cached_joint = g.add_unhandled_joint(key, JointData::from_joint(joint, None));
+            g.add_missing_parent(parent.to_owned(), cached_joint.clone());
+            cached_joint.key.to_string()
+        };
+        let bad = add_unhandled("missing_parent", "bad");
+        let child = add_unhandled(&bad, "child");
+        let grandchild = add_unhandled(&child, "grandchild");
+        let other = add_unhandled("missing_parent", "other");
+
+        cache.purge_bad_joint(Arc::new(bad.clone()), "bad joint".to_owned());
+        let g = cache.joints.read().unwrap();
+        for unit in &[&bad, &child, &grandchild] {
+            assert!(g.is_known_bad_joint(unit));
+            assert!(!g.is_known_unhandled_joint(unit));
+        }
+        assert!(!g.is_known_bad_joint(&other));
+        assert!(g.is_known_unhandled_joint(&other));
+    }
 }
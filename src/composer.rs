@@ -1,13 +1,27 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use cache::{CachedJoint, SDAG_CACHE};
 use config;
 use error::Result;
 use hashbrown::HashMap;
 use joint::{Joint, Level};
 use light::*;
-use sdag_object_base::object_hash;
+use network::wallet::{create_outbound_conn, WalletConn};
+use network::RequestError;
+use sdag_wallet_base::Base64KeyExt;
 use serde_json::Value;
 use signature::Signer;
 use spec::*;
+use statistics;
+use time::duration_to_ms;
+use wallet_info::WalletInfo;
+
+// the max times to repost a joint when the connection is broken
+const MAX_POST_RETRY: usize = 3;
+
+/// the extra amount of inputs to request from the hub for the commissions
+pub const COMMISSION_RESERVE: u64 = 1000;
 
 #[derive(Serialize, Deserialize)]
 pub struct ParentsAndLastBall {
@@ -26,6 +40,18 @@ pub struct ComposeInfo {
     pub text_message: Option<Message>,
     pub light_props: LightProps,
     pub pubk: String,
+    // pay all the inputs to the only output, the commissions are deducted from it
+    #[serde(default)]
+    pub send_all: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MultiAuthorComposeInfo {
+    // <address, pubkey>, the pubkey is none if the definition is already known
+    pub authors: Vec<(String, Option<String>)>,
+    pub earned_headers_commission_recipients: Vec<HeaderCommissionShare>,
+    pub messages: Vec<Message>,
+    pub light_props: LightProps,
 }
 
 /// we should pick last stable ball firstly.
@@ -166,12 +192,149 @@ pub fn create_text_message(text: &str) -> Result<Message> {
     Ok(Message {
         app: String::from("text"),
         payload_location: String::from("inline"),
-        payload_hash: object_hash::get_base64_hash(text)?,
+        payload_hash: get_base64_hash(text)?,
         payload: Some(Payload::Text(text.to_string())),
         ..Default::default()
     })
 }
 
+/// compose the signed payment joint with the inputs and parents from the hub
+pub fn compose_payment(
+    ws: &WalletConn,
+    wallet_info: &WalletInfo,
+    outputs: Vec<Output>,
+    text: Option<&str>,
+    send_all: bool,
+) -> Result<Joint> {
+    let address = &wallet_info._00_address;
+    let text_message = match text {
+        Some(msg) => Some(create_text_message(msg)?),
+        None => None,
+    };
+
+    let light_props = ws.get_light_props(address)?;
+    let total_amount = outputs.iter().map(|o| o.amount).sum::<u64>();
+    let inputs = ws.get_inputs_from_hub(
+        address,
+        total_amount + COMMISSION_RESERVE,
+        send_all,
+        &light_props.last_ball_unit,
+    )?;
+
+    let compose_info = ComposeInfo {
+        paid_address: address.clone(),
+        change_address: address.clone(),
+        outputs,
+        text_message,
+        inputs,
+        transaction_amount: total_amount,
+        light_props,
+        pubk: wallet_info._00_address_pubk.to_base64_key(),
+        send_all,
+    };
+    compose_joint(compose_info, wallet_info)
+}
+
+/// compose and post a payment, then wait until it become stable
+/// return the unit hash and the mci of the joint
+pub fn pay_and_wait(
+    ws: &Arc<WalletConn>,
+    wallet_info: &WalletInfo,
+    outputs: Vec<Output>,
+    text: Option<&str>,
+    timeout: Duration,
+) -> Result<(String, Level)> {
+    let joint = compose_payment(ws, wallet_info, outputs, text, false)?;
+
+    let start = Instant::now();
+    let ws = post_joint_with_retry(ws, &joint)?;
+    let mci = ws.wait_unit_stable(&joint.unit.unit, timeout)?;
+
+    let confirmation_ms = duration_to_ms(start.elapsed());
+    statistics::observe_histogram("sdag_payment_confirmation_time_ms", confirmation_ms);
+
+    Ok((joint.unit.unit, mci))
+}
+
+// post the joint, reconnect to the same hub and repost if the connection is broken
+// return the connection that the joint is posted through
+fn post_joint_with_retry(ws: &Arc<WalletConn>, joint: &Joint) -> Result<Arc<WalletConn>> {
+    let mut ws = ws.clone();
+    let mut retry = 0;
+    loop {
+        let err = match ws.post_joint(joint) {
+            Ok(()) => return Ok(ws),
+            Err(e) => e,
+        };
+
+        // the hub rejection is returned in the response, others are connection errors
+        if err.downcast_ref::<RequestError>().is_some() || retry >= MAX_POST_RETRY {
+            return Err(err);
+        }
+        retry += 1;
+
+        let peer_addr = ws.get_peer_addr().to_owned();
+        warn!(
+            "post_joint to {} failed, err={}, retry={}",
+            peer_addr, err, retry
+        );
+        match create_outbound_conn(peer_addr.as_str()) {
+            Ok(conn) => ws = conn,
+            Err(e) => error!("reconnect to {} failed, err={}", peer_addr, e),
+        }
+    }
+}
+
+/// estimate the (headers_commission, payload_commission) of a single author payment
+/// the outputs should include the change output, and the text message should be the
+/// one after externalized if it's too big
+pub fn estimate_fees(
+    outputs: &[Output],
+    inputs: &[Input],
+    text_message: Option<&Message>,
+    definition: Option<&Value>,
+) -> (u32, u32) {
+    // the placeholders have the same size as the real values
+    let hash = "-".repeat(config::HASH_LENGTH);
+    let mut authentifiers = ::std::collections::HashMap::new();
+    authentifiers.insert("r".to_string(), "-".repeat(config::SIG_LENGTH));
+
+    let payment_message = Message {
+        app: "payment".to_string(),
+        payload_location: "inline".to_string(),
+        payload_hash: hash.clone(),
+        payload: Some(Payload::Payment(Payment {
+            address: None,
+            asset: None,
+            definition_chash: None,
+            denomination: None,
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+        })),
+        payload_uri: None,
+        payload_uri_hash: None,
+        spend_proofs: vec![],
+    };
+
+    let mut messages = text_message.into_iter().cloned().collect::<Vec<_>>();
+    messages.push(payment_message);
+
+    let unit = Unit {
+        messages,
+        authors: vec![Author {
+            address: "-".repeat(config::ADDRESS_SIZE as usize),
+            authentifiers,
+            definition: definition.cloned().unwrap_or(Value::Null),
+        }],
+        last_ball: Some(hash.clone()),
+        last_ball_unit: Some(hash.clone()),
+        witness_list_unit: Some(hash),
+        ..Default::default()
+    };
+
+    (unit.calc_header_size(), unit.calc_payload_size())
+}
+
 pub fn compose_joint<T: Signer>(composer_info: ComposeInfo, signer: &T) -> Result<Joint> {
     let ComposeInfo {
         paid_address,
@@ -182,12 +345,22 @@ pub fn compose_joint<T: Signer>(composer_info: ComposeInfo, signer: &T) -> Resul
         light_props,
         text_message,
         pubk,
+        send_all,
     } = composer_info;
 
-    let mut new_outputs = vec![Output {
-        address: change_address.clone(),
-        amount: 0,
-    }];
+    // the first output takes the rest amount, which is the change output
+    // or the only output when send all
+    let mut new_outputs = if send_all {
+        if outputs.len() != 1 {
+            bail!("send all must have exactly one output");
+        }
+        Vec::new()
+    } else {
+        vec![Output {
+            address: change_address.clone(),
+            amount: 0,
+        }]
+    };
     new_outputs.append(&mut outputs);
 
     let mut unit = Unit {
@@ -248,6 +421,7 @@ pub fn compose_joint<T: Signer>(composer_info: ComposeInfo, signer: &T) -> Resul
         unit.payload_commission.unwrap()
     );
 
+    let transaction_amount = if send_all { 0 } else { transaction_amount };
     let change = inputs.amount as i64
         - transaction_amount as i64
         - i64::from(unit.headers_commission.unwrap())
@@ -277,7 +451,7 @@ pub fn compose_joint<T: Signer>(composer_info: ComposeInfo, signer: &T) -> Resul
                 }
             });
 
-            payment_message.payload_hash = object_hash::get_base64_hash(&x)?;
+            payment_message.payload_hash = get_base64_hash(&x)?;
         }
     }
 
@@ -296,3 +470,299 @@ pub fn compose_joint<T: Signer>(composer_info: ComposeInfo, signer: &T) -> Resul
         unit,
     })
 }
+
+/// compose a joint that is signed by all the authors
+/// each author must be signed by one of the signers
+pub fn compose_multi_author_joint(
+    info: MultiAuthorComposeInfo,
+    signers: &[&WalletInfo],
+) -> Result<Joint> {
+    let MultiAuthorComposeInfo {
+        authors,
+        mut earned_headers_commission_recipients,
+        messages,
+        light_props,
+    } = info;
+
+    if authors.len() < 2 {
+        bail!("multi author joint needs at least 2 authors");
+    }
+
+    let total_share = earned_headers_commission_recipients
+        .iter()
+        .map(|r| r.earned_headers_commission_share)
+        .sum::<u32>();
+    if total_share != 100 {
+        bail!(
+            "sum of earned_headers_commission_share is {}, not 100",
+            total_share
+        );
+    }
+    // the recipients must be sorted by address
+    earned_headers_commission_recipients.sort_by(|a, b| a.address.cmp(&b.address));
+
+    let mut unit = Unit {
+        messages,
+        earned_headers_commission_recipients,
+        ..Default::default()
+    };
+
+    unit.last_ball = Some(light_props.last_ball);
+    unit.last_ball_unit = Some(light_props.last_ball_unit);
+    unit.witness_list_unit = Some(light_props.witness_list_unit);
+    unit.parent_units = light_props.parent_units;
+
+    let mut authors = authors
+        .into_iter()
+        .map(|(address, pubk)| Author {
+            address,
+            authentifiers: {
+                // here we use a dummy signature to calc the correct header size
+                let mut sign = ::std::collections::HashMap::new();
+                sign.insert("r".to_string(), "-".repeat(config::SIG_LENGTH));
+                sign
+            },
+            definition: match pubk {
+                Some(pubk) => json!(["sig", { "pubkey": pubk }]),
+                None => Value::Null,
+            },
+        })
+        .collect::<Vec<_>>();
+    authors.sort_by(|a, b| a.address.cmp(&b.address));
+    unit.authors = authors;
+
+    unit.headers_commission = Some(unit.calc_header_size());
+    unit.payload_commission = Some(unit.calc_payload_size());
+
+    let unit_hash = unit.calc_unit_hash_to_sign();
+    for author in &mut unit.authors {
+        let signer = signers
+            .iter()
+            .find(|s| s._00_address == author.address)
+            .ok_or_else(|| format_err!("no signer for author {}", author.address))?;
+        let signature = signer.sign(&unit_hash, &author.address)?;
+        author.authentifiers.insert("r".to_string(), signature);
+    }
+
+    unit.timestamp = Some(::time::now() / 1000);
+    unit.unit = unit.calc_unit_hash();
+
+    Ok(Joint {
+        ball: None,
+        skiplist_units: Vec::new(),
+        unit,
+    })
+}
+
+/// replace the messages of the joint with their content hash like a stripped non-serial unit
+pub fn strip_to_content_hash(joint: &mut Joint) {
+    let unit = &mut joint.unit;
+    if unit.content_hash.is_some() {
+        return;
+    }
+
+    let content_hash = unit.get_unit_content_hash();
+    unit.messages.clear();
+    unit.content_hash = Some(content_hash);
+    unit.unit = unit.calc_unit_hash();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MNEMONIC_A: &str = "abandon abandon abandon abandon abandon abandon \
+                              abandon abandon abandon abandon abandon about";
+    const MNEMONIC_B: &str =
+        "legal winner thank year wave sausage worth useful legal winner thank yellow";
+
+    fn test_wallet(mnemonic: &str) -> WalletInfo {
+        WalletInfo::from_mnemonic(mnemonic).unwrap()
+    }
+
+    // the placeholders have the length of the real hashes
+    fn light_props() -> LightProps {
+        LightProps {
+            last_ball: "-".repeat(config::HASH_LENGTH),
+            last_ball_unit: "-".repeat(config::HASH_LENGTH),
+            parent_units: vec!["-".repeat(config::HASH_LENGTH)],
+            witness_list_unit: "-".repeat(config::HASH_LENGTH),
+            has_definition: false,
+        }
+    }
+
+    #[test]
+    fn test_compose_multi_author_joint() {
+        let wallet_a = test_wallet(MNEMONIC_A);
+        let wallet_b = test_wallet(MNEMONIC_B);
+        let pubk_a = wallet_a._00_address_pubk.to_base64_key();
+        let pubk_b = wallet_b._00_address_pubk.to_base64_key();
+
+        let share = |wallet: &WalletInfo| HeaderCommissionShare {
+            address: wallet._00_address.clone(),
+            earned_headers_commission_share: 50,
+        };
+        let info = MultiAuthorComposeInfo {
+            authors: vec![
+                (wallet_a._00_address.clone(), Some(pubk_a.clone())),
+                (wallet_b._00_address.clone(), Some(pubk_b.clone())),
+            ],
+            earned_headers_commission_recipients: vec![share(&wallet_a), share(&wallet_b)],
+            messages: vec![create_text_message("multi author").unwrap()],
+            light_props: light_props(),
+        };
+
+        // the shares must sum to 100
+        let mut bad_info = info.clone();
+        bad_info.earned_headers_commission_recipients.pop();
+        assert!(compose_multi_author_joint(bad_info, &[&wallet_a, &wallet_b]).is_err());
+
+        // every author must be signed
+        assert!(compose_multi_author_joint(info.clone(), &[&wallet_a]).is_err());
+
+        let joint = compose_multi_author_joint(info, &[&wallet_a, &wallet_b]).unwrap();
+        let unit = &joint.unit;
+        assert_eq!(unit.authors.len(), 2);
+        assert!(unit.authors[0].address < unit.authors[1].address);
+        assert!(
+            unit.earned_headers_commission_recipients[0].address
+                < unit.earned_headers_commission_recipients[1].address
+        );
+        assert_eq!(unit.unit, unit.calc_unit_hash());
+
+        let unit_hash = unit.calc_unit_hash_to_sign();
+        for author in &unit.authors {
+            let pubk = if author.address == wallet_a._00_address {
+                &pubk_a
+            } else {
+                &pubk_b
+            };
+            ::signature::verify(&unit_hash, &author.authentifiers["r"], pubk).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_compose_send_all_joint() {
+        let wallet = test_wallet(MNEMONIC_A);
+        let output = |amount| Output {
+            address: "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE".to_owned(),
+            amount,
+        };
+        let info = ComposeInfo {
+            paid_address: wallet._00_address.clone(),
+            change_address: wallet._00_address.clone(),
+            outputs: vec![output(0)],
+            inputs: InputsResponse {
+                inputs: vec![Input {
+                    unit: Some("input_unit".to_owned()),
+                    message_index: Some(0),
+                    output_index: Some(0),
+                    ..Default::default()
+                }],
+                amount: 100_000,
+            },
+            transaction_amount: 0,
+            text_message: None,
+            light_props: light_props(),
+            pubk: wallet._00_address_pubk.to_base64_key(),
+            send_all: true,
+        };
+
+        // only one output could take all the amount
+        let mut bad_info = info.clone();
+        bad_info.outputs.push(output(1_000));
+        assert!(compose_joint(bad_info, &wallet).is_err());
+
+        // no change output, the commissions are deducted from the only output
+        let joint = compose_joint(info, &wallet).unwrap();
+        let unit = &joint.unit;
+        let commissions = u64::from(unit.headers_commission.unwrap())
+            + u64::from(unit.payload_commission.unwrap());
+        match unit.messages[0].payload {
+            Some(Payload::Payment(ref payment)) => {
+                assert_eq!(payment.outputs.len(), 1);
+                assert_eq!(payment.outputs[0].address, output(0).address);
+                assert_eq!(payment.outputs[0].amount, 100_000 - commissions);
+            }
+            _ => panic!("no payment message"),
+        }
+    }
+
+    #[test]
+    fn test_estimate_fees() {
+        let wallet = test_wallet(MNEMONIC_A);
+        let pubk = wallet._00_address_pubk.to_base64_key();
+        let output = Output {
+            address: "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE".to_owned(),
+            amount: 1_000,
+        };
+        let inputs = vec![Input {
+            unit: Some("-".repeat(config::HASH_LENGTH)),
+            message_index: Some(0),
+            output_index: Some(0),
+            ..Default::default()
+        }];
+        let info = ComposeInfo {
+            paid_address: wallet._00_address.clone(),
+            change_address: wallet._00_address.clone(),
+            outputs: vec![output.clone()],
+            inputs: InputsResponse {
+                inputs: inputs.clone(),
+                amount: 100_000,
+            },
+            transaction_amount: 1_000,
+            text_message: Some(create_text_message("fees").unwrap()),
+            light_props: light_props(),
+            pubk: pubk.clone(),
+            send_all: false,
+        };
+        let text_message = info.text_message.clone();
+        let joint = compose_joint(info, &wallet).unwrap();
+
+        // the estimation includes the change output
+        let change = Output {
+            address: wallet._00_address.clone(),
+            amount: 0,
+        };
+        let fees = estimate_fees(
+            &[change, output],
+            &inputs,
+            text_message.as_ref(),
+            Some(&json!(["sig", { "pubkey": pubk }])),
+        );
+        assert_eq!(
+            fees,
+            (
+                joint.unit.headers_commission.unwrap(),
+                joint.unit.payload_commission.unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_strip_to_content_hash() {
+        let unit = UnitBuilder::new()
+            .author("SOME_AUTHOR", Value::Null, Default::default())
+            .message("text", Payload::Text("stripped".to_owned()))
+            .build()
+            .unwrap();
+        let content_hash = unit.get_unit_content_hash();
+        let mut joint = Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit,
+        };
+
+        strip_to_content_hash(&mut joint);
+        let unit = &joint.unit;
+        assert!(unit.messages.is_empty());
+        assert_eq!(unit.content_hash, Some(content_hash.clone()));
+        assert_eq!(unit.unit, unit.calc_unit_hash());
+
+        // already stripped
+        let unit_hash = unit.unit.clone();
+        strip_to_content_hash(&mut joint);
+        assert_eq!(joint.unit.content_hash, Some(content_hash));
+        assert_eq!(joint.unit.unit, unit_hash);
+    }
+}
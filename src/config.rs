@@ -1,9 +1,20 @@
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use error::Result;
 use log;
+use may::sync::RwLock;
+use notify::{self, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use sdag_wallet_base::{mnemonic, Mnemonic};
-use serde_json;
+use serde_json::{self, Value};
 use wallet_info::MY_WALLET;
 
 pub const HASH_LENGTH: usize = 44;
@@ -15,6 +26,13 @@ pub const COUNT_WITNESSES: usize = 12;
 pub const MAJORITY_OF_WITNESSES: usize = (COUNT_WITNESSES >> 1) + 1;
 
 pub const VERSION: &str = "1.0";
+// the compatible protocol extensions supported by the hub, exchanged in the version message
+// 1: the joint_batch message
+pub const FEATURE_VERSION: u32 = 1;
+pub const JOINT_BATCH_FEATURE_VERSION: u32 = 1;
+pub const MAX_JOINTS_PER_BATCH: usize = 64;
+// the requested joint batches are not rate limited within this time after the request
+pub const REQUESTED_JOINT_BATCH_TIMEOUT_MS: u64 = 10_000;
 pub const ALT: &str = "1";
 pub const LIBRARY: &str = "rust-sdag";
 // TODO: how to read version from Cargo.toml?
@@ -32,6 +50,18 @@ pub const MAX_DATA_FEED_NAME_LENGTH: usize = 64;
 pub const MAX_DATA_FEED_VALUE_LENGTH: usize = 64;
 pub const MAX_ITEMS_IN_CACHE: usize = 1_000;
 pub const MAX_OUTBOUND_CONNECTIONS: usize = 5;
+pub const KNOWN_UNITS_FILTER_SIZE: usize = 1_000_000;
+pub const KNOWN_UNITS_FILTER_FP_RATE: f64 = 0.01;
+pub const MAX_CHILDREN_DEPTH: usize = 10;
+pub const MAX_ANCESTORS_DEPTH: usize = 10;
+pub const MAX_TEXT_SEARCH_RESULTS: usize = 100;
+pub const MAX_REPLAY_MCIS: usize = 1_000;
+pub const MAX_LINK_PROOF_STEPS: usize = 200;
+pub const MAX_LINK_PROOFS_PER_REQUEST: usize = 100;
+pub const MAX_HISTORY_TRANSACTIONS: usize = 100;
+pub const MAX_HISTORY_OFFSET: usize = 10_000;
+pub const KV_WRITE_BATCH_TIMEOUT_MS: u64 = 10;
+pub const MAX_TEMP_BAD_THRESHOLD: usize = 10;
 pub const TRANSFER_INPUT_SIZE: u32 = 60;
 pub const ADDRESS_SIZE: u32 = 32;
 pub const HEADERS_COMMISSION_INPUT_SIZE: u32 = 18;
@@ -39,46 +69,677 @@ pub const WITNESSING_INPUT_SIZE: u32 = 26;
 pub const MAX_PAYLOAD_SIZE: u32 = 16384; //16k
 
 const SETTINGS_FILE: &str = "settings.json";
+const DEFAULT_GENESIS_UNIT: &str = "9AXarZlxv7/CgumgfLEmd1tQjyEnyW9JYPXFZUBWrJg=";
+// the interval to check if the config watcher is stopped
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_millis(100);
+// editors may write the file several times, the watcher reports after it's quiet for a while
+const CONFIG_WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+// the fields that are applied to the running hub on reload, the others need a restart
+const RELOADABLE_FIELDS: &[&str] = &[
+    "log_level",
+    "hub_url",
+    "max_outbound_connections",
+    "bad_connection_cache_size",
+];
 
+lazy_static! {
+    pub static ref CONFIG: RwLock<Settings> = RwLock::new(load_config());
+}
+
+fn open_settings() -> Result<Settings> {
+    let file = File::open(get_config_path()?)?;
+    Settings::from_reader(file)
+}
+
+pub fn update_mnemonic(mnemonic: &str) -> Result<()> {
+    let mut settings = get_settings();
+    settings.update_mnemonic(mnemonic)
+}
+
+pub fn get_settings() -> Settings {
+    match open_settings() {
+        Ok(s) => s,
+        Err(_) => {
+            warn!("can't open settings.json, will use default settings");
+            let settings = Settings::default();
+            settings.save_settings().ok();
+            settings
+        }
+    }
+}
+
+pub fn show_config() {
+    let cfg = CONFIG.read().unwrap();
+    println!("\nconfig:");
+    println!("\tpeer_id = {:?}", MY_WALLET._00_address);
+    println!("\thub_url = {:?}", cfg.hub_url);
+    println!("\tlisten_address = {:?}", cfg.listen_address);
+    println!("\tdatabase_path = {:?}", cfg.database_path);
+    println!("\tlog_level = {:?}", cfg.log_level);
+    println!("\tworker_thread_num = {:?}", cfg.worker_threads);
+    println!("\n");
+}
+
+pub fn get_genesis_unit() -> String {
+    // the config may be overridden by the environment
+    let genesis_unit = CONFIG.read().unwrap().genesis_unit.clone();
+    if !genesis_unit.is_empty() {
+        return genesis_unit;
+    }
+
+    let mut settings = get_settings();
+    if settings.genesis_unit.is_empty() {
+        settings.genesis_unit = String::from(DEFAULT_GENESIS_UNIT);
+        settings.save_settings().ok();
+    }
+    settings.genesis_unit
+}
+
+pub fn get_remote_hub_url() -> Vec<String> {
+    CONFIG.read().unwrap().hub_url.clone()
+}
+
+pub fn get_listen_address() -> Option<String> {
+    CONFIG.read().unwrap().listen_address.clone()
+}
+
+/// listen on both the ipv4 and ipv6 unspecified addresses
+pub fn is_listen_ipv6() -> bool {
+    CONFIG.read().unwrap().listen_ipv6
+}
+
+/// check the listen address, the ipv6 literal must be in the bracket notation like `[::]:6615`
+/// the host name is not resolved here, the server resolves it when it starts
+pub fn check_listen_address(address: &str) -> Result<()> {
+    if address.starts_with('[') {
+        if let Err(e) = address.parse::<SocketAddr>() {
+            bail!("invalid ipv6 listen address {}, err={}", address, e);
+        }
+        return Ok(());
+    }
+
+    let (host, port) = match address.rfind(':') {
+        Some(i) => (&address[..i], &address[i + 1..]),
+        None => bail!("no port in listen address {}", address),
+    };
+    ensure!(
+        !host.is_empty() && !host.contains(':'),
+        "invalid listen address {}",
+        address
+    );
+    if let Err(e) = port.parse::<u16>() {
+        bail!("invalid port of listen address {}, err={}", address, e);
+    }
+    Ok(())
+}
+
+pub fn get_database_path() -> String {
+    CONFIG.read().unwrap().database_path.clone()
+}
+
+pub fn get_payload_store_path() -> String {
+    CONFIG.read().unwrap().payload_store_path.clone()
+}
+
+pub fn get_log_level() -> log::LevelFilter {
+    use std::str::FromStr;
+    let level = CONFIG.read().unwrap().log_level.clone();
+    log::LevelFilter::from_str(&level).unwrap_or(log::LevelFilter::Warn)
+}
+
+/// the max outbound connections that the auto connection would keep
+pub fn get_max_outbound_connections() -> usize {
+    CONFIG.read().unwrap().max_outbound_connections
+}
+
+pub fn get_worker_thread_num() -> usize {
+    CONFIG.read().unwrap().worker_threads
+}
+
+pub fn get_max_pending_joints() -> usize {
+    CONFIG.read().unwrap().max_pending_joints
+}
+
+/// the max unhandled joints that a single peer could have in the cache
+pub fn get_max_pending_per_peer() -> usize {
+    CONFIG.read().unwrap().max_pending_per_peer
+}
+
+/// the max unsolicited joints per second that a single peer could push to us
+pub fn get_max_joints_per_second_per_peer() -> usize {
+    CONFIG.read().unwrap().max_joints_per_second_per_peer
+}
+
+pub fn get_max_inbound_per_ip() -> usize {
+    CONFIG.read().unwrap().max_inbound_per_ip
+}
+
+pub fn get_max_inbound_total() -> usize {
+    CONFIG.read().unwrap().max_inbound_total
+}
+
+/// the network statistics windows in seconds
+pub fn get_statistics_windows() -> Vec<u64> {
+    CONFIG.read().unwrap().statistics_windows.clone()
+}
+
+/// the max seconds a worker could spend on a single item before it's treated as stalled
+pub fn get_watchdog_timeout_secs() -> u64 {
+    CONFIG.read().unwrap().watchdog_timeout_secs
+}
+
+/// the max seconds a joint timestamp could be in the past
+pub fn get_max_joint_age_secs() -> u64 {
+    CONFIG.read().unwrap().max_joint_age_secs
+}
+
+/// the max seconds a joint timestamp could be in the future
+pub fn get_max_joint_future_secs() -> u64 {
+    CONFIG.read().unwrap().max_joint_future_secs
+}
+
+/// the address that witnesses lock their stake to
+pub fn get_witness_stake_address() -> String {
+    CONFIG.read().unwrap().witness_stake_address.clone()
+}
+
+/// the min stake a witness must lock, 0 means no stake is required
+pub fn get_witness_stake_amount() -> u64 {
+    CONFIG.read().unwrap().witness_stake_amount
+}
+
+/// the ws messages are not compressed if set, useful for the localhost peers
+pub fn is_compress_disabled() -> bool {
+    CONFIG.read().unwrap().no_compress
+}
+
+/// the deflate level of the compressed ws messages, 0 ~ 9
+pub fn get_compression_level() -> u32 {
+    CONFIG.read().unwrap().compression_level
+}
+
+/// save the business snapshot every the number of stable mcis, 0 means only on shutdown
+pub fn get_business_snapshot_interval() -> usize {
+    CONFIG.read().unwrap().business_snapshot_interval
+}
+
+/// the peer is banned once its reputation drops below this
+pub fn get_min_peer_reputation() -> i32 {
+    CONFIG.read().unwrap().min_peer_reputation
+}
+
+/// how long a banned peer is refused
+pub fn get_peer_ban_secs() -> u64 {
+    CONFIG.read().unwrap().peer_ban_secs
+}
+
+/// the oldest stable joints are evicted from memory above this, 0 means unlimited
+/// ignored by the kv_store_none backend since the evicted joints can't be loaded back
+pub fn get_max_memory_joints() -> usize {
+    CONFIG.read().unwrap().max_memory_joints
+}
+
+/// the max joints written to the kv store in one batch
+pub fn get_kv_write_batch_size() -> usize {
+    CONFIG.read().unwrap().kv_write_batch_size
+}
+
+/// the lmdb memory map size in bytes, the database never grows beyond it
+pub fn get_lmdb_map_size() -> usize {
+    CONFIG
+        .read()
+        .unwrap()
+        .lmdb_map_size_mb
+        .saturating_mul(1024 * 1024)
+}
+
+/// how many recently failed peer addresses are remembered to skip reconnecting
+pub fn get_bad_connection_cache_size() -> usize {
+    CONFIG.read().unwrap().bad_connection_cache_size
+}
+
+/// the remote ips that may scrape /metrics, the loopback ones are always allowed
+pub fn get_metrics_allowed_ips() -> Vec<IpAddr> {
+    CONFIG
+        .read()
+        .unwrap()
+        .metrics_allowed_ips
+        .iter()
+        .filter_map(|ip| ip.parse().ok())
+        .collect()
+}
+
+pub fn get_mnemonic() -> String {
+    let settings = get_settings();
+    settings.get_mnemonic()
+}
+
+//---------------------------------------------------------------------------------------
+// Settings
+//---------------------------------------------------------------------------------------
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Settings {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub log_level: Option<String>, // ["OFF", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub worker_thread_num: Option<usize>, // may set_workers()
+    #[serde(default)]
     pub hub_url: Vec<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub listen_address: Option<String>,
+    #[serde(default)]
+    pub listen_ipv6: bool,
+    #[serde(default = "default_database_path")]
+    pub database_path: String,
+    #[serde(default = "default_payload_store_path")]
+    pub payload_store_path: String,
+    // ["OFF", "ERROR", "WARN", "INFO", "DEBUG", "TRACE"]
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default = "default_worker_threads", alias = "worker_thread_num")]
+    pub worker_threads: usize, // may set_workers()
+    #[serde(default)]
+    pub witnesses: Vec<String>,
+    #[serde(default)]
     mnemonic: Option<String>,
-    pub genesis_unit: Option<String>,
+    #[serde(default)]
+    pub genesis_unit: String,
+    #[serde(default = "default_max_pending_joints")]
+    pub max_pending_joints: usize,
+    #[serde(default = "default_max_pending_per_peer")]
+    pub max_pending_per_peer: usize,
+    #[serde(default = "default_max_joints_per_second_per_peer")]
+    pub max_joints_per_second_per_peer: usize,
+    #[serde(default = "default_max_inbound_per_ip")]
+    pub max_inbound_per_ip: usize,
+    #[serde(default = "default_max_inbound_total")]
+    pub max_inbound_total: usize,
+    #[serde(default = "default_statistics_windows")]
+    pub statistics_windows: Vec<u64>,
+    #[serde(default = "default_watchdog_timeout_secs")]
+    pub watchdog_timeout_secs: u64,
+    #[serde(default = "default_max_joint_age_secs")]
+    pub max_joint_age_secs: u64,
+    #[serde(default = "default_max_joint_future_secs")]
+    pub max_joint_future_secs: u64,
+    #[serde(default)]
+    pub witness_stake_address: String,
+    #[serde(default)]
+    pub witness_stake_amount: u64,
+    #[serde(default = "default_max_outbound_connections")]
+    pub max_outbound_connections: usize,
+    #[serde(default)]
+    pub no_compress: bool,
+    #[serde(default = "default_compression_level")]
+    pub compression_level: u32,
+    #[serde(default = "default_business_snapshot_interval")]
+    pub business_snapshot_interval: usize,
+    #[serde(default = "default_min_peer_reputation")]
+    pub min_peer_reputation: i32,
+    #[serde(default = "default_peer_ban_secs")]
+    pub peer_ban_secs: u64,
+    #[serde(default = "default_max_memory_joints")]
+    pub max_memory_joints: usize,
+    #[serde(default = "default_kv_write_batch_size")]
+    pub kv_write_batch_size: usize,
+    #[serde(default = "default_lmdb_map_size_mb")]
+    pub lmdb_map_size_mb: usize,
+    #[serde(default = "default_bad_connection_cache_size")]
+    pub bad_connection_cache_size: usize,
+    #[serde(default)]
+    pub metrics_allowed_ips: Vec<String>,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
-            log_level: Some(String::from("WARN")),
-            worker_thread_num: Some(4),
-            listen_address: Some(String::from("127.0.0.1:6615")),
             hub_url: vec![String::from("127.0.0.1:6615")],
-            genesis_unit: Some(String::from("9AXarZlxv7/CgumgfLEmd1tQjyEnyW9JYPXFZUBWrJg=")),
+            listen_address: Some(String::from("127.0.0.1:6615")),
+            listen_ipv6: false,
+            database_path: default_database_path(),
+            payload_store_path: default_payload_store_path(),
+            log_level: String::from("WARN"),
+            worker_threads: default_worker_threads(),
+            witnesses: Vec::new(),
             mnemonic: Some(
                 mnemonic("")
                     .expect("failed to generate mnemonic")
                     .to_string(),
             ),
+            genesis_unit: String::from(DEFAULT_GENESIS_UNIT),
+            max_pending_joints: default_max_pending_joints(),
+            max_pending_per_peer: default_max_pending_per_peer(),
+            max_joints_per_second_per_peer: default_max_joints_per_second_per_peer(),
+            max_inbound_per_ip: default_max_inbound_per_ip(),
+            max_inbound_total: default_max_inbound_total(),
+            statistics_windows: default_statistics_windows(),
+            watchdog_timeout_secs: default_watchdog_timeout_secs(),
+            max_joint_age_secs: default_max_joint_age_secs(),
+            max_joint_future_secs: default_max_joint_future_secs(),
+            witness_stake_address: String::new(),
+            witness_stake_amount: 0,
+            max_outbound_connections: default_max_outbound_connections(),
+            no_compress: false,
+            compression_level: default_compression_level(),
+            business_snapshot_interval: default_business_snapshot_interval(),
+            min_peer_reputation: default_min_peer_reputation(),
+            peer_ban_secs: default_peer_ban_secs(),
+            max_memory_joints: default_max_memory_joints(),
+            kv_write_batch_size: default_kv_write_batch_size(),
+            lmdb_map_size_mb: default_lmdb_map_size_mb(),
+            bad_connection_cache_size: default_bad_connection_cache_size(),
+            metrics_allowed_ips: Vec::new(),
         }
     }
 }
 
-fn open_settings() -> Result<Settings> {
-    let mut settings_path = ::std::env::current_dir()?;
-    settings_path.push(SETTINGS_FILE);
-    let file = File::open(settings_path)?;
-    let settings = serde_json::from_reader(file)?;
-    Ok(settings)
+fn default_database_path() -> String {
+    String::from("./sdag_kv")
+}
+
+fn default_payload_store_path() -> String {
+    String::from("./sdag_payloads")
+}
+
+fn default_log_level() -> String {
+    if cfg!(debug_assertions) {
+        String::from("DEBUG")
+    } else {
+        String::from("WARN")
+    }
+}
+
+fn default_worker_threads() -> usize {
+    4
+}
+
+fn default_max_pending_joints() -> usize {
+    10_000
+}
+
+fn default_max_pending_per_peer() -> usize {
+    50
+}
+
+fn default_max_joints_per_second_per_peer() -> usize {
+    100
+}
+
+fn default_max_inbound_per_ip() -> usize {
+    3
+}
+
+fn default_max_inbound_total() -> usize {
+    128
+}
+
+fn default_statistics_windows() -> Vec<u64> {
+    vec![1, 60, 3600, 86400]
+}
+
+fn default_max_joint_age_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_max_joint_future_secs() -> u64 {
+    3600
+}
+
+fn default_watchdog_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_outbound_connections() -> usize {
+    MAX_OUTBOUND_CONNECTIONS
+}
+
+fn default_compression_level() -> u32 {
+    6
+}
+
+fn default_business_snapshot_interval() -> usize {
+    1000
+}
+
+fn default_min_peer_reputation() -> i32 {
+    -100
+}
+
+fn default_peer_ban_secs() -> u64 {
+    3600
+}
+
+fn default_max_memory_joints() -> usize {
+    1_000_000
+}
+
+fn default_kv_write_batch_size() -> usize {
+    64
+}
+
+// the map must fit in the address space of the target
+#[cfg(target_pointer_width = "64")]
+fn default_lmdb_map_size_mb() -> usize {
+    64 * 1024
+}
+
+#[cfg(not(target_pointer_width = "64"))]
+fn default_lmdb_map_size_mb() -> usize {
+    1024
+}
+
+fn default_bad_connection_cache_size() -> usize {
+    100
 }
 
 impl Settings {
+    pub fn from_reader<R: Read>(reader: R) -> Result<Settings> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// apply the `SDAG_*` environment variables, they have higher priority than the file
+    pub fn with_env_overrides(self) -> Self {
+        self.with_overrides(|key| env::var(key).ok())
+    }
+
+    /// apply the overrides found by `lookup`, the key is the environment variable name
+    pub fn with_overrides<F: Fn(&str) -> Option<String>>(mut self, lookup: F) -> Self {
+        if let Some(v) = lookup("SDAG_HUB_URL") {
+            self.hub_url = v
+                .split(',')
+                .map(|s| s.trim().to_owned())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+
+        if let Some(v) = lookup("SDAG_LISTEN_ADDRESS") {
+            self.listen_address = Some(v);
+        }
+
+        if let Some(v) = lookup("SDAG_DATABASE_PATH") {
+            self.database_path = v;
+        }
+
+        if let Some(v) = lookup("SDAG_LOG_LEVEL") {
+            self.log_level = v;
+        }
+
+        if let Some(v) = lookup("SDAG_WORKER_THREADS") {
+            match v.parse() {
+                Ok(n) => self.worker_threads = n,
+                Err(e) => warn!("invalid SDAG_WORKER_THREADS={}, err={}", v, e),
+            }
+        }
+
+        if let Some(v) = lookup("SDAG_GENESIS_UNIT") {
+            self.genesis_unit = v;
+        }
+
+        if let Some(v) = lookup("SDAG_NO_COMPRESS") {
+            self.no_compress = v != "0" && v != "false";
+        }
+
+        self
+    }
+
+    /// return all the errors found in the config, empty means the config is valid
+    pub fn validate(&self) -> Result<Vec<String>> {
+        use std::str::FromStr;
+        let mut errors = Vec::new();
+
+        if self.hub_url.is_empty() {
+            errors.push("hub_url is missing".to_owned());
+        }
+
+        if let Some(ref address) = self.listen_address {
+            if let Err(e) = check_listen_address(address) {
+                errors.push(e.to_string());
+            }
+        }
+
+        if self.database_path.is_empty() {
+            errors.push("database_path is missing".to_owned());
+        }
+
+        if log::LevelFilter::from_str(&self.log_level).is_err() {
+            errors.push(format!("invalid log_level: {:?}", self.log_level));
+        }
+
+        if self.worker_threads == 0 {
+            errors.push("worker_threads must be bigger than 0".to_owned());
+        }
+
+        if self.kv_write_batch_size == 0 {
+            errors.push("kv_write_batch_size must be bigger than 0".to_owned());
+        }
+
+        if self.lmdb_map_size_mb == 0 {
+            errors.push("lmdb_map_size_mb must be bigger than 0".to_owned());
+        }
+
+        if self.bad_connection_cache_size == 0 {
+            errors.push("bad_connection_cache_size must be bigger than 0".to_owned());
+        }
+
+        for ip in &self.metrics_allowed_ips {
+            if ip.parse::<IpAddr>().is_err() {
+                errors.push(format!("invalid metrics_allowed_ips entry: {:?}", ip));
+            }
+        }
+
+        if self.genesis_unit.is_empty() {
+            errors.push("genesis_unit is missing".to_owned());
+        } else if self.genesis_unit.len() != HASH_LENGTH {
+            errors.push(format!("invalid genesis_unit: {}", self.genesis_unit));
+        }
+
+        // empty witnesses means read them from the genesis unit
+        if !self.witnesses.is_empty() && self.witnesses.len() != COUNT_WITNESSES {
+            errors.push(format!(
+                "witnesses count must be {}, found {}",
+                COUNT_WITNESSES,
+                self.witnesses.len()
+            ));
+        }
+
+        if self.max_pending_per_peer == 0 {
+            errors.push("max_pending_per_peer must be bigger than 0".to_owned());
+        }
+
+        if self.max_joints_per_second_per_peer == 0 {
+            errors.push("max_joints_per_second_per_peer must be bigger than 0".to_owned());
+        }
+
+        if self.witness_stake_amount > 0 && self.witness_stake_address.is_empty() {
+            errors.push("witness_stake_address is required by witness_stake_amount".to_owned());
+        }
+
+        if self.statistics_windows.is_empty() || self.statistics_windows.contains(&0) {
+            errors.push(format!(
+                "invalid statistics_windows: {:?}",
+                self.statistics_windows
+            ));
+        }
+
+        if self.watchdog_timeout_secs == 0 {
+            errors.push("watchdog_timeout_secs must be bigger than 0".to_owned());
+        }
+
+        if self.min_peer_reputation >= 0 {
+            errors.push("min_peer_reputation must be less than 0".to_owned());
+        }
+
+        if self.peer_ban_secs == 0 {
+            errors.push("peer_ban_secs must be bigger than 0".to_owned());
+        }
+
+        if self.compression_level > 9 {
+            errors.push(format!(
+                "compression_level must be 0 ~ 9, found {}",
+                self.compression_level
+            ));
+        }
+
+        Ok(errors)
+    }
+
+    /// reload the config file, the new config must be valid
+    /// the changes are applied to the running subsystems and returned
+    pub fn reload(path: &Path) -> Result<ConfigDiff> {
+        let diff = reload_into(&CONFIG, path)?;
+        apply_diff(&diff);
+        Ok(diff)
+    }
+
+    // only the reloadable fields are taken from the new config
+    fn apply_reloadable(&mut self, new: &Settings) {
+        self.log_level = new.log_level.clone();
+        self.hub_url = new.hub_url.clone();
+        self.max_outbound_connections = new.max_outbound_connections;
+        self.bad_connection_cache_size = new.bad_connection_cache_size;
+    }
+
+    /// compare the configs, only the fields that matter to the running hub are compared
+    pub fn diff(old: &Settings, new: &Settings) -> ConfigDiff {
+        use std::str::FromStr;
+        let mut diff = ConfigDiff::default();
+
+        if old.log_level != new.log_level {
+            diff.log_level = log::LevelFilter::from_str(&new.log_level).ok();
+        }
+
+        diff.added_hub_urls = new
+            .hub_url
+            .iter()
+            .filter(|url| !old.hub_url.contains(url))
+            .cloned()
+            .collect();
+        diff.removed_hub_urls = old
+            .hub_url
+            .iter()
+            .filter(|url| !new.hub_url.contains(url))
+            .cloned()
+            .collect();
+
+        if old.max_outbound_connections != new.max_outbound_connections {
+            diff.max_outbound_connections = Some(new.max_outbound_connections);
+        }
+
+        if old.bad_connection_cache_size != new.bad_connection_cache_size {
+            diff.bad_connection_cache_size = Some(new.bad_connection_cache_size);
+        }
+
+        // the other changed fields are not reloaded
+        let fields = (serde_json::to_value(old), serde_json::to_value(new));
+        if let (Ok(Value::Object(old_fields)), Ok(Value::Object(new_fields))) = fields {
+            for (field, value) in &new_fields {
+                if !RELOADABLE_FIELDS.contains(&field.as_str())
+                    && old_fields.get(field) != Some(value)
+                {
+                    diff.restart_required.push(field.clone());
+                }
+            }
+        }
+
+        diff
+    }
+
     pub fn show_config(&self) {
         use std::io::stdout;
         println!("settings:");
@@ -121,74 +782,347 @@ impl Settings {
     }
 }
 
-pub fn update_mnemonic(mnemonic: &str) -> Result<()> {
-    let mut settings = get_settings();
-    settings.update_mnemonic(mnemonic)
+//---------------------------------------------------------------------------------------
+// ConfigDiff
+//---------------------------------------------------------------------------------------
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub log_level: Option<log::LevelFilter>,
+    pub added_hub_urls: Vec<String>,
+    pub removed_hub_urls: Vec<String>,
+    pub max_outbound_connections: Option<usize>,
+    pub bad_connection_cache_size: Option<usize>,
+    // the changed fields that only take effect after the hub restarted
+    pub restart_required: Vec<String>,
 }
 
-pub fn get_settings() -> Settings {
-    match open_settings() {
-        Ok(s) => s,
-        Err(_) => {
-            warn!("can't open settings.json, will use default settings");
-            let settings = Settings::default();
-            settings.save_settings().ok();
-            settings
-        }
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == ConfigDiff::default()
     }
 }
 
-pub fn show_config() {
-    let cfg = get_settings();
-    println!("\nconfig:");
-    println!("\tpeer_id = {:?}", MY_WALLET._00_address);
-    println!("\thub_url = {:?}", cfg.hub_url);
-    println!("\tlisten_address = {:?}", cfg.listen_address);
-    println!("\tlog_level = {:?}", cfg.log_level);
-    println!(
-        "\tworker_thread_num = {:?}",
-        cfg.worker_thread_num.unwrap_or(4)
-    );
-    println!("\n");
+/// emitted after the config is reloaded, the network subsystem should handle
+/// the hub_url and max_outbound_connections changes
+#[derive(Clone)]
+pub struct ConfigChangeEvent {
+    pub diff: ConfigDiff,
 }
+impl_event!(ConfigChangeEvent);
 
-pub fn get_genesis_unit() -> String {
-    let mut settings = get_settings();
-    match settings.genesis_unit {
-        Some(v) => v,
-        None => {
-            let genesis_unit = String::from("9AXarZlxv7/CgumgfLEmd1tQjyEnyW9JYPXFZUBWrJg=");
-            settings.genesis_unit = Some(genesis_unit);
-            settings.save_settings().ok();
-            settings.genesis_unit.unwrap()
+fn reload_into(config: &RwLock<Settings>, path: &Path) -> Result<ConfigDiff> {
+    let new_config = Settings::from_reader(File::open(path)?)?.with_env_overrides();
+    let errors = new_config.validate()?;
+    if !errors.is_empty() {
+        bail!("invalid config: {}", errors.join(", "));
+    }
+
+    let mut g = config.write().unwrap();
+    let diff = Settings::diff(&g, &new_config);
+    g.apply_reloadable(&new_config);
+    Ok(diff)
+}
+
+// apply the reloaded config to the running hub
+fn apply_diff(diff: &ConfigDiff) {
+    if let Some(level) = diff.log_level {
+        // the hub also reloads its tracing filter with the new level, see hub log_init
+        log::set_max_level(level);
+        info!("log level changed to {}", level);
+    }
+
+    for field in &diff.restart_required {
+        warn!(
+            "config {} changed, it requires a hub restart to take effect",
+            field
+        );
+    }
+
+    if !diff.is_empty() {
+        ::utils::event::emit_event_async(ConfigChangeEvent { diff: diff.clone() });
+    }
+}
+
+//---------------------------------------------------------------------------------------
+// ConfigWatchHandle
+//---------------------------------------------------------------------------------------
+/// stop watching the config file when dropped
+pub struct ConfigWatchHandle {
+    stopped: Arc<AtomicBool>,
+    // the file system notifications stop when the watcher is dropped
+    _watcher: RecommendedWatcher,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for ConfigWatchHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
         }
     }
 }
 
-pub fn get_remote_hub_url() -> Vec<String> {
-    get_settings().hub_url
+/// reload the config when the file is modified, the invalid config is ignored
+pub fn watch_config(path: &Path) -> Result<ConfigWatchHandle> {
+    watch_config_into(&CONFIG, path, apply_diff)
 }
 
-pub fn get_listen_address() -> Option<String> {
-    get_settings().listen_address
+fn watch_config_into<F>(
+    config: &'static RwLock<Settings>,
+    path: &Path,
+    on_reload: F,
+) -> Result<ConfigWatchHandle>
+where
+    F: Fn(&ConfigDiff) + Send + 'static,
+{
+    // the event paths are absolute, and editors may replace the file rather than write it
+    // so the parent dir is watched and the events are filtered by the file path
+    let path = fs::canonicalize(path)?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| format_err!("config {} has no parent dir", path.display()))?
+        .to_owned();
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, CONFIG_WATCH_DEBOUNCE)?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stopped_c = stopped.clone();
+    let handle = thread::spawn(move || {
+        while !stopped_c.load(Ordering::Relaxed) {
+            match rx.recv_timeout(CONFIG_WATCH_INTERVAL) {
+                Ok(event) => handle_watch_event(config, &path, event, &on_reload),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+
+    Ok(ConfigWatchHandle {
+        stopped,
+        _watcher: watcher,
+        handle: Some(handle),
+    })
 }
 
-pub fn get_log_level() -> log::LevelFilter {
-    use std::str::FromStr;
-    if let Some(v) = get_settings().log_level {
-        log::LevelFilter::from_str(&v).unwrap_or(log::LevelFilter::Warn)
-    } else if cfg!(debug_assertions) {
-        log::LevelFilter::Debug
-    } else {
-        log::LevelFilter::Warn
+// reload the config if the debounced event modified the watched file
+fn handle_watch_event<F>(
+    config: &RwLock<Settings>,
+    path: &Path,
+    event: DebouncedEvent,
+    on_reload: &F,
+) where
+    F: Fn(&ConfigDiff),
+{
+    match event {
+        DebouncedEvent::Write(ref p) | DebouncedEvent::Create(ref p) if p == path => {}
+        DebouncedEvent::Rename(_, ref p) if p == path => {}
+        DebouncedEvent::Error(e, _) => {
+            error!("failed to watch config {}, err={}", path.display(), e);
+            return;
+        }
+        _ => return,
+    }
+
+    match reload_into(config, path) {
+        Ok(diff) => {
+            info!("config reloaded from {}, {:?}", path.display(), diff);
+            on_reload(&diff);
+        }
+        Err(e) => error!("failed to reload config {}, err={}", path.display(), e),
     }
 }
 
-pub fn get_worker_thread_num() -> usize {
-    get_settings().worker_thread_num.unwrap_or(4)
+/// the config file path, it's the settings file in the current dir
+pub fn get_config_path() -> Result<PathBuf> {
+    let mut settings_path = ::std::env::current_dir()?;
+    settings_path.push(SETTINGS_FILE);
+    Ok(settings_path)
 }
 
-pub fn get_mnemonic() -> String {
-    let settings = get_settings();
-    settings.get_mnemonic()
+fn load_config() -> Settings {
+    get_settings().with_env_overrides()
+}
+
+/// reload the config from file, the new config must be valid
+pub fn reload_config() -> Result<()> {
+    Settings::reload(&get_config_path()?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID_CONFIG: &str = r#"{
+        "hub_url": ["127.0.0.1:6615"],
+        "listen_address": "127.0.0.1:6616",
+        "database_path": "./sdag_kv",
+        "log_level": "INFO",
+        "worker_threads": 4,
+        "genesis_unit": "9AXarZlxv7/CgumgfLEmd1tQjyEnyW9JYPXFZUBWrJg="
+    }"#;
+
+    #[test]
+    fn test_valid_config() {
+        let config = Settings::from_reader(VALID_CONFIG.as_bytes()).unwrap();
+        assert!(config.validate().unwrap().is_empty());
+        assert_eq!(config.listen_address, Some("127.0.0.1:6616".to_owned()));
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut vars = ::std::collections::HashMap::new();
+        vars.insert("SDAG_DATABASE_PATH", "/tmp/sdag_env_kv");
+        vars.insert("SDAG_HUB_URL", "127.0.0.1:6617, 127.0.0.1:6618");
+        vars.insert("SDAG_WORKER_THREADS", "many");
+        let config = Settings::from_reader(VALID_CONFIG.as_bytes())
+            .unwrap()
+            .with_overrides(|key| vars.get(key).map(|v| v.to_string()));
+
+        assert_eq!(config.database_path, "/tmp/sdag_env_kv");
+        assert_eq!(config.hub_url, vec!["127.0.0.1:6617", "127.0.0.1:6618"]);
+        // the invalid value is ignored
+        assert_eq!(config.worker_threads, 4);
+        assert_eq!(config.log_level, "INFO");
+    }
+
+    #[test]
+    fn test_old_settings_file() {
+        let settings = Settings::from_reader(
+            r#"{
+                "log_level": "INFO",
+                "worker_thread_num": 8,
+                "hub_url": ["127.0.0.1:6615"],
+                "mnemonic": null,
+                "genesis_unit": "9AXarZlxv7/CgumgfLEmd1tQjyEnyW9JYPXFZUBWrJg="
+            }"#
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(settings.worker_threads, 8);
+        assert_eq!(settings.database_path, "./sdag_kv");
+        assert!(settings.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let config = Settings::from_reader(r#"{"worker_threads": 0}"#.as_bytes()).unwrap();
+        let errors = config.validate().unwrap();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().any(|e| e.contains("hub_url")));
+        assert!(errors.iter().any(|e| e.contains("worker_threads")));
+        assert!(errors.iter().any(|e| e.contains("genesis_unit")));
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let old = Settings::from_reader(VALID_CONFIG.as_bytes()).unwrap();
+        assert!(Settings::diff(&old, &old).is_empty());
+
+        let mut new = old.clone();
+        new.hub_url = vec!["127.0.0.1:6617".to_owned()];
+        new.max_outbound_connections = 8;
+        new.bad_connection_cache_size = 200;
+        new.database_path = "./sdag_kv_new".to_owned();
+        let diff = Settings::diff(&old, &new);
+        assert_eq!(diff.added_hub_urls, vec!["127.0.0.1:6617".to_owned()]);
+        assert_eq!(diff.removed_hub_urls, vec!["127.0.0.1:6615".to_owned()]);
+        assert_eq!(diff.max_outbound_connections, Some(8));
+        assert_eq!(diff.bad_connection_cache_size, Some(200));
+        assert_eq!(diff.restart_required, vec!["database_path".to_owned()]);
+        assert_eq!(diff.log_level, None);
+    }
+
+    #[test]
+    fn test_watch_config() {
+        lazy_static! {
+            static ref TEST_CONFIG: RwLock<Settings> =
+                RwLock::new(Settings::from_reader(VALID_CONFIG.as_bytes()).unwrap());
+        }
+
+        let mut path = env::temp_dir();
+        path.push(format!("sdag_watch_config_{}.json", ::std::process::id()));
+        fs::write(&path, VALID_CONFIG).unwrap();
+        // the diff is not applied to the global log level
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let _handle = watch_config_into(&TEST_CONFIG, &path, move |diff| {
+            tx.send(diff.clone()).unwrap();
+        })
+        .unwrap();
+
+        // only the reloadable fields are taken
+        let new_config = VALID_CONFIG
+            .replace("INFO", "DEBUG")
+            .replace("./sdag_kv", "./sdag_kv_new");
+        fs::write(&path, new_config).unwrap();
+
+        // the event comes after the debounce, the timeout only bounds a broken watcher
+        let diff = rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("config change not detected");
+        assert_eq!(diff.log_level, Some(log::LevelFilter::Debug));
+        assert_eq!(diff.restart_required, vec!["database_path".to_owned()]);
+        let config = TEST_CONFIG.read().unwrap();
+        assert_eq!(config.log_level, "DEBUG");
+        assert_eq!(config.database_path, "./sdag_kv");
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_handle_watch_event() {
+        let config = RwLock::new(Settings::from_reader(VALID_CONFIG.as_bytes()).unwrap());
+        let mut path = env::temp_dir();
+        path.push(format!("sdag_watch_event_{}.json", ::std::process::id()));
+        fs::write(&path, VALID_CONFIG.replace("INFO", "DEBUG")).unwrap();
+
+        let diffs = ::std::cell::RefCell::new(Vec::new());
+        let on_reload = |diff: &ConfigDiff| diffs.borrow_mut().push(diff.clone());
+
+        // the events of the other files are ignored
+        let other = path.with_extension("bak");
+        handle_watch_event(&config, &path, DebouncedEvent::Write(other), &on_reload);
+        assert!(diffs.borrow().is_empty());
+        assert_eq!(config.read().unwrap().log_level, "INFO");
+
+        handle_watch_event(
+            &config,
+            &path,
+            DebouncedEvent::Write(path.clone()),
+            &on_reload,
+        );
+        assert_eq!(diffs.borrow().len(), 1);
+        assert_eq!(diffs.borrow()[0].log_level, Some(log::LevelFilter::Debug));
+        assert_eq!(config.read().unwrap().log_level, "DEBUG");
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_invalid_witness_count() {
+        let mut config = Settings::from_reader(VALID_CONFIG.as_bytes()).unwrap();
+        config.witnesses = vec!["witness".to_owned(); 3];
+        let errors = config.validate().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains(&format!("must be {}, found 3", COUNT_WITNESSES)));
+    }
+
+    #[test]
+    fn test_check_listen_address() {
+        assert!(check_listen_address("[::]:4000").is_ok());
+        assert!(check_listen_address("127.0.0.1:6615").is_ok());
+        // the host name is not resolved
+        assert!(check_listen_address("no.such.host.invalid:6615").is_ok());
+
+        assert!(check_listen_address("[::1:4000").is_err());
+        assert!(check_listen_address("::1:4000").is_err());
+        assert!(check_listen_address("127.0.0.1").is_err());
+        assert!(check_listen_address("127.0.0.1:port").is_err());
+
+        let mut config = Settings::from_reader(VALID_CONFIG.as_bytes()).unwrap();
+        config.listen_address = Some("[::1]:6616".to_owned());
+        config.listen_ipv6 = true;
+        assert!(config.validate().unwrap().is_empty());
+    }
 }
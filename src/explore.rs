@@ -1,8 +1,5 @@
-use std::collections::VecDeque;
-
 use cache::{JointData, SDAG_CACHE};
 use error::Result;
-use hashbrown::HashSet;
 use joint::JointSequence;
 use joint::Level;
 
@@ -103,125 +100,13 @@ impl ExploreBuilder {
         }
     }
 
-    fn append_unstable_units(&mut self) -> Result<()> {
-        let mut queue = VecDeque::new();
-        let mut visited = HashSet::new();
-
-        for joint in SDAG_CACHE.get_all_free_joints() {
-            queue.push_back(joint);
-        }
-
-        while let Some(joint) = queue.pop_front() {
-            let joint_data = joint.read()?;
-
-            let prop = joint_data.get_props();
-            if prop.is_stable || prop.level < self.min_level {
-                continue;
-            }
-
-            if prop.level <= self.max_level {
-                let display_unit = DisplayUnit::from(&*joint_data);
-                self.units[prop.level - self.min_level].push(display_unit)
-            }
-
-            for parent in joint_data.parents.iter() {
-                if visited.insert(parent.key.clone()) {
-                    queue.push_back(parent.clone());
-                }
-            }
-        }
-
-        Ok(())
-    }
-
-    fn append_stable_units(&mut self) -> Result<()> {
-        // loop until the nearby mc joints
-        let max_level = self.max_level + 10;
-        let mut mci = max_level;
-        let mut stable_joint = loop {
-            match SDAG_CACHE.get_mc_unit_hash(mci)? {
-                None => {
-                    if mci == Level::ZERO {
-                        // there is no stable unit yet
-                        return Ok(());
-                    }
-                    // this mci level is not available, try from the last stable one
-                    mci = ::main_chain::get_last_stable_mci();
-                    continue;
-                }
-
-                Some(unit) => {
-                    let joint = SDAG_CACHE.get_joint(&unit)?;
-
-                    if joint.read()?.get_level() <= max_level {
-                        // the first mc joint level less that less than max level
-                        break joint;
-                    }
-                }
-            }
-
-            if mci == Level::ZERO {
-                // not find any
-                error!("not find stable units max_level={:?}", self.max_level);
-                return Ok(());
-            }
-            mci -= 1;
-        };
-
-        // revert back one on mc if possible
-        // still we can't make sure that current mc joint include all
-        // joints whoes level is less than current mc joint level
-        for child in stable_joint.read()?.children.iter() {
-            let child_data = child.read()?;
-            if child_data.is_on_main_chain() {
-                stable_joint = (*child).clone();
-                break;
-            }
-        }
-
-        let mut queue = VecDeque::new();
-        let mut visited = HashSet::new();
-        queue.push_back(stable_joint);
-
-        while let Some(joint) = queue.pop_front() {
+    fn append_indexed_units(&mut self) -> Result<()> {
+        let joints =
+            SDAG_CACHE.get_joints_by_level(self.min_level.value(), self.max_level.value())?;
+        for joint in joints {
             let joint_data = joint.read()?;
-
             let level = joint_data.get_level();
-            if level < self.min_level {
-                continue;
-            }
-
-            if level <= self.max_level {
-                {
-                    let display_unit = DisplayUnit::from(&*joint_data);
-                    let level_joints = &mut self.units[level - self.min_level];
-                    if !level_joints.contains(&display_unit) {
-                        level_joints.push(display_unit)
-                    }
-                }
-
-                // find the missing ones
-                for child in joint_data.children.iter() {
-                    if visited.insert(child.key.clone()) {
-                        let child_data = child.read()?;
-                        let display_unit = DisplayUnit::from(&*child_data);
-                        let level = child_data.get_level();
-                        if level <= self.max_level {
-                            let level_joints = &mut self.units[level - self.min_level];
-                            if !level_joints.contains(&display_unit) {
-                                level_joints.push(display_unit)
-                            }
-                        }
-                        queue.push_back((*child).clone());
-                    }
-                }
-            }
-
-            for parent in joint_data.parents.iter() {
-                if visited.insert(parent.key.clone()) {
-                    queue.push_back(parent.clone());
-                }
-            }
+            self.units[level - self.min_level].push(DisplayUnit::from(&*joint_data));
         }
 
         Ok(())
@@ -237,8 +122,7 @@ pub fn get_joints_by_level(min_level: Level, max_level: Level) -> Result<Vec<Vec
         );
     }
     let mut builder = ExploreBuilder::new(min_level, max_level);
-    builder.append_unstable_units()?;
-    builder.append_stable_units()?;
+    builder.append_indexed_units()?;
     builder.adjust_mc_unit_position();
     Ok(builder.units)
 }
@@ -70,6 +70,7 @@ fn finalize_joint(cached_joint: CachedJoint) -> Result<()> {
     }
 
     joint_data.set_stable();
+    SDAG_CACHE.add_stable_joint(cached_joint.key.clone(), joint_data.get_mci());
     if joint_data.is_on_main_chain() {
         ::main_chain::set_last_stable_joint(joint_data.clone());
     }
@@ -81,7 +82,7 @@ fn finalize_joint(cached_joint: CachedJoint) -> Result<()> {
             &joint_data.unit.unit,
         );
 
-    ::utils::event::emit_event(NotifyEvent {
+    ::utils::event::emit_event_async(NotifyEvent {
         joint: joint_data.clone(),
     });
 
@@ -147,7 +148,8 @@ fn calc_ball(joint_data: &JointData, skiplist: &[String]) -> Result<(String)> {
     Ok(ball)
 }
 
-fn get_similar_mcis(mci: usize) -> Vec<usize> {
+/// the mcis of the skiplist units of the main chain unit at the mci
+pub fn get_similar_mcis(mci: usize) -> Vec<usize> {
     let mut similar_mcis = Vec::new();
     let mut devisor = 10;
     loop {
@@ -103,6 +103,24 @@ impl Level {
     pub fn is_valid(self) -> bool {
         self.0 >= 0
     }
+
+    /// return `Level::ZERO` if the sub would underflow, an invalid level is kept
+    #[inline]
+    pub fn saturating_sub(self, rhs: usize) -> Level {
+        if !self.is_valid() {
+            return self;
+        }
+        self.checked_sub(rhs).unwrap_or(Level::ZERO)
+    }
+
+    /// return `None` if the sub would underflow or the level is invalid
+    #[inline]
+    pub fn checked_sub(self, rhs: usize) -> Option<Level> {
+        if !self.is_valid() || rhs > self.0 as usize {
+            return None;
+        }
+        Some(Level(self.0 - rhs as isize))
+    }
 }
 
 impl From<usize> for Level {
@@ -146,8 +164,9 @@ impl JointSequence {
 // JointProperty
 // TODO: some property only init once, use OnceOption instead
 //---------------------------------------------------------------------------------------
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JointProperty {
+    // hops of the longest path from genesis, max parent level + 1, genesis is 0
     pub level: Level,
     pub best_parent_unit: String,
     // witnessed level
@@ -161,6 +180,15 @@ pub struct JointProperty {
     pub mci: Level,
     pub limci: Level,
     pub sub_mci: Level,
+    // the mci of the last ball joint, only cached after the last ball is stable
+    #[serde(default)]
+    pub last_ball_mci: Level,
+    // number of the ancestors include itself, capped by MAX_CONE_SIZE + 1, 0 means unknown
+    #[serde(default)]
+    pub cone_size: usize,
+    // hops of the longest path from genesis, max parent dag_depth + 1, genesis is 0
+    #[serde(default)]
+    pub dag_depth: usize,
     pub is_stable: bool,
     pub sequence: JointSequence,
     #[serde(skip)]
@@ -183,6 +211,9 @@ impl Default for JointProperty {
             mci: Default::default(),
             limci: Default::default(),
             sub_mci: Default::default(),
+            last_ball_mci: Default::default(),
+            cone_size: 0,
+            dag_depth: 0,
             is_stable: false,
             is_wl_increased: false,
             is_min_wl_increased: false,
@@ -249,3 +280,18 @@ fn test_write() {
     println!("{}", parents_set);
     // joint.save().unwrap();
 }
+
+#[test]
+fn test_level_sub() {
+    assert_eq!(Level::new(5).saturating_sub(2), Level::new(3));
+    assert_eq!(Level::new(1).saturating_sub(1), Level::ZERO);
+    assert_eq!(Level::new(1).saturating_sub(2), Level::ZERO);
+    assert_eq!(Level::ZERO.saturating_sub(usize::max_value()), Level::ZERO);
+    assert!(!Level::MINIMUM.saturating_sub(1).is_valid());
+
+    assert_eq!(Level::new(5).checked_sub(5), Some(Level::ZERO));
+    assert_eq!(Level::new(5).checked_sub(6), None);
+    assert_eq!(Level::ZERO.checked_sub(0), Some(Level::ZERO));
+    assert_eq!(Level::ZERO.checked_sub(1), None);
+    assert_eq!(Level::MINIMUM.checked_sub(0), None);
+}
@@ -0,0 +1,203 @@
+//! backend agnostic backup of the kv store
+//!
+//! the first line is the header `{"type":"header","count":N,"sha256":"<hex>"}`, followed
+//! by one line per entry `{"t":"<tree>","k":"<hex>","v":"<base64>"}`. The sha256 is
+//! calculated on all the lines after the header, including the line breaks.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use super::KvStore;
+use base64;
+use error::Result;
+use serde_json;
+use sha2::{Digest, Sha256};
+
+#[derive(Serialize, Deserialize)]
+struct BackupHeader {
+    #[serde(rename = "type")]
+    kind: String,
+    count: u64,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupEntry {
+    t: String,
+    k: String,
+    v: String,
+}
+
+impl KvStore {
+    /// export all the entries to the file, return the entry count
+    pub fn export_to_jsonl(&self, path: &Path) -> Result<u64> {
+        // the header needs the hash of all the entries, write them to a temp file first
+        let tmp_path = path.with_extension("tmp");
+        let mut hasher = Sha256::new();
+        let mut count = 0;
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            self.for_each_entry(|tree, key, value| {
+                let entry = BackupEntry {
+                    t: tree.to_owned(),
+                    k: to_hex(key),
+                    v: base64::encode(value),
+                };
+                let mut line = serde_json::to_vec(&entry)?;
+                line.push(b'\n');
+                hasher.input(&line);
+                writer.write_all(&line)?;
+                count += 1;
+                Ok(())
+            })?;
+            writer.flush()?;
+        }
+
+        let header = BackupHeader {
+            kind: String::from("header"),
+            count,
+            sha256: to_hex(&hasher.result()),
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+        ::std::io::copy(&mut File::open(&tmp_path)?, &mut writer)?;
+        writer.flush()?;
+        fs::remove_file(&tmp_path)?;
+
+        info!("exported {} kv entries to {}", count, path.display());
+        Ok(count)
+    }
+
+    /// import the entries exported by `export_to_jsonl`, return the entry count
+    /// without force, nothing is written if any key already exists
+    pub fn import_from_jsonl(&self, path: &Path, force: bool) -> Result<u64> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => bail!("empty backup file {}", path.display()),
+        };
+        let header: BackupHeader = serde_json::from_str(&header)?;
+        if header.kind != "header" {
+            bail!("invalid backup header type {}", header.kind);
+        }
+
+        let mut hasher = Sha256::new();
+        let mut entries = Vec::new();
+        for line in lines {
+            let line = line?;
+            hasher.input(line.as_bytes());
+            hasher.input(b"\n");
+
+            let entry: BackupEntry = serde_json::from_str(&line)?;
+            entries.push((entry.t, from_hex(&entry.k)?, base64::decode(&entry.v)?));
+        }
+
+        let sha256 = to_hex(&hasher.result());
+        if sha256 != header.sha256 {
+            bail!(
+                "backup sha256 mismatch, expect {}, found {}",
+                header.sha256,
+                sha256
+            );
+        }
+        if entries.len() as u64 != header.count {
+            bail!(
+                "backup count mismatch, expect {}, found {}",
+                header.count,
+                entries.len()
+            );
+        }
+
+        if !force {
+            for (tree, key, _) in &entries {
+                if self.get_entry(tree, key)?.is_some() {
+                    bail!("key {} already exists in {}", to_hex(key), tree);
+                }
+            }
+        }
+
+        self.put_entries(&entries)?;
+        info!(
+            "imported {} kv entries from {}",
+            entries.len(),
+            path.display()
+        );
+        Ok(header.count)
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        bail!("invalid hex string {}", s);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_path(name: &str) -> ::std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("sdag_{}_{}_{}", name, ::std::process::id(), nanos));
+        path
+    }
+
+    #[test]
+    fn test_export_import() -> Result<()> {
+        let src_path = temp_path("backup_src");
+        let dst_path = temp_path("backup_dst");
+        let file = temp_path("backup.jsonl");
+
+        let src = KvStore::load(&src_path.to_string_lossy())?;
+        let entries = (0..100)
+            .map(|i| {
+                let key = format!("backup_test:{:03}", i).into_bytes();
+                (String::from("misc"), key, vec![i as u8; 8])
+            })
+            .collect::<Vec<_>>();
+        src.put_entries(&entries)?;
+        assert_eq!(src.export_to_jsonl(&file)?, 100);
+
+        let dst = KvStore::load(&dst_path.to_string_lossy())?;
+        assert_eq!(dst.import_from_jsonl(&file, false)?, 100);
+        for (tree, key, value) in &entries {
+            assert_eq!(dst.get_entry(tree, key)?.as_ref(), Some(value));
+        }
+        // the keys exist now
+        assert!(dst.import_from_jsonl(&file, false).is_err());
+        assert_eq!(dst.import_from_jsonl(&file, true)?, 100);
+
+        let content = fs::read_to_string(&file)?;
+        let (header, body) = content.split_at(content.find('\n').unwrap() + 1);
+        let header: BackupHeader = serde_json::from_str(header)?;
+        assert_eq!(header.sha256, to_hex(&Sha256::digest(body.as_bytes())));
+
+        fs::remove_file(&file)?;
+        fs::remove_dir_all(&src_path).ok();
+        fs::remove_dir_all(&dst_path).ok();
+        Ok(())
+    }
+
+    #[test]
+    fn test_hex() {
+        let data = vec![0u8, 1, 0xab, 0xff];
+        assert_eq!(to_hex(&data), "0001abff");
+        assert_eq!(from_hex("0001abff").unwrap(), data);
+        assert!(from_hex("abc").is_err());
+    }
+}
@@ -0,0 +1,555 @@
+extern crate crossbeam;
+extern crate lmdb;
+
+use self::crossbeam::crossbeam_channel::Sender;
+use self::lmdb::{
+    Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, Transaction, WriteFlags,
+};
+
+use super::*;
+use business::{AddressStats, BusinessStateExport, BUSINESS_CACHE};
+use cache::{CachedJoint, SDAG_CACHE};
+use error::Result;
+use failure::ResultExt;
+use joint::{Joint, JointProperty, Level};
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+thread_local! {
+    // the joint writes of the batch writer thread, see kv_store_common::create_batch_writer
+    // and the writes of save_unstable_joints
+    static PENDING_BATCH: RefCell<Option<Vec<(Database, Vec<u8>, Vec<u8>)>>> = RefCell::new(None);
+}
+
+// lmdb never grows the map by itself, the size has to be raised in the settings
+fn map_full_err(e: lmdb::Error) -> ::failure::Error {
+    match e {
+        lmdb::Error::MapFull => format_err!(
+            "lmdb map of {} bytes is full, raise lmdb_map_size_mb in the settings",
+            ::config::get_lmdb_map_size()
+        ),
+        e => e.into(),
+    }
+}
+
+pub struct KvStore {
+    env: Environment,
+    pub joints: Database,
+    pub properties: Database,
+    pub children: Database,
+    pub misc: Database,
+    sender: Sender<(CachedJoint, bool)>,
+    _handlers: Vec<JoinHandle<()>>,
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        KvStore::load(&::config::get_database_path()).expect("init KvStore failed")
+    }
+}
+
+impl KvStore {
+    pub fn load(path: &str) -> Result<Self> {
+        ::std::fs::create_dir_all(path).context("Failed to create dir for KvStore")?;
+        // the dirty pages are written through the map, a crashed process never
+        // leaves a partially written transaction behind
+        // the coroutines may move between threads, so the txns are not bound to threads
+        let env = Environment::new()
+            .set_flags(EnvironmentFlags::WRITE_MAP | EnvironmentFlags::NO_TLS)
+            .set_max_dbs(KV_TREES.len() as u32)
+            .set_map_size(::config::get_lmdb_map_size())
+            .open(Path::new(path))
+            .context("Failed to read file for KvStore")?;
+
+        let joints = env
+            .create_db(Some("joints"), DatabaseFlags::empty())
+            .context("Failed to init joints KvStore")?;
+        let properties = env
+            .create_db(Some("properties"), DatabaseFlags::empty())
+            .context("Failed to init properties KvStore")?;
+        let children = env
+            .create_db(Some("children"), DatabaseFlags::empty())
+            .context("Failed to init children KvStore")?;
+        let misc = env
+            .create_db(Some("misc"), DatabaseFlags::empty())
+            .context("Failed to init misc KvStore")?;
+
+        let (sender, handlers) = kv_store_common::create_batch_writer();
+
+        Ok(KvStore {
+            env,
+            joints,
+            properties,
+            children,
+            misc,
+            sender,
+            _handlers: handlers,
+        })
+    }
+
+    fn get(&self, db: Database, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let txn = self.env.begin_ro_txn()?;
+        match txn.get(db, &key) {
+            Ok(value) => Ok(Some(value.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // the write goes into the pending batch of the current thread if any
+    fn put(&self, db: Database, key: &[u8], value: &[u8]) -> Result<()> {
+        let is_batched = PENDING_BATCH.with(|batch| match *batch.borrow_mut() {
+            Some(ref mut batch) => {
+                batch.push((db, key.to_vec(), value.to_vec()));
+                true
+            }
+            None => false,
+        });
+        if is_batched {
+            return Ok(());
+        }
+
+        let mut txn = self.env.begin_rw_txn()?;
+        txn.put(db, &key, &value, WriteFlags::empty())
+            .map_err(map_full_err)?;
+        txn.commit().map_err(map_full_err)?;
+        Ok(())
+    }
+
+    /// collect the following writes of the current thread until `commit_batch`
+    pub fn begin_batch(&self) {
+        PENDING_BATCH.with(|batch| *batch.borrow_mut() = Some(Vec::new()));
+    }
+
+    /// write the collected writes in one transaction
+    pub fn commit_batch(&self) -> Result<()> {
+        let batch = match PENDING_BATCH.with(|batch| batch.borrow_mut().take()) {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+
+        let mut txn = self.env.begin_rw_txn()?;
+        for (db, key, value) in batch {
+            txn.put(db, &key, &value, WriteFlags::empty())
+                .map_err(map_full_err)?;
+        }
+        txn.commit().map_err(map_full_err)?;
+        Ok(())
+    }
+
+    fn del(&self, db: Database, key: &[u8]) -> Result<()> {
+        let mut txn = self.env.begin_rw_txn()?;
+        match txn.del(db, &key, None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    // all the entries from the start key while the key starts with the prefix
+    fn scan(&self, db: Database, start: &[u8], prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        self.scan_while(db, start, prefix, |key, value| {
+            entries.push((key.to_vec(), value.to_vec()));
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    // call f with the entries from start that have the prefix, until f returns false
+    fn scan_while<F>(&self, db: Database, start: &[u8], prefix: &[u8], mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        let txn = self.env.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(db)?;
+        // lmdb refuses to seek to an empty key
+        let iter = if start.is_empty() {
+            cursor.iter_start()
+        } else {
+            cursor.iter_from(start)
+        };
+        for item in iter {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) || !f(key, value)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_joint_exist(&self, key: &str) -> Result<bool> {
+        Ok(self.get(self.joints, key.as_bytes())?.is_some())
+    }
+
+    pub fn read_joint(&self, key: &str) -> Result<Joint> {
+        if let Some(value) = self.get(self.joints, key.as_bytes())? {
+            return Ok(serde_json::from_slice(&value)?);
+        }
+
+        bail!("joint {} not exist in KV", key)
+    }
+
+    pub fn read_joint_children(&self, key: &str) -> Result<Vec<String>> {
+        if let Some(value) = self.get(self.children, key.as_bytes())? {
+            return Ok(serde_json::from_slice(&value)?);
+        }
+
+        bail!("joint children {} not exist in KV", key)
+    }
+
+    pub fn read_joint_property(&self, key: &str) -> Result<JointProperty> {
+        if let Some(value) = self.get(self.properties, key.as_bytes())? {
+            return Ok(serde_json::from_slice(&value)?);
+        }
+
+        bail!("joint property {} not exist in KV", key)
+    }
+
+    pub fn save_joint(&self, key: &str, joint: &Joint) -> Result<()> {
+        self.put(self.joints, key.as_bytes(), &serde_json::to_vec(joint)?)
+    }
+
+    pub fn update_joint(&self, key: &str, joint: &Joint) -> Result<()> {
+        self.put(self.joints, key.as_bytes(), &serde_json::to_vec(joint)?)
+    }
+
+    pub fn save_joint_children(&self, key: &str, children: Vec<String>) -> Result<()> {
+        self.put(
+            self.children,
+            key.as_bytes(),
+            &serde_json::to_vec(&children)?,
+        )
+    }
+
+    pub fn save_joint_property(&self, key: &str, property: &JointProperty) -> Result<()> {
+        self.put(
+            self.properties,
+            key.as_bytes(),
+            &serde_json::to_vec(property)?,
+        )?;
+        if property.is_stable && property.mci.is_valid() {
+            self.save_mci_index(property.mci, key)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_mci_index(&self, mci: Level, unit: &str) -> Result<()> {
+        self.put(self.misc, mci_index_key(mci, unit).as_bytes(), &[])
+    }
+
+    /// all the misc entries which key starts with the prefix, sorted by key
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(self.misc, prefix, prefix)
+    }
+
+    fn get_db(&self, tree: &str) -> Result<Database> {
+        match tree {
+            "joints" => Ok(self.joints),
+            "properties" => Ok(self.properties),
+            "children" => Ok(self.children),
+            "misc" => Ok(self.misc),
+            _ => bail!("unknown kv tree {}", tree),
+        }
+    }
+
+    /// call f with all the entries of all the trees
+    pub fn for_each_entry<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &[u8], &[u8]) -> Result<()>,
+    {
+        for &tree in KV_TREES {
+            let txn = self.env.begin_ro_txn()?;
+            let mut cursor = txn.open_ro_cursor(self.get_db(tree)?)?;
+            for item in cursor.iter_start() {
+                let (key, value) = item?;
+                f(tree, key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_entry(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get(self.get_db(tree)?, key)
+    }
+
+    pub fn put_entries(&self, entries: &[(String, Vec<u8>, Vec<u8>)]) -> Result<()> {
+        // all the entries are written in one transaction
+        let mut txn = self.env.begin_rw_txn()?;
+        for (tree, key, value) in entries {
+            txn.put(self.get_db(tree)?, key, value, WriteFlags::empty())
+                .map_err(map_full_err)?;
+        }
+        txn.commit().map_err(map_full_err)?;
+        Ok(())
+    }
+
+    pub fn rebuild_from_kv(&self) -> Result<()> {
+        info!("Rebuild from KV start!");
+        IS_REBUILDING_FROM_KV.store(true, Ordering::Release);
+
+        if !self.is_checkpoint_complete()? {
+            warn!("the unstable joints checkpoint is partial, replay the unstable joints");
+            self.clear_unstable_joints()?;
+        }
+
+        let txn = self.env.begin_ro_txn()?;
+        let mut cursor = txn.open_ro_cursor(self.joints)?;
+        let mut handle_joint_count = 0;
+        for item in cursor.iter_start() {
+            let (_, value) = item?;
+            let joint: Joint = serde_json::from_slice(&value)?;
+            kv_store_common::handle_kv_joint(joint)?;
+            handle_joint_count += 1;
+        }
+        drop(cursor);
+        txn.abort();
+
+        ::utils::wait_cond(None, || {
+            handle_joint_count == SDAG_CACHE.get_num_of_normal_joints()
+        })?;
+
+        info!("Rebuild from KV done!");
+        IS_REBUILDING_FROM_KV.store(false, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// save all the unstable joints in one transaction, the checkpoint marker is set to
+    /// false before the transaction and to true as the last write of it
+    pub fn save_unstable_joints(&self) -> Result<()> {
+        let joints = SDAG_CACHE.get_unstable_joints()?;
+        self.put(
+            self.misc,
+            CHECKPOINT_COMPLETE_KEY.as_bytes(),
+            &serde_json::to_vec(&false)?,
+        )?;
+
+        let complete = serde_json::to_vec(&true)?;
+        self.begin_batch();
+        let saved: Result<()> = joints.iter().map(|joint| joint.save_to_db()).collect();
+        if let Err(e) = saved {
+            // drop the pending writes, the checkpoint stays partial
+            PENDING_BATCH.with(|batch| batch.borrow_mut().take());
+            return Err(e);
+        }
+
+        self.put(self.misc, CHECKPOINT_COMPLETE_KEY.as_bytes(), &complete)?;
+        self.commit_batch()
+    }
+
+    // a missing marker means no checkpoint is ever written
+    fn is_checkpoint_complete(&self) -> Result<bool> {
+        match self.get(self.misc, CHECKPOINT_COMPLETE_KEY.as_bytes())? {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(true),
+        }
+    }
+
+    // remove the unstable joints, they would be synced from the peers again
+    fn clear_unstable_joints(&self) -> Result<()> {
+        // the properties can't be read while the cursor holds the read transaction
+        let mut keys = Vec::new();
+        self.scan_while(self.joints, b"", b"", |key, _value| {
+            keys.push(::std::str::from_utf8(key)?.to_owned());
+            Ok(true)
+        })?;
+
+        let mut unstable_keys = Vec::new();
+        for key in keys {
+            match self.read_joint_property(&key) {
+                Ok(ref property) if property.is_stable => {}
+                _ => unstable_keys.push(key),
+            }
+        }
+
+        info!("clear {} unstable joints", unstable_keys.len());
+        for key in unstable_keys {
+            self.delete_joint(&key)?;
+            self.delete_joint_property(&key)?;
+            self.del(self.children, key.as_bytes())?;
+        }
+
+        self.del(self.misc, CHECKPOINT_COMPLETE_KEY.as_bytes())
+    }
+
+    pub fn delete_joint(&self, key: &str) -> Result<()> {
+        self.del(self.joints, key.as_bytes())
+    }
+
+    pub fn delete_joint_property(&self, key: &str) -> Result<()> {
+        self.del(self.properties, key.as_bytes())
+    }
+
+    #[allow(dead_code)]
+    pub fn save_last_mci(&self, mci: Level) -> Result<()> {
+        self.put(self.misc, b"last_mci", &serde_json::to_vec(&mci)?)
+    }
+
+    #[allow(dead_code)]
+    fn read_last_mci(&self) -> Result<Level> {
+        let v = self
+            .get(self.misc, b"last_mci")?
+            .ok_or_else(|| format_err!("read last mci from kv failed"))?;
+
+        Ok(serde_json::from_slice(&v)?)
+    }
+
+    pub fn save_feed_value(
+        &self,
+        address: &str,
+        feed_name: &str,
+        mci: Level,
+        value: &Value,
+    ) -> Result<()> {
+        let key = kv_store_common::feed_key(address, feed_name, mci);
+        self.put(self.misc, key.as_bytes(), &serde_json::to_vec(value)?)
+    }
+
+    pub fn read_feed_range(
+        &self,
+        address: &str,
+        feed_name: &str,
+        from_mci: Level,
+        to_mci: Level,
+    ) -> Result<Vec<(Level, Value)>> {
+        let prefix = kv_store_common::feed_prefix(address, feed_name);
+        let from_key = kv_store_common::feed_key(address, feed_name, from_mci);
+
+        let mut values = Vec::new();
+        for (key, value) in self.scan(self.misc, from_key.as_bytes(), prefix.as_bytes())? {
+            let mci = kv_store_common::parse_feed_mci(&key)?;
+            if mci > to_mci {
+                break;
+            }
+            values.push((mci, serde_json::from_slice(&value)?));
+        }
+
+        Ok(values)
+    }
+
+    pub fn save_text(&self, unit: &str, mci: Level, text: &str) -> Result<()> {
+        let key = kv_store_common::text_key(mci, unit);
+        self.put(self.misc, key.as_bytes(), &serde_json::to_vec(text)?)
+    }
+
+    /// the first `limit` <unit, text> that match, in the order of being stable
+    pub fn search_texts<F>(&self, is_match: F, limit: usize) -> Result<Vec<(String, String)>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut texts = Vec::new();
+        if limit == 0 {
+            return Ok(texts);
+        }
+
+        let prefix = kv_store_common::TEXT_PREFIX.as_bytes();
+        self.scan_while(self.misc, prefix, prefix, |key, value| {
+            let text: String = serde_json::from_slice(value)?;
+            if is_match(&text) {
+                texts.push((kv_store_common::parse_text_unit(key)?, text));
+            }
+            Ok(texts.len() < limit)
+        })?;
+        Ok(texts)
+    }
+
+    pub fn save_address_stats(&self, address: &str, stats: &AddressStats) -> Result<()> {
+        let key = kv_store_common::stats_key(address);
+        self.put(self.misc, key.as_bytes(), &serde_json::to_vec(stats)?)
+    }
+
+    pub fn read_address_stats(&self, address: &str) -> Result<Option<AddressStats>> {
+        let key = kv_store_common::stats_key(address);
+        match self.get(self.misc, key.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_peer(&self, addr: &str, record: &PeerRecord) -> Result<()> {
+        self.put(
+            self.misc,
+            peer_key(addr).as_bytes(),
+            &serde_json::to_vec(record)?,
+        )
+    }
+
+    /// all the known peers and their records
+    pub fn read_peers(&self) -> Result<Vec<(String, PeerRecord)>> {
+        let mut peers = Vec::new();
+        for (key, value) in self.iter_prefix(PEER_PREFIX.as_bytes())? {
+            let addr = ::std::str::from_utf8(&key[PEER_PREFIX.len()..])?.to_owned();
+            peers.push((addr, serde_json::from_slice(&value)?));
+        }
+        Ok(peers)
+    }
+
+    /// the record of the peer, a single lookup without reading all the peers
+    pub fn read_peer(&self, addr: &str) -> Result<Option<PeerRecord>> {
+        match self.get_entry("misc", peer_key(addr).as_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_peer(&self, addr: &str) -> Result<()> {
+        self.del(self.misc, peer_key(addr).as_bytes())
+    }
+
+    pub fn save_business_snapshot(&self, snapshot: &BusinessStateExport) -> Result<()> {
+        self.put(
+            self.misc,
+            BUSINESS_SNAPSHOT_KEY.as_bytes(),
+            &serde_json::to_vec(snapshot)?,
+        )
+    }
+
+    pub fn read_business_snapshot(&self) -> Result<Option<BusinessStateExport>> {
+        match self.get(self.misc, BUSINESS_SNAPSHOT_KEY.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_business_snapshot(&self) -> Result<()> {
+        self.del(self.misc, BUSINESS_SNAPSHOT_KEY.as_bytes())
+    }
+
+    pub fn save_cache_async(&self, data: CachedJoint) -> Result<()> {
+        self.sender.send((data, false))?;
+        Ok(())
+    }
+
+    pub fn update_cache_async(&self, data: CachedJoint) -> Result<()> {
+        self.sender.send((data, true))?;
+        Ok(())
+    }
+
+    /// write all the pending data to the disk
+    pub fn flush(&self) -> Result<()> {
+        self.env.sync(true)?;
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        // the business state is restored from the snapshot on the next startup
+        match BUSINESS_CACHE.export_applied_state(Duration::from_secs(5)) {
+            Ok(snapshot) => self.save_business_snapshot(&snapshot)?,
+            Err(e) => warn!("skip saving business snapshot, err={}", e),
+        }
+        // a failed checkpoint leaves the marker false, they are replayed after restart
+        if let Err(e) = self.save_unstable_joints() {
+            warn!("failed to save unstable joints, err={}", e);
+        }
+        self.flush()?;
+
+        info!("kv store finished");
+
+        Ok(())
+    }
+}
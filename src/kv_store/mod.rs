@@ -8,6 +8,15 @@ mod sled;
 #[cfg(feature = "kv_store_rocksdb")]
 mod rocksdb;
 
+#[cfg(feature = "kv_store_lmdb")]
+mod lmdb;
+
+#[cfg(feature = "kv_store_sqlite")]
+mod sqlite;
+
+#[cfg(not(feature = "kv_store_none"))]
+mod backup;
+
 #[cfg(feature = "kv_store_none")]
 use self::kv_store_none::KvStore;
 
@@ -17,6 +26,12 @@ use self::sled::KvStore;
 #[cfg(feature = "kv_store_rocksdb")]
 use self::rocksdb::KvStore;
 
+#[cfg(feature = "kv_store_lmdb")]
+use self::lmdb::KvStore;
+
+#[cfg(feature = "kv_store_sqlite")]
+use self::sqlite::KvStore;
+
 lazy_static! {
     pub static ref KV_STORE: KvStore = KvStore::default();
 
@@ -28,6 +43,66 @@ pub fn is_rebuilding_from_kv() -> bool {
     IS_REBUILDING_FROM_KV.load(Ordering::Acquire)
 }
 
+/// if the saved data could be loaded back, kv_store_none keeps nothing
+pub fn is_persistent() -> bool {
+    cfg!(not(feature = "kv_store_none"))
+}
+
+/// the trees of all the backends, a backup contains the entries of them
+pub const KV_TREES: &[&str] = &["joints", "properties", "children", "misc"];
+
+/// the key prefix of the stable units index
+pub const MCI_INDEX_PREFIX: &str = "mci_idx:";
+
+/// the stable unit index key, mci is padded so that the keys are sorted by mci
+pub fn mci_index_key(mci: ::joint::Level, unit: &str) -> String {
+    format!("{}{:010}:{}", MCI_INDEX_PREFIX, mci.value(), unit)
+}
+
+/// the key of the business state snapshot saved periodically and on shutdown
+pub const BUSINESS_SNAPSHOT_KEY: &str = "business_snapshot";
+
+/// the key prefix of the periodic utxo snapshots, all of them are kept
+pub const UTXO_SNAPSHOT_PREFIX: &str = "utxo_snapshot:";
+
+/// the key of the sorted mcis of all the saved utxo snapshots
+pub const UTXO_SNAPSHOT_MCIS_KEY: &str = "utxo_snapshot_mcis";
+
+/// the utxo snapshot key, mci is padded so that the keys are sorted by mci
+pub fn utxo_snapshot_key(mci: ::joint::Level) -> String {
+    format!("{}{:010}", UTXO_SNAPSHOT_PREFIX, mci.value())
+}
+
+/// the key of the unstable joints checkpoint marker, false while the checkpoint is written
+pub const CHECKPOINT_COMPLETE_KEY: &str = "checkpoint_complete";
+
+/// the key prefix of the known peers
+pub const PEER_PREFIX: &str = "peer::";
+
+/// the known peer key
+pub fn peer_key(addr: &str) -> String {
+    format!("{}{}", PEER_PREFIX, addr)
+}
+
+/// the persisted peer, the old peers are pruned by the last seen time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerRecord {
+    // milliseconds since unix epoch
+    pub last_seen: u64,
+    // the peer is banned if the reputation is lower than min_peer_reputation
+    #[serde(default)]
+    pub reputation: i32,
+}
+
+/// parse the unit from the stable unit index key
+pub fn parse_mci_index_unit(key: &[u8]) -> Result<String> {
+    let key = ::std::str::from_utf8(key)?;
+    match key.rsplit(':').next() {
+        Some(unit) if key.starts_with(MCI_INDEX_PREFIX) => Ok(unit.to_owned()),
+        _ => bail!("invalid mci index key {}", key),
+    }
+}
+
 //---------------------------------------------------------------------------------------
 // LoadFromKv trait
 //---------------------------------------------------------------------------------------
@@ -42,20 +117,30 @@ pub trait LoadFromKv<K: ?Sized>: Sized {
 
 #[cfg(feature = "kv_store_none")]
 mod kv_store_none {
+    use business::{AddressStats, BusinessStateExport, UtxoCache};
     use cache::CachedJoint;
     use error::Result;
     use joint::{Joint, JointProperty, Level};
-    pub struct KvStore {}
+    use may::sync::RwLock;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+
+    pub struct KvStore {
+        // the stable texts are kept in memory, so that they could still be searched
+        texts: RwLock<BTreeMap<(usize, String), String>>,
+    }
 
     impl Default for KvStore {
         fn default() -> Self {
-            KvStore::load("./sdag_kv").expect("init KvStore failed")
+            KvStore::load(&::config::get_database_path()).expect("init KvStore failed")
         }
     }
 
     impl KvStore {
         pub fn load(_path: &str) -> Result<Self> {
-            Ok(KvStore {})
+            Ok(KvStore {
+                texts: RwLock::new(BTreeMap::new()),
+            })
         }
 
         pub fn is_joint_exist(&self, _key: &str) -> Result<bool> {
@@ -90,6 +175,30 @@ mod kv_store_none {
             Ok(())
         }
 
+        pub fn save_mci_index(&self, _mci: Level, _unit: &str) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn iter_prefix(&self, _prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            Ok(Vec::new())
+        }
+
+        pub fn save_peer(&self, _addr: &str, _record: &super::PeerRecord) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn read_peers(&self) -> Result<Vec<(String, super::PeerRecord)>> {
+            Ok(Vec::new())
+        }
+
+        pub fn read_peer(&self, _addr: &str) -> Result<Option<super::PeerRecord>> {
+            Ok(None)
+        }
+
+        pub fn delete_peer(&self, _addr: &str) -> Result<()> {
+            Ok(())
+        }
+
         pub fn rebuild_from_kv(&self) -> Result<()> {
             Ok(())
         }
@@ -110,6 +219,77 @@ mod kv_store_none {
             bail!("joint {} not exist in KV", key)
         }
 
+        pub fn save_feed_value(
+            &self,
+            _address: &str,
+            _feed_name: &str,
+            _mci: Level,
+            _value: &Value,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn read_feed_range(
+            &self,
+            _address: &str,
+            _feed_name: &str,
+            _from_mci: Level,
+            _to_mci: Level,
+        ) -> Result<Vec<(Level, Value)>> {
+            Ok(Vec::new())
+        }
+
+        pub fn save_text(&self, unit: &str, mci: Level, text: &str) -> Result<()> {
+            let mut texts = self.texts.write().unwrap();
+            texts.insert((mci.value(), unit.to_owned()), text.to_owned());
+            Ok(())
+        }
+
+        pub fn search_texts<F>(&self, is_match: F, limit: usize) -> Result<Vec<(String, String)>>
+        where
+            F: Fn(&str) -> bool,
+        {
+            let texts = self.texts.read().unwrap();
+            Ok(texts
+                .iter()
+                .filter(|(_, text)| is_match(text))
+                .take(limit)
+                .map(|((_, unit), text)| (unit.clone(), text.clone()))
+                .collect())
+        }
+
+        pub fn save_address_stats(&self, _address: &str, _stats: &AddressStats) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn read_address_stats(&self, _address: &str) -> Result<Option<AddressStats>> {
+            Ok(None)
+        }
+
+        pub fn save_business_snapshot(&self, _snapshot: &BusinessStateExport) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn read_business_snapshot(&self) -> Result<Option<BusinessStateExport>> {
+            Ok(None)
+        }
+
+        pub fn delete_business_snapshot(&self) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn save_utxo_snapshot(&self, _mci: Level, _utxo: &UtxoCache) -> Result<()> {
+            Ok(())
+        }
+
+        pub fn read_utxo_snapshot_mcis(&self) -> Result<Vec<Level>> {
+            Ok(Vec::new())
+        }
+
+        pub fn read_utxo_snapshot(&self, _mci: Level) -> Result<Option<UtxoCache>> {
+            Ok(None)
+        }
+
         pub fn save_cache_async(&self, _data: CachedJoint) -> Result<()> {
             Ok(())
         }
@@ -118,6 +298,18 @@ mod kv_store_none {
             Ok(())
         }
 
+        pub fn export_to_jsonl(&self, _path: &::std::path::Path) -> Result<u64> {
+            bail!("no kv store backend enabled")
+        }
+
+        pub fn import_from_jsonl(&self, _path: &::std::path::Path, _force: bool) -> Result<u64> {
+            bail!("no kv store backend enabled")
+        }
+
+        pub fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+
         pub fn finish(&self) -> Result<()> {
             Ok(())
         }
@@ -132,7 +324,10 @@ mod kv_store_common {
 
     use self::crossbeam::crossbeam_channel::{unbounded, Receiver, Sender};
     use super::*;
+    use business::UtxoCache;
     use cache::{CachedJoint, SDAG_CACHE};
+    use joint::Level;
+    use serde_json;
 
     pub fn handle_kv_joint(joint: crate::joint::Joint) -> Result<()> {
         use joint::JointSequence;
@@ -164,6 +359,99 @@ mod kv_store_common {
         Ok(())
     }
 
+    /// the key prefix of the data feed values
+    /// the parts are length prefixed, so a name containing ':' can't match another feed
+    pub fn feed_prefix(address: &str, feed_name: &str) -> String {
+        format!(
+            "feed:{}:{}:{}:{}:",
+            address.len(),
+            address,
+            feed_name.len(),
+            feed_name
+        )
+    }
+
+    /// the data feed key, mci is padded so that the keys are sorted by mci
+    pub fn feed_key(address: &str, feed_name: &str, mci: Level) -> String {
+        format!("{}{:010}", feed_prefix(address, feed_name), mci.value())
+    }
+
+    /// the key prefix of the stable text payloads
+    pub const TEXT_PREFIX: &str = "text:";
+
+    /// the key of the stable text payload of the unit, mci is padded so that
+    /// the texts are scanned in the order of being stable
+    pub fn text_key(mci: Level, unit: &str) -> String {
+        format!("{}{:010}:{}", TEXT_PREFIX, mci.value(), unit)
+    }
+
+    /// parse the unit from the text key
+    pub fn parse_text_unit(key: &[u8]) -> Result<String> {
+        let key = ::std::str::from_utf8(key)?;
+        match key.rsplit(':').next() {
+            Some(unit) if key.starts_with(TEXT_PREFIX) => Ok(unit.to_owned()),
+            _ => bail!("invalid text key {}", key),
+        }
+    }
+
+    /// the address stats key
+    pub fn stats_key(address: &str) -> String {
+        format!("stats:{}", address)
+    }
+
+    // the utxo snapshots are saved by the backend agnostic entries
+    impl KvStore {
+        /// save the utxo snapshot of the mci and add the mci to the snapshot mcis
+        /// the snapshot is written before the mcis, so a listed snapshot always exists
+        pub fn save_utxo_snapshot(&self, mci: Level, utxo: &UtxoCache) -> Result<()> {
+            let mut mcis = self.read_utxo_snapshot_mcis()?;
+            if !mcis.contains(&mci) {
+                mcis.push(mci);
+                mcis.sort_by_key(|mci| mci.value());
+            }
+
+            self.put_entries(&[
+                (
+                    "misc".to_owned(),
+                    utxo_snapshot_key(mci).into_bytes(),
+                    serde_json::to_vec(utxo)?,
+                ),
+                (
+                    "misc".to_owned(),
+                    UTXO_SNAPSHOT_MCIS_KEY.as_bytes().to_vec(),
+                    serde_json::to_vec(&mcis)?,
+                ),
+            ])
+        }
+
+        /// the mcis of all the saved utxo snapshots, sorted by mci
+        pub fn read_utxo_snapshot_mcis(&self) -> Result<Vec<Level>> {
+            match self.get_entry("misc", UTXO_SNAPSHOT_MCIS_KEY.as_bytes())? {
+                Some(v) => Ok(serde_json::from_slice(&v)?),
+                None => Ok(Vec::new()),
+            }
+        }
+
+        pub fn read_utxo_snapshot(&self, mci: Level) -> Result<Option<UtxoCache>> {
+            match self.get_entry("misc", utxo_snapshot_key(mci).as_bytes())? {
+                Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// parse the mci from the data feed key
+    pub fn parse_feed_mci(key: &[u8]) -> Result<Level> {
+        let key = ::std::str::from_utf8(key)?;
+        let mci = key
+            .rsplit(':')
+            .next()
+            .ok_or_else(|| format_err!("invalid feed key {}", key))?
+            .parse::<usize>()?;
+        Ok(Level::from(mci))
+    }
+
+    #[cfg(not(any(feature = "kv_store_rocksdb", feature = "kv_store_lmdb")))]
     pub fn create_thread_pool(size: usize) -> (Sender<(CachedJoint, bool)>, Vec<JoinHandle<()>>) {
         let (sender, receiver): (Sender<(CachedJoint, bool)>, Receiver<(CachedJoint, bool)>) =
             unbounded();
@@ -191,6 +479,79 @@ mod kv_store_common {
 
         (sender, handlers)
     }
+
+    /// save the cached joints in batches, a batch is committed when it has
+    /// kv_write_batch_size joints or the first joint waits KV_WRITE_BATCH_TIMEOUT_MS
+    #[cfg(any(feature = "kv_store_rocksdb", feature = "kv_store_lmdb"))]
+    pub fn create_batch_writer() -> (Sender<(CachedJoint, bool)>, Vec<JoinHandle<()>>) {
+        use std::time::{Duration, Instant};
+
+        let (sender, rx): (Sender<(CachedJoint, bool)>, Receiver<(CachedJoint, bool)>) =
+            unbounded();
+
+        // a single writer keeps the batches in order
+        let handler = std::thread::spawn(move || {
+            while let Ok(first) = rx.recv() {
+                let timeout = Duration::from_millis(::config::KV_WRITE_BATCH_TIMEOUT_MS);
+                let deadline = Instant::now() + timeout;
+                let batch_size = ::config::get_kv_write_batch_size();
+
+                let mut batch = vec![first];
+                while batch.len() < batch_size {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    match rx.recv_timeout(deadline - now) {
+                        Ok(v) => batch.push(v),
+                        Err(_) => break,
+                    }
+                }
+
+                t_c!(write_batch(&batch));
+            }
+        });
+
+        (sender, vec![handler])
+    }
+
+    #[cfg(any(feature = "kv_store_rocksdb", feature = "kv_store_lmdb"))]
+    fn write_batch(batch: &[(CachedJoint, bool)]) -> Result<()> {
+        debug!("writing a batch of {} cached joints", batch.len());
+
+        KV_STORE.begin_batch();
+        for (cached_joint, is_update) in batch {
+            let ret = if *is_update {
+                cached_joint.update_to_db()
+            } else {
+                cached_joint.save_to_db()
+            };
+            // the other joints of the batch are still saved
+            if let Err(e) = ret {
+                error!("save cached joint {} failed, err={}", cached_joint.key, e);
+            }
+        }
+        KV_STORE.commit_batch()
+    }
+}
+
+// the keys are backend agnostic, they are tested with kv_store_none as well
+#[cfg(test)]
+mod key_tests {
+    use super::*;
+    use joint::Level;
+
+    #[test]
+    fn test_mci_index_key() {
+        let key = mci_index_key(Level::from(42), "unit_hash");
+        assert_eq!(key, "mci_idx:0000000042:unit_hash");
+        assert_eq!(parse_mci_index_unit(key.as_bytes()).unwrap(), "unit_hash");
+        assert!(parse_mci_index_unit(b"stats:address").is_err());
+
+        // the keys are sorted by mci
+        let key_9 = mci_index_key(Level::from(9), "b");
+        assert!(key_9 < mci_index_key(Level::from(10), "a"));
+    }
 }
 
 #[cfg(all(test, not(feature = "kv_store_none")))]
@@ -329,6 +690,176 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn kv_store_feed_range_test() -> Result<()> {
+        use joint::Level;
+        let address = "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE";
+        let feed_name = "kv_store_feed_range_test";
+        for i in 1..6 {
+            KV_STORE.save_feed_value(address, feed_name, Level::from(i), &json!(i * 100))?;
+        }
+
+        let values = KV_STORE.read_feed_range(address, feed_name, 2.into(), 4.into())?;
+        assert_eq!(values.len(), 3);
+        for (i, (mci, value)) in values.into_iter().enumerate() {
+            assert_eq!(mci.value(), i + 2);
+            assert_eq!(value, json!((i + 2) * 100));
+        }
+
+        let values = KV_STORE.read_feed_range(address, feed_name, 6.into(), 10.into())?;
+        assert!(values.is_empty());
+
+        // the keys of a feed whose name extends the name are not scanned
+        let other_name = format!("{}:0000000003", feed_name);
+        KV_STORE.save_feed_value(address, &other_name, Level::from(1), &json!("other"))?;
+        let values = KV_STORE.read_feed_range(address, feed_name, 0.into(), 10.into())?;
+        assert_eq!(values.len(), 5);
+        assert!(values.iter().all(|(_, v)| *v != json!("other")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn kv_store_text_test() -> Result<()> {
+        KV_STORE.save_text("kv_text_unit_2", Level::from(2), "hello kvtext")?;
+        KV_STORE.save_text("kv_text_unit_1", Level::from(1), "Hello, kvtext!")?;
+        KV_STORE.save_text("kv_text_unit_3", Level::from(3), "other")?;
+
+        let units = |texts: Vec<(String, String)>| {
+            texts.into_iter().map(|(unit, _)| unit).collect::<Vec<_>>()
+        };
+        // sorted by mci and bounded by the limit
+        assert_eq!(
+            units(KV_STORE.search_texts(|t| t.contains("kvtex"), 10)?),
+            vec!["kv_text_unit_1", "kv_text_unit_2"]
+        );
+        assert_eq!(
+            KV_STORE.search_texts(|t| t.contains("kvtex"), 1)?,
+            vec![("kv_text_unit_1".to_owned(), "Hello, kvtext!".to_owned())]
+        );
+        assert!(KV_STORE
+            .search_texts(|t| t.contains("kvtex"), 0)?
+            .is_empty());
+        assert!(KV_STORE
+            .search_texts(|t| t.contains("unknown"), 10)?
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn kv_store_business_snapshot_test() -> Result<()> {
+        use business::BusinessStateExport;
+
+        let snapshot: BusinessStateExport = serde_json::from_value(json!({
+            "snapshot_mci": 10,
+            "utxo_count": 0,
+            "utxos": [],
+            "payload_commission_output": [],
+            "headers_commission_output": [],
+            "text": {"texts": []},
+            "data_feed": {"cur_time": 0, "feeds": []},
+            "address_stats": {},
+        }))?;
+        KV_STORE.save_business_snapshot(&snapshot)?;
+        let read = KV_STORE.read_business_snapshot()?.unwrap();
+        assert_eq!(read.snapshot_mci, snapshot.snapshot_mci);
+
+        KV_STORE.delete_business_snapshot()?;
+        assert!(KV_STORE.read_business_snapshot()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn kv_store_utxo_snapshot_test() -> Result<()> {
+        use business::UtxoCache;
+        use joint::Level;
+
+        let utxo = UtxoCache::default();
+        KV_STORE.save_utxo_snapshot(Level::new(2000), &utxo)?;
+        KV_STORE.save_utxo_snapshot(Level::new(1000), &utxo)?;
+        KV_STORE.save_utxo_snapshot(Level::new(2000), &utxo)?;
+
+        let mcis = KV_STORE.read_utxo_snapshot_mcis()?;
+        assert_eq!(mcis, vec![Level::new(1000), Level::new(2000)]);
+        assert!(KV_STORE.read_utxo_snapshot(Level::new(1000))?.is_some());
+        assert!(KV_STORE.read_utxo_snapshot(Level::new(1500))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn kv_store_peers_test() -> Result<()> {
+        let addr = "10.0.0.1:6615";
+        let record = |last_seen| PeerRecord {
+            last_seen,
+            reputation: -10,
+        };
+        KV_STORE.save_peer(addr, &record(100))?;
+        KV_STORE.save_peer(addr, &record(200))?;
+
+        let peers = KV_STORE.read_peers()?;
+        let found = peers.iter().filter(|(a, _)| a == addr).collect::<Vec<_>>();
+        assert_eq!(found, vec![&(addr.to_owned(), record(200))]);
+        assert_eq!(KV_STORE.read_peer(addr)?, Some(record(200)));
+
+        KV_STORE.delete_peer(addr)?;
+        assert!(KV_STORE.read_peers()?.iter().all(|(a, _)| a != addr));
+        assert_eq!(KV_STORE.read_peer(addr)?, None);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(feature = "kv_store_rocksdb", feature = "kv_store_lmdb"))]
+    fn kv_store_batch_test() -> Result<()> {
+        use spec::UnitBuilder;
+
+        // a unit that is not saved by the other tests
+        let joint = Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit: UnitBuilder::new().parent("kv_store_batch_test").build()?,
+        };
+        let key = joint.unit.unit.clone();
+
+        KV_STORE.begin_batch();
+        KV_STORE.save_joint(&key, &joint)?;
+        KV_STORE.save_joint_property(&key, &JointProperty::default())?;
+        // not visible until committed
+        assert!(KV_STORE.read_joint(&key).is_err());
+        assert!(KV_STORE.read_joint_property(&key).is_err());
+
+        KV_STORE.commit_batch()?;
+        assert_eq!(KV_STORE.read_joint(&key)?.unit.unit, key);
+        assert!(KV_STORE.read_joint_property(&key).is_ok());
+
+        // write directly without a batch
+        KV_STORE.delete_joint(&key)?;
+        KV_STORE.save_joint(&key, &joint)?;
+        assert!(KV_STORE.read_joint(&key).is_ok());
+
+        KV_STORE.delete_joint(&key)?;
+        KV_STORE.delete_joint_property(&key)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(
+        feature = "kv_store_rocksdb",
+        feature = "kv_store_lmdb",
+        feature = "kv_store_sqlite"
+    ))]
+    fn kv_store_checkpoint_marker_test() -> Result<()> {
+        KV_STORE.save_unstable_joints()?;
+        let marker = KV_STORE.iter_prefix(CHECKPOINT_COMPLETE_KEY.as_bytes())?;
+        assert_eq!(
+            marker,
+            vec![(
+                CHECKPOINT_COMPLETE_KEY.as_bytes().to_vec(),
+                serde_json::to_vec(&true)?
+            )]
+        );
+        Ok(())
+    }
+
     #[test]
     fn kv_store_update_joint_test() -> Result<()> {
         let mut joint: Joint = serde_json::from_str(JOINT)?;
@@ -2,15 +2,18 @@ extern crate crossbeam;
 extern crate rocksdb;
 
 use self::crossbeam::crossbeam_channel::Sender;
-use self::rocksdb::{ColumnFamilyDescriptor, IteratorMode, Options, DB};
+use self::rocksdb::{ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch, DB};
 
 use super::*;
+use business::{AddressStats, BusinessStateExport, BUSINESS_CACHE};
 use cache::{CachedJoint, SDAG_CACHE};
 use error::Result;
 use failure::ResultExt;
 use joint::{Joint, JointProperty, Level};
-use serde_json;
+use serde_json::{self, Value};
+use std::cell::RefCell;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 pub struct KvStore {
     pub joints: DB,
@@ -21,9 +24,37 @@ pub struct KvStore {
     _handlers: Vec<JoinHandle<()>>,
 }
 
+// the joint writes of the batch writer thread, see kv_store_common::create_batch_writer
+// a rocksdb batch can't cross the dbs, so each db has its own
+#[derive(Default)]
+struct PendingBatch {
+    joints: WriteBatch,
+    properties: WriteBatch,
+    children: WriteBatch,
+    misc: WriteBatch,
+}
+
+thread_local! {
+    static PENDING_BATCH: RefCell<Option<PendingBatch>> = RefCell::new(None);
+}
+
+// put the write into the pending batch of the current thread, return false if no batch
+fn write_to_batch<F>(f: F) -> Result<bool>
+where
+    F: FnOnce(&mut PendingBatch) -> ::std::result::Result<(), rocksdb::Error>,
+{
+    PENDING_BATCH.with(|batch| match *batch.borrow_mut() {
+        Some(ref mut batch) => {
+            f(batch)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    })
+}
+
 impl Default for KvStore {
     fn default() -> Self {
-        KvStore::load("./sdag_kv").expect("init KvStore failed")
+        KvStore::load(&::config::get_database_path()).expect("init KvStore failed")
     }
 }
 
@@ -51,7 +82,7 @@ impl KvStore {
         let misc =
             DB::open_default(format!("{}/misc", path)).context("Failed to init misc KvStore")?;
 
-        let (sender, handlers) = kv_store_common::create_thread_pool(8);
+        let (sender, handlers) = kv_store_common::create_batch_writer();
 
         Ok(KvStore {
             joints,
@@ -63,8 +94,8 @@ impl KvStore {
         })
     }
 
-    pub fn is_joint_exist(&self, _key: &str) -> Result<bool> {
-        Ok(false)
+    pub fn is_joint_exist(&self, key: &str) -> Result<bool> {
+        Ok(self.joints.get(key.as_bytes())?.is_some())
     }
 
     pub fn read_joint(&self, key: &str) -> Result<Joint> {
@@ -102,32 +133,125 @@ impl KvStore {
     }
 
     pub fn save_joint(&self, key: &str, joint: &Joint) -> Result<()> {
-        self.joints
-            .put(key.as_bytes(), &serde_json::to_vec(joint)?)?;
+        let value = serde_json::to_vec(joint)?;
+        if !write_to_batch(|b| b.joints.put(key.as_bytes(), &value))? {
+            self.joints.put(key.as_bytes(), &value)?;
+        }
         Ok(())
     }
 
     pub fn update_joint(&self, key: &str, joint: &Joint) -> Result<()> {
         if let Some(ball_cf) = self.joints.cf_handle("ball") {
-            self.joints.put_cf(
-                ball_cf,
-                key.as_bytes(),
-                &serde_json::to_vec(&(&joint.ball, &joint.skiplist_units))?,
-            )?;
+            let value = serde_json::to_vec(&(&joint.ball, &joint.skiplist_units))?;
+            if !write_to_batch(|b| b.joints.put_cf(ball_cf, key.as_bytes(), &value))? {
+                self.joints.put_cf(ball_cf, key.as_bytes(), &value)?;
+            }
         }
 
         Ok(())
     }
 
     pub fn save_joint_children(&self, key: &str, children: Vec<String>) -> Result<()> {
-        self.children
-            .put(key.as_bytes(), &serde_json::to_vec(&children)?)?;
+        let value = serde_json::to_vec(&children)?;
+        if !write_to_batch(|b| b.children.put(key.as_bytes(), &value))? {
+            self.children.put(key.as_bytes(), &value)?;
+        }
         Ok(())
     }
 
     pub fn save_joint_property(&self, key: &str, property: &JointProperty) -> Result<()> {
-        self.properties
-            .put(key.as_bytes(), &serde_json::to_vec(property)?)?;
+        let value = serde_json::to_vec(property)?;
+        if !write_to_batch(|b| b.properties.put(key.as_bytes(), &value))? {
+            self.properties.put(key.as_bytes(), &value)?;
+        }
+        if property.is_stable && property.mci.is_valid() {
+            self.save_mci_index(property.mci, key)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_mci_index(&self, mci: Level, unit: &str) -> Result<()> {
+        let key = mci_index_key(mci, unit);
+        if !write_to_batch(|b| b.misc.put(key.as_bytes(), &[]))? {
+            self.misc.put(key.as_bytes(), &[])?;
+        }
+        Ok(())
+    }
+
+    /// collect the following joint writes of the current thread until `commit_batch`
+    pub fn begin_batch(&self) {
+        PENDING_BATCH.with(|batch| *batch.borrow_mut() = Some(PendingBatch::default()));
+    }
+
+    /// write the collected joint writes, each db is updated atomically
+    pub fn commit_batch(&self) -> Result<()> {
+        if let Some(batch) = PENDING_BATCH.with(|batch| batch.borrow_mut().take()) {
+            // the properties are the last, a joint is complete once they are written
+            self.joints.write(batch.joints)?;
+            self.children.write(batch.children)?;
+            self.properties.write(batch.properties)?;
+            self.misc.write(batch.misc)?;
+        }
+        Ok(())
+    }
+
+    /// all the misc entries which key starts with the prefix, sorted by key
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        let mode = IteratorMode::From(prefix, Direction::Forward);
+        for (key, value) in self.misc.iterator(mode) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn get_db(&self, tree: &str) -> Result<&DB> {
+        match tree {
+            "joints" => Ok(&self.joints),
+            "properties" => Ok(&self.properties),
+            "children" => Ok(&self.children),
+            "misc" => Ok(&self.misc),
+            _ => bail!("unknown kv tree {}", tree),
+        }
+    }
+
+    /// call f with all the entries of all the trees
+    /// the ball and skiplist are merged into the joint, the same as the sled backend
+    pub fn for_each_entry<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &[u8], &[u8]) -> Result<()>,
+    {
+        for &tree in KV_TREES {
+            for (key, value) in self.get_db(tree)?.iterator(IteratorMode::Start) {
+                if tree == "joints" {
+                    let joint = self.read_joint(::std::str::from_utf8(&key)?)?;
+                    f(tree, &key, &serde_json::to_vec(&joint)?)?;
+                } else {
+                    f(tree, &key, &value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_entry(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_db(tree)?.get(key)?.map(|v| v.to_vec()))
+    }
+
+    pub fn put_entries(&self, entries: &[(String, Vec<u8>, Vec<u8>)]) -> Result<()> {
+        for (tree, key, value) in entries {
+            if tree == "joints" {
+                let joint: Joint = serde_json::from_slice(value)?;
+                let key = ::std::str::from_utf8(key)?;
+                self.save_joint(key, &joint)?;
+                self.update_joint(key, &joint)?;
+            } else {
+                self.get_db(tree)?.put(key, value)?;
+            }
+        }
         Ok(())
     }
 
@@ -135,6 +259,11 @@ impl KvStore {
         info!("Rebuild from KV start!");
         IS_REBUILDING_FROM_KV.store(true, Ordering::Release);
 
+        if !self.is_checkpoint_complete()? {
+            warn!("the unstable joints checkpoint is partial, replay the unstable joints");
+            self.clear_unstable_joints()?;
+        }
+
         let mut handle_joint_count = 0;
         for (_key, value) in self.joints.iterator(IteratorMode::Start) {
             let joint: Joint = serde_json::from_slice(&value)?;
@@ -151,14 +280,55 @@ impl KvStore {
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// save all the unstable joints in one batch, the checkpoint marker is set to false
+    /// before the batch and to true as the last write of the batch
     pub fn save_unstable_joints(&self) -> Result<()> {
         let joints = SDAG_CACHE.get_unstable_joints()?;
+        self.misc.put(
+            CHECKPOINT_COMPLETE_KEY.as_bytes(),
+            &serde_json::to_vec(&false)?,
+        )?;
+
+        self.begin_batch();
+        let saved: Result<()> = joints.iter().map(|joint| joint.save_to_db()).collect();
+        if let Err(e) = saved {
+            // drop the pending writes, the checkpoint stays partial
+            PENDING_BATCH.with(|batch| batch.borrow_mut().take());
+            return Err(e);
+        }
 
-        for joint in joints {
-            joint.save_to_db()?;
+        let value = serde_json::to_vec(&true)?;
+        write_to_batch(|b| b.misc.put(CHECKPOINT_COMPLETE_KEY.as_bytes(), &value))?;
+        self.commit_batch()
+    }
+
+    // a missing marker means no checkpoint is ever written
+    fn is_checkpoint_complete(&self) -> Result<bool> {
+        match self.misc.get(CHECKPOINT_COMPLETE_KEY.as_bytes())? {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(true),
+        }
+    }
+
+    // remove the unstable joints, they would be synced from the peers again
+    fn clear_unstable_joints(&self) -> Result<()> {
+        let mut unstable_keys = Vec::new();
+        for (key, _value) in self.joints.iterator(IteratorMode::Start) {
+            let key = ::std::str::from_utf8(&key)?.to_owned();
+            match self.read_joint_property(&key) {
+                Ok(ref property) if property.is_stable => {}
+                _ => unstable_keys.push(key),
+            }
         }
 
+        info!("clear {} unstable joints", unstable_keys.len());
+        for key in unstable_keys {
+            self.delete_joint(&key)?;
+            self.delete_joint_property(&key)?;
+            self.children.delete(key.as_bytes())?;
+        }
+
+        self.misc.delete(CHECKPOINT_COMPLETE_KEY.as_bytes())?;
         Ok(())
     }
 
@@ -188,6 +358,136 @@ impl KvStore {
         Ok(serde_json::from_slice(&v)?)
     }
 
+    pub fn save_feed_value(
+        &self,
+        address: &str,
+        feed_name: &str,
+        mci: Level,
+        value: &Value,
+    ) -> Result<()> {
+        let key = kv_store_common::feed_key(address, feed_name, mci);
+        self.misc.put(key.as_bytes(), &serde_json::to_vec(value)?)?;
+        Ok(())
+    }
+
+    pub fn read_feed_range(
+        &self,
+        address: &str,
+        feed_name: &str,
+        from_mci: Level,
+        to_mci: Level,
+    ) -> Result<Vec<(Level, Value)>> {
+        let prefix = kv_store_common::feed_prefix(address, feed_name);
+        let from_key = kv_store_common::feed_key(address, feed_name, from_mci);
+
+        let mut values = Vec::new();
+        let mode = IteratorMode::From(from_key.as_bytes(), Direction::Forward);
+        for (key, value) in self.misc.iterator(mode) {
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+
+            let mci = kv_store_common::parse_feed_mci(&key)?;
+            if mci > to_mci {
+                break;
+            }
+            values.push((mci, serde_json::from_slice(&value)?));
+        }
+
+        Ok(values)
+    }
+
+    pub fn save_text(&self, unit: &str, mci: Level, text: &str) -> Result<()> {
+        let key = kv_store_common::text_key(mci, unit);
+        self.misc.put(key.as_bytes(), &serde_json::to_vec(text)?)?;
+        Ok(())
+    }
+
+    /// the first `limit` <unit, text> that match, in the order of being stable
+    pub fn search_texts<F>(&self, is_match: F, limit: usize) -> Result<Vec<(String, String)>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let prefix = kv_store_common::TEXT_PREFIX.as_bytes();
+
+        let mut texts = Vec::new();
+        let mode = IteratorMode::From(prefix, Direction::Forward);
+        for (key, value) in self.misc.iterator(mode) {
+            if !key.starts_with(prefix) || texts.len() >= limit {
+                break;
+            }
+            let text: String = serde_json::from_slice(&value)?;
+            if is_match(&text) {
+                texts.push((kv_store_common::parse_text_unit(&key)?, text));
+            }
+        }
+
+        Ok(texts)
+    }
+
+    pub fn save_address_stats(&self, address: &str, stats: &AddressStats) -> Result<()> {
+        let key = kv_store_common::stats_key(address);
+        self.misc.put(key.as_bytes(), &serde_json::to_vec(stats)?)?;
+        Ok(())
+    }
+
+    pub fn read_address_stats(&self, address: &str) -> Result<Option<AddressStats>> {
+        let key = kv_store_common::stats_key(address);
+        match self.misc.get(key.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_peer(&self, addr: &str, record: &PeerRecord) -> Result<()> {
+        self.misc
+            .put(peer_key(addr).as_bytes(), &serde_json::to_vec(record)?)?;
+        Ok(())
+    }
+
+    /// all the known peers and their records
+    pub fn read_peers(&self) -> Result<Vec<(String, PeerRecord)>> {
+        let mut peers = Vec::new();
+        for (key, value) in self.iter_prefix(PEER_PREFIX.as_bytes())? {
+            let addr = ::std::str::from_utf8(&key[PEER_PREFIX.len()..])?.to_owned();
+            peers.push((addr, serde_json::from_slice(&value)?));
+        }
+        Ok(peers)
+    }
+
+    /// the record of the peer, a single lookup without reading all the peers
+    pub fn read_peer(&self, addr: &str) -> Result<Option<PeerRecord>> {
+        match self.get_entry("misc", peer_key(addr).as_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_peer(&self, addr: &str) -> Result<()> {
+        self.misc.delete(peer_key(addr).as_bytes())?;
+        Ok(())
+    }
+
+    pub fn save_business_snapshot(&self, snapshot: &BusinessStateExport) -> Result<()> {
+        self.misc.put(
+            BUSINESS_SNAPSHOT_KEY.as_bytes(),
+            &serde_json::to_vec(snapshot)?,
+        )?;
+        Ok(())
+    }
+
+    pub fn read_business_snapshot(&self) -> Result<Option<BusinessStateExport>> {
+        match self.misc.get(BUSINESS_SNAPSHOT_KEY.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_business_snapshot(&self) -> Result<()> {
+        self.misc.delete(BUSINESS_SNAPSHOT_KEY.as_bytes())?;
+        Ok(())
+    }
+
     pub fn save_cache_async(&self, data: CachedJoint) -> Result<()> {
         self.sender.send((data, false))?;
         Ok(())
@@ -198,7 +498,26 @@ impl KvStore {
         Ok(())
     }
 
+    /// write all the pending data to the disk
+    pub fn flush(&self) -> Result<()> {
+        self.joints.flush()?;
+        self.children.flush()?;
+        self.properties.flush()?;
+        self.misc.flush()?;
+        Ok(())
+    }
+
     pub fn finish(&self) -> Result<()> {
+        // the business state is restored from the snapshot on the next startup
+        match BUSINESS_CACHE.export_applied_state(Duration::from_secs(5)) {
+            Ok(snapshot) => self.save_business_snapshot(&snapshot)?,
+            Err(e) => warn!("skip saving business snapshot, err={}", e),
+        }
+        // a failed checkpoint leaves the marker false, they are replayed after restart
+        if let Err(e) = self.save_unstable_joints() {
+            warn!("failed to save unstable joints, err={}", e);
+        }
+        self.flush()?;
         info!("kv store finished");
 
         Ok(())
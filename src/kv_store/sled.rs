@@ -5,13 +5,15 @@ use self::crossbeam::crossbeam_channel::Sender;
 use self::sled::{Db, Tree};
 
 use super::*;
+use business::{AddressStats, BusinessStateExport, BUSINESS_CACHE};
 use cache::{CachedJoint, SDAG_CACHE};
 use error::Result;
 use failure::ResultExt;
 use joint::{Joint, JointProperty, Level};
-use serde_json;
+use serde_json::{self, Value};
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 pub struct KvStore {
     pub joints: Arc<Tree>,
@@ -24,7 +26,7 @@ pub struct KvStore {
 
 impl Default for KvStore {
     fn default() -> Self {
-        KvStore::load("./sdag_kv").expect("init KvStore failed")
+        KvStore::load(&::config::get_database_path()).expect("init KvStore failed")
     }
 }
 
@@ -56,8 +58,8 @@ impl KvStore {
         })
     }
 
-    pub fn is_joint_exist(&self, _key: &str) -> Result<bool> {
-        Ok(false)
+    pub fn is_joint_exist(&self, key: &str) -> Result<bool> {
+        Ok(self.joints.get(key)?.is_some())
     }
 
     pub fn read_joint(&self, key: &str) -> Result<Joint> {
@@ -101,6 +103,62 @@ impl KvStore {
 
     pub fn save_joint_property(&self, key: &str, property: &JointProperty) -> Result<()> {
         self.properties.set(key, serde_json::to_vec(property)?)?;
+        if property.is_stable && property.mci.is_valid() {
+            self.save_mci_index(property.mci, key)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_mci_index(&self, mci: Level, unit: &str) -> Result<()> {
+        self.misc.set(mci_index_key(mci, unit), Vec::new())?;
+        Ok(())
+    }
+
+    /// all the misc entries which key starts with the prefix, sorted by key
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        for item in self.misc.scan(prefix) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(entries)
+    }
+
+    fn get_tree(&self, tree: &str) -> Result<&Tree> {
+        match tree {
+            "joints" => Ok(&self.joints),
+            "properties" => Ok(&self.properties),
+            "children" => Ok(&self.children),
+            "misc" => Ok(&self.misc),
+            _ => bail!("unknown kv tree {}", tree),
+        }
+    }
+
+    /// call f with all the entries of all the trees
+    pub fn for_each_entry<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &[u8], &[u8]) -> Result<()>,
+    {
+        for &tree in KV_TREES {
+            for item in self.get_tree(tree)?.iter() {
+                let (key, value) = item?;
+                f(tree, &key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_entry(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_tree(tree)?.get(key)?.map(|v| v.to_vec()))
+    }
+
+    pub fn put_entries(&self, entries: &[(String, Vec<u8>, Vec<u8>)]) -> Result<()> {
+        for (tree, key, value) in entries {
+            self.get_tree(tree)?.set(key.as_slice(), value.clone())?;
+        }
         Ok(())
     }
 
@@ -125,7 +183,6 @@ impl KvStore {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn save_unstable_joints(&self) -> Result<()> {
         let joints = SDAG_CACHE.get_unstable_joints()?;
 
@@ -162,6 +219,133 @@ impl KvStore {
         Ok(serde_json::from_slice(&v)?)
     }
 
+    pub fn save_feed_value(
+        &self,
+        address: &str,
+        feed_name: &str,
+        mci: Level,
+        value: &Value,
+    ) -> Result<()> {
+        let key = kv_store_common::feed_key(address, feed_name, mci);
+        self.misc.set(key, serde_json::to_vec(value)?)?;
+        Ok(())
+    }
+
+    pub fn read_feed_range(
+        &self,
+        address: &str,
+        feed_name: &str,
+        from_mci: Level,
+        to_mci: Level,
+    ) -> Result<Vec<(Level, Value)>> {
+        let prefix = kv_store_common::feed_prefix(address, feed_name);
+        let from_key = kv_store_common::feed_key(address, feed_name, from_mci);
+
+        let mut values = Vec::new();
+        for item in self.misc.scan(from_key.as_bytes()) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+
+            let mci = kv_store_common::parse_feed_mci(&key)?;
+            if mci > to_mci {
+                break;
+            }
+            values.push((mci, serde_json::from_slice(&value)?));
+        }
+
+        Ok(values)
+    }
+
+    pub fn save_text(&self, unit: &str, mci: Level, text: &str) -> Result<()> {
+        let key = kv_store_common::text_key(mci, unit);
+        self.misc.set(key, serde_json::to_vec(text)?)?;
+        Ok(())
+    }
+
+    /// the first `limit` <unit, text> that match, in the order of being stable
+    pub fn search_texts<F>(&self, is_match: F, limit: usize) -> Result<Vec<(String, String)>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let prefix = kv_store_common::TEXT_PREFIX.as_bytes();
+
+        let mut texts = Vec::new();
+        for item in self.misc.scan(prefix) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) || texts.len() >= limit {
+                break;
+            }
+            let text: String = serde_json::from_slice(&value)?;
+            if is_match(&text) {
+                texts.push((kv_store_common::parse_text_unit(&key)?, text));
+            }
+        }
+
+        Ok(texts)
+    }
+
+    pub fn save_address_stats(&self, address: &str, stats: &AddressStats) -> Result<()> {
+        let key = kv_store_common::stats_key(address);
+        self.misc.set(key, serde_json::to_vec(stats)?)?;
+        Ok(())
+    }
+
+    pub fn read_address_stats(&self, address: &str) -> Result<Option<AddressStats>> {
+        let key = kv_store_common::stats_key(address);
+        match self.misc.get(key.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_peer(&self, addr: &str, record: &PeerRecord) -> Result<()> {
+        self.misc.set(peer_key(addr), serde_json::to_vec(record)?)?;
+        Ok(())
+    }
+
+    /// all the known peers and their records
+    pub fn read_peers(&self) -> Result<Vec<(String, PeerRecord)>> {
+        let mut peers = Vec::new();
+        for (key, value) in self.iter_prefix(PEER_PREFIX.as_bytes())? {
+            let addr = ::std::str::from_utf8(&key[PEER_PREFIX.len()..])?.to_owned();
+            peers.push((addr, serde_json::from_slice(&value)?));
+        }
+        Ok(peers)
+    }
+
+    /// the record of the peer, a single lookup without reading all the peers
+    pub fn read_peer(&self, addr: &str) -> Result<Option<PeerRecord>> {
+        match self.get_entry("misc", peer_key(addr).as_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_peer(&self, addr: &str) -> Result<()> {
+        self.misc.del(peer_key(addr))?;
+        Ok(())
+    }
+
+    pub fn save_business_snapshot(&self, snapshot: &BusinessStateExport) -> Result<()> {
+        self.misc
+            .set(BUSINESS_SNAPSHOT_KEY, serde_json::to_vec(snapshot)?)?;
+        Ok(())
+    }
+
+    pub fn read_business_snapshot(&self) -> Result<Option<BusinessStateExport>> {
+        match self.misc.get(BUSINESS_SNAPSHOT_KEY.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_business_snapshot(&self) -> Result<()> {
+        self.misc.del(BUSINESS_SNAPSHOT_KEY)?;
+        Ok(())
+    }
+
     pub fn save_cache_async(&self, data: CachedJoint) -> Result<()> {
         self.sender.send((data, false))?;
         Ok(())
@@ -172,11 +356,26 @@ impl KvStore {
         Ok(())
     }
 
-    pub fn finish(&self) -> Result<()> {
+    /// write all the pending data to the disk
+    pub fn flush(&self) -> Result<()> {
         self.joints.flush()?;
         self.children.flush()?;
         self.properties.flush()?;
         self.misc.flush()?;
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        // the business state is restored from the snapshot on the next startup
+        match BUSINESS_CACHE.export_applied_state(Duration::from_secs(5)) {
+            Ok(snapshot) => self.save_business_snapshot(&snapshot)?,
+            Err(e) => warn!("skip saving business snapshot, err={}", e),
+        }
+        // the unstable joints are kept, so they are not synced again after restart
+        if let Err(e) = self.save_unstable_joints() {
+            warn!("failed to save unstable joints, err={}", e);
+        }
+        self.flush()?;
 
         info!("kv store finished");
 
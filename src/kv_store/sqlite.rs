@@ -0,0 +1,568 @@
+extern crate crossbeam;
+extern crate rusqlite;
+
+use self::crossbeam::crossbeam_channel::Sender;
+use self::rusqlite::types::{ToSql, Value as SqlValue};
+use self::rusqlite::{Connection, OptionalExtension};
+
+use super::*;
+use business::{AddressStats, BusinessStateExport, BUSINESS_CACHE};
+use cache::{CachedJoint, SDAG_CACHE};
+use error::Result;
+use failure::ResultExt;
+use joint::{Joint, JointProperty, Level};
+use may::sync::Mutex;
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// the joints are loaded page by page when rebuilding, don't lock the db for too long
+const REBUILD_PAGE_SIZE: i64 = 1000;
+
+thread_local! {
+    // the writes of save_unstable_joints, they are committed in one transaction
+    static PENDING_BATCH: RefCell<Option<Vec<(String, String, Vec<u8>)>>> = RefCell::new(None);
+}
+
+pub struct KvStore {
+    conn: Mutex<Connection>,
+    sender: Sender<(CachedJoint, bool)>,
+    _handlers: Vec<JoinHandle<()>>,
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        KvStore::load(&::config::get_database_path()).expect("init KvStore failed")
+    }
+}
+
+// the kv tree is saved in the table with the same columns
+fn get_table(tree: &str) -> Result<&'static str> {
+    match tree {
+        "joints" => Ok("joints"),
+        "properties" => Ok("joint_properties"),
+        "children" => Ok("joint_children"),
+        "misc" => Ok("misc"),
+        _ => bail!("unknown kv tree {}", tree),
+    }
+}
+
+// the json values are saved as text so that they are readable in sql
+fn to_sql_value(value: &[u8]) -> SqlValue {
+    match ::std::str::from_utf8(value) {
+        Ok(s) => SqlValue::Text(s.to_owned()),
+        Err(_) => SqlValue::Blob(value.to_vec()),
+    }
+}
+
+fn from_sql_value(value: SqlValue) -> Result<Vec<u8>> {
+    match value {
+        SqlValue::Text(s) => Ok(s.into_bytes()),
+        SqlValue::Blob(v) => Ok(v),
+        SqlValue::Null => Ok(Vec::new()),
+        v => bail!("invalid kv value {:?}", v),
+    }
+}
+
+impl KvStore {
+    pub fn load(path: &str) -> Result<Self> {
+        ::std::fs::create_dir_all(path).context("Failed to create dir for KvStore")?;
+        let conn = Connection::open(format!("{}/sdag.sqlite", path))
+            .context("Failed to read file for KvStore")?;
+        // external tools could read the db while the hub is writing
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS joints (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS joint_properties (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS joint_children (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS misc (key TEXT PRIMARY KEY, value TEXT NOT NULL);",
+        )
+        .context("Failed to init tables of KvStore")?;
+
+        let (sender, handlers) = kv_store_common::create_thread_pool(8);
+
+        Ok(KvStore {
+            conn: Mutex::new(conn),
+            sender,
+            _handlers: handlers,
+        })
+    }
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let sql = format!("SELECT value FROM {} WHERE key = ?1", table);
+        let conn = self.conn.lock().unwrap();
+        let value: Option<SqlValue> = conn
+            .query_row(&sql, &[&key as &ToSql], |row| row.get(0))
+            .optional()?;
+        match value {
+            Some(v) => Ok(Some(from_sql_value(v)?)),
+            None => Ok(None),
+        }
+    }
+
+    // the write goes into the pending batch of the current thread if any
+    fn put(&self, table: &str, key: &str, value: &[u8]) -> Result<()> {
+        let is_batched = PENDING_BATCH.with(|batch| match *batch.borrow_mut() {
+            Some(ref mut batch) => {
+                batch.push((table.to_owned(), key.to_owned(), value.to_vec()));
+                true
+            }
+            None => false,
+        });
+        if is_batched {
+            return Ok(());
+        }
+
+        let sql = format!(
+            "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+            table
+        );
+        let value = to_sql_value(value);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(&sql, &[&key as &ToSql, &value])?;
+        Ok(())
+    }
+
+    // collect the following writes of the current thread until `commit_batch`
+    fn begin_batch(&self) {
+        PENDING_BATCH.with(|batch| *batch.borrow_mut() = Some(Vec::new()));
+    }
+
+    // write the collected writes in one transaction
+    fn commit_batch(&self) -> Result<()> {
+        let batch = match PENDING_BATCH.with(|batch| batch.borrow_mut().take()) {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for (table, key, value) in batch {
+            let sql = format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                table
+            );
+            tx.execute(&sql, &[&key as &ToSql, &to_sql_value(&value)])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<()> {
+        let sql = format!("DELETE FROM {} WHERE key = ?1", table);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(&sql, &[&key as &ToSql])?;
+        Ok(())
+    }
+
+    // all the entries from the start key while the key starts with the prefix
+    fn scan(&self, table: &str, start: &str, prefix: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        self.scan_while(table, start, prefix, |key, value| {
+            entries.push((key.to_vec(), value.to_vec()));
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    // call f with the entries from start that have the prefix, until f returns false
+    fn scan_while<F>(&self, table: &str, start: &str, prefix: &str, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        let sql = format!(
+            "SELECT key, value FROM {} WHERE key >= ?1 ORDER BY key",
+            table
+        );
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(&[&start as &ToSql], |row| {
+            (row.get::<_, String>(0), row.get::<_, SqlValue>(1))
+        })?;
+
+        for row in rows {
+            let (key, value) = row?;
+            if !key.starts_with(prefix) || !f(key.as_bytes(), &from_sql_value(value)?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_joint_exist(&self, key: &str) -> Result<bool> {
+        Ok(self.get("joints", key)?.is_some())
+    }
+
+    pub fn read_joint(&self, key: &str) -> Result<Joint> {
+        if let Some(value) = self.get("joints", key)? {
+            return Ok(serde_json::from_slice(&value)?);
+        }
+
+        bail!("joint {} not exist in KV", key)
+    }
+
+    pub fn read_joint_children(&self, key: &str) -> Result<Vec<String>> {
+        if let Some(value) = self.get("joint_children", key)? {
+            return Ok(serde_json::from_slice(&value)?);
+        }
+
+        bail!("joint children {} not exist in KV", key)
+    }
+
+    pub fn read_joint_property(&self, key: &str) -> Result<JointProperty> {
+        if let Some(value) = self.get("joint_properties", key)? {
+            return Ok(serde_json::from_slice(&value)?);
+        }
+
+        bail!("joint property {} not exist in KV", key)
+    }
+
+    pub fn save_joint(&self, key: &str, joint: &Joint) -> Result<()> {
+        self.put("joints", key, &serde_json::to_vec(joint)?)
+    }
+
+    pub fn update_joint(&self, key: &str, joint: &Joint) -> Result<()> {
+        self.put("joints", key, &serde_json::to_vec(joint)?)
+    }
+
+    pub fn save_joint_children(&self, key: &str, children: Vec<String>) -> Result<()> {
+        self.put("joint_children", key, &serde_json::to_vec(&children)?)
+    }
+
+    pub fn save_joint_property(&self, key: &str, property: &JointProperty) -> Result<()> {
+        self.put("joint_properties", key, &serde_json::to_vec(property)?)?;
+        if property.is_stable && property.mci.is_valid() {
+            self.save_mci_index(property.mci, key)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_mci_index(&self, mci: Level, unit: &str) -> Result<()> {
+        self.put("misc", &mci_index_key(mci, unit), &[])
+    }
+
+    /// all the misc entries which key starts with the prefix, sorted by key
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = ::std::str::from_utf8(prefix)?;
+        self.scan("misc", prefix, prefix)
+    }
+
+    /// call f with all the entries of all the trees
+    pub fn for_each_entry<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &[u8], &[u8]) -> Result<()>,
+    {
+        for &tree in KV_TREES {
+            for (key, value) in self.scan(get_table(tree)?, "", "")? {
+                f(tree, &key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_entry(&self, tree: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get(get_table(tree)?, ::std::str::from_utf8(key)?)
+    }
+
+    pub fn put_entries(&self, entries: &[(String, Vec<u8>, Vec<u8>)]) -> Result<()> {
+        // all the entries are written in one transaction
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for (tree, key, value) in entries {
+            let sql = format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                get_table(tree)?
+            );
+            let key = ::std::str::from_utf8(key)?;
+            tx.execute(&sql, &[&key as &ToSql, &to_sql_value(value)])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // the joints of the page after the rowid, in the saved order
+    fn read_joints_page(&self, after_rowid: i64) -> Result<Vec<(i64, Vec<u8>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT rowid, value FROM joints WHERE rowid > ?1 ORDER BY rowid LIMIT ?2")?;
+        let rows = stmt.query_map(&[&after_rowid as &ToSql, &REBUILD_PAGE_SIZE], |row| {
+            (row.get::<_, i64>(0), row.get::<_, SqlValue>(1))
+        })?;
+
+        let mut joints = Vec::new();
+        for row in rows {
+            let (rowid, value) = row?;
+            joints.push((rowid, from_sql_value(value)?));
+        }
+        Ok(joints)
+    }
+
+    pub fn rebuild_from_kv(&self) -> Result<()> {
+        info!("Rebuild from KV start!");
+        IS_REBUILDING_FROM_KV.store(true, Ordering::Release);
+
+        if !self.is_checkpoint_complete()? {
+            warn!("the unstable joints checkpoint is partial, replay the unstable joints");
+            self.clear_unstable_joints()?;
+        }
+
+        let mut handle_joint_count = 0;
+        let mut last_rowid = 0;
+        loop {
+            let joints = self.read_joints_page(last_rowid)?;
+            if joints.is_empty() {
+                break;
+            }
+
+            for (rowid, value) in joints {
+                let joint: Joint = serde_json::from_slice(&value)?;
+                kv_store_common::handle_kv_joint(joint)?;
+                handle_joint_count += 1;
+                last_rowid = rowid;
+            }
+        }
+        ::utils::wait_cond(None, || {
+            handle_joint_count == SDAG_CACHE.get_num_of_normal_joints()
+        })?;
+
+        info!("Rebuild from KV done!");
+        IS_REBUILDING_FROM_KV.store(false, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// save all the unstable joints in one transaction, the checkpoint marker is set to
+    /// false before the transaction and to true as the last write of it
+    pub fn save_unstable_joints(&self) -> Result<()> {
+        let joints = SDAG_CACHE.get_unstable_joints()?;
+        self.put(
+            "misc",
+            CHECKPOINT_COMPLETE_KEY,
+            &serde_json::to_vec(&false)?,
+        )?;
+
+        let complete = serde_json::to_vec(&true)?;
+        self.begin_batch();
+        let saved: Result<()> = joints.iter().map(|joint| joint.save_to_db()).collect();
+        if let Err(e) = saved {
+            // drop the pending writes, the checkpoint stays partial
+            PENDING_BATCH.with(|batch| batch.borrow_mut().take());
+            return Err(e);
+        }
+
+        self.put("misc", CHECKPOINT_COMPLETE_KEY, &complete)?;
+        self.commit_batch()
+    }
+
+    // a missing marker means no checkpoint is ever written
+    fn is_checkpoint_complete(&self) -> Result<bool> {
+        match self.get("misc", CHECKPOINT_COMPLETE_KEY)? {
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+            None => Ok(true),
+        }
+    }
+
+    // remove the unstable joints, they would be synced from the peers again
+    fn clear_unstable_joints(&self) -> Result<()> {
+        // the properties can't be read while the scan holds the connection
+        let mut keys = Vec::new();
+        self.scan_while("joints", "", "", |key, _value| {
+            keys.push(::std::str::from_utf8(key)?.to_owned());
+            Ok(true)
+        })?;
+
+        let mut unstable_keys = Vec::new();
+        for key in keys {
+            match self.read_joint_property(&key) {
+                Ok(ref property) if property.is_stable => {}
+                _ => unstable_keys.push(key),
+            }
+        }
+
+        info!("clear {} unstable joints", unstable_keys.len());
+        for key in unstable_keys {
+            self.delete_joint(&key)?;
+            self.delete_joint_property(&key)?;
+            self.del("joint_children", &key)?;
+        }
+
+        self.del("misc", CHECKPOINT_COMPLETE_KEY)
+    }
+
+    pub fn delete_joint(&self, key: &str) -> Result<()> {
+        self.del("joints", key)
+    }
+
+    pub fn delete_joint_property(&self, key: &str) -> Result<()> {
+        self.del("joint_properties", key)
+    }
+
+    #[allow(dead_code)]
+    pub fn save_last_mci(&self, mci: Level) -> Result<()> {
+        self.put("misc", "last_mci", &serde_json::to_vec(&mci)?)
+    }
+
+    #[allow(dead_code)]
+    fn read_last_mci(&self) -> Result<Level> {
+        let v = self
+            .get("misc", "last_mci")?
+            .ok_or_else(|| format_err!("read last mci from kv failed"))?;
+
+        Ok(serde_json::from_slice(&v)?)
+    }
+
+    pub fn save_feed_value(
+        &self,
+        address: &str,
+        feed_name: &str,
+        mci: Level,
+        value: &Value,
+    ) -> Result<()> {
+        let key = kv_store_common::feed_key(address, feed_name, mci);
+        self.put("misc", &key, &serde_json::to_vec(value)?)
+    }
+
+    pub fn read_feed_range(
+        &self,
+        address: &str,
+        feed_name: &str,
+        from_mci: Level,
+        to_mci: Level,
+    ) -> Result<Vec<(Level, Value)>> {
+        let prefix = kv_store_common::feed_prefix(address, feed_name);
+        let from_key = kv_store_common::feed_key(address, feed_name, from_mci);
+
+        let mut values = Vec::new();
+        for (key, value) in self.scan("misc", &from_key, &prefix)? {
+            let mci = kv_store_common::parse_feed_mci(&key)?;
+            if mci > to_mci {
+                break;
+            }
+            values.push((mci, serde_json::from_slice(&value)?));
+        }
+
+        Ok(values)
+    }
+
+    pub fn save_text(&self, unit: &str, mci: Level, text: &str) -> Result<()> {
+        let key = kv_store_common::text_key(mci, unit);
+        self.put("misc", &key, &serde_json::to_vec(text)?)
+    }
+
+    /// the first `limit` <unit, text> that match, in the order of being stable
+    pub fn search_texts<F>(&self, is_match: F, limit: usize) -> Result<Vec<(String, String)>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut texts = Vec::new();
+        if limit == 0 {
+            return Ok(texts);
+        }
+
+        let prefix = kv_store_common::TEXT_PREFIX;
+        self.scan_while("misc", prefix, prefix, |key, value| {
+            let text: String = serde_json::from_slice(value)?;
+            if is_match(&text) {
+                texts.push((kv_store_common::parse_text_unit(key)?, text));
+            }
+            Ok(texts.len() < limit)
+        })?;
+        Ok(texts)
+    }
+
+    pub fn save_address_stats(&self, address: &str, stats: &AddressStats) -> Result<()> {
+        let key = kv_store_common::stats_key(address);
+        self.put("misc", &key, &serde_json::to_vec(stats)?)
+    }
+
+    pub fn read_address_stats(&self, address: &str) -> Result<Option<AddressStats>> {
+        let key = kv_store_common::stats_key(address);
+        match self.get("misc", &key)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_peer(&self, addr: &str, record: &PeerRecord) -> Result<()> {
+        self.put("misc", &peer_key(addr), &serde_json::to_vec(record)?)
+    }
+
+    /// all the known peers and their records
+    pub fn read_peers(&self) -> Result<Vec<(String, PeerRecord)>> {
+        let mut peers = Vec::new();
+        for (key, value) in self.iter_prefix(PEER_PREFIX.as_bytes())? {
+            let addr = ::std::str::from_utf8(&key[PEER_PREFIX.len()..])?.to_owned();
+            peers.push((addr, serde_json::from_slice(&value)?));
+        }
+        Ok(peers)
+    }
+
+    /// the record of the peer, a single lookup without reading all the peers
+    pub fn read_peer(&self, addr: &str) -> Result<Option<PeerRecord>> {
+        match self.get_entry("misc", peer_key(addr).as_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_peer(&self, addr: &str) -> Result<()> {
+        self.del("misc", &peer_key(addr))
+    }
+
+    pub fn save_business_snapshot(&self, snapshot: &BusinessStateExport) -> Result<()> {
+        self.put(
+            "misc",
+            BUSINESS_SNAPSHOT_KEY,
+            &serde_json::to_vec(snapshot)?,
+        )
+    }
+
+    pub fn read_business_snapshot(&self) -> Result<Option<BusinessStateExport>> {
+        match self.get("misc", BUSINESS_SNAPSHOT_KEY)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_business_snapshot(&self) -> Result<()> {
+        self.del("misc", BUSINESS_SNAPSHOT_KEY)
+    }
+
+    pub fn save_cache_async(&self, data: CachedJoint) -> Result<()> {
+        self.sender.send((data, false))?;
+        Ok(())
+    }
+
+    pub fn update_cache_async(&self, data: CachedJoint) -> Result<()> {
+        self.sender.send((data, true))?;
+        Ok(())
+    }
+
+    /// write all the pending data to the disk
+    pub fn flush(&self) -> Result<()> {
+        // move all the wal content into the db file
+        self.conn
+            .lock()
+            .unwrap()
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        // the business state is restored from the snapshot on the next startup
+        match BUSINESS_CACHE.export_applied_state(Duration::from_secs(5)) {
+            Ok(snapshot) => self.save_business_snapshot(&snapshot)?,
+            Err(e) => warn!("skip saving business snapshot, err={}", e),
+        }
+        // a failed checkpoint leaves the marker false, they are replayed after restart
+        if let Err(e) = self.save_unstable_joints() {
+            warn!("failed to save unstable joints, err={}", e);
+        }
+        self.flush()?;
+
+        info!("kv store finished");
+
+        Ok(())
+    }
+}
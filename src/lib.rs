@@ -11,13 +11,17 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
+extern crate flate2;
 extern crate hashbrown;
 extern crate may_waiter;
+extern crate net2;
+extern crate notify;
 extern crate rcu_cell;
 extern crate sdag_object_base;
 extern crate sdag_wallet_base;
 extern crate serde;
 extern crate smallvec;
+extern crate tracing;
 extern crate tungstenite;
 extern crate url;
 
@@ -106,6 +110,8 @@ pub mod paid_witnessing;
 pub mod signature;
 pub mod spec;
 pub mod statistics;
+#[cfg(feature = "test_utils")]
+pub mod test_utils;
 pub mod time;
 pub mod validation;
 pub mod wallet_info;
@@ -1,8 +1,24 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
 use error::Result;
 
 use business::BUSINESS_CACHE;
-use cache::SDAG_CACHE;
-use spec::{Input, Payload, Unit};
+use cache::{CachedJoint, SDAG_CACHE};
+use composer;
+use config;
+use finalization::get_similar_mcis;
+use hashbrown::{HashMap, HashSet};
+use joint::{JointSequence, Level};
+use may::coroutine;
+use may::sync::RwLock;
+use network::wallet::{create_outbound_conn, WalletConn};
+use sdag_object_base::object_hash;
+use sdag_wallet_base::Base64KeyExt;
+use serde_json::{self, Value};
+use spec::{Input, Message, Output, Payload, Unit};
+use wallet_info::WalletInfo;
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct LightProps {
@@ -29,6 +45,17 @@ pub struct HistoryRequest {
     pub num: usize,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct FullHistoryRequest {
+    pub address: String,
+    // the number of the newest transactions to skip
+    #[serde(default)]
+    pub offset: usize,
+    // default to config::MAX_HISTORY_TRANSACTIONS
+    #[serde(default)]
+    pub num: usize,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Text {
     pub from_addr: Vec<String>,
@@ -51,6 +78,186 @@ pub struct HistoryResponse {
     pub transactions: Vec<TransactionInfo>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxStatus {
+    Pending,
+    /// the joint conflicts with others for now and may still become good
+    TempBad,
+    Stable,
+}
+
+/// the hub reply of post_joint, the joint is saved and relayed in both cases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostJointStatus {
+    Accepted,
+    /// the joint conflicts with others for now and may still become good
+    TempBad,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FullHistoryEntry {
+    pub unit_hash: String,
+    pub from_addr: String,
+    pub to_addr: String,
+    pub amount: i64,
+    pub time: Option<u64>,
+    pub status: TxStatus,
+}
+
+impl FullHistoryEntry {
+    fn new(tx: TransactionInfo, status: TxStatus) -> Self {
+        FullHistoryEntry {
+            unit_hash: tx.unit_hash,
+            from_addr: tx.from_addr,
+            to_addr: tx.to_addr,
+            amount: tx.amount,
+            time: tx.time,
+            status,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FullHistoryResponse {
+    // the pending transactions come first, both are the newest first
+    pub transactions: Vec<FullHistoryEntry>,
+    // the offset of the next page, none if there are no more transactions
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UtxoEntry {
+    pub unit: String,
+    pub amount: u64,
+    pub mci: Option<u32>,
+    pub is_stable: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BalanceDetails {
+    // sum of the stable utxos
+    pub balance: u64,
+    pub stable_utxos: Vec<UtxoEntry>,
+    pub pending_utxos: Vec<UtxoEntry>,
+}
+
+/// a stable unit with the fields to calculate its ball
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkProofStep {
+    pub unit: String,
+    pub mci: Level,
+    pub ball: String,
+    pub parent_balls: Vec<String>,
+    pub skiplist_balls: Vec<String>,
+    pub is_nonserial: bool,
+}
+
+impl LinkProofStep {
+    /// the balls are sorted the same as the ball calculation
+    pub fn new(
+        unit: String,
+        mci: Level,
+        mut parent_balls: Vec<String>,
+        mut skiplist_balls: Vec<String>,
+        is_nonserial: bool,
+    ) -> Self {
+        parent_balls.sort();
+        skiplist_balls.sort();
+        let ball = object_hash::calc_ball_hash(&unit, &parent_balls, &skiplist_balls, is_nonserial);
+        LinkProofStep {
+            unit,
+            mci,
+            ball,
+            parent_balls,
+            skiplist_balls,
+            is_nonserial,
+        }
+    }
+
+    fn is_ball_valid(&self) -> bool {
+        self.ball
+            == object_hash::calc_ball_hash(
+                &self.unit,
+                &self.parent_balls,
+                &self.skiplist_balls,
+                self.is_nonserial,
+            )
+    }
+
+    // if the ball is included by this one
+    fn includes(&self, ball: &str) -> bool {
+        self.parent_balls.iter().any(|b| b == ball) || self.skiplist_balls.iter().any(|b| b == ball)
+    }
+}
+
+/// the ball chain from the stable unit to the main chain unit of its mci
+/// each ball is included by the next one as a parent ball
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkProof {
+    pub unit: String,
+    pub steps: Vec<LinkProofStep>,
+}
+
+impl LinkProof {
+    /// verify the balls of the steps and that each ball is included by the next one
+    /// return the last step
+    pub fn verify(&self) -> Result<&LinkProofStep> {
+        ensure!(
+            self.steps.len() <= config::MAX_LINK_PROOF_STEPS,
+            "link proof of unit {} is too long",
+            self.unit
+        );
+        let last = match self.steps.last() {
+            Some(step) => step,
+            None => bail!("empty link proof for unit {}", self.unit),
+        };
+        ensure!(
+            self.steps[0].unit == self.unit,
+            "link proof starts from unit {}, expect {}",
+            self.steps[0].unit,
+            self.unit
+        );
+
+        for step in &self.steps {
+            ensure!(step.is_ball_valid(), "wrong ball of unit {}", step.unit);
+        }
+        for pair in self.steps.windows(2) {
+            ensure!(
+                pair[1].includes(&pair[0].ball),
+                "ball of unit {} is not included by unit {}",
+                pair[0].unit,
+                pair[1].unit
+            );
+        }
+        Ok(last)
+    }
+}
+
+/// the proof that the unit is stable at its mci, the link proof of the unit ends on the
+/// main chain proof, which goes from the genesis to the reference mci by the best parents
+/// and the skiplist units. the mcis are counted from the genesis by the skiplist rules
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JointProof {
+    pub unit: String,
+    pub reference_mci: Level,
+    pub link_proof: LinkProof,
+    pub main_chain_proof: LinkProof,
+}
+
+impl JointProof {
+    /// the ball of the reference mci, the client should compare it with a trusted one
+    pub fn get_reference_ball(&self) -> Option<&str> {
+        self.main_chain_proof.steps.last().map(|s| s.ball.as_str())
+    }
+
+    /// the mci of the unit, only trusted after the proof is verified
+    pub fn get_mci(&self) -> Option<Level> {
+        self.link_proof.steps.last().map(|s| s.mci)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct InputsRequest {
     pub paid_address: String,
@@ -82,6 +289,41 @@ pub fn get_inputs_for_amount(input_request: InputsRequest) -> Result<InputsRespo
     Ok(InputsResponse { inputs, amount })
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct EstimateFeesRequest {
+    pub outputs: Vec<Output>,
+    pub inputs: Vec<Input>,
+    pub text_message: Option<Message>,
+    // none if the definition is already known by the hub
+    pub definition: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EstimateFeesResponse {
+    pub headers_commission: u32,
+    pub payload_commission: u32,
+}
+
+pub fn estimate_fees(request: &EstimateFeesRequest) -> EstimateFeesResponse {
+    let (headers_commission, payload_commission) = composer::estimate_fees(
+        &request.outputs,
+        &request.inputs,
+        request.text_message.as_ref(),
+        request.definition.as_ref(),
+    );
+
+    EstimateFeesResponse {
+        headers_commission,
+        payload_commission,
+    }
+}
+
+/// get the balance with stable and pending utxos of the address
+/// pending utxos are those in the temp state but not stable yet
+pub fn get_balance_with_utxo_details(address: &str) -> Result<BalanceDetails> {
+    Ok(BUSINESS_CACHE.get_balance_details(address))
+}
+
 /// get history by address, return transactions
 pub fn get_latest_history(history_request: &HistoryRequest) -> Result<HistoryResponse> {
     // note: just support get stable history currently
@@ -94,12 +336,105 @@ pub fn get_latest_history(history_request: &HistoryRequest) -> Result<HistoryRes
     })
 }
 
-/// get transactions from unstable joints
-fn _get_unstable_history(
-    _history_request: &HistoryRequest,
-    _need_tx_count: usize,
-) -> Vec<TransactionInfo> {
-    unimplemented!()
+/// get one page of the transactions of the address, both the pending and the stable ones
+pub fn get_full_address_history(request: &FullHistoryRequest) -> Result<FullHistoryResponse> {
+    let num = match request.num {
+        0 => config::MAX_HISTORY_TRANSACTIONS,
+        num => num,
+    };
+    ensure!(
+        num <= config::MAX_HISTORY_TRANSACTIONS,
+        "num must not exceed {}",
+        config::MAX_HISTORY_TRANSACTIONS
+    );
+    ensure!(
+        request.offset <= config::MAX_HISTORY_OFFSET,
+        "offset must not exceed {}",
+        config::MAX_HISTORY_OFFSET
+    );
+
+    // one more transaction to tell if there is a next page
+    let need_tx_count = request.offset + num + 1;
+    let mut entries = get_unstable_history(&request.address, need_tx_count)?;
+    if entries.len() < need_tx_count {
+        let history_request = HistoryRequest {
+            address: request.address.clone(),
+            num: need_tx_count - entries.len(),
+        };
+        let stable = get_stable_history(&history_request)?;
+        entries.extend(
+            stable
+                .into_iter()
+                .map(|tx| FullHistoryEntry::new(tx, TxStatus::Stable)),
+        );
+    }
+
+    let next_offset = if entries.len() > request.offset + num {
+        Some(request.offset + num)
+    } else {
+        None
+    };
+    let transactions = entries.into_iter().skip(request.offset).take(num).collect();
+
+    Ok(FullHistoryResponse {
+        transactions,
+        next_offset,
+    })
+}
+
+/// get at most `need_tx_count` transactions from unstable joints, the newest first
+fn get_unstable_history(address: &str, need_tx_count: usize) -> Result<Vec<FullHistoryEntry>> {
+    let mut entries = Vec::new();
+
+    // the unstable joints are sorted from the oldest
+    for joint in SDAG_CACHE.get_unstable_joints()?.iter().rev() {
+        let joint_data = joint.read()?;
+        let status = match joint_data.get_sequence() {
+            JointSequence::Good => TxStatus::Pending,
+            sequence if sequence.is_temp_bad() => TxStatus::TempBad,
+            // the final bad joints would never be confirmed
+            _ => continue,
+        };
+
+        let mut transactions = Vec::new();
+        let unit = &joint_data.unit;
+        if unit.authors.iter().any(|a| a.address == address) {
+            // send money to others
+            for msg in &unit.messages {
+                if let Some(Payload::Payment(ref payment)) = msg.payload {
+                    for output in &payment.outputs {
+                        // skip ourself change
+                        if output.address == address {
+                            continue;
+                        }
+
+                        transactions.push(TransactionInfo {
+                            unit_hash: unit.unit.clone(),
+                            from_addr: address.to_owned(),
+                            to_addr: output.address.clone(),
+                            amount: output.amount as i64,
+                            time: unit.timestamp,
+                        });
+                    }
+                }
+            }
+        } else {
+            // receive money from others
+            get_receive_tx(unit, address, need_tx_count, &mut transactions);
+        }
+
+        entries.extend(
+            transactions
+                .into_iter()
+                .map(|tx| FullHistoryEntry::new(tx, status)),
+        );
+        if entries.len() >= need_tx_count {
+            entries.truncate(need_tx_count);
+            break;
+        }
+    }
+
+    Ok(entries)
 }
 
 /// get transactions from stable joints
@@ -209,3 +544,571 @@ fn get_receive_tx(
 
     false
 }
+
+/// prepare the link proofs of the stable units for light clients
+pub fn prepare_link_proofs(units: &[String]) -> Result<Value> {
+    ensure!(
+        units.len() <= config::MAX_LINK_PROOFS_PER_REQUEST,
+        "too many units for link proofs, max {}",
+        config::MAX_LINK_PROOFS_PER_REQUEST
+    );
+
+    let mut proofs = Vec::with_capacity(units.len());
+    for unit in units {
+        proofs.push(get_link_proof(unit, &get_cached_step, &get_mc_unit)?);
+    }
+    Ok(serde_json::to_value(proofs)?)
+}
+
+// get_step: returns the link proof step of a stable unit and its parent units
+// get_mc_unit: returns the main chain unit of the mci
+fn get_link_proof<F, G>(unit: &str, get_step: &F, get_mc_unit: &G) -> Result<LinkProof>
+where
+    F: Fn(&str) -> Result<(LinkProofStep, Vec<String>)>,
+    G: Fn(Level) -> Result<String>,
+{
+    let (step, _) = get_step(unit)?;
+    let mc_unit = get_mc_unit(step.mci)?;
+    Ok(LinkProof {
+        unit: unit.to_owned(),
+        steps: find_parent_path(&mc_unit, unit, get_step)?,
+    })
+}
+
+/// prepare the proof that the stable unit is stable at its mci, up to the reference mci
+pub fn prepare_joint_proof(unit: &str, reference_mci: Level) -> Result<JointProof> {
+    let last_stable_mci = ::main_chain::get_last_stable_mci();
+    get_joint_proof(
+        unit,
+        reference_mci,
+        last_stable_mci,
+        &get_cached_step,
+        &get_mc_unit,
+    )
+}
+
+fn get_joint_proof<F, G>(
+    unit: &str,
+    reference_mci: Level,
+    last_stable_mci: Level,
+    get_step: &F,
+    get_mc_unit: &G,
+) -> Result<JointProof>
+where
+    F: Fn(&str) -> Result<(LinkProofStep, Vec<String>)>,
+    G: Fn(Level) -> Result<String>,
+{
+    let (step, _) = get_step(unit)?;
+    let mci = step.mci;
+    ensure!(
+        mci <= reference_mci && reference_mci <= last_stable_mci,
+        "reference mci {} should be within [{}, {}]",
+        reference_mci.value(),
+        mci.value(),
+        last_stable_mci.value()
+    );
+
+    let mut steps = Vec::new();
+    for mc_mci in get_main_chain_path(mci.value(), reference_mci.value()) {
+        let (step, _) = get_step(&get_mc_unit(Level::new(mc_mci))?)?;
+        steps.push(step);
+    }
+
+    Ok(JointProof {
+        unit: unit.to_owned(),
+        reference_mci,
+        link_proof: get_link_proof(unit, get_step, get_mc_unit)?,
+        main_chain_proof: LinkProof {
+            unit: steps[0].unit.clone(),
+            steps,
+        },
+    })
+}
+
+// the main chain mcis from the genesis to the reference mci through the mci
+// each one is the best parent or a skiplist unit of the next one, the farthest is taken
+fn get_main_chain_path(mci: usize, reference_mci: usize) -> Vec<usize> {
+    let mut path = vec![0];
+    let mut cur = 0;
+    for &target in &[mci, reference_mci] {
+        while cur < target {
+            let mut step = 1;
+            while cur % (step * 10) == 0 && cur + step * 10 <= target {
+                step *= 10;
+            }
+            cur += step;
+            path.push(cur);
+        }
+    }
+    path
+}
+
+/// verify the joint proof from the genesis, the mci of the unit is then trusted
+/// the reference ball should be compared with a trusted ball by the caller
+pub fn verify_joint_proof(proof: &JointProof, genesis_ball: &str) -> Result<()> {
+    let main_chain = &proof.main_chain_proof;
+    let last = main_chain.verify()?;
+    let genesis = &main_chain.steps[0];
+    ensure!(
+        genesis.ball == genesis_ball && genesis.mci == Level::new(0),
+        "main chain proof does not start from the genesis"
+    );
+
+    // the ball doesn't cover the mci, so the mcis are checked by the main chain rules
+    // the mc unit includes the previous mc unit as the best parent, and the mc units
+    // of the similar mcis as the skiplist units
+    for step in &main_chain.steps {
+        ensure!(
+            step.skiplist_balls.len() == get_similar_mcis(step.mci.value()).len(),
+            "wrong skiplist balls of main chain unit {}",
+            step.unit
+        );
+    }
+    for pair in main_chain.steps.windows(2) {
+        let (prev, next) = (&pair[0], &pair[1]);
+        let by_parent = next.parent_balls.contains(&prev.ball) && next.mci == prev.mci + 1;
+        let by_skiplist = next.skiplist_balls.contains(&prev.ball)
+            && get_similar_mcis(next.mci.value()).contains(&prev.mci.value());
+        ensure!(
+            by_parent || by_skiplist,
+            "wrong mci {} of main chain unit {}",
+            next.mci.value(),
+            next.unit
+        );
+    }
+    ensure!(
+        last.mci == proof.reference_mci,
+        "joint proof ends at mci {}, expect {}",
+        last.mci.value(),
+        proof.reference_mci.value()
+    );
+
+    // the unit is of the mci of the main chain unit that includes it
+    let link = &proof.link_proof;
+    ensure!(
+        link.unit == proof.unit,
+        "link proof of unit {}, expect {}",
+        link.unit,
+        proof.unit
+    );
+    let mc_step = link.verify()?;
+    ensure!(
+        main_chain.steps.contains(mc_step),
+        "link proof of unit {} does not end on the main chain",
+        proof.unit
+    );
+    ensure!(
+        link.steps.iter().all(|s| s.mci == mc_step.mci),
+        "wrong mci in link proof of unit {}",
+        proof.unit
+    );
+    Ok(())
+}
+
+fn get_mc_unit(mci: Level) -> Result<String> {
+    SDAG_CACHE
+        .get_mc_unit_hash(mci)?
+        .ok_or_else(|| format_err!("no main chain unit at mci {}", mci.value()))
+}
+
+// the parent path from the unit to the main chain unit of the same mci
+// the path is at most MAX_LINK_PROOF_STEPS long
+fn find_parent_path<F>(mc_unit: &str, unit: &str, get_step: &F) -> Result<Vec<LinkProofStep>>
+where
+    F: Fn(&str) -> Result<(LinkProofStep, Vec<String>)>,
+{
+    let (mc_step, parents) = get_step(mc_unit)?;
+    let mci = mc_step.mci;
+
+    // <unit, (step, child on the path)>
+    let mut path_steps = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(mc_unit.to_owned());
+    path_steps.insert(mc_unit.to_owned(), (mc_step, None));
+    queue.push_back((mc_unit.to_owned(), parents, 1));
+
+    while let Some((key, parents, depth)) = queue.pop_front() {
+        if key == unit {
+            let mut path = Vec::with_capacity(depth);
+            let mut next = Some(key);
+            while let Some(key) = next {
+                let (step, child) = path_steps.remove(&key).unwrap();
+                path.push(step);
+                next = child;
+            }
+            return Ok(path);
+        }
+
+        if depth >= config::MAX_LINK_PROOF_STEPS {
+            continue;
+        }
+        for parent in parents {
+            if !visited.insert(parent.clone()) {
+                continue;
+            }
+            let (step, grand_parents) = get_step(&parent)?;
+            if step.mci != mci {
+                continue;
+            }
+            path_steps.insert(parent.clone(), (step, Some(key.clone())));
+            queue.push_back((parent, grand_parents, depth + 1));
+        }
+    }
+
+    bail!("unit {} is not found at mci {}", unit, mci.value())
+}
+
+// the link proof step of the stable joint in cache and its parent units
+fn get_cached_step(unit: &str) -> Result<(LinkProofStep, Vec<String>)> {
+    let joint_data = SDAG_CACHE.get_joint(unit)?.read()?;
+    ensure!(joint_data.is_stable(), "unit {} is not stable", unit);
+
+    let get_ball = |joint: &CachedJoint| -> Result<String> {
+        joint
+            .read()?
+            .ball
+            .clone()
+            .ok_or_else(|| format_err!("no ball for unit {}", joint.key))
+    };
+
+    let mut parent_balls = Vec::new();
+    for parent in joint_data.parents.iter() {
+        parent_balls.push(get_ball(&*parent)?);
+    }
+    let mut skiplist_balls = Vec::new();
+    for skiplist_unit in &joint_data.skiplist_units {
+        skiplist_balls.push(get_ball(&SDAG_CACHE.get_joint(skiplist_unit)?)?);
+    }
+
+    let step = LinkProofStep::new(
+        joint_data.unit.unit.clone(),
+        joint_data.get_mci(),
+        parent_balls,
+        skiplist_balls,
+        joint_data.get_sequence() != JointSequence::Good,
+    );
+    ensure!(
+        joint_data.ball.as_ref() == Some(&step.ball),
+        "stored ball of unit {} does not match",
+        step.unit
+    );
+    Ok((step, joint_data.unit.parent_units.clone()))
+}
+
+//---------------------------------------------------------------------------------------
+// LightWallet
+//---------------------------------------------------------------------------------------
+/// embedded light client for library users, no need to run a hub
+pub struct LightWallet {
+    // the connection would be replaced when reconnected
+    conn: Arc<RwLock<Arc<WalletConn>>>,
+    wallet: WalletInfo,
+}
+
+impl LightWallet {
+    /// connect to the hub and init the wallet from the mnemonic
+    pub fn new(hub_url: &str, mnemonic: &str) -> Result<Self> {
+        let wallet = WalletInfo::from_mnemonic(mnemonic)?;
+        let conn = Arc::new(RwLock::new(create_outbound_conn(hub_url)?));
+        start_reconnect_timer(hub_url.to_owned(), Arc::downgrade(&conn));
+
+        Ok(LightWallet { conn, wallet })
+    }
+
+    fn get_conn(&self) -> Arc<WalletConn> {
+        self.conn.read().unwrap().clone()
+    }
+
+    /// the stable balance of the wallet address
+    pub fn balance(&self) -> Result<u64> {
+        self.get_conn().get_balance(&self.wallet._00_address)
+    }
+
+    /// compose and post a payment, return the unit hash
+    pub fn send(&self, outputs: Vec<Output>, text: Option<&str>) -> Result<String> {
+        let conn = self.get_conn();
+        let address = &self.wallet._00_address;
+
+        let text_message = match text {
+            Some(msg) => Some(composer::create_text_message(msg)?),
+            None => None,
+        };
+
+        let light_props = conn.get_light_props(address)?;
+        let total_amount = outputs.iter().map(|o| o.amount).sum::<u64>();
+        let inputs = conn.get_inputs_from_hub(
+            address,
+            total_amount + composer::COMMISSION_RESERVE,
+            false,
+            &light_props.last_ball_unit,
+        )?;
+
+        let compose_info = composer::ComposeInfo {
+            paid_address: address.clone(),
+            change_address: address.clone(),
+            outputs,
+            text_message,
+            inputs,
+            transaction_amount: total_amount,
+            light_props,
+            pubk: self.wallet._00_address_pubk.to_base64_key(),
+            send_all: false,
+        };
+
+        let joint = composer::compose_joint(compose_info, &self.wallet)?;
+        conn.post_joint(&joint)?;
+        Ok(joint.unit.unit)
+    }
+
+    /// the latest n transactions of the wallet address
+    pub fn history(&self, n: usize) -> Result<HistoryResponse> {
+        self.get_conn()
+            .get_latest_history(self.wallet._00_address.clone(), n)
+    }
+
+    pub fn receive_address(&self) -> &str {
+        &self.wallet._00_address
+    }
+
+    /// wait the unit become stable, return the mci of the unit
+    pub fn wait_stable(&self, unit_hash: &str, timeout: Duration) -> Result<Level> {
+        self.get_conn().wait_unit_stable(unit_hash, timeout)
+    }
+}
+
+// check the connection periodically and reconnect if it's broken
+// the timer exits when the wallet is dropped
+fn start_reconnect_timer(hub_url: String, conn: Weak<RwLock<Arc<WalletConn>>>) {
+    go!(move || loop {
+        coroutine::sleep(Duration::from_secs(5));
+
+        let conn = match conn.upgrade() {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        let ws = conn.read().unwrap().clone();
+        if ws.send_heartbeat().is_ok() {
+            continue;
+        }
+
+        warn!("light wallet lost connection to {}, reconnecting", hub_url);
+        match create_outbound_conn(hub_url.as_str()) {
+            Ok(ws) => *conn.write().unwrap() = ws,
+            Err(e) => error!("light wallet reconnect failed, err={}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // <unit, (step, parent units)>
+    type Dag = HashMap<String, (LinkProofStep, Vec<String>)>;
+
+    fn get_balls(dag: &Dag, units: &[&str]) -> Vec<String> {
+        units.iter().map(|u| dag[*u].0.ball.clone()).collect()
+    }
+
+    // add a stable unit, the parents and the skiplist units must be added before
+    fn add_unit(dag: &mut Dag, unit: &str, mci: usize, parents: &[&str], skiplist: &[&str]) {
+        let step = LinkProofStep::new(
+            unit.to_owned(),
+            Level::new(mci),
+            get_balls(dag, parents),
+            get_balls(dag, skiplist),
+            false,
+        );
+        let parents = parents.iter().map(|p| p.to_string()).collect();
+        dag.insert(unit.to_owned(), (step, parents));
+    }
+
+    fn get_step(dag: &Dag, unit: &str) -> Result<(LinkProofStep, Vec<String>)> {
+        dag.get(unit)
+            .cloned()
+            .ok_or_else(|| format_err!("unit {} not found", unit))
+    }
+
+    fn get_mc_unit(mci: Level) -> Result<String> {
+        Ok(format!("mc_{}", mci.value()))
+    }
+
+    // the main chain from mc_0 to mc_25 with the skiplist units
+    // the payment is included by mc_12 through side_b
+    fn payment_dag() -> Dag {
+        let mut dag = Dag::new();
+        add_unit(&mut dag, "mc_0", 0, &[], &[]);
+        for mci in 1..=25 {
+            let unit = format!("mc_{}", mci);
+            let best_parent = format!("mc_{}", mci - 1);
+            let skiplist: Vec<String> = get_similar_mcis(mci)
+                .iter()
+                .map(|m| format!("mc_{}", m))
+                .collect();
+            let skiplist: Vec<&str> = skiplist.iter().map(|s| s.as_str()).collect();
+
+            if mci == 12 {
+                add_unit(&mut dag, "payment", mci, &["mc_11"], &[]);
+                add_unit(&mut dag, "side_b", mci, &["payment"], &[]);
+                add_unit(&mut dag, &unit, mci, &[&best_parent, "side_b"], &skiplist);
+            } else {
+                add_unit(&mut dag, &unit, mci, &[&best_parent], &skiplist);
+            }
+        }
+        dag
+    }
+
+    #[test]
+    fn test_link_proof_round_trip() {
+        let dag = payment_dag();
+        let get_step = |unit: &str| get_step(&dag, unit);
+
+        let proof = get_link_proof("payment", &get_step, &get_mc_unit).unwrap();
+        let units: Vec<_> = proof.steps.iter().map(|s| s.unit.as_str()).collect();
+        assert_eq!(units, vec!["payment", "side_b", "mc_12"]);
+
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: LinkProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+        assert_eq!(decoded.verify().unwrap().ball, dag["mc_12"].0.ball);
+
+        // the main chain unit proves itself
+        let proof = get_link_proof("mc_1", &get_step, &get_mc_unit).unwrap();
+        assert_eq!(proof.steps.len(), 1);
+        assert!(proof.verify().is_ok());
+
+        assert!(get_link_proof("unknown", &get_step, &get_mc_unit).is_err());
+    }
+
+    #[test]
+    fn test_link_proof_verify() {
+        let dag = payment_dag();
+        let get_step = |unit: &str| get_step(&dag, unit);
+        let proof = get_link_proof("payment", &get_step, &get_mc_unit).unwrap();
+
+        // the chain is broken
+        let mut broken = proof.clone();
+        broken.steps.remove(1);
+        assert!(broken.verify().is_err());
+
+        // the ball is tampered
+        let mut tampered = proof.clone();
+        tampered.steps[1].parent_balls[0] = dag["mc_1"].0.ball.clone();
+        assert!(tampered.verify().is_err());
+
+        // it proves another unit
+        let mut other = proof.clone();
+        other.unit = "side_b".to_owned();
+        assert!(other.verify().is_err());
+
+        let mut too_long = proof;
+        while too_long.steps.len() <= config::MAX_LINK_PROOF_STEPS {
+            too_long.steps.push(too_long.steps.last().unwrap().clone());
+        }
+        assert!(too_long.verify().is_err());
+    }
+
+    #[test]
+    fn test_get_main_chain_path() {
+        assert_eq!(get_main_chain_path(0, 0), vec![0]);
+        assert_eq!(
+            get_main_chain_path(12, 25),
+            vec![0, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25]
+        );
+
+        let path = get_main_chain_path(1234, 1300);
+        assert_eq!(&path[..4], &[0, 1000, 1100, 1200]);
+        assert!(path.contains(&1234));
+        assert_eq!(path.last(), Some(&1300));
+        // each one is the best parent or a skiplist unit of the next one
+        for pair in path.windows(2) {
+            assert!(pair[1] == pair[0] + 1 || get_similar_mcis(pair[1]).contains(&pair[0]));
+        }
+    }
+
+    #[test]
+    fn test_joint_proof_round_trip() {
+        let dag = payment_dag();
+        let get_step = |unit: &str| get_step(&dag, unit);
+        let (mci_11, mci_25) = (Level::new(11), Level::new(25));
+
+        let proof = get_joint_proof("payment", mci_25, mci_25, &get_step, &get_mc_unit).unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: JointProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+        verify_joint_proof(&decoded, &dag["mc_0"].0.ball).unwrap();
+        assert_eq!(decoded.get_mci(), Some(Level::new(12)));
+        assert_eq!(
+            decoded.get_reference_ball(),
+            Some(dag["mc_25"].0.ball.as_str())
+        );
+        // the skiplist units make it shorter than the main chain
+        assert!(decoded.main_chain_proof.steps.len() < 26);
+
+        // the reference mci is not stable or before the unit
+        assert!(get_joint_proof("payment", mci_25, mci_11, &get_step, &get_mc_unit).is_err());
+        assert!(get_joint_proof("payment", mci_11, mci_25, &get_step, &get_mc_unit).is_err());
+    }
+
+    #[test]
+    fn test_verify_joint_proof() {
+        let dag = payment_dag();
+        let get_step = |unit: &str| get_step(&dag, unit);
+        let genesis_ball = dag["mc_0"].0.ball.clone();
+        let mci_25 = Level::new(25);
+        let proof = get_joint_proof("payment", mci_25, mci_25, &get_step, &get_mc_unit).unwrap();
+
+        // a different genesis
+        assert!(verify_joint_proof(&proof, &dag["mc_1"].0.ball).is_err());
+
+        // the chain doesn't reach the genesis
+        let mut no_genesis = proof.clone();
+        no_genesis.main_chain_proof.steps.remove(0);
+        no_genesis.main_chain_proof.unit = no_genesis.main_chain_proof.steps[0].unit.clone();
+        assert!(verify_joint_proof(&no_genesis, &genesis_ball).is_err());
+
+        // the ball doesn't cover the mci, claim another mci for the unit
+        let mut wrong_mci = proof.clone();
+        for step in &mut wrong_mci.link_proof.steps {
+            step.mci = Level::new(13);
+        }
+        assert!(verify_joint_proof(&wrong_mci, &genesis_ball).is_err());
+
+        // and for the main chain unit too
+        for step in &mut wrong_mci.main_chain_proof.steps {
+            if step.unit == "mc_12" {
+                step.mci = Level::new(13);
+            }
+        }
+        assert!(verify_joint_proof(&wrong_mci, &genesis_ball).is_err());
+
+        let mut other_reference = proof.clone();
+        other_reference.reference_mci = Level::new(24);
+        assert!(verify_joint_proof(&other_reference, &genesis_ball).is_err());
+
+        let mut other_unit = proof;
+        other_unit.unit = "side_b".to_owned();
+        assert!(verify_joint_proof(&other_unit, &genesis_ball).is_err());
+    }
+
+    #[test]
+    fn test_link_proof_length_capped() {
+        // a long chain of units of the same mci
+        let mut dag = Dag::new();
+        add_unit(&mut dag, "mc_0", 0, &[], &[]);
+        let mut parent = "mc_0".to_owned();
+        for i in 0..config::MAX_LINK_PROOF_STEPS {
+            let unit = format!("side_{}", i);
+            add_unit(&mut dag, &unit, 1, &[&parent], &[]);
+            parent = unit;
+        }
+        add_unit(&mut dag, "mc_1", 1, &[&parent], &[]);
+        let get_step = |unit: &str| get_step(&dag, unit);
+
+        let last = format!("side_{}", config::MAX_LINK_PROOF_STEPS - 1);
+        assert!(get_link_proof(&last, &get_step, &get_mc_unit).is_ok());
+        assert!(get_link_proof("side_0", &get_step, &get_mc_unit).is_err());
+    }
+}
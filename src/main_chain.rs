@@ -1,5 +1,6 @@
 use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::Duration;
 
 use cache::{CachedJoint, JointData, SDAG_CACHE};
 use error::Result;
@@ -9,9 +10,11 @@ use joint::Level;
 use may::coroutine::JoinHandle;
 use may::sync::mpsc;
 use rcu_cell::{RcuCell, RcuReader};
+use utils::{OnceOption, Watchdog};
 
 lazy_static! {
     pub static ref MAIN_CHAIN_WORKER: MainChainWorker = MainChainWorker::default();
+    static ref MAIN_CHAIN_WATCHDOG: OnceOption<Watchdog> = OnceOption::new();
     static ref LAST_STABLE_JOINT: RcuCell<RcuReader<JointData>> = {
         match calc_last_stable_joint() {
             Ok(joint) => RcuCell::new(Some(joint)),
@@ -26,6 +29,7 @@ lazy_static! {
 //---------------------------------------------------------------------------------------
 // MciStableEvent
 //---------------------------------------------------------------------------------------
+#[derive(Clone)]
 pub struct MciStableEvent {
     pub mci: Level,
 }
@@ -56,6 +60,16 @@ impl MainChainWorker {
     }
 }
 
+/// monitor the main chain worker, report it if it stalled longer than timeout
+pub fn start_watchdog(timeout: Duration) {
+    if MAIN_CHAIN_WATCHDOG
+        .set(Watchdog::start("main_chain", timeout))
+        .is_some()
+    {
+        warn!("main chain watchdog already started");
+    }
+}
+
 fn start_main_chain_worker(rx: mpsc::Receiver<RcuReader<JointData>>) -> JoinHandle<()> {
     go!(move || {
         // init it as -1 then the genesis min_wl = 0 can go forward
@@ -72,6 +86,8 @@ fn start_main_chain_worker(rx: mpsc::Receiver<RcuReader<JointData>>) -> JoinHand
         );
 
         while let Ok(joint) = rx.recv() {
+            // pong the watchdog when the joint is done
+            let _g = MAIN_CHAIN_WATCHDOG.get().map(Watchdog::guard);
             if joint.get_min_wl() <= last_stable_level {
                 continue;
             }
@@ -91,6 +107,7 @@ fn start_main_chain_worker(rx: mpsc::Receiver<RcuReader<JointData>>) -> JoinHand
 }
 
 fn update_main_chain(joint: RcuReader<JointData>) -> Result<Level> {
+    ::tracing::debug!(unit_hash = %joint.unit.unit, "update_main_chain");
     let mut valid_mc_joints = build_unstable_main_chain_from_joint(joint)?;
     let mut stable_joint = valid_mc_joints.pop().expect("no stable joint found!");
     // directly update to longest max stable unit since we have already verified
@@ -176,12 +193,14 @@ fn mark_main_chain_joint_stable(main_chain_joint: &RcuReader<JointData>, mci: Le
     // update the global property
     SDAG_CACHE.set_mc_unit_hash(mci, main_chain_joint.unit.unit.clone())?;
 
-    info!(
-        "main chain update: last_stable_joint = {:?}",
-        main_chain_joint.get_props()
+    ::tracing::info!(
+        unit_hash = %main_chain_joint.unit.unit,
+        mci = mci.value(),
+        props = ?main_chain_joint.get_props(),
+        "main chain update: last_stable_joint"
     );
 
-    ::utils::event::emit_event(MciStableEvent { mci });
+    ::utils::event::emit_event_async(MciStableEvent { mci });
 
     Ok(())
 }
@@ -247,7 +266,7 @@ fn get_lastest_effective_unit(joint: &RcuReader<JointData>) -> Result<RcuReader<
 }
 
 // build the mc from joint until it's min_wl
-fn build_unstable_main_chain_from_joint_to_min_wl(
+pub fn build_unstable_main_chain_from_joint_to_min_wl(
     joint: &RcuReader<JointData>,
     min_wl: Level,
 ) -> Result<(Vec<RcuReader<JointData>>)> {
@@ -267,7 +286,7 @@ fn build_unstable_main_chain_from_joint_to_min_wl(
 
 // find all witness units form the joint until a level
 // the witness unit must not on the main chain
-fn get_all_alt_witness_units(
+pub fn get_all_alt_witness_units(
     joint: &RcuReader<JointData>,
     mc: &[RcuReader<JointData>],
     until_level: Level,
@@ -317,7 +336,7 @@ fn get_all_alt_witness_units(
 }
 
 // update the max intersect point of main chains
-fn update_mc_to_intersect(
+pub fn update_mc_to_intersect(
     mc: &mut Vec<RcuReader<JointData>>,
     visited: &mut HashSet<Arc<String>>,
     mut joint: RcuReader<JointData>,
@@ -440,7 +459,7 @@ pub fn find_best_joint<'a, I: IntoIterator<Item = &'a CachedJoint>>(
 
     for joint in p_it {
         let cur_joint_data = joint.read().context("find_best_joint")?;
-        if cur_joint_data.is_precedence_than(&*best_joint_data) {
+        if cur_joint_data.is_precedence_than(&*best_joint_data)? {
             best_joint_data = cur_joint_data;
         }
     }
@@ -448,24 +467,6 @@ pub fn find_best_joint<'a, I: IntoIterator<Item = &'a CachedJoint>>(
     Ok(Some(best_joint_data))
 }
 
-// calc the max stable unit
-pub fn calc_max_stable_unit(joint: RcuReader<JointData>) -> Result<CachedJoint> {
-    let min_wl = joint.get_min_wl();
-    let mut mc_joints = build_unstable_main_chain_from_joint_to_min_wl(&joint, min_wl)?;
-    let alt_witnesses = get_all_alt_witness_units(&joint, &mc_joints, min_wl)?;
-    // if we already visit the witness, we can skip it
-    let mut visited = HashSet::new();
-    for joint in alt_witnesses {
-        if visited.insert(Arc::new(joint.unit.unit.to_owned())) {
-            update_mc_to_intersect(&mut mc_joints, &mut visited, joint)?;
-        }
-    }
-    let max_stable_unit = mc_joints
-        .pop()
-        .ok_or_else(|| format_err!("calc max stable unit failed, unit={}", joint.unit.unit))?;
-    Ok(SDAG_CACHE.get_joint(&max_stable_unit.unit.unit)?)
-}
-
 /// judge if earlier_joint is relative stable to later_joint
 pub fn is_stable_to_joint(
     last_ball: &RcuReader<JointData>,
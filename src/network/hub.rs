@@ -1,34 +1,35 @@
 use std::collections::HashMap as StdHashMap;
-use std::net::ToSocketAddrs;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use super::network_base::{Sender, Server, WsConnection};
+use super::network_base::{client_handshake, Sender, Server, WsConnection};
 use business::{self, BUSINESS_CACHE};
-use cache::{JointData, SDAG_CACHE};
+use cache::{BroadcastKind, JointData, SDAG_CACHE};
 use catchup;
 use composer::*;
 use config;
 use error::Result;
 use failure::ResultExt;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use joint::{Joint, JointSequence, Level};
+use kv_store::{PeerRecord, KV_STORE};
 use light;
 use main_chain;
 use may::coroutine;
 use may::net::TcpStream;
-use may::sync::RwLock;
+use may::sync::{Mutex, RwLock, Semphore};
 use notify_watcher;
 use rcu_cell::RcuReader;
 use sdag_object_base::object_hash;
 use serde_json::{self, Value};
+use spec::MAX_CONE_SIZE;
 use statistics;
-use tungstenite::client::client;
-use tungstenite::handshake::client::Request;
+use time::duration_to_ms;
 use tungstenite::protocol::Role;
-use url::Url;
-use utils::{AtomicLock, FifoCache, MapLock, OnceOption};
+use utils::event::HandlerGuard;
+use utils::{AtomicLock, FifoCache, MapLock, OnceOption, COROUTINE_REGISTRY};
 use validation;
 use wallet_info::MY_WALLET;
 
@@ -45,10 +46,42 @@ lazy_static! {
     static ref JOINT_IN_REQ: MapLock<String> = MapLock::new();
     static ref IS_CATCHING_UP: AtomicLock = AtomicLock::new();
     static ref SELF_LISTEN_ADDRESS: Option<String> = config::get_listen_address();
-    static ref BAD_CONNECTION: FifoCache<String, ()> = FifoCache::with_capacity(10);
+    // <peer address, the time until the peer is not dialed>
+    static ref BAD_CONNECTION: FifoCache<String, Instant> =
+        FifoCache::with_capacity(config::get_bad_connection_cache_size());
+    // <peer_id, the time until the peer is refused>
+    static ref BANNED_PEERS: RwLock<HashMap<Arc<String>, Instant>> = RwLock::new(HashMap::new());
     static ref UNKNOWN_PEER_ID: Arc<String> = Arc::new(String::from("unknown_peer"));
+    static ref RECONNECT_SCHEDULER: ReconnectScheduler = ReconnectScheduler::new();
+    static ref START_TIME: Instant = Instant::now();
+    // <mci, checkpoint> the last exported checkpoint
+    static ref CHECKPOINT_CACHE: Mutex<Option<(Level, Value)>> = Mutex::new(None);
 }
 
+// the peer that failed to subscribe is not connected again within this time
+const BAD_CONNECTION_EXPIRE: Duration = Duration::from_secs(600);
+// the reputation changes of the peer that sent a good or bad joint
+const GOOD_JOINT_REPUTATION: i32 = 1;
+const BAD_JOINT_REPUTATION: i32 = -10;
+// the good joints can't make up for the bad joints unlimitedly
+const MAX_PEER_REPUTATION: i32 = 100;
+// base delay of the outbound reconnect backoff
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+// the persisted peers that are not seen for a week are pruned
+const PEER_EXPIRE_MS: u64 = 7 * 24 * 3600 * 1000;
+// the last seen time of the active peer is saved at this interval
+const PEER_SEEN_SAVE_INTERVAL: Duration = Duration::from_secs(600);
+// max delay of the outbound reconnect backoff
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
+// added to the peer latency when weighting, so that the near 0ms peers are not dominant
+const LATENCY_SMOOTHING_MS: u64 = 10;
+// the cached checkpoint is exported again after the number of mcis become stable
+const CHECKPOINT_REFRESH_MCIS: usize = 1000;
+// max not stable mci subscriptions of a connection
+const MAX_PENDING_MCI_SUBS: usize = 100;
+// max addresses a light client could watch on a connection
+const MAX_WATCHED_ADDRESSES: usize = 100;
+
 //---------------------------------------------------------------------------------------
 // HubNetState
 //---------------------------------------------------------------------------------------
@@ -67,6 +100,21 @@ pub struct HubNetState {
     pub out_bounds: Vec<ConnState>,
 }
 
+//---------------------------------------------------------------------------------------
+// HealthReport
+//---------------------------------------------------------------------------------------
+/// the light weight hub state for the load balancer probes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthReport {
+    // has peers, not catching up, no missing joints and few temp-bad joints
+    pub is_synced: bool,
+    pub last_stable_mci: usize,
+    pub missing_joints: usize,
+    pub temp_bad_joints: usize,
+    pub peer_count: usize,
+    pub uptime_secs: u64,
+}
+
 //---------------------------------------------------------------------------------------
 // WsConnections
 //---------------------------------------------------------------------------------------
@@ -74,6 +122,10 @@ pub struct HubNetState {
 pub struct WsConnections {
     // <peer_id, conn>
     conns: RwLock<HashMap<Arc<String>, Arc<HubConn>>>,
+    // <ip, inbound connection count>
+    ip_counts: RwLock<HashMap<IpAddr, usize>>,
+    // <peer_id, unsolicited joint rate limiter>
+    joint_buckets: RwLock<HashMap<Arc<String>, TokenBucket>>,
     next_conn: AtomicUsize,
 }
 
@@ -81,47 +133,164 @@ impl WsConnections {
     fn new() -> Self {
         WsConnections {
             conns: RwLock::new(HashMap::new()),
+            ip_counts: RwLock::new(HashMap::new()),
+            joint_buckets: RwLock::new(HashMap::new()),
             next_conn: AtomicUsize::new(0),
         }
     }
 
     pub fn add_p2p_conn(&self, conn: Arc<HubConn>, is_inbound: bool) -> Result<()> {
-        init_connection(&conn)?;
+        let inbound_ip = if is_inbound {
+            let ip = get_peer_ip(&conn);
+            if let Some(ip) = ip {
+                let ret = add_inbound_ip(
+                    &mut self.ip_counts.write().unwrap(),
+                    ip,
+                    config::get_max_inbound_per_ip(),
+                    config::get_max_inbound_total(),
+                );
+                if let Err(reject) = ret {
+                    statistics::increase_counter(reject.counter_name());
+                    conn.close();
+                    bail!(
+                        "reject inbound connection from {}, {:?} limit exceeded",
+                        conn.get_peer_addr(),
+                        reject
+                    );
+                }
+            }
+            ip
+        } else {
+            None
+        };
+
+        if let Err(e) = init_connection(&conn).and_then(|_| add_peer_host(&conn, is_inbound)) {
+            if let Some(ip) = inbound_ip {
+                remove_inbound_ip(&mut self.ip_counts.write().unwrap(), ip);
+            }
+            return Err(e);
+        }
+
         if is_inbound {
             conn.set_inbound();
         }
-        add_peer_host(&conn)?;
         let peer_id = conn.get_peer_id();
         warn!(
             "add_p2p_conn peer_id={} peer_addr={}",
             peer_id,
             conn.get_peer_addr()
         );
-        self.conns.write().unwrap().insert(peer_id, conn);
+        let old = self.conns.write().unwrap().insert(peer_id, conn);
+        if let Some(old) = old {
+            // the replaced connection would not be found in the close path
+            self.release_inbound(&old);
+        }
         Ok(())
     }
 
     pub fn close_all(&self) {
         let mut g = self.conns.write().unwrap();
         g.clear();
+        self.ip_counts.write().unwrap().clear();
+        self.joint_buckets.write().unwrap().clear();
     }
 
-    fn close(&self, conn: &HubConn) {
+    // return true if the connection is removed
+    fn close(&self, conn: &HubConn) -> bool {
         // find out the actor and remove it
-        let mut g = self.conns.write().unwrap();
-        g.remove(&conn.get_peer_id());
+        let removed = {
+            let mut g = self.conns.write().unwrap();
+            let peer_id = conn.get_peer_id();
+            // the connection may be rejected or replaced, don't remove others
+            match g.get(&peer_id) {
+                Some(c) if ::std::ptr::eq(&**c, conn) => g.remove(&peer_id),
+                _ => None,
+            }
+        };
+
+        match removed {
+            Some(conn) => {
+                self.release_inbound(&conn);
+                statistics::remove_peer_latency(&conn.get_peer_id());
+                self.joint_buckets
+                    .write()
+                    .unwrap()
+                    .remove(&conn.get_peer_id());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // return false if the peer has pushed too many joints in the last second
+    fn take_joint_token(&self, peer_id: &Arc<String>) -> bool {
+        let rate = config::get_max_joints_per_second_per_peer();
+        let now = Instant::now();
+        let mut g = self.joint_buckets.write().unwrap();
+        g.entry(peer_id.clone())
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .take(rate, now)
+    }
+
+    fn release_inbound(&self, conn: &HubConn) {
+        if !conn.is_inbound() {
+            return;
+        }
+        if let Some(ip) = get_peer_ip(conn) {
+            remove_inbound_ip(&mut self.ip_counts.write().unwrap(), ip);
+        }
+    }
+
+    fn get_connection_limits(&self) -> Value {
+        let g = self.ip_counts.read().unwrap();
+        let per_ip = g
+            .iter()
+            .map(|(ip, count)| (ip.to_string(), *count))
+            .collect::<StdHashMap<_, _>>();
+        json!({
+            "inbound_total": g.values().sum::<usize>(),
+            "inbound_per_ip": per_ip,
+            "max_inbound_per_ip": config::get_max_inbound_per_ip(),
+            "max_inbound_total": config::get_max_inbound_total(),
+        })
     }
 
+    /// pick a peer randomly, the lower latency peer has the higher probability
+    /// the unmeasured peers are weighted as the slowest one
+    /// fall back to round robin if no peer latency is known yet
     pub fn get_next_peer(&self) -> Option<Arc<HubConn>> {
-        let g = self.conns.read().unwrap();
-        let mut peers = g.values();
-        let len = peers.len();
-        if len == 0 {
+        use rand::{thread_rng, Rng};
+
+        let peers = self.get_peers_with_latency();
+        if peers.is_empty() {
             return None;
         }
 
-        let idx = self.next_conn.fetch_add(1, Ordering::Relaxed) % len;
-        peers.nth(idx).cloned()
+        let latencies = peers.iter().map(|(_, l)| *l).collect::<Vec<_>>();
+        let idx = match fill_unknown_latencies(&latencies) {
+            None => {
+                increase_selection_counter("round_robin");
+                self.next_conn.fetch_add(1, Ordering::Relaxed) % peers.len()
+            }
+            Some(latencies) => {
+                increase_selection_counter("weighted");
+                weighted_select_by_latency(&latencies, thread_rng().gen_range(0.0, 1.0))?
+            }
+        };
+        peers.into_iter().nth(idx).map(|(c, _)| c)
+    }
+
+    /// return the connections sorted by the ascending p50 latency
+    /// the unmeasured peers are put at the end
+    pub fn get_peers_sorted_by_latency(&self) -> Vec<Arc<HubConn>> {
+        sort_peers_by_latency(self.get_peers_with_latency())
+    }
+
+    fn get_peers_with_latency(&self) -> Vec<(Arc<HubConn>, Option<u64>)> {
+        let g = self.conns.read().unwrap();
+        g.iter()
+            .map(|(id, c)| (c.clone(), statistics::get_peer_p50_latency(id)))
+            .collect()
     }
 
     // return all remote peer addresses
@@ -165,13 +334,48 @@ impl WsConnections {
             None => return,
         };
 
-        for conn in self.conns.read().unwrap().values().cloned() {
-            // only send to who subscribed and not the source
-            if conn.is_subscribed() && joint.get_peer_id() != Some(conn.get_peer_id()) {
-                let joint = joint.clone();
-                try_go!(move || conn.send_joint(&joint));
-            }
-        }
+        // only send to who subscribed and not the source,
+        // and the light clients that subscribed the new joints or watch their addresses
+        let source = joint.get_peer_id();
+        let watched_joint = joint.clone();
+        let addresses = get_related_addresses(&watched_joint.unit);
+        self.broadcast_to_subset(joint, BroadcastKind::Joint, |c| {
+            is_broadcast_target(c, &source) || c.is_joints_subscribed() || c.is_watching(&addresses)
+        });
+    }
+
+    /// broadcast the joint to the connections that satisfy the predicate
+    pub fn broadcast_to_subset<P>(
+        &self,
+        joint: RcuReader<JointData>,
+        kind: BroadcastKind,
+        predicate: P,
+    ) where
+        P: Fn(&Arc<HubConn>) -> bool + Sync,
+    {
+        let conns = select_conns(self.conns.read().unwrap().values().cloned(), predicate);
+        send_joint_to(conns, joint, kind);
+    }
+
+    /// broadcast the joint to k random subscribed peers, except the source
+    pub fn broadcast_to_k_random(&self, joint: RcuReader<JointData>, k: usize) {
+        use rand::{seq::SliceRandom, thread_rng};
+
+        let source = joint.get_peer_id();
+        let mut conns = select_conns(self.conns.read().unwrap().values().cloned(), |c| {
+            is_broadcast_target(c, &source)
+        });
+        conns.shuffle(&mut thread_rng());
+        conns.truncate(k);
+        send_joint_to(conns, joint, BroadcastKind::Joint);
+    }
+
+    /// multicast the joint only to the witness peers
+    pub fn broadcast_to_witnesses(&self, joint: RcuReader<JointData>) {
+        use my_witness::MY_WITNESSES;
+        self.broadcast_to_subset(joint, BroadcastKind::Joint, |c| {
+            MY_WITNESSES.contains(&*c.get_peer_id())
+        });
     }
 
     /// notify message to watcher
@@ -205,12 +409,26 @@ impl WsConnections {
     pub fn request_free_joints_from_all_peers(&self) -> Result<()> {
         for conn in self.conns.read().unwrap().values().cloned() {
             if conn.get_listen_addr().is_some() {
+                conn.set_joints_requested();
                 try_go!(move || conn.send_just_saying("refresh", Value::Null));
             }
         }
         Ok(())
     }
 
+    /// tell all the peers that the witness has signed two conflicting joints
+    pub fn report_double_sign(&self, address: &str, unit_a: &str, unit_b: &str) {
+        let report = json!({
+            "address": address,
+            "unit_a": unit_a,
+            "unit_b": unit_b,
+        });
+        for conn in self.conns.read().unwrap().values().cloned() {
+            let report = report.clone();
+            try_go!(move || conn.send_just_saying("report_double_sign", report));
+        }
+    }
+
     fn get_outbound_peers(&self, hub_id: &str) -> Vec<ConnState> {
         // filter out the connection with the same hub_id
         self.conns
@@ -271,6 +489,16 @@ impl WsConnections {
             .collect()
     }
 
+    /// the connected hubs, the light clients are not counted
+    fn get_peer_count(&self) -> usize {
+        self.conns
+            .read()
+            .unwrap()
+            .values()
+            .filter(|c| !c.is_inbound() || c.is_subscribed())
+            .count()
+    }
+
     fn get_net_state(&self) -> HubNetState {
         HubNetState {
             in_bounds: self.get_inbound_peers(),
@@ -298,12 +526,27 @@ impl WsConnections {
             .values()
             .filter(|c| !c.is_inbound())
             .count();
-        if config::MAX_OUTBOUND_CONNECTIONS > outbound_connecions {
-            return config::MAX_OUTBOUND_CONNECTIONS - outbound_connecions;
+        let max_outbound = config::get_max_outbound_connections();
+        if max_outbound > outbound_connecions {
+            return max_outbound - outbound_connecions;
         }
         0
     }
 
+    // remove the connections to the address without reconnecting
+    fn close_by_address(&self, addr: &str) -> usize {
+        let conns = self
+            .conns
+            .read()
+            .unwrap()
+            .values()
+            .filter(|c| !c.is_inbound() && c.get_peer_addr() == addr)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        conns.iter().filter(|c| self.close(c)).count()
+    }
+
     fn contains(&self, addr: &str) -> bool {
         if let Some(peer_id) = statistics::get_peer_id_by_address(addr) {
             return self.conns.read().unwrap().contains_key(&peer_id);
@@ -320,9 +563,34 @@ impl WsConnections {
 pub struct HubData {
     // indicate if this connection is a subscribed peer
     is_subscribed: AtomicBool,
+    // indicate if the light client wants all the new joints
+    is_joints_subscribed: AtomicBool,
     is_inbound: AtomicBool,
     peer_id: OnceOption<Arc<String>>,
     listen_addr: OnceOption<String>,
+    // the reconnect attempts that made this outbound connection, a peer that drops
+    // again before sending a good joint is reconnected with a longer backoff
+    reconnect_attempts: AtomicU32,
+    // decreased by the bad joints and increased by the good joints from the peer
+    reputation: AtomicI32,
+    // the number of unhandled joints that come from the peer
+    pending_from_peer: AtomicUsize,
+    // the peer host is dialed by us, only the verified host is saved to the peer db
+    is_host_verified: AtomicBool,
+    // the feature version in the version message, 0 for the old peers
+    feature_version: AtomicU32,
+    // the addresses watched by the light client, their joints are pushed to it
+    watched_addresses: Arc<RwLock<HashSet<String>>>,
+    // the (mci, id) subscribed by "light/subscribe_mci" and "subscribe_to_mci" that are
+    // not stable yet, the id is None for "light/subscribe_mci"
+    pending_mci_subs: Arc<Mutex<Vec<(Level, Option<String>)>>>,
+    // the MciStableEvent handler of the connection, set by the first subscription
+    mci_stable_guard: OnceOption<HandlerGuard>,
+    // when we last sent "subscribe" or "refresh" to the peer, the joint batches it
+    // replies with are not rate limited
+    joints_requested_at: Mutex<Option<Instant>>,
+    // the joints waiting to be broadcast to the peer in one batch
+    pending_broadcast: Mutex<Vec<(RcuReader<JointData>, BroadcastKind)>>,
 }
 
 pub type HubConn = WsConnection<HubData>;
@@ -331,9 +599,46 @@ impl Default for HubData {
     fn default() -> Self {
         HubData {
             is_subscribed: AtomicBool::new(false),
+            is_joints_subscribed: AtomicBool::new(false),
             is_inbound: AtomicBool::new(false),
             peer_id: OnceOption::new(),
             listen_addr: OnceOption::new(),
+            reconnect_attempts: AtomicU32::new(0),
+            reputation: AtomicI32::new(0),
+            pending_from_peer: AtomicUsize::new(0),
+            is_host_verified: AtomicBool::new(false),
+            feature_version: AtomicU32::new(0),
+            watched_addresses: Arc::new(RwLock::new(HashSet::new())),
+            pending_mci_subs: Arc::new(Mutex::new(Vec::new())),
+            mci_stable_guard: OnceOption::new(),
+            joints_requested_at: Mutex::new(None),
+            pending_broadcast: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl HubData {
+    fn get_pending_from_peer(&self) -> usize {
+        self.pending_from_peer.load(Ordering::Relaxed)
+    }
+
+    fn increase_pending(&self) {
+        self.pending_from_peer.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the joint may be added before the peer reconnected, don't go below zero
+    fn decrease_pending(&self) {
+        let mut count = self.pending_from_peer.load(Ordering::Relaxed);
+        while count > 0 {
+            match self.pending_from_peer.compare_exchange_weak(
+                count,
+                count - 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => count = current,
+            }
         }
     }
 }
@@ -342,13 +647,15 @@ impl Server<HubData> for HubData {
     fn on_message(ws: Arc<HubConn>, subject: String, body: Value) -> Result<()> {
         match subject.as_str() {
             "version" => ws.on_version(body)?,
-            "error" => error!("receive error: {}", body),
+            "error" => ws.on_error(body)?,
             "info" => info!("receive info: {}", body),
             "result" => info!("receive result: {}", body),
             "joint" => ws.on_joint(body)?,
+            "joint_batch" => ws.on_joint_batch(body)?,
             "refresh" => ws.on_refresh(body)?,
             "light/new_address_to_watch" => ws.on_new_address_to_watch(body)?,
             "free_joint_list" => ws.on_free_joint_list(body)?,
+            "report_double_sign" => ws.on_report_double_sign(body)?,
 
             subject => bail!(
                 "on_message unknown subject: {} body {}",
@@ -359,6 +666,20 @@ impl Server<HubData> for HubData {
         Ok(())
     }
 
+    fn http_info() -> Value {
+        json!({
+            "version": config::VERSION,
+            "peers": WSS.get_inbound_peers().len(),
+            "last_stable_mci": main_chain::get_last_stable_mci().value(),
+            "is_synced": !IS_CATCHING_UP.is_locked(),
+        })
+    }
+
+    fn http_health() -> (bool, Value) {
+        let health = get_network_health();
+        (health.is_synced, json!(health))
+    }
+
     fn on_request(ws: Arc<HubConn>, command: String, params: Value) -> Result<Value> {
         let response = match command.as_str() {
             "heartbeat" => ws.on_heartbeat(params)?,
@@ -368,10 +689,27 @@ impl Server<HubData> for HubData {
             "net_state" => ws.on_get_net_state(params)?,
             "net_statistics" => ws.on_get_net_statistics(params)?,
             "light/inputs" => ws.on_get_inputs(params)?,
+            "light/estimate_fees" => ws.on_estimate_fees(params)?,
+            "light/watch_address" => ws.on_watch_address(params)?,
             "light/get_history" => ws.on_get_history(params)?,
+            "light/get_full_history" => ws.on_get_full_history(params)?,
             "light/light_props" => ws.on_get_light_props(params)?,
             "light/get_link_proofs" => ws.on_get_link_proofs(params)?,
+            "light/get_feed_history" => ws.on_get_feed_history(params)?,
+            "light/get_data_feed" => ws.on_get_data_feed(params)?,
+            "light/search_text" => ws.on_search_text(params)?,
+            "light/get_text" => ws.on_get_text_payload(params)?,
+            "light/get_balance_details" => ws.on_get_balance_details(params)?,
+            "light/get_address_stats" => ws.on_get_address_stats(params)?,
+            "light/get_balance_at_mci" => ws.on_get_balance_at_mci(params)?,
+            "light/get_unconfirmed_inputs" => ws.on_get_unconfirmed_inputs(params)?,
+            "light/get_last_stable" => ws.on_get_last_stable(params)?,
+            "light/subscribe_mci" => HubConn::on_subscribe_mci(&ws, params)?,
+            "light/subscribe_joints" => ws.on_subscribe_joints(params)?,
+            "light/unsubscribe_joints" => ws.on_unsubscribe_joints(params)?,
+            "subscribe_to_mci" => HubConn::on_subscribe_to_mci(&ws, params)?,
             "get_joint" => ws.on_get_joint(params)?,
+            "get_joint_proof" => ws.on_get_joint_proof(params)?,
             "get_peers" => ws.on_get_peers(params)?,
             "get_text" => ws.on_get_text(params)?,
             "get_balance" => ws.on_get_balance(params)?,
@@ -380,6 +718,7 @@ impl Server<HubData> for HubData {
             "get_free_joints" => ws.on_get_free_joints(params)?,
             "get_joints_info" => ws.on_get_joints_info(params)?,
             "get_network_info" => ws.on_get_network_info(params)?,
+            "get_network_health" => ws.on_get_network_health(params)?,
             "get_joints_by_mci" => ws.on_get_joints_by_mci(params)?,
             "get_missing_joints" => ws.on_get_missing_joints(params)?,
             "get_bad_joints" => ws.on_get_bad_joints(params)?,
@@ -389,6 +728,16 @@ impl Server<HubData> for HubData {
             "get_children" => ws.on_get_children(params)?,
             "get_tps" => ws.on_get_tps(params)?,
             "watch" => ws.on_watch(params)?,
+            "get_pending_count" => ws.on_get_pending_count(params)?,
+            "get_connection_limits" => ws.on_get_connection_limits(params)?,
+            "get_joint_cone" => ws.on_get_joint_cone(params)?,
+            "get_children_recursive" => ws.on_get_children_recursive(params)?,
+            "get_ancestors" => ws.on_get_ancestors(params)?,
+            "get_double_spends" => ws.on_get_double_spends(params)?,
+            "debug/export_business_state" => ws.on_export_business_state(params)?,
+            "debug/import_business_state" => ws.on_import_business_state(params)?,
+            "get_checkpoint" => ws.on_get_checkpoint(params)?,
+            "post_checkpoint" => ws.on_post_checkpoint(params)?,
 
             command => bail!("on_request unknown command: {}", command),
         };
@@ -412,6 +761,27 @@ impl HubConn {
         data.is_subscribed.store(true, Ordering::Relaxed);
     }
 
+    pub fn is_joints_subscribed(&self) -> bool {
+        let data = self.get_data();
+        data.is_joints_subscribed.load(Ordering::Relaxed)
+    }
+
+    fn set_joints_subscribed(&self, is_subscribed: bool) {
+        let data = self.get_data();
+        data.is_joints_subscribed
+            .store(is_subscribed, Ordering::Relaxed);
+    }
+
+    /// the outbound connection to a configured hub_url is a trusted source
+    fn is_source(&self) -> bool {
+        !self.is_inbound() && is_addr_of_urls(self.get_peer_addr(), &config::get_remote_hub_url())
+    }
+
+    /// the debug requests are only served for the local connections
+    fn is_local(&self) -> bool {
+        get_peer_ip(self).map_or(false, |ip| ip.is_loopback())
+    }
+
     pub fn is_inbound(&self) -> bool {
         let data = self.get_data();
         data.is_inbound.load(Ordering::Relaxed)
@@ -446,6 +816,83 @@ impl HubConn {
             data.listen_addr.set(addr);
         }
     }
+
+    /// the address that we could connect to, the inbound peer is known by its listen address
+    fn get_peer_host(&self) -> Option<String> {
+        if self.is_inbound() {
+            self.get_listen_addr()
+        } else {
+            Some(self.get_peer_addr().to_owned())
+        }
+    }
+
+    fn set_joints_requested(&self) {
+        *self.get_data().joints_requested_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// if we are waiting for the joints requested from the peer
+    fn is_joints_requested(&self) -> bool {
+        let timeout = Duration::from_millis(config::REQUESTED_JOINT_BATCH_TIMEOUT_MS);
+        match *self.get_data().joints_requested_at.lock().unwrap() {
+            Some(t) => t.elapsed() < timeout,
+            None => false,
+        }
+    }
+
+    fn set_host_verified(&self) {
+        let data = self.get_data();
+        data.is_host_verified.store(true, Ordering::Relaxed);
+    }
+
+    // the peer host that is known to be reachable, not just reported by the peer
+    fn get_verified_host(&self) -> Option<String> {
+        if self.get_data().is_host_verified.load(Ordering::Relaxed) {
+            self.get_peer_host()
+        } else {
+            None
+        }
+    }
+
+    // save the time that the peer was last active
+    fn save_peer_seen(&self) {
+        if let Some(addr) = self.get_verified_host() {
+            let idle_ms = duration_to_ms(self.get_last_recv_tm().elapsed());
+            let last_seen = ::time::now().saturating_sub(idle_ms);
+            t!(save_peer_host(&addr, last_seen, self.get_reputation()));
+        }
+    }
+
+    fn watch_address(&self, address: String) -> Result<()> {
+        let mut watched = self.get_data().watched_addresses.write().unwrap();
+        if watched.len() >= MAX_WATCHED_ADDRESSES && !watched.contains(&address) {
+            bail!("too many watched addresses, max={}", MAX_WATCHED_ADDRESSES);
+        }
+        info!("peer_id={} watch address {}", self.get_peer_id(), address);
+        watched.insert(address);
+        Ok(())
+    }
+
+    /// if the light client watches any of the addresses
+    pub fn is_watching(&self, addresses: &[&String]) -> bool {
+        let watched = self.get_data().watched_addresses.read().unwrap();
+        addresses.iter().any(|a| watched.contains(a.as_str()))
+    }
+
+    /// if the peer could handle the joint_batch message
+    fn is_joint_batch_supported(&self) -> bool {
+        self.get_data().feature_version.load(Ordering::Relaxed)
+            >= config::JOINT_BATCH_FEATURE_VERSION
+    }
+
+    pub fn get_reputation(&self) -> i32 {
+        self.get_data().reputation.load(Ordering::Relaxed)
+    }
+
+    fn set_reputation(&self, reputation: i32) {
+        self.get_data()
+            .reputation
+            .store(reputation, Ordering::Relaxed);
+    }
 }
 
 // the server side impl
@@ -461,6 +908,12 @@ impl HubConn {
             self.close();
         }
 
+        // the old peers don't have the feature version
+        let feature_version = version["feature_version"].as_u64().unwrap_or(0);
+        self.get_data()
+            .feature_version
+            .store(feature_version as u32, Ordering::Relaxed);
+
         info!("got peer version: {}", version);
         Ok(())
     }
@@ -474,6 +927,124 @@ impl HubConn {
         Ok(json!({"address": addr, "balance": balance}))
     }
 
+    fn on_get_balance_details(&self, param: Value) -> Result<Value> {
+        let addr = param
+            .as_str()
+            .ok_or_else(|| format_err!("no address for get_balance_details"))?;
+        let details = light::get_balance_with_utxo_details(addr)?;
+
+        Ok(serde_json::to_value(details)?)
+    }
+
+    fn on_get_balance_at_mci(&self, param: Value) -> Result<Value> {
+        let addr = param["address"]
+            .as_str()
+            .ok_or_else(|| format_err!("no address for get_balance_at_mci"))?;
+        ensure!(object_hash::is_chash_valid(addr), "address not valid");
+        let mci = param["mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("no mci for get_balance_at_mci"))?;
+        let balance = BUSINESS_CACHE.get_balance_at_mci(addr, Level::new(mci as usize))?;
+
+        Ok(json!({"address": addr, "mci": mci, "balance": balance}))
+    }
+
+    fn on_get_unconfirmed_inputs(&self, param: Value) -> Result<Value> {
+        let addr = param
+            .as_str()
+            .ok_or_else(|| format_err!("no address for get_unconfirmed_inputs"))?;
+        ensure!(object_hash::is_chash_valid(addr), "address not valid");
+        let spends = BUSINESS_CACHE.get_pending_spends(addr);
+
+        Ok(serde_json::to_value(spends)?)
+    }
+
+    fn on_get_address_stats(&self, param: Value) -> Result<Value> {
+        let addr = param
+            .as_str()
+            .ok_or_else(|| format_err!("no address for get_address_stats"))?;
+        let stats = BUSINESS_CACHE.get_address_stats(addr)?;
+
+        Ok(serde_json::to_value(stats)?)
+    }
+
+    fn on_get_last_stable(&self, _param: Value) -> Result<Value> {
+        Ok(json!({ "last_stable_mci": main_chain::get_last_stable_mci().value() }))
+    }
+
+    /// push "mci_reached" to the client when the target mci become stable
+    /// return `reached` directly if the target mci is already stable
+    fn on_subscribe_mci(ws: &Arc<HubConn>, param: Value) -> Result<Value> {
+        let target_mci = param["target_mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("no target_mci"))? as usize;
+
+        let last_stable_mci = main_chain::get_last_stable_mci().value();
+        if last_stable_mci >= target_mci {
+            return Ok(json!({ "reached": true, "mci": last_stable_mci }));
+        }
+
+        HubConn::add_mci_sub(ws, Level::new(target_mci), None)?;
+        Ok(json!({ "reached": false, "mci": last_stable_mci }))
+    }
+
+    /// push "mci_stable" with the subscription id once the mci become stable
+    /// the request is acked at once, the subscriptions are dropped with the connection
+    fn on_subscribe_to_mci(ws: &Arc<HubConn>, param: Value) -> Result<Value> {
+        let mci = param["mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("no mci for subscribe_to_mci"))? as usize;
+        let id = param["id"]
+            .as_str()
+            .ok_or_else(|| format_err!("no id for subscribe_to_mci"))?;
+
+        HubConn::add_mci_sub(ws, Level::new(mci), Some(id.to_owned()))?;
+        Ok(json!("ack"))
+    }
+
+    // all the mci subscriptions of the connection are pushed by one MciStableEvent handler
+    fn add_mci_sub(ws: &Arc<HubConn>, mci: Level, id: Option<String>) -> Result<()> {
+        use main_chain::MciStableEvent;
+        use utils::event::Event;
+
+        let data = ws.get_data();
+        {
+            let mut subs = data.pending_mci_subs.lock().unwrap();
+            if subs.len() >= MAX_PENDING_MCI_SUBS {
+                bail!(
+                    "too many pending mci subscriptions, max={}",
+                    MAX_PENDING_MCI_SUBS
+                );
+            }
+            // the guard is only set under the lock, so the handler is registered once
+            if data.mci_stable_guard.get().is_none() {
+                let subs = data.pending_mci_subs.clone();
+                let ws_c = Arc::downgrade(ws);
+                data.mci_stable_guard
+                    .set(MciStableEvent::add_handler_guard(move |v| {
+                        let reached = take_reached_mci_subs(&subs, v.mci);
+                        if reached.is_empty() {
+                            return;
+                        }
+                        if let Some(ws) = ws_c.upgrade() {
+                            try_go!(move || ws.send_mci_stable(reached));
+                        }
+                    }));
+            }
+            subs.push((mci, id));
+        }
+
+        // the mci may already be stable before the subscription is added
+        let reached =
+            take_reached_mci_subs(&data.pending_mci_subs, main_chain::get_last_stable_mci());
+        if !reached.is_empty() {
+            let ws = ws.clone();
+            try_go!(move || ws.send_mci_stable(reached));
+        }
+
+        Ok(())
+    }
+
     fn on_get_text(&self, param: Value) -> Result<Value> {
         let unit = param.as_str().ok_or_else(|| format_err!("wrong address"))?;
 
@@ -494,6 +1065,12 @@ impl HubConn {
         Ok(serde_json::to_value(ret)?)
     }
 
+    fn on_estimate_fees(&self, param: Value) -> Result<Value> {
+        let request: light::EstimateFeesRequest = serde_json::from_value(param)?;
+
+        Ok(serde_json::to_value(light::estimate_fees(&request))?)
+    }
+
     fn on_get_joints_info(&self, _param: Value) -> Result<Value> {
         Ok(json!(light::NumOfUnit {
             valid_unit: SDAG_CACHE.get_num_of_normal_joints(),
@@ -546,6 +1123,11 @@ impl HubConn {
         );
         self.set_subscribed();
         self.set_peer_id(peer_id);
+        if is_banned_peer(&self.get_peer_id()) {
+            statistics::increase_counter("sdag_banned_inbound_rejected_total");
+            self.close();
+            bail!("reject banned peer_id={}", peer_id);
+        }
         if let Some(ws) = WSS.get_connection(self.get_peer_id()) {
             let peer_addr = ws.get_peer_addr();
             if peer_addr != self.get_peer_addr() {
@@ -561,6 +1143,22 @@ impl HubConn {
         // get listen address
         let listen_addr = param["listen_addr"].as_str();
         self.set_listen_addr(listen_addr.map(|s| s.to_owned()));
+        // the inbound peer could only be connected by its listen address
+        if let Some(addr) = listen_addr {
+            self.set_reputation(load_peer_reputation(addr));
+            // the reported address is only saved after we could dial back to it
+            let addr = addr.to_owned();
+            let peer_id = self.get_peer_id();
+            try_go!(move || {
+                let stream = TcpStream::connect(addr.as_str())?;
+                client_handshake(stream)?;
+                if let Some(ws) = WSS.get_connection(peer_id) {
+                    ws.set_host_verified();
+                    ws.save_peer_seen();
+                }
+                Ok(())
+            });
+        }
 
         // send some joint in a background task
         let last_mci = param["last_mci"].as_u64();
@@ -588,6 +1186,18 @@ impl HubConn {
         }))
     }
 
+    // the light client get the new joints without becoming a peer
+    fn on_subscribe_joints(&self, _param: Value) -> Result<Value> {
+        ensure!(self.is_inbound(), "light clients have to be inbound");
+        self.set_joints_subscribed(true);
+        Ok(Value::Null)
+    }
+
+    fn on_unsubscribe_joints(&self, _param: Value) -> Result<Value> {
+        self.set_joints_subscribed(false);
+        Ok(Value::Null)
+    }
+
     fn on_get_joint(&self, param: Value) -> Result<Value> {
         let unit: String = serde_json::from_value(param)?;
 
@@ -640,6 +1250,31 @@ impl HubConn {
         Ok(json!(joints))
     }
 
+    fn on_error(&self, param: Value) -> Result<()> {
+        error!("receive error: {}", param);
+
+        // the peer is too busy to handle our joints, it's a transient error
+        // resend our free joints list later, so that the peer could request the lost joints
+        if param.as_str() == Some("busy") {
+            use rand::{thread_rng, Rng};
+            let delay: u64 = thread_rng().gen_range(1000, 5000);
+            let ws = WSS.get_connection(self.get_peer_id()).ok_or_else(|| {
+                format_err!("failed to find connection, peer_id={}", self.get_peer_id())
+            })?;
+            try_go!(move || {
+                coroutine::sleep(Duration::from_millis(delay));
+                let free_joints = SDAG_CACHE.get_good_free_joints()?;
+                let free_units = free_joints
+                    .iter()
+                    .map(|j| j.key.to_string())
+                    .collect::<Vec<_>>();
+                ws.send_free_joint_list(&free_units)
+            });
+        }
+
+        Ok(())
+    }
+
     fn on_joint(&self, param: Value) -> Result<()> {
         let joint: Joint = serde_json::from_value(param)?;
         info!("receive a joint: {:?}", joint);
@@ -647,6 +1282,43 @@ impl HubConn {
         self.handle_online_joint(joint, false)
     }
 
+    fn on_joint_batch(&self, mut param: Value) -> Result<()> {
+        // check the length before parsing all the joints
+        let len = param["joints"]
+            .as_array()
+            .map(|joints| joints.len())
+            .ok_or_else(|| format_err!("no joints in batch"))?;
+        info!("receive a joint batch, len={}", len);
+        ensure!(
+            len <= config::MAX_JOINTS_PER_BATCH,
+            "too many joints in batch, len={}",
+            len
+        );
+        let joints: Vec<Joint> = serde_json::from_value(param["joints"].take())?;
+
+        // the joints we requested are not rate limited, or catchup would lose joints
+        let is_requested =
+            param["is_requested"].as_bool().unwrap_or(false) && self.is_joints_requested();
+
+        // one bad joint should not drop the rest
+        for joint in joints {
+            if joint.unit.unit.is_empty() {
+                error!("on_joint_batch: no unit");
+                continue;
+            }
+            let unit = joint.unit.unit.clone();
+            let ret = if is_requested {
+                self.add_online_joint(joint, false)
+            } else {
+                self.handle_online_joint(joint, false)
+            };
+            if let Err(e) = ret {
+                error!("on_joint_batch: unit={}, err={}", unit, e);
+            }
+        }
+        Ok(())
+    }
+
     fn on_catchup(&self, param: Value) -> Result<Value> {
         let catchup_req: catchup::CatchupReq = serde_json::from_value(param)?;
         let catchup_chain = catchup::prepare_catchup_chain(catchup_req)?;
@@ -684,8 +1356,20 @@ impl HubConn {
             return self.send_error(Value::from("address not valid"));
         }
 
-        // TODO: client should report it's interested address
-        unimplemented!()
+        if let Err(e) = self.watch_address(address) {
+            return self.send_error(Value::from(e.to_string()));
+        }
+        Ok(())
+    }
+
+    fn on_watch_address(&self, param: Value) -> Result<Value> {
+        ensure!(self.is_inbound(), "light clients have to be inbound");
+
+        let address: String = serde_json::from_value(param).context("not an address string")?;
+        ensure!(object_hash::is_chash_valid(&address), "address not valid");
+
+        self.watch_address(address)?;
+        Ok(Value::Null)
     }
 
     fn on_get_peers(&self, param: Value) -> Result<Value> {
@@ -717,11 +1401,84 @@ impl HubConn {
         Ok(serde_json::to_value(net_stats)?)
     }
 
+    fn on_export_business_state(&self, _param: Value) -> Result<Value> {
+        ensure!(
+            self.is_local(),
+            "export business state is only allowed locally"
+        );
+        Ok(serde_json::to_value(
+            BUSINESS_CACHE.export_state(Duration::from_secs(5))?,
+        )?)
+    }
+
+    fn on_import_business_state(&self, param: Value) -> Result<Value> {
+        ensure!(
+            self.is_local(),
+            "import business state is only allowed locally"
+        );
+        BUSINESS_CACHE.import_state(serde_json::from_value(param)?)?;
+        Ok(Value::Null)
+    }
+
+    // the checkpoint is only served to the subscribed full nodes
+    // it's exported once for CHECKPOINT_REFRESH_MCIS, the whole utxo set is serialized
+    fn on_get_checkpoint(&self, _: Value) -> Result<Value> {
+        ensure!(
+            self.is_subscribed(),
+            "checkpoint is only served to subscribed peers"
+        );
+
+        let last_stable_mci = main_chain::get_last_stable_mci();
+        let mut cache = CHECKPOINT_CACHE.lock().unwrap();
+        if let Some((mci, ref checkpoint)) = *cache {
+            if last_stable_mci.value() < mci.value() + CHECKPOINT_REFRESH_MCIS {
+                return Ok(checkpoint.clone());
+            }
+        }
+
+        let checkpoint = serde_json::to_value(SDAG_CACHE.export_checkpoint(last_stable_mci)?)?;
+        *cache = Some((last_stable_mci, checkpoint.clone()));
+        Ok(checkpoint)
+    }
+
+    fn on_post_checkpoint(&self, param: Value) -> Result<Value> {
+        ensure!(
+            self.is_source(),
+            "checkpoint is only accepted from the source hub"
+        );
+        SDAG_CACHE.import_checkpoint(serde_json::from_value(param)?)?;
+        Ok(Value::from("accepted"))
+    }
+
     fn on_get_witnesses(&self, _: Value) -> Result<Value> {
         use my_witness::MY_WITNESSES;
         Ok(serde_json::to_value(&*MY_WITNESSES)?)
     }
 
+    /// a peer found that a witness signed two conflicting joints
+    fn on_report_double_sign(&self, param: Value) -> Result<()> {
+        #[derive(Deserialize)]
+        struct DoubleSignReport {
+            address: String,
+            unit_a: String,
+            unit_b: String,
+        }
+
+        let report: DoubleSignReport = serde_json::from_value(param)?;
+        warn!(
+            "receive double sign report from peer_id={}, address={}, unit_a={}, unit_b={}",
+            self.get_peer_id(),
+            report.address,
+            report.unit_a,
+            report.unit_b
+        );
+        statistics::increase_counter("sdag_double_sign_reports_total");
+
+        // fetch the conflicting joints we don't have yet, so that our own
+        // serial check marks the nonserial one
+        self.request_new_missing_joints([report.unit_a, report.unit_b].iter())
+    }
+
     /// get free joint list from peers, request my lost free joints
     fn on_free_joint_list(&self, param: Value) -> Result<()> {
         // disable broadcast during catchup
@@ -748,12 +1505,26 @@ impl HubConn {
     }
 
     fn on_post_joint(&self, param: Value) -> Result<Value> {
+        if self.is_busy() {
+            bail!("busy");
+        }
+
         let joint: Joint = serde_json::from_value(param)?;
         info!("receive a posted joint: {:?}", joint);
 
+        let unit = joint.unit.unit.clone();
         self.handle_online_joint(joint, true)?;
 
-        Ok(Value::from("accepted"))
+        // the joint is already relayed, the poster must not send it again with other inputs
+        // the temp-bad joint is left to purge_old_temp_bad_free_joints if it stays temp-bad
+        let mut status = light::PostJointStatus::Accepted;
+        if let Ok(cached_joint) = SDAG_CACHE.get_joint(&unit) {
+            if cached_joint.read()?.get_sequence().is_temp_bad() {
+                status = light::PostJointStatus::TempBad;
+            }
+        }
+
+        Ok(serde_json::to_value(status)?)
     }
 
     fn on_get_history(&self, param: Value) -> Result<Value> {
@@ -768,16 +1539,112 @@ impl HubConn {
         Ok(serde_json::to_value(ret)?)
     }
 
-    fn on_get_link_proofs(&self, _params: Value) -> Result<Value> {
+    fn on_get_full_history(&self, param: Value) -> Result<Value> {
         if !self.is_inbound() {
             bail!("light clients have to be inbound");
         }
-        // let units: Vec<String> =
-        //     serde_json::from_value(params).context("prepare_Link_proofs.params is error")?;
-        // Ok(serde_json::to_value(light::prepare_link_proofs(
-        //     &units,
-        // )?)?)
-        Ok(json![null])
+
+        let request: light::FullHistoryRequest = serde_json::from_value(param)?;
+        ensure!(
+            object_hash::is_chash_valid(&request.address),
+            "address not valid"
+        );
+
+        let ret = light::get_full_address_history(&request)?;
+
+        Ok(serde_json::to_value(ret)?)
+    }
+
+    fn on_get_link_proofs(&self, params: Value) -> Result<Value> {
+        if !self.is_inbound() {
+            bail!("light clients have to be inbound");
+        }
+        let units: Vec<String> =
+            serde_json::from_value(params).context("prepare_link_proofs.params is error")?;
+        light::prepare_link_proofs(&units)
+    }
+
+    fn on_get_joint_proof(&self, param: Value) -> Result<Value> {
+        let unit = param["unit"]
+            .as_str()
+            .ok_or_else(|| format_err!("unit not in param"))?;
+        // prove to the last stable mci by default
+        let reference_mci = match param["reference_mci"].as_u64() {
+            Some(mci) => Level::from(mci as usize),
+            None => main_chain::get_last_stable_mci(),
+        };
+
+        let proof = light::prepare_joint_proof(unit, reference_mci)?;
+        Ok(serde_json::to_value(proof)?)
+    }
+
+    fn on_get_feed_history(&self, param: Value) -> Result<Value> {
+        let feed_name = param["feed_name"]
+            .as_str()
+            .ok_or_else(|| format_err!("feed_name not in param"))?;
+        let address = param["address"]
+            .as_str()
+            .ok_or_else(|| format_err!("address not in param"))?;
+        let from_mci = param["from_mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("from_mci not in param"))?;
+        let to_mci = param["to_mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("to_mci not in param"))?;
+
+        let history = business::data_feed::TimerCache::get_feed_history(
+            feed_name,
+            address,
+            Level::from(from_mci as usize),
+            Level::from(to_mci as usize),
+        )?;
+
+        Ok(serde_json::to_value(history)?)
+    }
+
+    fn on_get_data_feed(&self, param: Value) -> Result<Value> {
+        let feed_name = param["feed_name"]
+            .as_str()
+            .ok_or_else(|| format_err!("feed_name not in param"))?;
+        let address = param["address"]
+            .as_str()
+            .ok_or_else(|| format_err!("address not in param"))?;
+
+        // null if the feed is never posted by the address
+        Ok(BUSINESS_CACHE
+            .get_data_feed(address, feed_name)?
+            .unwrap_or(Value::Null))
+    }
+
+    fn on_search_text(&self, param: Value) -> Result<Value> {
+        let pattern = param["pattern"]
+            .as_str()
+            .ok_or_else(|| format_err!("pattern not in param"))?;
+        let limit = param["limit"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(config::MAX_TEXT_SEARCH_RESULTS);
+        ensure!(
+            limit <= config::MAX_TEXT_SEARCH_RESULTS,
+            "limit must not exceed {}",
+            config::MAX_TEXT_SEARCH_RESULTS
+        );
+        ensure!(!pattern.is_empty(), "pattern must not be empty");
+
+        let texts = business::text::TextCache::search(pattern, limit)?
+            .into_iter()
+            .map(|(unit, text)| json!({ "unit": unit, "text": text }))
+            .collect::<Vec<_>>();
+        Ok(Value::Array(texts))
+    }
+
+    fn on_get_text_payload(&self, param: Value) -> Result<Value> {
+        let unit = param
+            .as_str()
+            .ok_or_else(|| format_err!("no unit in get_text"))?;
+
+        // null if the unit has no inline text
+        Ok(json!(business::text::get_text_payload(unit)?))
     }
 
     fn on_get_network_info(&self, _param: Value) -> Result<Value> {
@@ -796,6 +1663,10 @@ impl HubConn {
         }))
     }
 
+    fn on_get_network_health(&self, _param: Value) -> Result<Value> {
+        Ok(serde_json::to_value(get_network_health())?)
+    }
+
     fn on_get_joints_by_mci(&self, param: Value) -> Result<Value> {
         let mci = param
             .as_i64()
@@ -831,9 +1702,11 @@ impl HubConn {
             .get_joint(&unit)
             .and_then(|j| j.read())
             .and_then(|j| {
+                let mut property = j.get_all_props().read().unwrap().clone();
+                property.dag_depth = j.dag_depth_from_genesis()?;
                 Ok(json!({
                     "joint": (**j).clone(),
-                    "property": &*j.get_all_props().read().unwrap()
+                    "property": property
                 }))
             })
     }
@@ -883,18 +1756,146 @@ impl HubConn {
 
         Ok(Value::Null)
     }
+
+    fn on_get_pending_count(&self, _param: Value) -> Result<Value> {
+        Ok(json!({
+            "pending_count": SDAG_CACHE.get_pending_count(),
+            "max_pending_joints": config::get_max_pending_joints(),
+        }))
+    }
+
+    fn on_get_connection_limits(&self, _param: Value) -> Result<Value> {
+        Ok(WSS.get_connection_limits())
+    }
+
+    fn on_get_joint_cone(&self, param: Value) -> Result<Value> {
+        let unit = param["unit"]
+            .as_str()
+            .ok_or_else(|| format_err!("no unit in get_joint_cone"))?;
+        let max_count = param["max_count"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(MAX_CONE_SIZE);
+
+        let cone_size = SDAG_CACHE.get_joint_cone_size(unit, max_count)?;
+        Ok(json!({ "unit": unit, "cone_size": cone_size }))
+    }
+
+    fn on_get_children_recursive(&self, param: Value) -> Result<Value> {
+        let unit = param["unit"]
+            .as_str()
+            .ok_or_else(|| format_err!("no unit in get_children_recursive"))?;
+        let max_depth = param["max_depth"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(config::MAX_CHILDREN_DEPTH);
+        ensure!(
+            max_depth <= config::MAX_CHILDREN_DEPTH,
+            "max_depth must not exceed {}",
+            config::MAX_CHILDREN_DEPTH
+        );
+
+        let children = SDAG_CACHE.get_joint_children_recursive(unit, max_depth)?;
+        Ok(json!({ "unit": unit, "children": children }))
+    }
+
+    fn on_get_ancestors(&self, param: Value) -> Result<Value> {
+        let unit = param["unit"]
+            .as_str()
+            .ok_or_else(|| format_err!("no unit in get_ancestors"))?;
+        let depth = param["depth"]
+            .as_u64()
+            .map(|v| v as usize)
+            .ok_or_else(|| format_err!("no depth in get_ancestors"))?;
+        ensure!(
+            depth <= config::MAX_ANCESTORS_DEPTH,
+            "depth must not exceed {}",
+            config::MAX_ANCESTORS_DEPTH
+        );
+
+        let mut ancestors = Vec::new();
+        for joint in SDAG_CACHE.get_joint_ancestors(unit, depth)? {
+            ancestors.push((**joint.read()?).clone());
+        }
+        Ok(json!({ "unit": unit, "ancestors": ancestors }))
+    }
+
+    fn on_get_double_spends(&self, param: Value) -> Result<Value> {
+        let unit: String = serde_json::from_value(param)?;
+        let conflicts = SDAG_CACHE.get_double_spend_joints(&unit)?;
+        Ok(serde_json::to_value(conflicts)?)
+    }
 }
 
 impl HubConn {
+    // too many pending joints, refuse new joints until they are consumed
+    fn is_busy(&self) -> bool {
+        if SDAG_CACHE.get_pending_count() <= config::get_max_pending_joints() {
+            return false;
+        }
+
+        statistics::increase_peer_counter("sdag_backpressure_events_total", &self.get_peer_id());
+        true
+    }
+
+    // the peer has too many unhandled joints, refuse its new joints until they are resolved
+    fn is_peer_busy(&self) -> bool {
+        if self.get_data().get_pending_from_peer() < config::get_max_pending_per_peer() {
+            return false;
+        }
+
+        statistics::increase_peer_counter("sdag_per_peer_backpressure_total", &self.get_peer_id());
+        true
+    }
+
+    // the peer has pushed too many joints in the last second, drop its new joints
+    fn is_peer_flooding(&self) -> bool {
+        let peer_id = self.get_peer_id();
+        if WSS.take_joint_token(&peer_id) {
+            return false;
+        }
+
+        statistics::increase_peer_counter("sdag_rate_limited_joints_total", &peer_id);
+        true
+    }
+
+    // handle the joint pushed by the peer, which is rate limited
     fn handle_online_joint(&self, joint: Joint, is_post: bool) -> Result<()> {
+        if self.is_peer_flooding() {
+            warn!(
+                "too many joints per second from peer {}, drop joint {}",
+                self.get_peer_id(),
+                joint.unit.unit
+            );
+            return Ok(());
+        }
+
+        self.add_online_joint(joint, is_post)
+    }
+
+    fn add_online_joint(&self, joint: Joint, is_post: bool) -> Result<()> {
         // clear the main chain index, main chain index is used by light only
         // joint.unit.main_chain_index = None;
 
+        if self.is_busy() {
+            warn!("too many pending joints, reject joint {}", joint.unit.unit);
+            return self.send_error(Value::from("busy"));
+        }
+
+        if self.is_peer_busy() {
+            warn!(
+                "too many pending joints from peer {}, reject joint {}",
+                self.get_peer_id(),
+                joint.unit.unit
+            );
+            return self.send_error(Value::from("too many pending joints"));
+        }
+
         // check content_hash or unit_hash first!
         validation::validate_unit_hash(&joint.unit)?;
 
         // check if unit is in work, when g is dropped unlock the unit
-        let g = UNIT_IN_WORK.try_lock(vec![joint.unit.unit.to_owned()]);
+        let g = UNIT_IN_WORK.try_lock_sorted(&[joint.unit.unit.to_owned()]);
         if g.is_none() {
             // the unit is in work, do nothing
             return Ok(());
@@ -909,6 +1910,8 @@ impl HubConn {
         };
         let joint_data = cached_joint.read().unwrap();
         joint_data.set_is_post(is_post);
+        // the source peer already has the joint
+        joint_data.mark_broadcast_to(BroadcastKind::Joint, self.get_peer_id());
 
         if let Some(ref hash) = joint_data.unit.content_hash {
             error!("unit {} content hash = {}", cached_joint.key, hash);
@@ -924,11 +1927,11 @@ impl HubConn {
             if !SDAG_CACHE.is_ball_in_hash_tree(ball) {
                 // need to catchup and keep the joint in unhandled till timeout
                 let ws = WSS.get_connection(self.get_peer_id()).unwrap();
-                go!(move || {
+                COROUTINE_REGISTRY.spawn(move || {
                     // if we already in catchup mode, just return
                     let _g = match IS_CATCHING_UP.try_lock() {
                         Some(g) => g,
-                        None => return,
+                        None => return Ok(()),
                     };
 
                     if let Err(e) = start_catchup(ws) {
@@ -936,6 +1939,7 @@ impl HubConn {
                     }
                     // after the catchup done, clear the hash tree ball
                     SDAG_CACHE.clear_hash_tree_ball();
+                    Ok(())
                 });
                 return Ok(());
             }
@@ -947,27 +1951,39 @@ impl HubConn {
         Ok(())
     }
 
-    // record peer event in database
-    #[allow(dead_code)]
-    fn write_event(&self, _event: &str) -> Result<()> {
-        // TODO: record peer event
-        // if event.contains("invalid") || event.contains("nonserial") {
-        //     let host = self.get_peer_addr();
-        //     let event_string: String = event.to_string();
-        //     let column = format!("count_{}_joints", &event_string);
-        //     let sql = format!(
-        //         "UPDATE peer_hosts SET {}={}+1 WHERE peer_host=?",
-        //         column, column
-        //     );
-        //     let mut stmt = db.prepare_cached(&sql)?;
-        //     stmt.execute(&[host])?;
-
-        //     let mut stmt =
-        //         db.prepare_cached("INSERT INTO peer_events (peer_host, event) VALUES (?, ?)")?;
-        //     stmt.execute(&[host, &event_string])?;
-        // }
+    // the reputation is saved to the peer db when the connection is closed
+    fn update_reputation(&self, delta: i32) {
+        let reputation = add_reputation(self.get_reputation(), delta);
+        self.set_reputation(reputation);
+        if delta > 0 {
+            self.get_data()
+                .reconnect_attempts
+                .store(0, Ordering::Relaxed);
+        }
+        if reputation < config::get_min_peer_reputation() {
+            self.ban();
+        }
+    }
 
-        Ok(())
+    // close the connection and refuse the peer for peer_ban_secs
+    fn ban(&self) {
+        warn!(
+            "ban peer {}, reputation={}",
+            self.get_peer_addr(),
+            self.get_reputation()
+        );
+        statistics::increase_peer_counter("sdag_banned_peers_total", &self.get_peer_id());
+
+        // the ip may be shared by other users behind the same NAT, ban the peer id only
+        let expire = Duration::from_secs(config::get_peer_ban_secs());
+        ban_peer(self.get_peer_id(), expire);
+        // and don't dial its address during the ban
+        if let Some(addr) = self.get_verified_host() {
+            add_bad_connection(addr, expire);
+        }
+        // the peer is on probation when the ban expires
+        self.set_reputation(config::get_min_peer_reputation());
+        self.close();
     }
 
     fn request_catchup(&self) -> Result<Vec<String>> {
@@ -998,7 +2014,7 @@ impl HubConn {
         let mut new_units = Vec::new();
 
         for unit in units {
-            let g = UNIT_IN_WORK.try_lock(vec![unit.clone()]);
+            let g = UNIT_IN_WORK.try_lock_sorted(&[unit.clone()]);
             if g.is_none() {
                 // other thread is working on the unit, skip it
                 debug!("request unit in working. unit={}", unit);
@@ -1045,6 +2061,27 @@ impl HubConn {
         self.send_just_saying("joint", serde_json::to_value(joint)?)
     }
 
+    /// send the joints in batches, one by one if the peer doesn't support it
+    fn send_joints_batch(&self, joints: &[&Joint], is_requested: bool) -> Result<()> {
+        if !self.is_joint_batch_supported() {
+            for joint in joints {
+                self.send_joint(joint)?;
+            }
+            return Ok(());
+        }
+
+        for batch in joints.chunks(config::MAX_JOINTS_PER_BATCH) {
+            for _ in batch {
+                statistics::increase_stats(self.get_peer_id(), false, true);
+            }
+            self.send_just_saying(
+                "joint_batch",
+                json!({ "joints": batch, "is_requested": is_requested }),
+            )?;
+        }
+        Ok(())
+    }
+
     fn send_free_joint_list(&self, free_units: &[String]) -> Result<()> {
         self.send_just_saying("free_joint_list", serde_json::to_value(free_units)?)
     }
@@ -1069,20 +2106,19 @@ impl HubConn {
         }
 
         // only send latest stable joints
+        let mut joints = Vec::new();
         for joint in SDAG_CACHE.get_joints_by_mci(last_stable_mci)? {
-            self.send_joint(&clear_ball_after_min_retrievable_mci(&*joint.read()?)?)?;
+            joints.push(clear_ball_after_min_retrievable_mci(&*joint.read()?)?);
         }
-
-        Ok(())
+        self.send_joints_batch(&joints.iter().collect::<Vec<_>>(), true)
     }
 
     fn send_free_joints(&self) -> Result<()> {
-        let joints = SDAG_CACHE.get_good_free_joints()?;
-        for joint in joints {
-            let joint = joint.read()?;
-            self.send_joint(&**joint)?;
+        let mut joints = Vec::new();
+        for joint in SDAG_CACHE.get_good_free_joints()? {
+            joints.push(joint.read()?);
         }
-        Ok(())
+        self.send_joints_batch(&joints.iter().map(|j| &***j).collect::<Vec<_>>(), true)
     }
 }
 
@@ -1093,6 +2129,7 @@ impl HubConn {
             "version",
             json!({
                 "protocol_version": config::VERSION,
+                "feature_version": config::FEATURE_VERSION,
                 "alt": config::ALT,
                 "library": config::LIBRARY,
                 "library_version": config::LIBRARY_VERSION,
@@ -1105,6 +2142,8 @@ impl HubConn {
 
     fn send_subscribe(&self) -> Result<()> {
         let last_mci = main_chain::get_last_stable_mci();
+        // the peer replies with its stable and free joints
+        self.set_joints_requested();
 
         match self.send_request(
             "subscribe",
@@ -1121,6 +2160,9 @@ impl HubConn {
                         if self.get_peer_id() == *UNKNOWN_PEER_ID {
                             self.set_peer_id(peer_id);
                         }
+                        if is_banned_peer(&self.get_peer_id()) {
+                            bail!("peer_id={} is banned", peer_id);
+                        }
                     }
                     // the client must send it peer id back
                     None => bail!("no peer_id set in response of subscribe"),
@@ -1132,7 +2174,7 @@ impl HubConn {
             }
             Err(e) => {
                 // save the peer address to avoid connect to it again
-                BAD_CONNECTION.insert(self.get_peer_addr().to_string(), ());
+                add_bad_connection(self.get_peer_addr().to_string(), BAD_CONNECTION_EXPIRE);
                 bail!(
                     "send subscribe failed, err={}, peer={}",
                     e,
@@ -1145,7 +2187,9 @@ impl HubConn {
     }
 
     fn send_heartbeat(&self) -> Result<()> {
+        let start = Instant::now();
         self.send_request("heartbeat", &Value::Null)?;
+        statistics::record_peer_latency(&self.get_peer_id(), duration_to_ms(start.elapsed()));
         Ok(())
     }
 
@@ -1157,21 +2201,44 @@ impl HubConn {
     // remove self from global
     fn close(&self) {
         info!("close connection: {}", self.get_peer_addr());
+        // cancel the mci subscriptions, nothing would be pushed to a closed connection
+        self.get_data().pending_mci_subs.lock().unwrap().clear();
         // we hope that when all related joints are resolved
         // the connection could drop automatically
-        WSS.close(self);
+        let removed = WSS.close(self);
+        if removed {
+            self.save_peer_seen();
+        }
+        if removed && !self.is_inbound() {
+            let attempts = self.get_data().reconnect_attempts.load(Ordering::Relaxed);
+            schedule_reconnect(self.get_peer_addr().to_owned(), attempts);
+        }
+    }
+
+    fn send_mci_stable(&self, subs: Vec<(Level, Option<String>)>) -> Result<()> {
+        for (mci, id) in subs {
+            match id {
+                Some(id) => {
+                    self.send_just_saying("mci_stable", json!({ "mci": mci.value(), "id": id }))?
+                }
+                None => self.send_just_saying("mci_reached", json!({ "mci": mci.value() }))?,
+            }
+        }
+        Ok(())
     }
 
     fn request_joints(&self, units: impl IntoIterator<Item = String>) -> Result<()> {
         fn request_joint(ws: Arc<HubConn>, unit: &str) -> Result<()> {
             // if the joint is in request, just ignore
-            let g = JOINT_IN_REQ.try_lock(vec![unit.to_owned()]);
+            let g = JOINT_IN_REQ.try_lock_sorted(&[unit.to_owned()]);
             if g.is_none() {
                 debug!("already request_joint: {}", unit);
                 return Ok(());
             }
 
+            let start = Instant::now();
             let mut v = ws.send_request("get_joint", &Value::from(unit))?;
+            statistics::record_peer_latency(&ws.get_peer_id(), duration_to_ms(start.elapsed()));
             if v["joint_not_found"].as_str() == Some(&unit) {
                 // TODO: if self connection failed to request joint, should
                 // let available ws to try a again here. see #72
@@ -1191,7 +2258,8 @@ impl HubConn {
             }
             drop(g);
 
-            ws.handle_online_joint(joint, false)
+            // requested joints are not rate limited, or catchup would be throttled
+            ws.add_online_joint(joint, false)
         }
 
         let ws = WSS.get_connection(self.get_peer_id()).ok_or_else(|| {
@@ -1200,7 +2268,7 @@ impl HubConn {
 
         for unit in units {
             let ws = ws.clone();
-            try_go!(move || request_joint(ws, &unit));
+            COROUTINE_REGISTRY.spawn(move || request_joint(ws, &unit));
         }
         Ok(())
     }
@@ -1222,6 +2290,53 @@ impl HubConn {
     }
 }
 
+//---------------------------------------------------------------------------------------
+// ReconnectScheduler
+//---------------------------------------------------------------------------------------
+struct Backoff {
+    next_allowed: Instant,
+    failures: u32,
+}
+
+// auto connection would skip the failed peers until their backoff expired
+struct ReconnectScheduler {
+    // <peer_addr, backoff>
+    peers: RwLock<HashMap<String, Backoff>>,
+}
+
+impl ReconnectScheduler {
+    fn new() -> Self {
+        ReconnectScheduler {
+            peers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn is_allowed(&self, peer: &str, now: Instant) -> bool {
+        match self.peers.read().unwrap().get(peer) {
+            Some(backoff) => backoff.next_allowed <= now,
+            None => true,
+        }
+    }
+
+    // double the wait on each failure, start from 1s and capped at 5min
+    fn on_failure(&self, peer: &str, now: Instant) {
+        let mut g = self.peers.write().unwrap();
+        let failures = g.get(peer).map_or(0, |b| b.failures);
+        let delay = get_reconnect_delay(failures, RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY, 0);
+        g.insert(
+            peer.to_owned(),
+            Backoff {
+                next_allowed: now + delay,
+                failures: failures.saturating_add(1),
+            },
+        );
+    }
+
+    fn on_success(&self, peer: &str) {
+        self.peers.write().unwrap().remove(peer);
+    }
+}
+
 //---------------------------------------------------------------------------------------
 // Global Functions
 //---------------------------------------------------------------------------------------
@@ -1234,6 +2349,30 @@ pub fn broadcast_free_joint_list() {
     }
 }
 
+/// record the process start time for the uptime in the health report
+pub fn record_start_time() {
+    ::lazy_static::initialize(&START_TIME);
+}
+
+pub fn get_network_health() -> HealthReport {
+    let missing_joints = SDAG_CACHE.get_missing_joints().len();
+    let temp_bad_joints = SDAG_CACHE.get_temp_bad_joints().len();
+    let peer_count = WSS.get_peer_count();
+
+    HealthReport {
+        // a node without any peer can't know whether it's behind the network
+        is_synced: peer_count > 0
+            && !IS_CATCHING_UP.is_locked()
+            && missing_joints == 0
+            && temp_bad_joints < config::MAX_TEMP_BAD_THRESHOLD,
+        last_stable_mci: main_chain::get_last_stable_mci().value(),
+        missing_joints,
+        temp_bad_joints,
+        peer_count,
+        uptime_secs: START_TIME.elapsed().as_secs(),
+    }
+}
+
 pub fn auto_connection() {
     let mut counts = WSS.get_needed_outbound_peers();
     if counts == 0 {
@@ -1242,7 +2381,10 @@ pub fn auto_connection() {
 
     let peers = get_unconnected_peers_in_config();
     for peer in peers {
-        match create_outbound_conn(&peer) {
+        if !RECONNECT_SCHEDULER.is_allowed(&peer, Instant::now()) {
+            continue;
+        }
+        match connect_with_backoff(&peer) {
             Ok(_) => {
                 counts -= 1;
                 if counts == 0 {
@@ -1255,10 +2397,10 @@ pub fn auto_connection() {
 
     let peers = get_unconnected_remote_peers();
     for peer in peers {
-        if BAD_CONNECTION.get(&peer).is_some() {
+        if is_bad_connection(&peer) || !RECONNECT_SCHEDULER.is_allowed(&peer, Instant::now()) {
             continue;
         }
-        match create_outbound_conn(&peer) {
+        match connect_with_backoff(&peer) {
             Ok(_) => {
                 counts -= 1;
                 if counts == 0 {
@@ -1271,10 +2413,10 @@ pub fn auto_connection() {
 
     let peers = get_unconnected_peers_in_db();
     for peer in peers {
-        if BAD_CONNECTION.get(&peer).is_some() {
+        if is_bad_connection(&peer) || !RECONNECT_SCHEDULER.is_allowed(&peer, Instant::now()) {
             continue;
         }
-        match create_outbound_conn(&peer) {
+        match connect_with_backoff(&peer) {
             Ok(_) => {
                 counts -= 1;
                 if counts == 0 {
@@ -1286,25 +2428,108 @@ pub fn auto_connection() {
     }
 }
 
+/// apply the reloaded hub_url and max_outbound_connections
+pub fn on_config_changed(diff: &config::ConfigDiff) {
+    for url in &diff.removed_hub_urls {
+        let closed = WSS.close_by_address(url);
+        info!("hub_url {} removed, closed {} connections", url, closed);
+    }
+
+    for url in &diff.added_hub_urls {
+        if WSS.contains(url) {
+            continue;
+        }
+        if let Err(e) = create_outbound_conn(url) {
+            error!("failed to connect to new config peer={}, err={}", url, e);
+        }
+    }
+
+    if diff.max_outbound_connections.is_some() {
+        auto_connection();
+    }
+
+    if let Some(size) = diff.bad_connection_cache_size {
+        BAD_CONNECTION.resize(size);
+    }
+}
+
 pub fn create_outbound_conn<A: ToSocketAddrs>(address: A) -> Result<Arc<HubConn>> {
     let stream = TcpStream::connect(address)?;
     let peer = match stream.peer_addr() {
         Ok(addr) => addr.to_string(),
         Err(_) => "unknown peer".to_owned(),
     };
-    let url = Url::parse("wss://localhost/")?;
-    let req = Request::from(url);
-    let (conn, _) = client(req, stream)?;
+    let (conn, compress) = client_handshake(stream)?;
 
-    let ws = WsConnection::new(conn, HubData::default(), peer, Role::Client)?;
+    let ws = WsConnection::new(conn, HubData::default(), peer, Role::Client, compress)?;
 
     WSS.add_p2p_conn(ws.clone(), false)?;
     Ok(ws)
 }
 
+// connect to the peer and record the result in the reconnect scheduler
+fn connect_with_backoff(peer: &str) -> Result<Arc<HubConn>> {
+    let ret = create_outbound_conn(peer);
+    match ret {
+        Ok(_) => RECONNECT_SCHEDULER.on_success(peer),
+        Err(_) => RECONNECT_SCHEDULER.on_failure(peer, Instant::now()),
+    }
+    ret
+}
+
+// reconnect the closed outbound peer with exponential backoff
+fn schedule_reconnect(addr: String, mut attempts: u32) {
+    use rand::{thread_rng, Rng};
+
+    go!(move || loop {
+        if is_bad_connection(&addr) {
+            info!("skip reconnect to bad peer {}", addr);
+            return;
+        }
+
+        let jitter = thread_rng().gen_range(0, duration_to_ms(RECONNECT_BASE_DELAY));
+        let delay =
+            get_reconnect_delay(attempts, RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY, jitter);
+        coroutine::sleep(delay);
+
+        // the auto connection timer may already connected it
+        if WSS.contains(&addr) {
+            return;
+        }
+
+        statistics::increase_peer_counter("sdag_reconnect_attempts_total", &addr);
+        match create_outbound_conn(&addr) {
+            Ok(ws) => {
+                attempts = attempts.saturating_add(1);
+                info!("reconnected to {} after {} attempts", addr, attempts);
+                RECONNECT_SCHEDULER.on_success(&addr);
+                ws.get_data()
+                    .reconnect_attempts
+                    .store(attempts, Ordering::Relaxed);
+                return;
+            }
+            Err(e) => {
+                warn!("reconnect to {} failed, err={}", addr, e);
+                attempts = attempts.saturating_add(1);
+            }
+        }
+    });
+}
+
+// base * 2^attempts + jitter, capped at max
+fn get_reconnect_delay(attempts: u32, base: Duration, max: Duration, jitter_ms: u64) -> Duration {
+    let base_ms = duration_to_ms(base);
+    let max_ms = duration_to_ms(max);
+    let backoff = 1u64
+        .checked_shl(attempts)
+        .and_then(|n| base_ms.checked_mul(n))
+        .unwrap_or(max_ms);
+    Duration::from_millis(::std::cmp::min(backoff.saturating_add(jitter_ms), max_ms))
+}
+
 /// remove those long time not ready joints
 pub fn purge_junk_unhandled_joints(timeout: u64) {
-    let now = crate::time::now();
+    let now = crate::time::monotonic_now();
 
     // maybe we are catching up the missing parents
     let _g = match IS_CATCHING_UP.try_lock() {
@@ -1318,7 +2543,7 @@ pub fn purge_junk_unhandled_joints(timeout: u64) {
 
 /// remove those long time temp-bad free joints
 pub fn purge_temp_bad_free_joints(timeout: u64) -> Result<()> {
-    let now = crate::time::now();
+    let now = crate::time::monotonic_now();
     SDAG_CACHE.purge_old_temp_bad_free_joints(now, timeout)
 }
 
@@ -1337,7 +2562,7 @@ pub fn re_request_lost_joints() -> Result<()> {
         return Ok(());
     }
 
-    let ws = match WSS.get_next_peer() {
+    let ws = match WSS.get_peers_sorted_by_latency().into_iter().next() {
         None => bail!("failed to find next peer"),
         Some(c) => c,
     };
@@ -1346,14 +2571,69 @@ pub fn re_request_lost_joints() -> Result<()> {
     // this is not an atomic operation, but it's fine to request the unit in working
     let new_units = units
         .into_iter()
-        .filter(|x| UNIT_IN_WORK.try_lock(vec![(*x).to_owned()]).is_some())
+        .filter(|x| UNIT_IN_WORK.try_lock_sorted(&[(*x).to_owned()]).is_some())
         .collect::<Vec<_>>();
 
     info!("lost units {:?}", new_units);
 
+    // request from the lowest latency peer
+    increase_selection_counter("lowest");
     ws.request_joints(new_units)
 }
 
+fn increase_selection_counter(strategy: &str) {
+    statistics::increase_counter(&format!(
+        "sdag_peer_latency_selection_total{{strategy=\"{}\"}}",
+        strategy
+    ));
+}
+
+// sort the peers by the ascending latency, the unknown ones are the last
+fn sort_peers_by_latency<T>(mut peers: Vec<(T, Option<u64>)>) -> Vec<T> {
+    peers.sort_by_key(|(_, latency)| latency.unwrap_or(u64::MAX));
+    peers.into_iter().map(|(p, _)| p).collect()
+}
+
+// replace the unknown latencies with the worst known one
+// return None if no latency is known
+fn fill_unknown_latencies(latencies: &[Option<u64>]) -> Option<Vec<u64>> {
+    let worst = latencies.iter().filter_map(|l| *l).max()?;
+    Some(latencies.iter().map(|l| l.unwrap_or(worst)).collect())
+}
+
+// select an index with the weight `1 / (latency + LATENCY_SMOOTHING_MS)`
+// r is a random number in [0, 1)
+fn weighted_select_by_latency(latencies: &[u64], r: f64) -> Option<usize> {
+    let weights = latencies
+        .iter()
+        .map(|l| 1.0 / (*l + LATENCY_SMOOTHING_MS) as f64)
+        .collect::<Vec<_>>();
+    let mut target = r * weights.iter().sum::<f64>();
+    for (i, w) in weights.iter().enumerate() {
+        if target < *w {
+            return Some(i);
+        }
+        target -= w;
+    }
+    // the float error may fall out of the range
+    if weights.is_empty() {
+        None
+    } else {
+        Some(weights.len() - 1)
+    }
+}
+
+// remove and return the mci subscriptions that are reached by the stable mci
+fn take_reached_mci_subs(
+    subs: &Mutex<Vec<(Level, Option<String>)>>,
+    mci: Level,
+) -> Vec<(Level, Option<String>)> {
+    let mut subs = subs.lock().unwrap();
+    let (reached, pending) = subs.drain(..).partition(|sub| sub.0 <= mci);
+    *subs = pending;
+    reached
+}
+
 pub fn notify_watchers_about_stable_joints(mci: Level) -> Result<()> {
     use joint::WRITER_MUTEX;
     // the event was emitted from inside mysql transaction, make sure it completes so that the changes are visible
@@ -1368,7 +2648,7 @@ pub fn notify_watchers_about_stable_joints(mci: Level) -> Result<()> {
     }
 
     let last_ball_mci = SDAG_CACHE.get_last_ball_mci_of_mci(mci)?;
-    let prev_last_ball_mci = SDAG_CACHE.get_last_ball_mci_of_mci((mci.value() - 1).into())?;
+    let prev_last_ball_mci = SDAG_CACHE.get_last_ball_mci_of_mci(mci.saturating_sub(1))?;
 
     if last_ball_mci == prev_last_ball_mci {
         return Ok(());
@@ -1389,6 +2669,7 @@ fn init_connection(ws: &Arc<HubConn>) -> Result<()> {
     let mut rng = thread_rng();
     let n: u64 = rng.gen_range(0, 1000);
     let ws_c = Arc::downgrade(ws);
+    let mut last_saved = Instant::now();
 
     // start the heartbeat timer for each connection
     go!(move || loop {
@@ -1397,6 +2678,10 @@ fn init_connection(ws: &Arc<HubConn>) -> Result<()> {
             Some(ws) => ws,
             None => return,
         };
+        if last_saved.elapsed() >= PEER_SEEN_SAVE_INTERVAL {
+            ws.save_peer_seen();
+            last_saved = Instant::now();
+        }
         if ws.get_last_recv_tm().elapsed() < Duration::from_secs(5) {
             continue;
         }
@@ -1412,11 +2697,272 @@ fn init_connection(ws: &Arc<HubConn>) -> Result<()> {
     Ok(())
 }
 
-fn add_peer_host(_bound: &HubConn) -> Result<()> {
-    // TODO: impl save peer host to database
+fn add_peer_host(conn: &HubConn, is_inbound: bool) -> Result<()> {
+    // the inbound peer address is a temp port, the listen address is saved on subscribe
+    if is_inbound {
+        return Ok(());
+    }
+    conn.set_host_verified();
+    conn.set_reputation(load_peer_reputation(conn.get_peer_addr()));
+    save_peer_host(conn.get_peer_addr(), ::time::now(), conn.get_reputation())
+}
+
+fn save_peer_host(addr: &str, last_seen: u64, reputation: i32) -> Result<()> {
+    let record = PeerRecord {
+        last_seen,
+        reputation,
+    };
+    KV_STORE.save_peer(addr, &record)
+}
+
+// the reputation saved in the peer db, the new peer starts from 0
+fn load_peer_reputation(addr: &str) -> i32 {
+    match KV_STORE.read_peer(addr) {
+        Ok(record) => record.map_or(0, |record| record.reputation),
+        Err(e) => {
+            error!("failed to read peer {}, err={}", addr, e);
+            0
+        }
+    }
+}
+
+fn add_reputation(reputation: i32, delta: i32) -> i32 {
+    ::std::cmp::min(reputation.saturating_add(delta), MAX_PEER_REPUTATION)
+}
+
+fn add_bad_connection(addr: String, expire: Duration) {
+    BAD_CONNECTION.insert(addr, Instant::now() + expire);
+}
+
+fn is_bad_connection(addr: &str) -> bool {
+    match BAD_CONNECTION.get(&addr.to_owned()) {
+        Some(expire_time) => Instant::now() < expire_time,
+        None => false,
+    }
+}
+
+// the bans are not in the BAD_CONNECTION, which could be flushed by new bad addresses
+fn ban_peer(peer_id: Arc<String>, expire: Duration) {
+    let now = Instant::now();
+    let mut banned = BANNED_PEERS.write().unwrap();
+    banned.retain(|_, expire_time| now < *expire_time);
+    banned.insert(peer_id, now + expire);
+}
+
+fn is_banned_peer(peer_id: &Arc<String>) -> bool {
+    match BANNED_PEERS.read().unwrap().get(peer_id) {
+        Some(expire_time) => Instant::now() < *expire_time,
+        None => false,
+    }
+}
+
+/// update the reputation of the peer that sent the joint
+/// the peer is banned if its reputation is too low
+pub fn update_peer_reputation(peer_id: &Arc<String>, is_good_joint: bool) {
+    if let Some(conn) = WSS.get_connection(peer_id.clone()) {
+        conn.update_reputation(if is_good_joint {
+            GOOD_JOINT_REPUTATION
+        } else {
+            BAD_JOINT_REPUTATION
+        });
+    }
+}
+
+/// a joint from the peer is added to the unhandled joints
+pub fn increase_pending_from_peer(peer_id: &Arc<String>) {
+    if let Some(conn) = WSS.get_connection(peer_id.clone()) {
+        conn.get_data().increase_pending();
+    }
+}
+
+/// a joint from the peer is removed from the unhandled joints
+pub fn decrease_pending_from_peer(peer_id: &Arc<String>) {
+    if let Some(conn) = WSS.get_connection(peer_id.clone()) {
+        conn.get_data().decrease_pending();
+    }
+}
+
+// return the alive peers with the latest seen first, and the expired peers
+fn split_expired_peers(
+    mut peers: Vec<(String, PeerRecord)>,
+    now: u64,
+    expire_ms: u64,
+) -> (Vec<String>, Vec<String>) {
+    peers.sort_by(|a, b| b.1.last_seen.cmp(&a.1.last_seen));
+    let (alive, expired): (Vec<_>, Vec<_>) = peers
+        .into_iter()
+        .partition(|(_, r)| now.saturating_sub(r.last_seen) < expire_ms);
+    (
+        alive.into_iter().map(|(addr, _)| addr).collect(),
+        expired.into_iter().map(|(addr, _)| addr).collect(),
+    )
+}
+
+// the default broadcast targets: subscribed and not the source
+fn is_broadcast_target(conn: &Arc<HubConn>, source: &Option<Arc<String>>) -> bool {
+    conn.is_subscribed() && source.as_ref() != Some(&conn.get_peer_id())
+}
+
+fn select_conns<T, I, P>(conns: I, predicate: P) -> Vec<T>
+where
+    I: IntoIterator<Item = T>,
+    P: Fn(&T) -> bool,
+{
+    conns.into_iter().filter(|c| predicate(c)).collect()
+}
+
+// filter out the connections that already got the joint in this kind of message
+fn dedup_broadcast<T, F>(
+    joint: &JointData,
+    kind: BroadcastKind,
+    conns: Vec<T>,
+    get_peer_id: F,
+) -> Vec<T>
+where
+    F: Fn(&T) -> Arc<String>,
+{
+    conns
+        .into_iter()
+        .filter(|c| {
+            let is_sent = joint.is_broadcast_to(kind, &get_peer_id(c));
+            if is_sent {
+                statistics::increase_counter("sdag_broadcast_dedup_skipped_total");
+            }
+            !is_sent
+        })
+        .collect()
+}
+
+fn send_joint_to(conns: Vec<Arc<HubConn>>, joint: RcuReader<JointData>, kind: BroadcastKind) {
+    let conns = dedup_broadcast(&joint, kind, conns, |c| c.get_peer_id());
+    for conn in conns {
+        let is_first = {
+            let mut pending = conn.get_data().pending_broadcast.lock().unwrap();
+            pending.push((joint.clone(), kind));
+            pending.len() == 1
+        };
+        // the joints queued before the flush runs are sent in the same batch
+        if is_first {
+            COROUTINE_REGISTRY.spawn(move || flush_pending_broadcast(&conn));
+        }
+    }
+}
+
+fn flush_pending_broadcast(conn: &HubConn) -> Result<()> {
+    let pending = ::std::mem::replace(
+        &mut *conn.get_data().pending_broadcast.lock().unwrap(),
+        Vec::new(),
+    );
+    let joints = pending.iter().map(|(j, _)| &***j).collect::<Vec<_>>();
+    conn.send_joints_batch(&joints, false)?;
+    // only mark after the send succeed, so a failed one is retried next time
+    for (joint, kind) in pending {
+        joint.mark_broadcast_to(kind, conn.get_peer_id());
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum InboundLimit {
+    PerIp,
+    Global,
+}
+
+impl InboundLimit {
+    fn counter_name(&self) -> &'static str {
+        match *self {
+            InboundLimit::PerIp => "sdag_inbound_rejected_per_ip_total",
+            InboundLimit::Global => "sdag_inbound_rejected_global_total",
+        }
+    }
+}
+
+fn get_peer_ip(conn: &HubConn) -> Option<IpAddr> {
+    conn.get_peer_addr()
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip())
+}
+
+// the urls could be host names, so they are resolved to compare with the peer address
+fn is_addr_of_urls(peer_addr: &str, urls: &[String]) -> bool {
+    let peer_addr = match peer_addr.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => return false,
+    };
+    urls.iter().any(|url| match url.to_socket_addrs() {
+        Ok(mut addrs) => addrs.any(|addr| addr == peer_addr),
+        Err(e) => {
+            warn!("failed to resolve {}, err={}", url, e);
+            false
+        }
+    })
+}
+
+// refill `rate` tokens per second and hold at most `rate` tokens
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: usize, now: Instant) -> Self {
+        TokenBucket {
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    // the rate is passed in every time so that a reloaded config takes effect
+    fn take(&mut self, rate: usize, now: Instant) -> bool {
+        if now > self.last_refill {
+            let elapsed = now - self.last_refill;
+            let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            self.tokens = (self.tokens + secs * rate as f64).min(rate as f64);
+            self.last_refill = now;
+        }
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// count the inbound connection of the ip, return the exceeded limit if rejected
+fn add_inbound_ip(
+    ip_counts: &mut HashMap<IpAddr, usize>,
+    ip: IpAddr,
+    max_per_ip: usize,
+    max_total: usize,
+) -> ::std::result::Result<(), InboundLimit> {
+    if ip_counts.values().sum::<usize>() >= max_total {
+        return Err(InboundLimit::Global);
+    }
+
+    let count = ip_counts.entry(ip).or_insert(0);
+    if *count >= max_per_ip {
+        return Err(InboundLimit::PerIp);
+    }
+    *count += 1;
     Ok(())
 }
 
+fn remove_inbound_ip(ip_counts: &mut HashMap<IpAddr, usize>, ip: IpAddr) {
+    let is_empty = match ip_counts.get_mut(&ip) {
+        Some(count) => {
+            *count = count.saturating_sub(1);
+            *count == 0
+        }
+        None => false,
+    };
+
+    if is_empty {
+        ip_counts.remove(&ip);
+    }
+}
+
 fn get_unconnected_remote_peers() -> Vec<String> {
     WSS.get_peers_from_remote()
         .into_iter()
@@ -1432,18 +2978,65 @@ fn get_unconnected_peers_in_config() -> Vec<String> {
 }
 
 fn get_unconnected_peers_in_db() -> Vec<String> {
-    // TODO: impl
-    Vec::new()
+    let peers = match KV_STORE.read_peers() {
+        Ok(peers) => peers,
+        Err(e) => {
+            error!("failed to read peers from db, err={}", e);
+            return Vec::new();
+        }
+    };
+
+    let (alive, expired) = split_expired_peers(peers, ::time::now(), PEER_EXPIRE_MS);
+    for addr in expired {
+        info!("prune expired peer {}", addr);
+        t!(KV_STORE.delete_peer(&addr));
+    }
+
+    alive
+        .into_iter()
+        .filter(|peer| Some(peer) != SELF_LISTEN_ADDRESS.as_ref() && !WSS.contains(peer))
+        .collect()
 }
 
 fn start_catchup(ws: Arc<HubConn>) -> Result<()> {
-    info!("catchup started");
+    // the peer that sent the unknown ball is the one known to have the
+    // stable joints, don't switch to another peer even if it's faster
+    info!("catchup started with {}", ws.get_peer_addr());
 
     // before a catchup the hash_tree_ball should be clear
     assert_eq!(SDAG_CACHE.get_hash_tree_ball_len(), 0);
     let mut catchup_chain_balls = ws.request_catchup()?;
     catchup_chain_balls.reverse();
 
+    let low_watermark = Arc::new(Semphore::new(0));
+    SDAG_CACHE.register_hash_tree_low_watermark(HASH_TREE_LOW_WATERMARK, low_watermark.clone());
+    let ret = catchup_hash_tree(&ws, &catchup_chain_balls, &low_watermark);
+    SDAG_CACHE.unregister_hash_tree_low_watermark(&low_watermark);
+    ret?;
+
+    // wait all the catchup done
+    ::utils::wait_cond(Some(Duration::from_secs(10)), || {
+        SDAG_CACHE.get_hash_tree_ball_len() == 0
+    })
+    .context("catchup wait last ball timeout")?;
+    info!("catchup done");
+
+    // wait until there is no more working
+    ::utils::wait_cond(None, || UNIT_IN_WORK.get_waiter_num() == 0).ok();
+
+    WSS.request_free_joints_from_all_peers()?;
+
+    Ok(())
+}
+
+// the max hash tree balls in processing before requesting the next batch
+const HASH_TREE_LOW_WATERMARK: usize = 1000;
+
+fn catchup_hash_tree(
+    ws: &Arc<HubConn>,
+    catchup_chain_balls: &[String],
+    low_watermark: &Semphore,
+) -> Result<()> {
     for batch in catchup_chain_balls.windows(2) {
         let start = batch[0].clone();
         let end = batch[1].clone();
@@ -1459,36 +3052,45 @@ fn start_catchup(ws: Arc<HubConn>) -> Result<()> {
         ws.request_new_missing_joints(batch_balls.iter().map(|j| &j.unit))?;
 
         // wait the batch number below a value and then start another batch
-        ::utils::wait_cond(Some(Duration::from_secs(10)), || {
-            SDAG_CACHE.get_hash_tree_ball_len() < 1000
-        })
-        .context("catchup wait hash tree batch timeout")?;
+        // a post from previous batch may be left, so always check the len again
+        let start_time = Instant::now();
+        while SDAG_CACHE.get_hash_tree_ball_len() >= HASH_TREE_LOW_WATERMARK {
+            if !low_watermark.wait_timeout(Duration::from_secs(10)) {
+                bail!("catchup wait hash tree batch timeout");
+            }
+        }
+        let elapsed = start_time.elapsed();
+        let wait_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        statistics::observe_histogram("sdag_catchup_wait_ms", wait_ms);
     }
+    Ok(())
+}
 
-    // wait all the catchup done
-    ::utils::wait_cond(Some(Duration::from_secs(10)), || {
-        SDAG_CACHE.get_hash_tree_ball_len() == 0
-    })
-    .context("catchup wait last ball timeout")?;
-    info!("catchup done");
-
-    // wait until there is no more working
-    ::utils::wait_cond(None, || UNIT_IN_WORK.get_waiter_num() == 0).ok();
-
-    WSS.request_free_joints_from_all_peers()?;
+/// push the new joints to the subscribed peers and the light clients that subscribed
+/// the joints or watch their addresses, and the stable units to the watching light clients
+pub fn register_push_handlers() {
+    use notify_watcher::NotifyEvent;
+    use utils::event::Event;
+    use validation::NewJointEvent;
 
-    Ok(())
+    NewJointEvent::add_handler(|e| WSS.broadcast_joint(e.joint.clone()));
+    NotifyEvent::add_async_handler(|e| {
+        notify_watchers_about_stable_joint(&e.joint, e.joint.get_mci())
+    });
 }
 
-#[allow(dead_code)]
-fn notify_watchers(joint: &Joint) -> Result<()> {
+/// push the stable mci of the joint to the light clients that watch its addresses
+pub fn notify_watchers_about_stable_joint(joint: &Joint, mci: Level) {
     let unit = &joint.unit;
-
-    // already stable, light clients will require a proof
-    if joint.ball.is_some() {
-        return Ok(());
+    let body = json!({ "unit": unit.unit, "mci": mci.value() });
+    for conn in get_watching_conns(&get_related_addresses(unit)) {
+        let body = body.clone();
+        COROUTINE_REGISTRY.spawn(move || conn.send_just_saying("stable", body));
     }
+}
 
+// the author and payment output addresses of the unit
+fn get_related_addresses(unit: &::spec::Unit) -> Vec<&String> {
     let mut addresses = unit.authors.iter().map(|a| &a.address).collect::<Vec<_>>();
     for message in &unit.messages {
         use spec::Payload;
@@ -1507,24 +3109,31 @@ fn notify_watchers(joint: &Joint) -> Result<()> {
             _ => unreachable!("payload should be a payment"),
         }
     }
+    addresses
+}
 
-    // TODO: find out peers and send the message to them
-    // light clients need timestamp
-    let mut joint = joint.clone();
-    joint.unit.timestamp = Some(::time::now() / 1000);
+// the connections that watch any of the addresses
+fn get_watching_conns(addresses: &[&String]) -> Vec<Arc<HubConn>> {
+    select_conns(WSS.conns.read().unwrap().values().cloned(), |c| {
+        c.is_watching(addresses)
+    })
+}
 
-    let peer_id = Arc::new(String::from("interested_id"));
-    if let Some(ws) = WSS.get_connection(peer_id) {
-        ws.send_joint(&joint)?;
+// send the stable joints in (from_mci, to_mci] to the light clients that watch them
+fn notify_light_clients_about_stable_joints(from_mci: Level, to_mci: Level) -> Result<()> {
+    for mci in from_mci.value() + 1..=to_mci.value() {
+        for joint in SDAG_CACHE.get_joints_by_mci(Level::new(mci))? {
+            let joint = joint.read()?;
+            let watched_joint = joint.clone();
+            WSS.broadcast_to_subset(joint, BroadcastKind::StableJoint, |c| {
+                notify_watcher::is_watched_by(&c.get_peer_id(), &watched_joint.unit)
+            });
+        }
     }
 
     Ok(())
 }
 
-fn notify_light_clients_about_stable_joints(_from_mci: Level, _to_mci: Level) -> Result<()> {
-    unimplemented!()
-}
-
 fn clear_ball_after_min_retrievable_mci(joint_data: &JointData) -> Result<Joint> {
     let mut joint = (**joint_data).clone();
 
@@ -1540,3 +3149,332 @@ fn clear_ball_after_min_retrievable_mci(joint_data: &JointData) -> Result<Joint>
 
     Ok(joint)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_is_addr_of_urls() {
+        let urls = vec!["localhost:6615".to_owned(), "10.0.0.1:6616".to_owned()];
+        assert!(is_addr_of_urls("127.0.0.1:6615", &urls));
+        assert!(is_addr_of_urls("10.0.0.1:6616", &urls));
+        assert!(!is_addr_of_urls("127.0.0.1:6616", &urls));
+        assert!(!is_addr_of_urls("unknown peer", &urls));
+    }
+
+    #[test]
+    fn test_split_expired_peers() {
+        let record = |last_seen| PeerRecord {
+            last_seen,
+            reputation: 0,
+        };
+        let peers = vec![
+            ("a".to_owned(), record(1_000)),
+            ("b".to_owned(), record(9_000)),
+            ("c".to_owned(), record(5_000)),
+        ];
+
+        let (alive, expired) = split_expired_peers(peers, 10_000, 6_000);
+        assert_eq!(alive, vec!["b".to_owned(), "c".to_owned()]);
+        assert_eq!(expired, vec!["a".to_owned()]);
+    }
+
+    #[test]
+    fn test_add_reputation() {
+        assert_eq!(add_reputation(0, BAD_JOINT_REPUTATION), -10);
+        assert_eq!(add_reputation(-10, GOOD_JOINT_REPUTATION), -9);
+        assert_eq!(
+            add_reputation(MAX_PEER_REPUTATION, GOOD_JOINT_REPUTATION),
+            MAX_PEER_REPUTATION
+        );
+        assert_eq!(
+            add_reputation(::std::i32::MIN, BAD_JOINT_REPUTATION),
+            ::std::i32::MIN
+        );
+    }
+
+    #[test]
+    fn test_bad_connection_expire() {
+        add_bad_connection("10.0.0.1:6615".to_owned(), Duration::from_secs(60));
+        add_bad_connection("10.0.0.2:6615".to_owned(), Duration::from_secs(0));
+        assert!(is_bad_connection("10.0.0.1:6615"));
+        assert!(!is_bad_connection("10.0.0.2:6615"));
+        assert!(!is_bad_connection("10.0.0.3:6615"));
+    }
+
+    #[test]
+    fn test_ban_peer() {
+        let peer_a = Arc::new("test_ban_peer_a".to_owned());
+        let peer_b = Arc::new("test_ban_peer_b".to_owned());
+        ban_peer(peer_a.clone(), Duration::from_secs(60));
+        ban_peer(peer_b.clone(), Duration::from_secs(0));
+        assert!(is_banned_peer(&peer_a));
+        assert!(!is_banned_peer(&peer_b));
+
+        // the expired bans are dropped
+        ban_peer(
+            Arc::new("test_ban_peer_c".to_owned()),
+            Duration::from_secs(60),
+        );
+        assert!(!BANNED_PEERS.read().unwrap().contains_key(&peer_b));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, start);
+        for _ in 0..10 {
+            assert!(bucket.take(10, start));
+        }
+        assert!(!bucket.take(10, start));
+
+        // refill one token every 100ms
+        let now = start + Duration::from_millis(250);
+        assert!(bucket.take(10, now));
+        assert!(bucket.take(10, now));
+        assert!(!bucket.take(10, now));
+
+        // never hold more than a second of tokens
+        let now = now + Duration::from_secs(10);
+        for _ in 0..10 {
+            assert!(bucket.take(10, now));
+        }
+        assert!(!bucket.take(10, now));
+    }
+
+    #[test]
+    fn test_select_peers_by_latency() {
+        let peers = vec![
+            ("d", None),
+            ("c", Some(100)),
+            ("a", Some(10)),
+            ("b", Some(50)),
+        ];
+        assert_eq!(sort_peers_by_latency(peers), vec!["a", "b", "c", "d"]);
+
+        assert_eq!(fill_unknown_latencies(&[None, None]), None);
+        assert_eq!(
+            fill_unknown_latencies(&[Some(10), None, Some(100)]),
+            Some(vec![10, 100, 100])
+        );
+
+        let latencies = [10, 50, 100];
+        let n = 10_000;
+        let mut counts = [0; 3];
+        for i in 0..n {
+            let r = (i as f64 + 0.5) / n as f64;
+            counts[weighted_select_by_latency(&latencies, r).unwrap()] += 1;
+        }
+        assert!(counts[0] > counts[1] && counts[1] > counts[2]);
+        let ratio = counts[0] as f64 / counts[2] as f64;
+        assert!(ratio > 4.5 && ratio < 6.5, "ratio={}", ratio);
+
+        assert_eq!(weighted_select_by_latency(&[], 0.5), None);
+    }
+
+    #[test]
+    fn test_select_subscribed_conns() {
+        struct MockConn {
+            id: usize,
+            is_subscribed: bool,
+        }
+
+        let conns = (0..5)
+            .map(|id| MockConn {
+                id,
+                is_subscribed: id % 2 == 0,
+            })
+            .collect::<Vec<_>>();
+
+        let selected = select_conns(conns.iter(), |c| c.is_subscribed);
+        assert_eq!(selected.len(), 3);
+        assert_eq!(
+            selected.iter().map(|c| c.id).collect::<Vec<_>>(),
+            vec![0, 2, 4]
+        );
+    }
+
+    #[test]
+    fn test_broadcast_dedup() {
+        use spec::Unit;
+
+        struct MockConn {
+            peer_id: Arc<String>,
+            ip: IpAddr,
+        }
+
+        let ip_a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip_b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mock_conns = || {
+            vec![
+                MockConn {
+                    peer_id: Arc::new("A".to_owned()),
+                    ip: ip_a,
+                },
+                MockConn {
+                    peer_id: Arc::new("A1".to_owned()),
+                    ip: ip_a,
+                },
+                MockConn {
+                    peer_id: Arc::new("B".to_owned()),
+                    ip: ip_b,
+                },
+            ]
+        };
+
+        let source = Arc::new("A".to_owned());
+        let joint = Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit: Unit::default(),
+        };
+        let joint = JointData::from_joint(joint, Some(source.clone()));
+        joint.mark_broadcast_to(BroadcastKind::Joint, source);
+
+        // the same joint is broadcast twice, each peer receive it only once
+        let kind = BroadcastKind::Joint;
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let conns = dedup_broadcast(&joint, kind, mock_conns(), |c| c.peer_id.clone());
+            // only "B" got it, the send to "A1" failed
+            for c in &conns {
+                if c.ip == ip_b {
+                    joint.mark_broadcast_to(kind, c.peer_id.clone());
+                }
+            }
+            received.extend(conns);
+        }
+
+        // the failed send is retried, the successful one is not
+        let same_ip = received.iter().filter(|c| c.ip == ip_a).collect::<Vec<_>>();
+        assert_eq!(same_ip.len(), 2);
+        assert!(same_ip.iter().all(|c| c.peer_id.as_str() == "A1"));
+        assert_eq!(received.len(), 3);
+        assert!(joint.is_broadcast_to(kind, &Arc::new("B".to_owned())));
+        assert!(!joint.is_broadcast_to(kind, &Arc::new("A1".to_owned())));
+
+        // the stable joint push is not suppressed by the joint broadcast
+        let stable = BroadcastKind::StableJoint;
+        let conns = dedup_broadcast(&joint, stable, mock_conns(), |c| c.peer_id.clone());
+        assert_eq!(conns.len(), 3);
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(300);
+
+        assert_eq!(get_reconnect_delay(0, base, max, 0), Duration::from_secs(1));
+        assert_eq!(get_reconnect_delay(3, base, max, 0), Duration::from_secs(8));
+        assert_eq!(
+            get_reconnect_delay(3, base, max, 999),
+            Duration::from_millis(8999)
+        );
+
+        // the delay never exceed the max even for huge attempts
+        for attempts in 9..70 {
+            assert_eq!(get_reconnect_delay(attempts, base, max, 500), max);
+        }
+
+        // the delay is always increasing within [base * 2^n, base * 2^n + base)
+        let mut last = Duration::from_secs(0);
+        for attempts in 0..8 {
+            let low = get_reconnect_delay(attempts, base, max, 0);
+            let high = get_reconnect_delay(attempts, base, max, 999);
+            assert!(low > last && high < low * 2);
+            last = low;
+        }
+    }
+
+    #[test]
+    fn test_reconnect_scheduler() {
+        let scheduler = ReconnectScheduler::new();
+        let peer = "127.0.0.1:6615";
+        let now = Instant::now();
+        assert!(scheduler.is_allowed(peer, now));
+
+        scheduler.on_failure(peer, now);
+        assert!(!scheduler.is_allowed(peer, now));
+        assert!(scheduler.is_allowed(peer, now + Duration::from_secs(1)));
+
+        // the wait is doubled on each failure
+        scheduler.on_failure(peer, now);
+        assert!(!scheduler.is_allowed(peer, now + Duration::from_secs(1)));
+        assert!(scheduler.is_allowed(peer, now + Duration::from_secs(2)));
+
+        // and capped at 5 min
+        for _ in 0..20 {
+            scheduler.on_failure(peer, now);
+        }
+        assert!(!scheduler.is_allowed(peer, now + Duration::from_secs(299)));
+        assert!(scheduler.is_allowed(peer, now + Duration::from_secs(300)));
+
+        scheduler.on_success(peer);
+        assert!(scheduler.is_allowed(peer, now));
+    }
+
+    #[test]
+    fn test_inbound_per_ip_limit() {
+        let mut ip_counts = HashMap::new();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        for _ in 0..3 {
+            assert_eq!(add_inbound_ip(&mut ip_counts, ip, 3, 128), Ok(()));
+        }
+        assert_eq!(
+            add_inbound_ip(&mut ip_counts, ip, 3, 128),
+            Err(InboundLimit::PerIp)
+        );
+        assert_eq!(ip_counts[&ip], 3);
+
+        // a closed connection release the slot
+        remove_inbound_ip(&mut ip_counts, ip);
+        assert_eq!(add_inbound_ip(&mut ip_counts, ip, 3, 128), Ok(()));
+    }
+
+    #[test]
+    fn test_inbound_global_limit() {
+        let mut ip_counts = HashMap::new();
+        let mut results = Vec::new();
+        for i in 0..130u32 {
+            let ip = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i));
+            results.push(add_inbound_ip(&mut ip_counts, ip, 3, 128));
+        }
+
+        assert!(results[..128].iter().all(|r| r.is_ok()));
+        assert_eq!(results[128], Err(InboundLimit::Global));
+        assert_eq!(results[129], Err(InboundLimit::Global));
+        assert_eq!(ip_counts.values().sum::<usize>(), 128);
+    }
+
+    #[test]
+    fn test_pending_from_peer() {
+        let data = HubData::default();
+        let max_pending = config::Settings::default().max_pending_per_peer;
+
+        // the same check as is_peer_busy
+        let accepted = (0..55)
+            .filter(|_| {
+                if data.get_pending_from_peer() >= max_pending {
+                    return false;
+                }
+                data.increase_pending();
+                true
+            })
+            .count();
+        assert_eq!(accepted, 50);
+        assert_eq!(data.get_pending_from_peer(), 50);
+
+        // the unhandled joints are normalized or purged
+        for _ in 0..11 {
+            data.decrease_pending();
+        }
+        assert_eq!(data.get_pending_from_peer(), 39);
+
+        // the joints added before a reconnect don't underflow the counter
+        let data = HubData::default();
+        data.decrease_pending();
+        assert_eq!(data.get_pending_from_peer(), 0);
+    }
+}
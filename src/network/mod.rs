@@ -3,4 +3,4 @@ mod network_base;
 pub mod hub;
 pub mod wallet;
 
-pub use self::network_base::{WsConnection, WsServer};
+pub use self::network_base::{PendingRequest, RequestError, ServerHandle, WsConnection, WsServer};
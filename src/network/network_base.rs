@@ -1,25 +1,54 @@
-// use std::io::Read;
+use std::borrow::Cow;
+use std::io::{self, Cursor, Read, Write};
 use std::marker::PhantomData;
-use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use error::Result;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use may::coroutine::JoinHandle;
 use may::net::{TcpListener, TcpStream};
 use may::sync::{AtomicOption, RwLock};
-use may_waiter::WaiterMap;
+use may_waiter::{MapWaiter, WaiterMap};
+use net2::TcpBuilder;
 use serde_json::{self, Value};
+use tungstenite::client::client;
+use tungstenite::handshake::client::Request;
+use tungstenite::handshake::server::Request as HandshakeRequest;
 use tungstenite::protocol::Role;
-use tungstenite::server::accept;
+use tungstenite::server::accept_hdr;
 use tungstenite::{Message, WebSocket};
+use url::Url;
+
+// tungstenite doesn't support the permessage-deflate extension, so the compression
+// is negotiated by this header and the compressed messages are sent as binary packets
+const COMPRESS_HEADER: &str = "Sdag-Compress";
+const COMPRESS_METHOD: &str = "deflate";
+// the small messages are not worth compressing
+const MIN_COMPRESS_SIZE: usize = 256;
+// guard against the compression bomb, the same as the tungstenite max message size
+const MAX_DECOMPRESSED_SIZE: u64 = 64 << 20;
 
 // the server part trait
 pub trait Server<T> {
     fn on_message(ws: Arc<WsConnection<T>>, subject: String, body: Value) -> Result<()>;
     fn on_request(ws: Arc<WsConnection<T>>, command: String, params: Value) -> Result<Value>;
     fn close(ws: Arc<WsConnection<T>>);
+
+    /// the json body returned to a plain http GET on the server port
+    fn http_info() -> Value {
+        json!({ "version": ::config::VERSION })
+    }
+
+    /// whether the server is healthy and the json body returned to a http GET /health
+    /// on the server port, an unhealthy server replies 503
+    fn http_health() -> (bool, Value) {
+        (true, Self::http_info())
+    }
 }
 
 pub trait Sender {
@@ -79,6 +108,8 @@ pub struct WsConnection<T> {
     data: T,
     // for request unique id generation
     id: AtomicUsize,
+    // if the compression is negotiated in the handshake
+    compress: bool,
 }
 
 impl<T> Sender for WsConnection<T> {
@@ -90,8 +121,14 @@ impl<T> Sender for WsConnection<T> {
             debug!("SENDING to {}: huge message", self.peer_addr);
         }
 
+        let msg = if self.compress && msg.len() >= MIN_COMPRESS_SIZE {
+            compress_message(msg, ::config::get_compression_level())?
+        } else {
+            Message::Text(msg)
+        };
+
         let mut g = self.ws.write().unwrap();
-        g.ws.write_message(Message::Text(msg))?;
+        g.ws.write_message(msg)?;
         Ok(())
     }
 }
@@ -132,7 +169,13 @@ impl<T> Drop for WsConnection<T> {
 
 impl<T> WsConnection<T> {
     /// create a client from stream socket
-    pub fn new(ws: WebSocket<TcpStream>, data: T, peer: String, role: Role) -> Result<Arc<Self>>
+    pub fn new(
+        ws: WebSocket<TcpStream>,
+        data: T,
+        peer: String,
+        role: Role,
+        compress: bool,
+    ) -> Result<Arc<Self>>
     where
         T: Server<T> + Send + Sync + 'static,
     {
@@ -150,6 +193,7 @@ impl<T> WsConnection<T> {
             listener: AtomicOption::none(),
             data,
             id: AtomicUsize::new(0),
+            compress,
         });
 
         // we can't have a strong ref in the driver coroutine!
@@ -169,6 +213,7 @@ impl<T> WsConnection<T> {
                 };
                 let msg = match msg {
                     Message::Text(s) => s,
+                    Message::Binary(ref data) if compress => t_c!(decompress_message(data)),
                     _ => {
                         error!("only text ws packet are supported");
                         continue;
@@ -265,6 +310,14 @@ impl<T> WsConnection<T> {
     }
 
     pub fn send_request(&self, command: &str, param: &Value) -> Result<Value> {
+        self.send_request_pipelined(command, param)?.wait()
+    }
+
+    /// send the request without waiting for the response
+    ///
+    /// each request has its own tag, so many requests can be in flight on the
+    /// same connection, the responses are matched by the tag in any order
+    pub fn send_request_pipelined(&self, command: &str, param: &Value) -> Result<PendingRequest> {
         let mut request = match param {
             Value::Null => json!({ "command": command }),
             _ => json!({"command": command, "params": param}),
@@ -272,9 +325,36 @@ impl<T> WsConnection<T> {
         let tag = self.id.fetch_add(1, Ordering::Relaxed);
         request["tag"] = json!(tag.to_string());
 
-        let blocker = self.req_map.new_waiter(tag);
+        // register the waiter before sending, the response may come at once
+        let waiter = self.req_map.new_waiter(tag);
         self.send_message("request", request)?;
 
+        Ok(PendingRequest {
+            command: command.to_owned(),
+            waiter,
+        })
+    }
+}
+
+/// the error replied by the peer for a request
+/// it means the request is handled, the connection is still good
+#[derive(Debug, Fail)]
+#[fail(display = "{} err: {}", command, error)]
+pub struct RequestError {
+    pub command: String,
+    pub error: Value,
+}
+
+/// a sent request that is waiting for its response
+/// the waiter is removed from the connection when it's dropped
+pub struct PendingRequest<'a> {
+    command: String,
+    waiter: MapWaiter<'a, usize, Value>,
+}
+
+impl<'a> PendingRequest<'a> {
+    /// block until the response arrives or the request is stalled
+    pub fn wait(self) -> Result<Value> {
         let timeout = Some(Duration::from_secs(::config::STALLED_TIMEOUT as u64));
         #[derive(Deserialize)]
         struct Response {
@@ -284,50 +364,390 @@ impl<T> WsConnection<T> {
             response: Value,
         };
 
-        let rsp: Response = serde_json::from_value(blocker.wait_rsp(timeout)?[1].take())?;
+        let mut rsp: Response = serde_json::from_value(self.waiter.wait_rsp(timeout)?[1].take())?;
         if !rsp.response["error"].is_null() {
-            bail!("{} err: {}", command, rsp.response["error"]);
+            return Err(RequestError {
+                command: self.command,
+                error: rsp.response["error"].take(),
+            }
+            .into());
         }
         Ok(rsp.response)
     }
 }
 
+/// the client side handshake, return the ws and if the compression is negotiated
+pub fn client_handshake(stream: TcpStream) -> Result<(WebSocket<TcpStream>, bool)> {
+    let url = Url::parse("wss://localhost/")?;
+    let mut req = Request::from(url);
+    let enable_compress = !::config::is_compress_disabled();
+    if enable_compress {
+        req.add_header(Cow::from(COMPRESS_HEADER), Cow::from(COMPRESS_METHOD));
+    }
+    let (conn, rsp) = client(req, stream)?;
+
+    let compress = enable_compress
+        && rsp.headers.find_first(COMPRESS_HEADER) == Some(COMPRESS_METHOD.as_bytes());
+    Ok((conn, compress))
+}
+
+// compress the message if it gets smaller
+fn compress_message(msg: String, level: u32) -> Result<Message> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(msg.as_bytes())?;
+    let data = encoder.finish()?;
+    if data.len() >= msg.len() {
+        return Ok(Message::Text(msg));
+    }
+
+    ::statistics::add_counter("sdag_compress_input_bytes_total", msg.len());
+    ::statistics::add_counter("sdag_compressed_bytes_saved_total", msg.len() - data.len());
+    Ok(Message::Binary(data))
+}
+
+fn decompress_message(data: &[u8]) -> Result<String> {
+    let mut msg = String::new();
+    DeflateDecoder::new(data)
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_string(&mut msg)?;
+    ensure!(
+        msg.len() as u64 <= MAX_DECOMPRESSED_SIZE,
+        "decompressed message is too large"
+    );
+    Ok(msg)
+}
+
+// the max size of a http request head that we would read
+const MAX_HTTP_HEAD_SIZE: usize = 8 * 1024;
+
+// replay the already read request head to the ws handshake
+struct PrefixedStream {
+    prefix: Cursor<Vec<u8>>,
+    stream: TcpStream,
+}
+
+impl Read for PrefixedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.prefix.position() as usize) < self.prefix.get_ref().len() {
+            return self.prefix.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for PrefixedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// read until the end of the http request head
+fn read_http_head(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_HEAD_SIZE {
+            bail!("http request head is too large");
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            bail!("connection closed before the http request head finished");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(head)
+}
+
+fn is_websocket_upgrade(head: &[u8]) -> bool {
+    String::from_utf8_lossy(head)
+        .to_lowercase()
+        .lines()
+        .any(|l| l.starts_with("upgrade:") && l.contains("websocket"))
+}
+
+fn requests_compression(head: &[u8]) -> bool {
+    let header = format!("{}:", COMPRESS_HEADER.to_lowercase());
+    String::from_utf8_lossy(head)
+        .to_lowercase()
+        .lines()
+        .any(|l| l.starts_with(&header) && l.contains(COMPRESS_METHOD))
+}
+
+fn send_http_info(stream: &mut TcpStream, info: &Value) -> Result<()> {
+    send_http_response(stream, "200 OK", "application/json", &info.to_string())
+}
+
+fn send_http_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
 // helper struct for easy use
 pub struct WsServer<T>(PhantomData<T>);
 
+/// the running server, dropping the handle keeps the server running
+pub struct ServerHandle {
+    // the local addresses of the listeners
+    addrs: Vec<SocketAddr>,
+    stopped: Arc<AtomicBool>,
+    accept_loops: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// stop accepting new connections and wait until all the accept loops quit
+    /// the accepted connections are not closed
+    pub fn shutdown(self) {
+        self.stopped.store(true, Ordering::Release);
+        // wake up the blocked accepts, they see the flag and quit
+        for addr in &self.addrs {
+            let ip = match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            if let Err(e) = TcpStream::connect(SocketAddr::new(ip, addr.port())) {
+                error!("failed to wake up the listener {}, err={}", addr, e);
+            }
+        }
+        for handle in self.accept_loops {
+            handle.join().ok();
+        }
+    }
+}
+
 impl<T> WsServer<T> {
     // f is used to save the connection globally
-    pub fn start<A, F>(address: A, f: F) -> Result<JoinHandle<()>>
+    pub fn start<A, F>(address: A, f: F) -> Result<ServerHandle>
     where
         A: ToSocketAddrs,
-        F: Fn(Arc<WsConnection<T>>) + Send + 'static,
+        F: Fn(Arc<WsConnection<T>>) + Send + Sync + 'static,
         T: Server<T> + Default + Send + Sync + 'static,
     {
-        let mut address = match address.to_socket_addrs()?.next() {
+        let address = match address.to_socket_addrs()?.next() {
             Some(addr) => addr,
             None => bail!("can't resolve address"),
         };
-        // IP 0.0.0.0 can accept all request, so need change hub server ip to 0.0.0.0 for docker test
-        address.set_ip(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
 
-        let listener = match TcpListener::bind(address) {
-            Ok(listener) => listener,
-            Err(e) => bail!("can't bind to address {}, err={}", address, e),
-        };
+        let mut listeners = Vec::new();
+        for addr in get_bind_addrs(address, ::config::is_listen_ipv6()) {
+            listeners.push(bind_listener(addr)?);
+        }
+
+        let mut addrs = Vec::with_capacity(listeners.len());
+        for listener in &listeners {
+            addrs.push(listener.local_addr()?);
+        }
+
+        let f = Arc::new(f);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_loops = listeners
+            .into_iter()
+            .map(|listener| {
+                let f = f.clone();
+                let stopped = stopped.clone();
+                go!(move || accept_loop(listener, &*f, &stopped))
+            })
+            .collect();
+
+        Ok(ServerHandle {
+            addrs,
+            stopped,
+            accept_loops,
+        })
+    }
+}
+
+// IP 0.0.0.0 can accept all request, so need change hub server ip to 0.0.0.0 for docker test
+// the same for the ipv6 address, and both of them are listened for the dual stack
+fn get_bind_addrs(address: SocketAddr, dual_stack: bool) -> Vec<SocketAddr> {
+    let port = address.port();
+    let v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    let v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), port);
+    if dual_stack {
+        vec![v4, v6]
+    } else if address.is_ipv6() {
+        vec![v6]
+    } else {
+        vec![v4]
+    }
+}
+
+// the ipv6 socket only accepts ipv6 so that the ipv4 one could bind the same port
+fn bind_listener(address: SocketAddr) -> Result<TcpListener> {
+    let builder = match address {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?;
+
+    let listener = match builder.bind(address).and_then(|b| b.listen(1024)) {
+        Ok(listener) => listener,
+        Err(e) => bail!("can't bind to address {}, err={}", address, e),
+    };
+    Ok(TcpListener::new(listener)?)
+}
+
+// only the loopback and the configured ips could scrape the metrics
+#[cfg(feature = "prometheus")]
+fn is_metrics_allowed(peer_addr: Option<SocketAddr>) -> bool {
+    match peer_addr {
+        Some(addr) => {
+            addr.ip().is_loopback() || ::config::get_metrics_allowed_ips().contains(&addr.ip())
+        }
+        None => false,
+    }
+}
+
+fn accept_loop<T, F>(listener: TcpListener, f: &F, stopped: &AtomicBool)
+where
+    F: Fn(Arc<WsConnection<T>>),
+    T: Server<T> + Default + Send + Sync + 'static,
+{
+    for stream in listener.incoming() {
+        // the server is shutdown, the stream is the wake up connection
+        if stopped.load(Ordering::Acquire) {
+            return;
+        }
+
+        match stream {
+            Ok(mut stream) => {
+                let peer_addr = stream.peer_addr().ok();
+                let peer = match peer_addr {
+                    Some(addr) => addr.to_string(),
+                    None => "unknown peer".to_owned(),
+                };
 
-        Ok(go!(move || for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let peer = match stream.peer_addr() {
-                        Ok(addr) => addr.to_string(),
-                        Err(_) => "unknown peer".to_owned(),
+                // a plain http GET is answered with the server info, as a health check
+                let head = t_c!(read_http_head(&mut stream));
+                #[cfg(feature = "prometheus")]
+                {
+                    if head.starts_with(b"GET /metrics ") {
+                        if !is_metrics_allowed(peer_addr) {
+                            t_c!(send_http_response(
+                                &mut stream,
+                                "403 Forbidden",
+                                "text/plain",
+                                "forbidden"
+                            ));
+                            continue;
+                        }
+                        let metrics = ::statistics::prometheus_text_export();
+                        let content_type = "text/plain; version=0.0.4";
+                        t_c!(send_http_response(
+                            &mut stream,
+                            "200 OK",
+                            content_type,
+                            &metrics
+                        ));
+                        continue;
+                    }
+                }
+                if head.starts_with(b"GET /health ") {
+                    let (is_healthy, health) = T::http_health();
+                    let status = if is_healthy {
+                        "200 OK"
+                    } else {
+                        "503 Service Unavailable"
                     };
-                    let ws = t_c!(accept(stream));
-                    let ws = t_c!(WsConnection::new(ws, T::default(), peer, Role::Server));
-                    f(ws);
+                    let body = health.to_string();
+                    t_c!(send_http_response(
+                        &mut stream,
+                        status,
+                        "application/json",
+                        &body
+                    ));
+                    continue;
                 }
-                Err(e) => error!("failed to accept, err={}", e),
+                if head.starts_with(b"GET /") && !is_websocket_upgrade(&head) {
+                    t_c!(send_http_info(&mut stream, &T::http_info()));
+                    continue;
+                }
+
+                let compress = !::config::is_compress_disabled() && requests_compression(&head);
+                let prefixed = PrefixedStream {
+                    prefix: Cursor::new(head),
+                    stream: t_c!(stream.try_clone()),
+                };
+                t_c!(accept_hdr(prefixed, |_: &HandshakeRequest| {
+                    let header = (COMPRESS_HEADER.to_owned(), COMPRESS_METHOD.to_owned());
+                    Ok(if compress { Some(vec![header]) } else { None })
+                }));
+                // the client would not send any frame before the handshake finished
+                let ws = WebSocket::from_raw_socket(stream, Role::Server, None);
+                let role = Role::Server;
+                let ws = t_c!(WsConnection::new(ws, T::default(), peer, role, compress));
+                f(ws);
             }
-        }))
+            Err(e) => error!("failed to accept, err={}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_message() {
+        let msg = json!(["justsaying", { "subject": "joint", "body": vec!["A"; 1000] }]);
+        let msg = msg.to_string();
+        let data = match compress_message(msg.clone(), 6).unwrap() {
+            Message::Binary(data) => data,
+            _ => panic!("the message is not compressed"),
+        };
+        assert!(data.len() < msg.len());
+        assert_eq!(decompress_message(&data).unwrap(), msg);
+
+        // the tiny message would not get smaller
+        let msg = "[\"ok\"]".to_owned();
+        match compress_message(msg.clone(), 6).unwrap() {
+            Message::Text(s) => assert_eq!(s, msg),
+            _ => panic!("the message should not be compressed"),
+        }
+
+        assert!(decompress_message(b"not deflated").is_err());
+    }
+
+    #[test]
+    fn test_requests_compression() {
+        let head = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nSdag-Compress: deflate\r\n\r\n";
+        assert!(requests_compression(head));
+        let head = b"GET / HTTP/1.1\r\nsdag-compress: gzip\r\n\r\n";
+        assert!(!requests_compression(head));
+        let head = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+        assert!(!requests_compression(head));
+    }
+
+    #[test]
+    fn test_bind_addrs() {
+        let v4 = "127.0.0.1:6615".parse().unwrap();
+        let v6 = "[::1]:6615".parse().unwrap();
+        let bind_v4: SocketAddr = "0.0.0.0:6615".parse().unwrap();
+        let bind_v6: SocketAddr = "[::]:6615".parse().unwrap();
+
+        assert_eq!(get_bind_addrs(v4, false), vec![bind_v4]);
+        assert_eq!(get_bind_addrs(v6, false), vec![bind_v6]);
+        assert_eq!(get_bind_addrs(v4, true), vec![bind_v4, bind_v6]);
     }
 }
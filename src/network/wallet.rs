@@ -1,29 +1,44 @@
 use std::collections::HashMap as StdHashMap;
 use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use super::network_base::{Sender, Server, WsConnection};
+use super::network_base::{client_handshake, Sender, Server, WsConnection};
+use composer;
 use config;
 use error::Result;
+use hashbrown::{HashMap, HashSet};
 use joint::Joint;
 use joint::JointProperty;
+use joint::JointSequence;
+use joint::Level;
 use light;
 use may::coroutine;
 use may::net::TcpStream;
-use may::sync::Semphore;
+use may::sync::{Mutex, Semphore};
+use sdag_wallet_base::Base64KeyExt;
 use serde_json::{self, Value};
-use tungstenite::client::client;
-use tungstenite::handshake::client::Request;
+use spec::{Input, Message, Output, Payload};
 use tungstenite::protocol::Role;
-use url::Url;
-use wallet_info::MY_WALLET;
+use utils::FifoCache;
+use wallet_info::{WalletInfo, MY_WALLET};
 
 //---------------------------------------------------------------------------------------
 // WalletData
 //---------------------------------------------------------------------------------------
 pub struct WalletData {
     init_done: Semphore,
+    // posted when hub push "light/have_updates"
+    have_updates: Semphore,
+    // handlers for the joints pushed by hub
+    live_handlers: LiveHandlers,
+    // the max mci pushed by hub "mci_reached" or "mci_stable"
+    reached_mci: AtomicUsize,
+    // posted when hub push "mci_reached" or "mci_stable"
+    mci_reached: Semphore,
+    // <unit, mci> of the watched units pushed by hub "stable"
+    stable_units: FifoCache<String, Level>,
 }
 
 impl WalletData {
@@ -37,12 +52,49 @@ impl WalletData {
     fn trigger_init_done(&self) {
         self.init_done.post();
     }
+
+    fn wait_updates(&self, dur: Duration) -> bool {
+        self.have_updates.wait_timeout(dur)
+    }
+
+    fn trigger_updates(&self) {
+        self.have_updates.post();
+    }
+
+    fn get_reached_mci(&self) -> usize {
+        self.reached_mci.load(Ordering::Acquire)
+    }
+
+    fn wait_mci_reached(&self, dur: Duration) -> bool {
+        self.mci_reached.wait_timeout(dur)
+    }
+
+    fn trigger_mci_reached(&self, mci: usize) {
+        let mut cur = self.reached_mci.load(Ordering::Acquire);
+        while cur < mci {
+            match self.reached_mci.compare_exchange_weak(
+                cur,
+                mci,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(v) => cur = v,
+            }
+        }
+        self.mci_reached.post();
+    }
 }
 
 impl Default for WalletData {
     fn default() -> Self {
         WalletData {
             init_done: Semphore::new(0),
+            have_updates: Semphore::new(0),
+            live_handlers: LiveHandlers::default(),
+            reached_mci: AtomicUsize::new(0),
+            mci_reached: Semphore::new(0),
+            stable_units: FifoCache::with_capacity(1000),
         }
     }
 }
@@ -50,6 +102,11 @@ impl Server<WalletData> for WalletData {
     fn on_message(ws: Arc<WalletConn>, subject: String, body: Value) -> Result<()> {
         match subject.as_str() {
             "version" => ws.on_version(body)?,
+            "light/have_updates" => ws.on_have_updates(body)?,
+            "joint" => ws.on_joint(body)?,
+            // both carry the stable mci, the id of "mci_stable" is not used by wallet
+            "mci_reached" | "mci_stable" => ws.on_mci_reached(body)?,
+            "stable" => ws.on_stable(body)?,
             subject => error!("on_message unknown subject: {}", subject),
         }
         Ok(())
@@ -67,6 +124,65 @@ impl Server<WalletData> for WalletData {
     fn close(_ws: Arc<WalletConn>) {}
 }
 
+//---------------------------------------------------------------------------------------
+// LiveHandlers
+//---------------------------------------------------------------------------------------
+type JointHandler = Box<dyn Fn(Joint) + Send>;
+
+// the registered live joint handlers with their address filter
+#[derive(Default)]
+struct LiveHandlers {
+    next_id: AtomicUsize,
+    handlers: Mutex<HashMap<usize, (Option<String>, JointHandler)>>,
+}
+
+impl LiveHandlers {
+    fn add(&self, address_filter: Option<String>, handler: JointHandler) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.handlers
+            .lock()
+            .unwrap()
+            .insert(id, (address_filter, handler));
+        id
+    }
+
+    // return true if there is no handler left
+    fn remove(&self, id: usize) -> bool {
+        let mut g = self.handlers.lock().unwrap();
+        g.remove(&id);
+        g.is_empty()
+    }
+
+    fn dispatch(&self, joint: &Joint) {
+        for (address_filter, handler) in self.handlers.lock().unwrap().values() {
+            match address_filter {
+                Some(address) if !is_related_joint(joint, address) => {}
+                _ => handler(joint.clone()),
+            }
+        }
+    }
+}
+
+/// cancel the live subscription when dropped
+pub struct SubscriptionHandle<'a> {
+    conn: &'a WalletConn,
+    id: usize,
+}
+
+impl<'a> Drop for SubscriptionHandle<'a> {
+    fn drop(&mut self) {
+        // stop the hub pushing joints when no one is listening
+        if self.conn.get_data().live_handlers.remove(self.id) {
+            if let Err(e) = self
+                .conn
+                .send_request("light/unsubscribe_joints", &Value::Null)
+            {
+                error!("failed to unsubscribe, err={}", e);
+            }
+        }
+    }
+}
+
 //---------------------------------------------------------------------------------------
 // WalletConn
 //---------------------------------------------------------------------------------------
@@ -87,13 +203,225 @@ impl WalletConn {
         )
     }
 
-    fn send_heartbeat(&self) -> Result<()> {
+    pub fn send_heartbeat(&self) -> Result<()> {
         self.send_request("heartbeat", &Value::Null)?;
         Ok(())
     }
 
     pub fn post_joint(&self, joint: &Joint) -> Result<()> {
-        self.send_request("post_joint", &serde_json::to_value(joint)?)?;
+        self.post_joint_status(joint)?;
+        Ok(())
+    }
+
+    /// post the joint and return the status replied by the hub
+    pub fn post_joint_status(&self, joint: &Joint) -> Result<light::PostJointStatus> {
+        let rsp = self.send_request("post_joint", &serde_json::to_value(joint)?)?;
+        Ok(serde_json::from_value(rsp)?)
+    }
+
+    /// post the joint and wait until it is out of temp-bad, return the good joint
+    ///
+    /// the temp-bad joint is already relayed by the hub, so it's never composed again
+    /// with other inputs which may pay twice. if it's still temp-bad after max_retries
+    /// polls, it is replaced by a joint that spends the same inputs
+    pub fn post_joint_with_retry(
+        &self,
+        joint: &Joint,
+        wallet_info: &WalletInfo,
+        max_retries: u8,
+    ) -> Result<Joint> {
+        if self.post_joint_status(joint)? == light::PostJointStatus::Accepted {
+            return Ok(joint.clone());
+        }
+
+        let unit = &joint.unit.unit;
+        let sequence = poll_with_backoff(max_retries, Duration::from_millis(500), || {
+            let (_, property) = self.get_joint_by_unit_hash(unit)?;
+            if property.sequence.is_temp_bad() {
+                warn!("post_joint_with_retry: unit {} is still temp-bad", unit);
+                return Ok(None);
+            }
+            Ok(Some(property.sequence))
+        })?;
+
+        match sequence {
+            Some(JointSequence::Good) => Ok(joint.clone()),
+            Some(sequence) => bail!("unit {} is {:?}", unit, sequence),
+            None => self.replace_joint(unit, wallet_info),
+        }
+    }
+
+    /// replace the temp-bad joint with a joint that spends the same inputs and pays the
+    /// same outputs but has fresh parents, so that at most one of them becomes good
+    /// the consensus decides which one, the other is purged by the temp-bad timeout
+    pub fn replace_joint(&self, original_unit: &str, wallet_info: &WalletInfo) -> Result<Joint> {
+        let (original, property) = self.get_joint_by_unit_hash(original_unit)?;
+        ensure!(
+            property.sequence.is_temp_bad(),
+            "unit {} is not temp-bad",
+            original_unit
+        );
+
+        let inputs = replacement_inputs(&original);
+        let joint = self.recompose_payment(&original, wallet_info, inputs)?;
+        self.post_joint(&joint)?;
+        Ok(joint)
+    }
+
+    // compose the payment of the joint again with the latest light props and the inputs
+    fn recompose_payment(
+        &self,
+        joint: &Joint,
+        wallet_info: &WalletInfo,
+        inputs: light::InputsResponse,
+    ) -> Result<Joint> {
+        let address = &wallet_info._00_address;
+        let mut outputs = Vec::new();
+        let mut text_message = None;
+        for msg in &joint.unit.messages {
+            match msg.payload {
+                Some(Payload::Payment(ref payment)) => outputs.extend(
+                    payment
+                        .outputs
+                        .iter()
+                        .filter(|o| &o.address != address)
+                        .cloned(),
+                ),
+                _ if msg.app == "text" => text_message = Some(msg.clone()),
+                _ => {}
+            }
+        }
+        ensure!(
+            !outputs.is_empty(),
+            "unit {} pays nothing to others",
+            joint.unit.unit
+        );
+
+        // the change output always goes first, it's a send all payment without it
+        let send_all = outputs.len() == 1
+            && joint.unit.messages.iter().any(|msg| match msg.payload {
+                Some(Payload::Payment(ref payment)) => payment.outputs.len() == 1,
+                _ => false,
+            });
+        let transaction_amount = outputs.iter().fold(0, |acc, x| acc + x.amount);
+
+        let light_props = self.get_light_props(address)?;
+
+        let compose_info = composer::ComposeInfo {
+            paid_address: address.clone(),
+            change_address: address.clone(),
+            outputs,
+            inputs,
+            transaction_amount,
+            text_message,
+            light_props,
+            pubk: wallet_info._00_address_pubk.to_base64_key(),
+            send_all,
+        };
+        composer::compose_joint(compose_info, wallet_info)
+    }
+
+    /// post the joint and wait until it become stable, return the mci of the joint
+    pub fn post_joint_and_wait_stable(&self, joint: &Joint, timeout: Duration) -> Result<Level> {
+        self.post_joint(joint)?;
+        self.wait_unit_stable(&joint.unit.unit, timeout)
+    }
+
+    /// wait an already posted unit become stable, return the mci of the joint
+    pub fn wait_unit_stable(&self, unit_hash: &str, timeout: Duration) -> Result<Level> {
+        let poll_interval = Duration::from_millis(500);
+        let start = Instant::now();
+
+        loop {
+            // the hub pushes the stable units of the watched addresses
+            if let Some(mci) = self.get_data().stable_units.get(&unit_hash.to_owned()) {
+                return Ok(mci);
+            }
+
+            match self.get_joint_by_unit_hash(unit_hash) {
+                Ok((_, property)) => {
+                    if property.is_stable {
+                        return Ok(property.mci);
+                    }
+                }
+                // the hub may not receive the joint yet
+                Err(e) => debug!("wait_unit_stable: unit={}, err={}", unit_hash, e),
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                bail!("wait unit {} stable timeout", unit_hash);
+            }
+
+            // wake up early if the hub push updates
+            let left = timeout - elapsed;
+            self.get_data()
+                .wait_updates(::std::cmp::min(left, poll_interval));
+        }
+    }
+
+    /// wait until the target mci become stable on the hub
+    /// the hub push "mci_reached" if it supports "light/subscribe_mci", or else the
+    /// last stable mci is polled
+    pub fn subscribe_mci_stable(&self, target_mci: u32, timeout: Duration) -> Result<()> {
+        let poll_interval = Duration::from_millis(500);
+        let target_mci = target_mci as usize;
+        let start = Instant::now();
+
+        let is_pushed =
+            match self.send_request("light/subscribe_mci", &json!({ "target_mci": target_mci })) {
+                Ok(response) => {
+                    if response["reached"].as_bool() == Some(true) {
+                        return Ok(());
+                    }
+                    true
+                }
+                Err(e) => {
+                    debug!("light/subscribe_mci not supported, poll instead, err={}", e);
+                    false
+                }
+            };
+
+        loop {
+            let is_reached = if is_pushed {
+                self.get_data().get_reached_mci() >= target_mci
+            } else {
+                self.get_last_stable_mci()? >= target_mci
+            };
+            if is_reached {
+                return Ok(());
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                bail!("wait mci {} stable timeout", target_mci);
+            }
+
+            let left = timeout - elapsed;
+            self.get_data()
+                .wait_mci_reached(::std::cmp::min(left, poll_interval));
+        }
+    }
+
+    // get the last stable mci of the hub
+    pub fn get_last_stable_mci(&self) -> Result<usize> {
+        let response = self.send_request("light/get_last_stable", &Value::Null)?;
+        let mci = response["last_stable_mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("get last stable mci failed"))?;
+
+        Ok(mci as usize)
+    }
+
+    /// export the stable business state of the hub, only allowed for local hub
+    pub fn export_business_state(&self) -> Result<::business::BusinessStateExport> {
+        let response = self.send_request("debug/export_business_state", &Value::Null)?;
+        Ok(serde_json::from_value(response)?)
+    }
+
+    /// replace the business state of the hub, only allowed for local hub
+    pub fn import_business_state(&self, state: &::business::BusinessStateExport) -> Result<()> {
+        self.send_request("debug/import_business_state", &serde_json::to_value(state)?)?;
         Ok(())
     }
 
@@ -117,6 +445,28 @@ impl WalletConn {
         Ok(serde_json::from_value(inputs_response)?)
     }
 
+    /// estimate the (headers_commission, payload_commission) of the payment
+    pub fn estimate_fees(
+        &self,
+        outputs: &[Output],
+        inputs: &[Input],
+        text_message: Option<&Message>,
+        definition: Option<&Value>,
+    ) -> Result<(u32, u32)> {
+        let response = self.send_request(
+            "light/estimate_fees",
+            &serde_json::to_value(light::EstimateFeesRequest {
+                outputs: outputs.to_vec(),
+                inputs: inputs.to_vec(),
+                text_message: text_message.cloned(),
+                definition: definition.cloned(),
+            })?,
+        )?;
+
+        let fees: light::EstimateFeesResponse = serde_json::from_value(response)?;
+        Ok((fees.headers_commission, fees.payload_commission))
+    }
+
     //returned spendable the number of coins
     pub fn get_balance(&self, address: &str) -> Result<u64> {
         let response = self.send_request("get_balance", &serde_json::to_value(address)?)?;
@@ -127,6 +477,44 @@ impl WalletConn {
         Ok(balance)
     }
 
+    // get the balance with stable and pending utxos
+    pub fn get_balance_details(&self, address: &str) -> Result<light::BalanceDetails> {
+        let response =
+            self.send_request("light/get_balance_details", &serde_json::to_value(address)?)?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+
+    // get the stable utxos of the address that are spent by the pending joints
+    pub fn get_unconfirmed_inputs(&self, address: &str) -> Result<Vec<::business::UtxoKey>> {
+        let response = self.send_request(
+            "light/get_unconfirmed_inputs",
+            &serde_json::to_value(address)?,
+        )?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+
+    // get the stable balance of the address at a past stable mci
+    pub fn get_balance_at_mci(&self, address: &str, mci: usize) -> Result<u64> {
+        let response = self.send_request(
+            "light/get_balance_at_mci",
+            &json!({"address": address, "mci": mci}),
+        )?;
+
+        response["balance"]
+            .as_u64()
+            .ok_or_else(|| format_err!("no balance in get_balance_at_mci response"))
+    }
+
+    // get the statistics of the address
+    pub fn get_address_stats(&self, address: &str) -> Result<::business::AddressStats> {
+        let response =
+            self.send_request("light/get_address_stats", &serde_json::to_value(address)?)?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+
     // get tps info (latest 24 hours TPS)
     pub fn get_tps(&self) -> Result<::statistics::FinalizeJointTPS> {
         let tps_info = self.send_request("get_tps", &Value::Null)?;
@@ -134,12 +522,27 @@ impl WalletConn {
         Ok(serde_json::from_value(tps_info)?)
     }
 
+    // get the number of pending joints in hub
+    pub fn get_pending_count(&self) -> Result<usize> {
+        let response = self.send_request("get_pending_count", &Value::Null)?;
+        let count = response["pending_count"]
+            .as_u64()
+            .ok_or_else(|| format_err!("get pending count failed"))?;
+
+        Ok(count as usize)
+    }
+
     // get the network status
     pub fn get_net_state(&self) -> Result<super::hub::HubNetState> {
         let response = self.send_request("net_state", &Value::Null)?;
         Ok(serde_json::from_value(response)?)
     }
 
+    pub fn get_network_health(&self) -> Result<super::hub::HealthReport> {
+        let response = self.send_request("get_network_health", &Value::Null)?;
+        Ok(serde_json::from_value(response)?)
+    }
+
     // get the network statistics
     pub fn get_net_statistics(&self) -> Result<StdHashMap<String, ::statistics::LastConnStat>> {
         let response = self.send_request("net_statistics", &Value::Null)?;
@@ -194,6 +597,29 @@ impl WalletConn {
         Ok(serde_json::from_value(response)?)
     }
 
+    /// get the ancestor joints within depth hops, the walk stops at the stable ancestors
+    pub fn get_ancestors(&self, unit: &str, depth: usize) -> Result<Vec<Joint>> {
+        let response =
+            self.send_request("get_ancestors", &json!({ "unit": unit, "depth": depth }))?;
+        Ok(serde_json::from_value(response["ancestors"].clone())?)
+    }
+
+    /// return the free and unhandled joints that spend the same output with the unit
+    pub fn get_double_spends(&self, unit: &str) -> Result<Vec<String>> {
+        let response = self.send_request("get_double_spends", &serde_json::to_value(unit)?)?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+
+    /// return the ancestors chain along the best parent, parent before child
+    pub fn get_ancestors_chain(
+        &self,
+        unit: &str,
+        depth: usize,
+    ) -> Result<Vec<(Joint, JointProperty)>> {
+        get_ancestors_chain(unit, depth, |unit| self.get_joint_by_unit_hash(unit))
+    }
+
     pub fn get_joints_info(&self) -> Result<light::NumOfUnit> {
         let response = self.send_request("get_joints_info", &Value::Null)?;
 
@@ -237,6 +663,37 @@ impl WalletConn {
         Ok(serde_json::from_value(response)?)
     }
 
+    /// get one page of the pending and stable transactions of the address
+    pub fn get_full_history(
+        &self,
+        request: &light::FullHistoryRequest,
+    ) -> Result<light::FullHistoryResponse> {
+        let response =
+            self.send_request("light/get_full_history", &serde_json::to_value(request)?)?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+
+    pub fn get_link_proofs(&self, units: &[String]) -> Result<Vec<light::LinkProof>> {
+        let response = self.send_request("light/get_link_proofs", &serde_json::to_value(units)?)?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+
+    /// the reference mci is the last stable mci of the hub if not specified
+    pub fn get_joint_proof(
+        &self,
+        unit: &str,
+        reference_mci: Option<usize>,
+    ) -> Result<light::JointProof> {
+        let response = self.send_request(
+            "get_joint_proof",
+            &json!({"unit": unit, "reference_mci": reference_mci}),
+        )?;
+
+        Ok(serde_json::from_value(response)?)
+    }
+
     pub fn get_text(&self, unit: &str) -> Result<light::Text> {
         let response = self.send_request("get_text", &serde_json::to_value(unit)?)?;
 
@@ -254,11 +711,51 @@ impl WalletConn {
         Ok(serde_json::from_value(witnesses)?)
     }
 
+    /// let the hub push the new joints related to the address to the live handlers
+    /// and push the "stable" notification when they become stable
+    pub fn watch_address(&self, address: &str) -> Result<()> {
+        self.send_request("light/watch_address", &serde_json::to_value(address)?)?;
+        Ok(())
+    }
+
     pub fn add_watcher(&self, watch_address: &[String]) -> Result<()> {
         self.send_request("watch", &serde_json::to_value(watch_address.to_owned())?)?;
 
         Ok(())
     }
+
+    /// receive the new joints pushed by hub, the handler is called for the joints that are
+    /// authored by or paid to the filter address, or all the joints if no filter is given
+    /// the handler must not drop any subscription handle of the same connection
+    pub fn subscribe_live<F>(
+        &self,
+        address_filter: Option<String>,
+        handler: F,
+    ) -> Result<SubscriptionHandle>
+    where
+        F: Fn(Joint) + Send + 'static,
+    {
+        let handle = self.add_live_handler(address_filter, handler);
+        self.send_request("light/subscribe_joints", &Value::Null)?;
+        Ok(handle)
+    }
+
+    /// register a handler for the joints pushed by hub without subscribing, e.g. the joints
+    /// of the watched addresses
+    pub fn add_live_handler<F>(
+        &self,
+        address_filter: Option<String>,
+        handler: F,
+    ) -> SubscriptionHandle
+    where
+        F: Fn(Joint) + Send + 'static,
+    {
+        let id = self
+            .get_data()
+            .live_handlers
+            .add(address_filter, Box::new(handler));
+        SubscriptionHandle { conn: self, id }
+    }
 }
 
 // the server side impl
@@ -276,6 +773,40 @@ impl WalletConn {
         Ok(())
     }
 
+    fn on_have_updates(&self, _body: Value) -> Result<()> {
+        self.get_data().trigger_updates();
+        Ok(())
+    }
+
+    fn on_mci_reached(&self, body: Value) -> Result<()> {
+        let mci = body["mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("no mci in the pushed stable mci"))?;
+        self.get_data().trigger_mci_reached(mci as usize);
+        Ok(())
+    }
+
+    fn on_stable(&self, body: Value) -> Result<()> {
+        let unit = body["unit"]
+            .as_str()
+            .ok_or_else(|| format_err!("no unit in stable"))?;
+        let mci = body["mci"]
+            .as_u64()
+            .ok_or_else(|| format_err!("no mci in stable"))?;
+
+        let data = self.get_data();
+        data.stable_units
+            .insert(unit.to_owned(), Level::new(mci as usize));
+        data.trigger_updates();
+        Ok(())
+    }
+
+    fn on_joint(&self, body: Value) -> Result<()> {
+        let joint: Joint = serde_json::from_value(body)?;
+        self.get_data().live_handlers.dispatch(&joint);
+        Ok(())
+    }
+
     fn on_heartbeat(&self, _: Value) -> Result<Value> {
         Ok(Value::Null)
     }
@@ -321,18 +852,244 @@ fn init_connection(ws: &Arc<WalletConn>) -> Result<()> {
     ws.get_data().wait_init_done()
 }
 
+// the inputs of the joint, the amount equals to the outputs plus the commissions
+fn replacement_inputs(joint: &Joint) -> light::InputsResponse {
+    let mut inputs = Vec::new();
+    let mut amount = u64::from(joint.unit.headers_commission.unwrap_or(0))
+        + u64::from(joint.unit.payload_commission.unwrap_or(0));
+    for msg in &joint.unit.messages {
+        if let Some(Payload::Payment(ref payment)) = msg.payload {
+            inputs.extend(payment.inputs.iter().cloned());
+            amount += payment.outputs.iter().fold(0, |acc, x| acc + x.amount);
+        }
+    }
+    light::InputsResponse { inputs, amount }
+}
+
+// call f with exponential backoff until it returns some value or max_retries is reached
+fn poll_with_backoff<T, F>(max_retries: u8, mut backoff: Duration, mut f: F) -> Result<Option<T>>
+where
+    F: FnMut() -> Result<Option<T>>,
+{
+    for _ in 0..max_retries {
+        coroutine::sleep(backoff);
+        backoff *= 2;
+        if let Some(v) = f()? {
+            return Ok(Some(v));
+        }
+    }
+    Ok(None)
+}
+
+// the joint is authored by the address or pays to the address
+fn is_related_joint(joint: &Joint, address: &str) -> bool {
+    let unit = &joint.unit;
+    unit.authors.iter().any(|a| a.address == address)
+        || unit.messages.iter().any(|msg| match msg.payload {
+            Some(Payload::Payment(ref payment)) => {
+                payment.outputs.iter().any(|o| o.address == address)
+            }
+            _ => false,
+        })
+}
+
+// follow the best parent from the unit until genesis or reach the depth
+fn get_ancestors_chain<F>(
+    unit: &str,
+    depth: usize,
+    mut get_joint: F,
+) -> Result<Vec<(Joint, JointProperty)>>
+where
+    F: FnMut(&str) -> Result<(Joint, JointProperty)>,
+{
+    let mut chain = Vec::new();
+    let mut visited = HashSet::new();
+    let mut unit = unit.to_owned();
+
+    while chain.len() < depth {
+        if !visited.insert(unit.clone()) {
+            warn!("found cycle in best parent chain, unit={}", unit);
+            break;
+        }
+
+        let (joint, property) = get_joint(&unit)?;
+        // genesis has no last ball unit
+        let is_genesis = joint.unit.last_ball_unit.is_none();
+        unit = property.best_parent_unit.clone();
+        chain.push((joint, property));
+
+        if is_genesis {
+            break;
+        }
+    }
+
+    chain.reverse();
+    Ok(chain)
+}
+
 pub fn create_outbound_conn<A: ToSocketAddrs>(address: A) -> Result<Arc<WalletConn>> {
     let stream = TcpStream::connect(address)?;
     let peer = match stream.peer_addr() {
         Ok(addr) => addr.to_string(),
         Err(_) => "unknown peer".to_owned(),
     };
-    let url = Url::parse("wss://localhost/")?;
-    let req = Request::from(url);
-    let (conn, _) = client(req, stream)?;
+    let (conn, compress) = client_handshake(stream)?;
 
-    let ws = WsConnection::new(conn, WalletData::default(), peer, Role::Client)?;
+    let ws = WsConnection::new(conn, WalletData::default(), peer, Role::Client, compress)?;
 
     init_connection(&ws)?;
     Ok(ws)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spec::{Output, Payment, Unit, UnitBuilder};
+
+    #[test]
+    fn test_get_ancestors_chain() {
+        // unit_0 is genesis, unit_i's best parent is unit_{i-1}
+        let get_joint = |unit: &str| -> Result<(Joint, JointProperty)> {
+            let index: usize = unit.trim_start_matches("unit_").parse()?;
+            let mut joint = Joint {
+                ball: None,
+                skiplist_units: Vec::new(),
+                unit: Unit::default(),
+            };
+            joint.unit.unit = unit.to_owned();
+
+            let mut property = JointProperty::default();
+            property.level = Level::new(index);
+            if index > 0 {
+                joint.unit.last_ball_unit = Some("unit_0".to_owned());
+                property.best_parent_unit = format!("unit_{}", index - 1);
+            }
+            Ok((joint, property))
+        };
+
+        let chain = get_ancestors_chain("unit_4", 10, get_joint).unwrap();
+        let units = chain
+            .iter()
+            .map(|c| c.0.unit.unit.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            units,
+            vec!["unit_0", "unit_1", "unit_2", "unit_3", "unit_4"]
+        );
+
+        let chain = get_ancestors_chain("unit_4", 2, get_joint).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].0.unit.unit, "unit_3");
+    }
+
+    fn payment_joint(from: &str, to: &str) -> Joint {
+        let payment = Payload::Payment(Payment {
+            address: None,
+            asset: None,
+            definition_chash: None,
+            denomination: None,
+            inputs: Vec::new(),
+            outputs: vec![Output {
+                address: to.to_owned(),
+                amount: 100,
+            }],
+        });
+        let unit = UnitBuilder::new()
+            .author(from, Value::Null, StdHashMap::new())
+            .message("payment", payment)
+            .build()
+            .unwrap();
+
+        Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit,
+        }
+    }
+
+    #[test]
+    fn test_live_handlers_filter() {
+        let live_handlers = LiveHandlers::default();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_1 = received.clone();
+        let id = live_handlers.add(
+            Some("ADDRESS_A".to_owned()),
+            Box::new(move |joint: Joint| received_1.lock().unwrap().push(joint.unit.unit)),
+        );
+
+        let matching = payment_joint("ADDRESS_B", "ADDRESS_A");
+        let non_matching = payment_joint("ADDRESS_B", "ADDRESS_C");
+        live_handlers.dispatch(&matching);
+        live_handlers.dispatch(&non_matching);
+        assert_eq!(*received.lock().unwrap(), vec![matching.unit.unit.clone()]);
+
+        // the handler is not called after removed
+        assert!(live_handlers.remove(id));
+        live_handlers.dispatch(&matching);
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_replacement_inputs() {
+        let mut joint = payment_joint("ADDRESS_A", "ADDRESS_B");
+        joint.unit.headers_commission = Some(300);
+        joint.unit.payload_commission = Some(200);
+        if let Some(Payload::Payment(ref mut payment)) = joint.unit.messages[0].payload {
+            payment.inputs.push(Input {
+                unit: Some("UNIT_0".to_owned()),
+                message_index: Some(0),
+                output_index: Some(1),
+                ..Default::default()
+            });
+            payment.outputs.push(Output {
+                address: "ADDRESS_A".to_owned(),
+                amount: 400,
+            });
+        }
+
+        // the replacement spends exactly the same inputs, so it can't pay twice
+        let inputs = replacement_inputs(&joint);
+        assert_eq!(inputs.inputs.len(), 1);
+        assert_eq!(inputs.inputs[0].unit, Some("UNIT_0".to_owned()));
+        assert_eq!(inputs.inputs[0].output_index, Some(1));
+        assert_eq!(inputs.amount, 100 + 400 + 300 + 200);
+    }
+
+    #[test]
+    fn test_poll_with_backoff() {
+        let backoff = Duration::from_millis(1);
+
+        // stop polling once resolved
+        let mut polls = 0;
+        let rsp = poll_with_backoff(5, backoff, || {
+            polls += 1;
+            Ok(if polls == 3 { Some(polls) } else { None })
+        });
+        assert_eq!(rsp.unwrap(), Some(3));
+        assert_eq!(polls, 3);
+
+        // give up after max_retries
+        let mut polls = 0;
+        let rsp = poll_with_backoff(2, backoff, || -> Result<Option<()>> {
+            polls += 1;
+            Ok(None)
+        });
+        assert_eq!(rsp.unwrap(), None);
+        assert_eq!(polls, 2);
+
+        // the error is returned at once
+        let rsp = poll_with_backoff(5, backoff, || -> Result<Option<()>> { bail!("lost") });
+        assert!(rsp.is_err());
+    }
+
+    #[test]
+    fn test_post_joint_status() {
+        // the accepted reply is the same as before
+        let accepted: light::PostJointStatus =
+            serde_json::from_value(Value::from("accepted")).unwrap();
+        assert_eq!(accepted, light::PostJointStatus::Accepted);
+
+        let temp_bad = serde_json::to_value(light::PostJointStatus::TempBad).unwrap();
+        assert_eq!(temp_bad, Value::from("temp-bad"));
+    }
+}
@@ -10,6 +10,7 @@ use spec::{Payload, Unit};
 //---------------------------------------------------------------------------------------
 // NotifyEvent
 //---------------------------------------------------------------------------------------
+#[derive(Clone)]
 pub struct NotifyEvent {
     pub joint: RcuReader<JointData>,
 }
@@ -100,6 +101,17 @@ pub fn watcher_insert(self_address: &str, watch_addresses: &[String]) {
     WATCHERS.insert(self_address, watch_addresses);
 }
 
+/// return true if the peer watches the first author or any output address of the unit
+pub fn is_watched_by(peer_id: &str, unit: &Unit) -> bool {
+    let g = WATCHERS.watchers.read().unwrap();
+    let is_watching = |address: &String| match g.get(address) {
+        Some(watchers) => watchers.contains(peer_id),
+        None => false,
+    };
+
+    is_watching(&unit.authors[0].address) || get_output_addresses(unit).iter().any(is_watching)
+}
+
 /// network interface struct
 /// include all messages, except changes
 #[derive(Default, Serialize, Deserialize, Clone)]
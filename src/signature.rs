@@ -1,6 +1,8 @@
 use base64;
 use error::Result;
 use failure::ResultExt;
+use rand::{thread_rng, RngCore};
+use sdag_object_base::object_hash;
 use secp256k1::{key, All, Message, Secp256k1, Signature};
 
 lazy_static! {
@@ -23,6 +25,32 @@ pub fn sign(hash: &[u8], priv_key: &[u8]) -> Result<String> {
     Ok(base64::encode(&sig[..]))
 }
 
+/// same as `sign` but the priv_key is a base64 string
+pub fn sign_b64(hash: &[u8], b64_priv_key: &str) -> Result<String> {
+    sign(hash, &base64::decode(b64_priv_key)?)
+}
+
+/// generate a random key pair
+/// return (b64_priv_key, b64_pub_key, address of the single sig definition)
+pub fn generate_keypair() -> (String, String, String) {
+    let mut rng = thread_rng();
+    let priv_key = loop {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        // the random bytes may be out of the curve order, just try again
+        if let Ok(key) = key::SecretKey::from_slice(&bytes) {
+            break key;
+        }
+    };
+
+    let pub_key = key::PublicKey::from_secret_key(&SECP256K1, &priv_key);
+    let b64_pub_key = base64::encode(&pub_key.serialize()[..]);
+    let definition = json!(["sig", { "pubkey": b64_pub_key }]);
+    let address = object_hash::get_chash(&definition).expect("definition is always valid json");
+
+    (base64::encode(&priv_key[..]), b64_pub_key, address)
+}
+
 /// verify the bas64 string signiture with the hash and pub key (a bas64 string)
 pub fn verify(hash: &[u8], b64_sig: &str, b64_pub_key: &str) -> Result<()> {
     let msg = Message::from_slice(hash)?;
@@ -58,3 +86,20 @@ fn test_signature() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_generate_keypair() -> Result<()> {
+    use sha2::{Digest, Sha256};
+
+    let (priv_key, pub_key, address) = generate_keypair();
+    assert!(object_hash::is_chash_valid(&address));
+
+    let hello = Sha256::digest(b"hello");
+    let sig = sign_b64(&hello, &priv_key)?;
+    assert!(verify(&hello, &sig, &pub_key).is_ok());
+
+    let world = Sha256::digest(b"world");
+    assert!(verify(&world, &sig, &pub_key).is_err());
+
+    Ok(())
+}
@@ -3,7 +3,26 @@ use std::collections::HashMap as StdHashMap;
 use config;
 use error::Result;
 use sdag_object_base::{obj_ser, object_hash};
+use serde::Serialize;
 use serde_json::Value;
+use statistics;
+
+// consensus rules, all the nodes must agree on the values below
+
+/// the max levels the last ball of a joint could be behind its parents
+pub const MAX_LAST_BALL_LOOKBACK: usize = 100;
+/// the last ball lookback rules only apply to joints whose last ball mci is not less than this
+pub const LAST_BALL_LOOKBACK_UPGRADE_MCI: usize = 1_000_000;
+/// the max levels of the address delegation in a definition
+pub const MAX_ADDRESS_DELEGATION_DEPTH: usize = 5;
+/// the payload commission is distributed for the joints stable since this mci
+pub const PAYLOAD_COMMISSION_UPGRADE_MCI: usize = 1_000_000;
+/// the headers commission is distributed for the joints stable since this mci
+pub const HEADERS_COMMISSION_UPGRADE_MCI: usize = 1_000_000;
+/// the cone size breaks the precedence tie of the joints whose last ball mci is not less than this
+pub const CONE_SIZE_UPGRADE_MCI: usize = 1_000_000;
+/// the cone size used to break the precedence tie is counted up to this
+pub const MAX_CONE_SIZE: usize = 1_000;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -182,12 +201,29 @@ struct NakedUnit<'a> {
 #[inline]
 lazy_static! {
     pub static ref GENESIS_UNIT: String = ::config::get_genesis_unit();
+    // the same payload and unit are hashed several times during validation and composing
+    static ref HASH_CACHE: object_hash::HashCache =
+        object_hash::HashCache::with_capacity(3 * config::get_max_pending_joints());
 }
 
 pub fn is_genesis_unit(unit: &str) -> bool {
     unit == *GENESIS_UNIT
 }
 
+/// get the base64 hash of an immutable object, the result is cached
+pub fn get_base64_hash<T>(object: &T) -> Result<String>
+where
+    T: Serialize + ?Sized,
+{
+    let (hash, is_hit) = HASH_CACHE.get_base64_hash(object)?;
+    if is_hit {
+        statistics::increase_counter("sdag_object_hash_cache_hits_total");
+    } else {
+        statistics::increase_counter("sdag_object_hash_cache_misses_total");
+    }
+    Ok(hash)
+}
+
 impl Unit {
     pub fn is_genesis_unit(&self) -> bool {
         // is_genesis_unit(&self.unit)
@@ -227,13 +263,12 @@ impl Unit {
     }
 
     pub fn get_unit_content_hash(&self) -> String {
-        object_hash::get_base64_hash(&self.get_naked_unit()).expect("get_unit_content_hash failed")
+        get_base64_hash(&self.get_naked_unit()).expect("get_unit_content_hash failed")
     }
 
     pub fn calc_unit_hash(&self) -> String {
         if self.content_hash.is_some() {
-            return object_hash::get_base64_hash(&self.get_naked_unit())
-                .expect("get_unit_hash naked failed");
+            return get_base64_hash(&self.get_naked_unit()).expect("get_unit_hash naked failed");
         }
 
         #[derive(Debug, Serialize)]
@@ -278,7 +313,7 @@ impl Unit {
             witness_list_unit: &self.witness_list_unit,
         };
 
-        object_hash::get_base64_hash(&stripped_unit).expect("get_unit_hash failed")
+        get_base64_hash(&stripped_unit).expect("get_unit_hash failed")
     }
 
     pub fn calc_unit_hash_to_sign(&self) -> Vec<u8> {
@@ -378,6 +413,129 @@ impl Default for Unit {
     }
 }
 
+//---------------------------------------------------------------------------------------
+// UnitBuilder
+//---------------------------------------------------------------------------------------
+/// fluent builder for `Unit`, the commissions and hashes are calculated by `build`
+#[derive(Default)]
+pub struct UnitBuilder {
+    unit: Unit,
+}
+
+impl UnitBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn alt(mut self, alt: &str) -> Self {
+        self.unit.alt = alt.to_owned();
+        self
+    }
+
+    pub fn version(mut self, version: &str) -> Self {
+        self.unit.version = version.to_owned();
+        self
+    }
+
+    pub fn author(
+        mut self,
+        address: &str,
+        definition: Value,
+        authentifiers: StdHashMap<String, String>,
+    ) -> Self {
+        self.unit.authors.push(Author {
+            address: address.to_owned(),
+            authentifiers,
+            definition,
+        });
+        self
+    }
+
+    /// add an inline message, the payload hash is calculated by `build`
+    pub fn message(mut self, app: &str, payload: Payload) -> Self {
+        self.unit.messages.push(Message {
+            app: app.to_owned(),
+            payload: Some(payload),
+            payload_location: "inline".to_owned(),
+            ..Default::default()
+        });
+        self
+    }
+
+    pub fn parent(mut self, unit: &str) -> Self {
+        self.unit.parent_units.push(unit.to_owned());
+        self
+    }
+
+    pub fn last_ball(mut self, ball: &str, unit: &str) -> Self {
+        self.unit.last_ball = Some(ball.to_owned());
+        self.unit.last_ball_unit = Some(unit.to_owned());
+        self
+    }
+
+    pub fn witnesses(mut self, witnesses: Vec<String>) -> Self {
+        self.unit.witnesses = witnesses;
+        self
+    }
+
+    pub fn witness_list_unit(mut self, unit: &str) -> Self {
+        self.unit.witness_list_unit = Some(unit.to_owned());
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.unit.timestamp = Some(timestamp);
+        self
+    }
+
+    /// calculate the payload hashes, commissions and the unit hash
+    pub fn build(self) -> Result<Unit> {
+        let mut unit = self.unit;
+        UnitBuilder::calc_payload_hashes(&mut unit)?;
+        unit.headers_commission = Some(unit.calc_header_size());
+        unit.payload_commission = Some(unit.calc_payload_size());
+        unit.unit = unit.calc_unit_hash();
+        Ok(unit)
+    }
+
+    /// build the unit and sign it for each author with the base64 private key
+    pub fn sign(self, private_key: &str) -> Result<Unit> {
+        use base64;
+        use signature;
+
+        let priv_key = base64::decode(private_key)?;
+        let mut unit = self.unit;
+        UnitBuilder::calc_payload_hashes(&mut unit)?;
+
+        // here we use a dummy signature to calc the correct header size
+        for author in &mut unit.authors {
+            author
+                .authentifiers
+                .insert("r".to_owned(), "-".repeat(config::SIG_LENGTH));
+        }
+        unit.headers_commission = Some(unit.calc_header_size());
+        unit.payload_commission = Some(unit.calc_payload_size());
+
+        let unit_hash = unit.calc_unit_hash_to_sign();
+        for author in &mut unit.authors {
+            let sig = signature::sign(&unit_hash, &priv_key)?;
+            author.authentifiers.insert("r".to_owned(), sig);
+        }
+
+        unit.unit = unit.calc_unit_hash();
+        Ok(unit)
+    }
+
+    fn calc_payload_hashes(unit: &mut Unit) -> Result<()> {
+        for msg in &mut unit.messages {
+            if let Some(ref payload) = msg.payload {
+                msg.payload_hash = get_base64_hash(payload)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct Definition<'a> {
     pub op: &'a str,
     pub args: &'a Value,
@@ -461,6 +619,77 @@ fn test_unit_hash() {
     assert_eq!(unit.calc_payload_size(), 157);
 }
 
+#[test]
+fn test_unit_builder() {
+    let payment = Payload::Payment(Payment {
+        address: None,
+        asset: None,
+        definition_chash: None,
+        denomination: None,
+        inputs: vec![Input {
+            unit: Some("rHwZyXWZRFeU/LA3Kga+xGvjijNXYQwTbufMjqdxmPg=".to_owned()),
+            message_index: Some(0),
+            output_index: Some(0),
+            ..Default::default()
+        }],
+        outputs: vec![Output {
+            address: "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI".to_owned(),
+            amount: 82375,
+        }],
+    });
+    let mut authentifiers = StdHashMap::new();
+    authentifiers.insert("r".to_owned(), "-".repeat(config::SIG_LENGTH));
+
+    let built = UnitBuilder::new()
+        .author(
+            "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI",
+            Value::Null,
+            authentifiers.clone(),
+        )
+        .message("payment", payment.clone())
+        .parent("Y+A+trJA30+P6PsC0hX5CwhNDj80w4OmJMcnq5Ou1FU=")
+        .last_ball(
+            "SVnrEYhIOKmku91eWlwnPMV2gf/lMYpg36AL/zfakag=",
+            "dimZTmLvmjNfo7I6Go9juCIokk5I+tgyxAfNPlg16G4=",
+        )
+        .witness_list_unit("MtzrZeOHHjqVZheuLylf0DX7zhp10nBsQX5e/+cA3PQ=")
+        .timestamp(1_527_218_469)
+        .build()
+        .unwrap();
+
+    let mut unit = Unit {
+        authors: vec![Author {
+            address: "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI".to_owned(),
+            authentifiers,
+            definition: Value::Null,
+        }],
+        messages: vec![Message {
+            app: "payment".to_owned(),
+            payload_hash: get_base64_hash(&payment).unwrap(),
+            payload: Some(payment),
+            payload_location: "inline".to_owned(),
+            ..Default::default()
+        }],
+        parent_units: vec!["Y+A+trJA30+P6PsC0hX5CwhNDj80w4OmJMcnq5Ou1FU=".to_owned()],
+        last_ball: Some("SVnrEYhIOKmku91eWlwnPMV2gf/lMYpg36AL/zfakag=".to_owned()),
+        last_ball_unit: Some("dimZTmLvmjNfo7I6Go9juCIokk5I+tgyxAfNPlg16G4=".to_owned()),
+        witness_list_unit: Some("MtzrZeOHHjqVZheuLylf0DX7zhp10nBsQX5e/+cA3PQ=".to_owned()),
+        timestamp: Some(1_527_218_469),
+        ..Default::default()
+    };
+    unit.headers_commission = Some(unit.calc_header_size());
+    unit.payload_commission = Some(unit.calc_payload_size());
+    unit.unit = unit.calc_unit_hash();
+
+    assert_eq!(built.unit, unit.unit);
+    assert_eq!(built.headers_commission, unit.headers_commission);
+    assert_eq!(built.payload_commission, unit.payload_commission);
+    assert_eq!(
+        built.messages[0].payload_hash,
+        unit.messages[0].payload_hash
+    );
+}
+
 #[test]
 fn test_unit_json() {
     use serde_json;
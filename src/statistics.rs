@@ -1,10 +1,16 @@
 use std::collections::HashMap as StdHashMap;
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
 use hashbrown::HashMap;
 use network::hub;
 
+// the upper bounds of the histogram buckets
+const HISTOGRAM_BUCKETS: [u64; 6] = [1, 10, 100, 1_000, 10_000, 60_000];
+// the latest latency samples kept for each peer
+const MAX_LATENCY_SAMPLES: usize = 100;
+
 lazy_static! {
     // stored all connection statistics
     static ref ALL_STATS: STATS = STATS::default();
@@ -19,109 +25,137 @@ pub struct STATS {
     conn_stats: RwLock<HashMap<Arc<String>, ConnStats>>,
     // finalize_joint_count = AtomicUsize::new(0);
     finalize_joint_stats: FinalizeJointStats,
+    // key is the counter name with labels, like `name{peer_id="xxx"}`
+    event_counters: RwLock<HashMap<String, AtomicUsize>>,
+    // key is peer_id, val is the latest request latencies in ms
+    peer_latencies: RwLock<HashMap<String, VecDeque<u64>>>,
 }
 
 impl STATS {
     /// hub timer call update every secs
-    /// 1) if timestamp % 60 == 0, collect all secs and set mins[timestamp/60], then reset all secs to None
-    /// 2) if timestamp % 3600 == 0, collect all mins and set hours[(timestamp/3600)%24], then reset all mins to None
-    /// 3) if timestamp % 86400 == 0, collect all hours and set days[(timestamp/86400)%30], then reset all hours to None
+    /// remove the connection stats that have no records in the biggest window
     fn conn_stats_update(&self) {
-        let timestamp = (::time::now() / 1000) as usize;
-        let is_mins = timestamp % 60 == 0;
-        if !is_mins {
-            return;
-        }
-
-        let mins_index = (timestamp / 60) % 60;
-        let is_hours = timestamp % 3600 == 0;
-        let hours_index = (timestamp / 3600) % 24;
-        let is_days = timestamp % 86400 == 0;
-        let days_index = (timestamp / 86400) % 30;
-
-        let mut to_remove = Vec::new();
-
+        let now = ::time::monotonic_now() / 1000;
         let mut w_g = self.conn_stats.write().unwrap();
-
-        for (id, stat) in w_g.iter_mut() {
-            if is_mins {
-                let total_secs = sum_all_stats(&stat.secs);
-                stat.mins[mins_index] = total_secs;
-                stat.secs = [StatsPerPeriod::default(); 60];
-            }
-            if is_hours {
-                let total_mins = sum_all_stats(&stat.mins);
-                stat.hours[hours_index] = total_mins;
-                stat.mins = [StatsPerPeriod::default(); 60];
-            }
-            if is_days {
-                let total_hours = sum_all_stats(&stat.hours);
-                stat.days[days_index] = total_hours;
-                stat.hours = [StatsPerPeriod::default(); 24];
-
-                // if the connection has no any stats in last 1 day, then remove it
-                if total_hours.is_zero() {
-                    to_remove.push(id.clone());
-                }
-            }
-        }
+        let to_remove = w_g
+            .iter()
+            .filter(|(_, stat)| stat.is_expired(now))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
 
         for id in to_remove {
             w_g.remove(&id);
         }
     }
 
-    /// get all last stats, last stats may less than the real stats
-    /// example: if now is 01:30:30
-    /// 1) last_min is stats of (01:30:00, 01:30:30]
-    /// 2) last_hour is stats of (01:00:00, 01:30:30]
-    /// 3) last_day is stats of (00:30:00, 01:30:30]
+    /// get all last stats of each configured window
+    /// example: if now is 01:30:30, the 60s window is stats of (01:29:30, 01:30:30]
     fn get_all_last_stats(&self) -> StdHashMap<String, LastConnStat> {
-        let now = (::time::now() / 1000) as usize;
-        let mut all_stats = StdHashMap::new();
+        let now = ::time::monotonic_now() / 1000;
         let r_g = self.conn_stats.read().unwrap();
 
-        for (id, stat) in r_g.iter() {
-            let total_sec = (*stat).secs[now % 60];
-            let total_min = sum_all_stats(&stat.secs);
-            let total_hour = sum_all_stats(&[sum_all_stats(&stat.mins), total_min]);
-            let total_day = sum_all_stats(&[sum_all_stats(&stat.hours), total_hour]);
-
-            let last_stat = LastConnStat {
-                peer_addr: stat.peer_addr.to_string(),
-                sec: total_sec,
-                min: total_min,
-                hour: total_hour,
-                day: total_day,
-                is_connected: false,
-            };
-            all_stats.insert(id.to_string(), last_stat);
-        }
+        r_g.iter()
+            .map(|(id, stat)| {
+                let last_stat = LastConnStat {
+                    peer_addr: stat.peer_addr.to_string(),
+                    windows: stat.get_window_stats(now),
+                    is_connected: false,
+                };
+                (id.to_string(), last_stat)
+            })
+            .collect()
+    }
 
-        all_stats
+    fn get_last_stats(&self, peer_id: &str) -> Option<LastConnStat> {
+        let now = ::time::monotonic_now() / 1000;
+        let r_g = self.conn_stats.read().unwrap();
+        r_g.get(&peer_id.to_owned()).map(|stat| LastConnStat {
+            peer_addr: stat.peer_addr.to_string(),
+            windows: stat.get_window_stats(now),
+            is_connected: false,
+        })
     }
 
     fn increase_sec(&self, peer_id: Arc<String>, is_rx: bool, is_good: bool) {
-        let index = (::time::now() / 1000 % 60) as usize;
+        let now = ::time::monotonic_now() / 1000;
 
         if let Some(v) = self.conn_stats.write().unwrap().get_mut(&peer_id) {
-            return v.secs[index].increase(is_rx, is_good);
+            return v.increase(now, is_rx, is_good);
         }
 
         // init a new conn_stat and insert
-        let mut new_stat = StatsPerPeriod::default();
-        new_stat.increase(is_rx, is_good);
-
         let peer_addr = match hub::WSS.get_connection(peer_id.clone()) {
             Some(conn) => conn.get_peer_addr().to_string(),
             None => String::from("unknown"),
         };
 
-        let mut new_stats = ConnStats::new(peer_addr);
-        new_stats.secs[index] = new_stat;
+        let mut new_stats = ConnStats::new(peer_addr, ::config::get_statistics_windows(), now);
+        new_stats.increase(now, is_rx, is_good);
         self.conn_stats.write().unwrap().insert(peer_id, new_stats);
     }
 
+    // only the first update of a new key takes the write lock
+    fn with_counter<F: FnOnce(&AtomicUsize)>(&self, key: &str, f: F) {
+        if let Some(counter) = self.event_counters.read().unwrap().get(key) {
+            return f(counter);
+        }
+
+        let mut g = self.event_counters.write().unwrap();
+        f(g.entry(key.to_owned())
+            .or_insert_with(|| AtomicUsize::new(0)));
+    }
+
+    fn add_counter(&self, key: &str, value: usize) {
+        self.with_counter(key, |c| {
+            c.fetch_add(value, Ordering::Relaxed);
+        });
+    }
+
+    fn set_gauge(&self, key: &str, value: usize) {
+        self.with_counter(key, |c| c.store(value, Ordering::Relaxed));
+    }
+
+    // the buckets are cumulative, like `name_bucket{le="100"}`
+    fn observe_histogram(&self, name: &str, value: u64) {
+        for le in HISTOGRAM_BUCKETS.iter().filter(|le| value <= **le) {
+            self.add_counter(&format!("{}_bucket{{le=\"{}\"}}", name, le), 1);
+        }
+        self.add_counter(&format!("{}_bucket{{le=\"+Inf\"}}", name), 1);
+        self.add_counter(&format!("{}_sum", name), value as usize);
+        self.add_counter(&format!("{}_count", name), 1);
+    }
+
+    fn record_peer_latency(&self, peer_id: &str, ms: u64) {
+        let mut g = self.peer_latencies.write().unwrap();
+        let samples = g.entry(peer_id.to_owned()).or_insert_with(VecDeque::new);
+        if samples.len() >= MAX_LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(ms);
+    }
+
+    // return None if the peer has no samples
+    fn get_peer_p50_latency(&self, peer_id: &str) -> Option<u64> {
+        let r_g = self.peer_latencies.read().unwrap();
+        let mut samples = match r_g.get(peer_id) {
+            Some(s) if !s.is_empty() => s.iter().cloned().collect::<Vec<_>>(),
+            _ => return None,
+        };
+        samples.sort();
+        Some(samples[(samples.len() - 1) / 2])
+    }
+
+    fn remove_peer_latency(&self, peer_id: &str) {
+        self.peer_latencies.write().unwrap().remove(peer_id);
+    }
+
+    fn get_all_counters(&self) -> StdHashMap<String, usize> {
+        let r_g = self.event_counters.read().unwrap();
+        r_g.iter()
+            .map(|(k, v)| (k.clone(), v.load(Ordering::Relaxed)))
+            .collect()
+    }
+
     fn get_peer_id_by_address(&self, peer_addr: &str) -> Option<String> {
         let r_g = self.conn_stats.read().unwrap();
         for (key, val) in r_g.iter() {
@@ -140,21 +174,69 @@ impl STATS {
 
 pub struct ConnStats {
     peer_addr: String,
-    secs: [StatsPerPeriod; 60],  // capacity 60
-    mins: [StatsPerPeriod; 60],  // capacity 60
-    hours: [StatsPerPeriod; 24], // capacity 24
-    days: [StatsPerPeriod; 30],  // capacity 30
+    // window sizes in seconds
+    windows: Vec<u64>,
+    // per second ring buffer, sized by the biggest window
+    buckets: Vec<StatsPerPeriod>,
+    // the timestamp in seconds of the latest bucket
+    last_sec: u64,
 }
 
 impl ConnStats {
-    fn new(peer_addr: String) -> Self {
+    fn new(peer_addr: String, windows: Vec<u64>, now: u64) -> Self {
+        let size = windows.iter().cloned().max().unwrap_or(1).max(1);
         ConnStats {
             peer_addr,
-            secs: [StatsPerPeriod::default(); 60],
-            mins: [StatsPerPeriod::default(); 60],
-            hours: [StatsPerPeriod::default(); 24],
-            days: [StatsPerPeriod::default(); 30],
+            windows,
+            buckets: vec![StatsPerPeriod::default(); size as usize],
+            last_sec: now,
+        }
+    }
+
+    // clear the outdated buckets between the latest bucket and now
+    fn advance(&mut self, now: u64) {
+        if now <= self.last_sec {
+            return;
         }
+
+        let size = self.buckets.len() as u64;
+        if now - self.last_sec >= size {
+            self.buckets = vec![StatsPerPeriod::default(); size as usize];
+        } else {
+            for sec in self.last_sec + 1..=now {
+                self.buckets[(sec % size) as usize] = StatsPerPeriod::default();
+            }
+        }
+        self.last_sec = now;
+    }
+
+    fn increase(&mut self, now: u64, is_rx: bool, is_good: bool) {
+        self.advance(now);
+        let size = self.buckets.len() as u64;
+        self.buckets[(now % size) as usize].increase(is_rx, is_good);
+    }
+
+    // sum the buckets in (now - window, now] for each window
+    fn get_window_stats(&self, now: u64) -> Vec<(u64, StatsPerPeriod)> {
+        let size = self.buckets.len() as u64;
+        self.windows
+            .iter()
+            .map(|&window| {
+                let mut total = StatsPerPeriod::default();
+                // the buckets after last_sec are not cleared yet, they must be empty
+                let end = ::std::cmp::min(now, self.last_sec);
+                let start = (now + 1).saturating_sub(window);
+                let start = ::std::cmp::max(start, (self.last_sec + 1).saturating_sub(size));
+                for sec in start..=end {
+                    total.add(&self.buckets[(sec % size) as usize]);
+                }
+                (window, total)
+            })
+            .collect()
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_sec) >= self.buckets.len() as u64
     }
 }
 
@@ -181,8 +263,10 @@ impl StatsPerPeriod {
         }
     }
 
-    fn is_zero(&self) -> bool {
-        self.rx_good == 0 && self.rx_bad == 0 && self.tx_total == 0
+    pub fn add(&mut self, other: &StatsPerPeriod) {
+        self.rx_good += other.rx_good;
+        self.rx_bad += other.rx_bad;
+        self.tx_total += other.tx_total;
     }
 }
 
@@ -190,32 +274,54 @@ impl StatsPerPeriod {
 // LastConnStat
 //---------------------------------------------------------------------------------------
 /// network interface struct
+/// the window stats are serialized with the window size as key, like `{"60s":{...}}`
 #[derive(Default, Serialize, Deserialize)]
 pub struct LastConnStat {
     pub peer_addr: String,
-    pub sec: StatsPerPeriod,
-    pub min: StatsPerPeriod,
-    pub hour: StatsPerPeriod,
-    pub day: StatsPerPeriod,
+    #[serde(flatten, with = "window_stats")]
+    pub windows: Vec<(u64, StatsPerPeriod)>,
     pub is_connected: bool,
 }
 
-//---------------------------------------------------------------------------------------
-// Global Functions
-//---------------------------------------------------------------------------------------
-#[inline]
-fn sum_all_stats(stats: &[StatsPerPeriod]) -> StatsPerPeriod {
-    let mut total_state = StatsPerPeriod::default();
-
-    for stat in stats {
-        total_state.rx_good += stat.rx_good;
-        total_state.rx_bad += stat.rx_bad;
-        total_state.tx_total += stat.tx_total;
+mod window_stats {
+    use super::StatsPerPeriod;
+    use serde::de::{Deserialize, Deserializer, Error};
+    use serde::ser::{SerializeMap, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S>(windows: &[(u64, StatsPerPeriod)], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(windows.len()))?;
+        for (window, stat) in windows {
+            map.serialize_entry(&format!("{}s", window), stat)?;
+        }
+        map.end()
     }
 
-    total_state
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<(u64, StatsPerPeriod)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = HashMap::<String, StatsPerPeriod>::deserialize(deserializer)?;
+        let mut windows = map
+            .into_iter()
+            .map(|(k, v)| {
+                k.trim_end_matches('s')
+                    .parse::<u64>()
+                    .map(|window| (window, v))
+                    .map_err(|_| D::Error::custom(format!("invalid window {}", k)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        windows.sort_by_key(|v| v.0);
+        Ok(windows)
+    }
 }
 
+//---------------------------------------------------------------------------------------
+// Global Functions
+//---------------------------------------------------------------------------------------
 #[derive(Default)]
 struct FinalizeJointStats {
     count: AtomicUsize,
@@ -290,6 +396,8 @@ pub fn update_stats() {
 /// only increase secs, mins/hours/days will update by timer
 #[inline]
 pub fn increase_stats(peer_id: Arc<String>, is_rx: bool, is_good: bool) {
+    #[cfg(feature = "prometheus")]
+    prometheus_export::increase_stats(is_rx, is_good);
     ALL_STATS.increase_sec(peer_id, is_rx, is_good);
 }
 
@@ -298,6 +406,56 @@ pub fn get_all_last_stats() -> StdHashMap<String, LastConnStat> {
     ALL_STATS.get_all_last_stats()
 }
 
+/// get the last statistics of a peer
+pub fn get_last_stats(peer_id: &str) -> Option<LastConnStat> {
+    ALL_STATS.get_last_stats(peer_id)
+}
+
+/// increase the event counter of the specified name
+pub fn increase_counter(name: &str) {
+    ALL_STATS.add_counter(name, 1);
+}
+
+/// add the value to the event counter of the specified name, like the bytes count
+pub fn add_counter(name: &str, value: usize) {
+    ALL_STATS.add_counter(name, value);
+}
+
+/// increase the event counter of the specified name for a peer
+pub fn increase_peer_counter(name: &str, peer_id: &str) {
+    ALL_STATS.add_counter(&format!("{}{{peer_id=\"{}\"}}", name, peer_id), 1);
+}
+
+/// set the current value of a gauge, it's exported with the event counters
+pub fn set_gauge(name: &str, value: usize) {
+    ALL_STATS.set_gauge(name, value);
+}
+
+/// record a sample of the specified histogram, it's exported with the event counters
+pub fn observe_histogram(name: &str, value: u64) {
+    ALL_STATS.observe_histogram(name, value);
+}
+
+/// get all the event counters
+pub fn get_all_counters() -> StdHashMap<String, usize> {
+    ALL_STATS.get_all_counters()
+}
+
+/// record the latency of a request to the peer
+pub fn record_peer_latency(peer_id: &str, ms: u64) {
+    ALL_STATS.record_peer_latency(peer_id, ms);
+}
+
+/// get the median latency in ms of the latest requests to the peer, None if unknown
+pub fn get_peer_p50_latency(peer_id: &str) -> Option<u64> {
+    ALL_STATS.get_peer_p50_latency(peer_id)
+}
+
+/// drop the latency samples of a closed peer
+pub fn remove_peer_latency(peer_id: &str) {
+    ALL_STATS.remove_peer_latency(peer_id);
+}
+
 pub fn get_peer_id_by_address(peer_addr: &str) -> Option<String> {
     ALL_STATS.get_peer_id_by_address(peer_addr)
 }
@@ -305,3 +463,304 @@ pub fn get_peer_id_by_address(peer_addr: &str) -> Option<String> {
 pub fn get_tps_info() -> FinalizeJointTPS {
     ALL_STATS.finalize_joint_stats.get_tps_info()
 }
+
+/// all the metrics in the prometheus text format, including the event counters
+#[cfg(feature = "prometheus")]
+pub fn prometheus_text_export() -> String {
+    prometheus_export::text_export(&get_all_counters())
+}
+
+//---------------------------------------------------------------------------------------
+// Prometheus
+//---------------------------------------------------------------------------------------
+#[cfg(feature = "prometheus")]
+mod prometheus_export {
+    extern crate prometheus;
+
+    use std::collections::HashMap as StdHashMap;
+
+    use self::prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+    use cache::SDAG_CACHE;
+
+    lazy_static! {
+        static ref METRICS: Metrics = Metrics::new();
+    }
+
+    struct Metrics {
+        registry: Registry,
+        rx_good: IntCounter,
+        rx_bad: IntCounter,
+        tx_total: IntCounter,
+        normal_joints: IntGauge,
+        bad_joints: IntGauge,
+        temp_bad_joints: IntGauge,
+        unhandled_joints: IntGauge,
+        last_stable_mci: IntGauge,
+    }
+
+    impl Metrics {
+        fn new() -> Self {
+            let registry = Registry::new();
+            let r = &registry;
+            let rx_good = register_counter(r, "sdag_rx_good_total", "good received");
+            let rx_bad = register_counter(r, "sdag_rx_bad_total", "bad received");
+            let tx_total = register_counter(r, "sdag_tx_total", "sent");
+            let normal_joints = register_gauge(r, "sdag_normal_joints", "normal joints");
+            let bad_joints = register_gauge(r, "sdag_bad_joints", "known bad joints");
+            let temp_bad_joints = register_gauge(r, "sdag_temp_bad_joints", "temp bad joints");
+            let unhandled_joints = register_gauge(r, "sdag_unhandled_joints", "unhandled joints");
+            let last_stable_mci = register_gauge(r, "sdag_last_stable_mci", "last stable mci");
+
+            Metrics {
+                registry,
+                rx_good,
+                rx_bad,
+                tx_total,
+                normal_joints,
+                bad_joints,
+                temp_bad_joints,
+                unhandled_joints,
+                last_stable_mci,
+            }
+        }
+
+        // the cache gauges are expensive to count, so they are pulled on export
+        fn update_gauges(&self) {
+            let normal_joints = SDAG_CACHE.get_num_of_normal_joints();
+            self.normal_joints.set(normal_joints as i64);
+            let bad_joints = SDAG_CACHE.get_num_of_bad_joints();
+            self.bad_joints.set(bad_joints as i64);
+            let temp_bad_joints = SDAG_CACHE.get_num_of_temp_bad_joints();
+            self.temp_bad_joints.set(temp_bad_joints as i64);
+            let unhandled_joints = SDAG_CACHE.get_num_of_unhandled_joints();
+            self.unhandled_joints.set(unhandled_joints as i64);
+            let last_stable_mci = ::main_chain::get_last_stable_mci();
+            self.last_stable_mci.set(last_stable_mci.value() as i64);
+        }
+    }
+
+    // the counters are summed over all the peers, a label per peer is unbounded
+    fn register_counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+        let counter = IntCounter::new(name, help).expect("invalid prometheus counter");
+        registry
+            .register(Box::new(counter.clone()))
+            .expect("register prometheus counter failed");
+        counter
+    }
+
+    fn register_gauge(registry: &Registry, name: &str, help: &str) -> IntGauge {
+        let gauge = IntGauge::new(name, help).expect("invalid prometheus gauge");
+        registry
+            .register(Box::new(gauge.clone()))
+            .expect("register prometheus gauge failed");
+        gauge
+    }
+
+    pub fn increase_stats(is_rx: bool, is_good: bool) {
+        let counter = match (is_rx, is_good) {
+            (true, true) => &METRICS.rx_good,
+            (true, false) => &METRICS.rx_bad,
+            (false, _) => &METRICS.tx_total,
+        };
+        counter.inc();
+    }
+
+    pub fn text_export(counters: &StdHashMap<String, usize>) -> String {
+        METRICS.update_gauges();
+
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buf) {
+            error!("failed to encode prometheus metrics, err={}", e);
+        }
+        let mut text = String::from_utf8_lossy(&buf).into_owned();
+
+        // the event counters are already named like `name{label="x"}`
+        // the per peer ones are summed over all the peers
+        let mut samples = StdHashMap::new();
+        for (name, value) in counters {
+            let name = match name.find("{peer_id=") {
+                Some(i) => &name[..i],
+                None => name.as_str(),
+            };
+            *samples.entry(name).or_insert(0) += *value;
+        }
+        let mut samples = samples.into_iter().collect::<Vec<_>>();
+        samples.sort();
+
+        let mut last_family = "";
+        for &(name, value) in &samples {
+            let family = get_family(name, &samples);
+            if family.0 != last_family {
+                text.push_str(&format!("# TYPE {} {}\n", family.0, family.1));
+                last_family = family.0;
+            }
+            text.push_str(&format!("{} {}\n", name, value));
+        }
+        text
+    }
+
+    // the metric family name and type of the sample
+    // the histograms are recorded as `name_bucket`, `name_sum` and `name_count`
+    fn get_family<'a>(name: &'a str, samples: &[(&str, usize)]) -> (&'a str, &'static str) {
+        let name = match name.find('{') {
+            Some(i) => &name[..i],
+            None => name,
+        };
+        for suffix in &["_bucket", "_sum", "_count"] {
+            if name.ends_with(suffix) {
+                let base = &name[..name.len() - suffix.len()];
+                let bucket = format!("{}_bucket", base);
+                if samples.iter().any(|(s, _)| s.starts_with(&bucket)) {
+                    return (base, "histogram");
+                }
+            }
+        }
+        if name.ends_with("_total") {
+            (name, "counter")
+        } else {
+            (name, "gauge")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_rx_good(stat: &ConnStats, now: u64) -> Vec<usize> {
+        stat.get_window_stats(now)
+            .iter()
+            .map(|(_, s)| s.rx_good)
+            .collect()
+    }
+
+    #[test]
+    fn test_histogram() {
+        let stats = STATS::default();
+        stats.observe_histogram("wait_ms", 5);
+        stats.observe_histogram("wait_ms", 500);
+        stats.observe_histogram("wait_ms", 100_000);
+
+        let counters = stats.get_all_counters();
+        assert_eq!(counters.get("wait_ms_bucket{le=\"1\"}"), None);
+        assert_eq!(counters["wait_ms_bucket{le=\"10\"}"], 1);
+        assert_eq!(counters["wait_ms_bucket{le=\"1000\"}"], 2);
+        assert_eq!(counters["wait_ms_bucket{le=\"+Inf\"}"], 3);
+        assert_eq!(counters["wait_ms_sum"], 100_505);
+        assert_eq!(counters["wait_ms_count"], 3);
+    }
+
+    #[test]
+    fn test_concurrent_counters() {
+        let stats = Arc::new(STATS::default());
+        let handles = (0..4)
+            .map(|_| {
+                let stats = stats.clone();
+                ::std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        stats.add_counter("events_total", 1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        stats.set_gauge("last_mci", 10);
+        stats.set_gauge("last_mci", 7);
+
+        let counters = stats.get_all_counters();
+        assert_eq!(counters["events_total"], 4000);
+        // a gauge is overwritten, not added
+        assert_eq!(counters["last_mci"], 7);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus_text_export() {
+        prometheus_export::increase_stats(true, true);
+        prometheus_export::increase_stats(false, true);
+
+        let mut counters = StdHashMap::new();
+        counters.insert("sdag_test_events_total{peer_id=\"x\"}".to_owned(), 3);
+        counters.insert("sdag_test_events_total{peer_id=\"y\"}".to_owned(), 2);
+        counters.insert("sdag_test_wait_ms_bucket{le=\"+Inf\"}".to_owned(), 1);
+        counters.insert("sdag_test_wait_ms_count".to_owned(), 1);
+        counters.insert("sdag_test_wait_ms_sum".to_owned(), 5);
+        let text = prometheus_export::text_export(&counters);
+
+        assert!(text.contains("# TYPE sdag_rx_good_total counter\nsdag_rx_good_total "));
+        assert!(text.contains("# TYPE sdag_last_stable_mci gauge\n"));
+        // the per peer counters are summed
+        assert!(text.contains("# TYPE sdag_test_events_total counter\nsdag_test_events_total 5\n"));
+        assert!(!text.contains("peer_id"));
+        // one type line for the histogram family
+        assert!(text.contains("# TYPE sdag_test_wait_ms histogram\n"));
+        assert_eq!(text.matches("# TYPE sdag_test_wait_ms").count(), 1);
+    }
+
+    #[test]
+    fn test_peer_p50_latency() {
+        let stats = STATS::default();
+        assert_eq!(stats.get_peer_p50_latency("peer"), None);
+
+        for ms in &[30, 10, 20, 50] {
+            stats.record_peer_latency("peer", *ms);
+        }
+        assert_eq!(stats.get_peer_p50_latency("peer"), Some(20));
+
+        // the old samples are dropped
+        for _ in 0..MAX_LATENCY_SAMPLES {
+            stats.record_peer_latency("peer", 100);
+        }
+        assert_eq!(stats.get_peer_p50_latency("peer"), Some(100));
+
+        stats.remove_peer_latency("peer");
+        assert_eq!(stats.get_peer_p50_latency("peer"), None);
+    }
+
+    #[test]
+    fn test_rolling_windows() {
+        let now = 1000;
+        let mut stat = ConnStats::new("127.0.0.1:6615".to_owned(), vec![2, 10], now);
+        stat.increase(now, true, true);
+        stat.increase(now, true, true);
+        stat.increase(now + 1, true, true);
+        assert_eq!(get_rx_good(&stat, now + 1), vec![3, 3]);
+
+        // the first two events roll out of the 2s window
+        assert_eq!(get_rx_good(&stat, now + 2), vec![1, 3]);
+
+        // advance time by 3 secs
+        stat.increase(now + 4, true, false);
+        assert_eq!(get_rx_good(&stat, now + 4), vec![0, 3]);
+        assert_eq!(stat.get_window_stats(now + 4)[0].1.rx_bad, 1);
+
+        // all events are out of the 10s window
+        assert_eq!(get_rx_good(&stat, now + 11), vec![0, 0]);
+        assert!(!stat.is_expired(now + 13));
+        assert!(stat.is_expired(now + 14));
+    }
+
+    #[test]
+    fn test_last_conn_stat_serde() {
+        let stat = LastConnStat {
+            peer_addr: "127.0.0.1:6615".to_owned(),
+            windows: vec![
+                (1, StatsPerPeriod::default()),
+                (60, StatsPerPeriod::default()),
+            ],
+            is_connected: true,
+        };
+
+        let value = ::serde_json::to_value(&stat).unwrap();
+        assert!(value["1s"].is_object());
+        assert!(value["60s"].is_object());
+
+        let stat: LastConnStat = ::serde_json::from_value(value).unwrap();
+        assert_eq!(
+            stat.windows.iter().map(|w| w.0).collect::<Vec<_>>(),
+            vec![1, 60]
+        );
+    }
+}
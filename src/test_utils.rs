@@ -0,0 +1,133 @@
+//! helpers to run a complete hub inside the test process
+//!
+//! The hub state (`KV_STORE`, `SDAG_CACHE`, the workers, `WSS`) are process wide
+//! globals, so only one `InProcessHub` could be alive at a time, a new hub would
+//! block until the previous one is dropped. The `KV_STORE` is opened only once, so
+//! all the hubs in the process share one temp database, the first hub must be
+//! created before anything else touches the `KV_STORE`.
+//!
+//! Only built with the `test_utils` feature, e.g. `cargo test --features test_utils`.
+
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use business::BUSINESS_WORKER;
+use config;
+use error::Result;
+use finalization::FINALIZATION_WORKER;
+use joint::Joint;
+use kv_store::KV_STORE;
+use main_chain::MAIN_CHAIN_WORKER;
+use may::sync::{Mutex, MutexGuard};
+use network::hub::{register_push_handlers, WSS};
+use network::wallet::{create_outbound_conn, WalletConn};
+use network::{ServerHandle, WsServer};
+
+lazy_static! {
+    static ref HUB_LOCK: Mutex<()> = Mutex::new(());
+    // set to the config when first accessed, before the KV_STORE is opened
+    static ref DB_PATH: PathBuf = init_db_path();
+    // the event handlers are process wide, only registered by the first hub
+    static ref PUSH_HANDLERS: () = register_push_handlers();
+}
+
+//---------------------------------------------------------------------------------------
+// InProcessHub
+//---------------------------------------------------------------------------------------
+pub struct InProcessHub {
+    address: String,
+    server: Option<ServerHandle>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl InProcessHub {
+    /// start a hub that listens on a random local port
+    pub fn new() -> Result<Self> {
+        let guard = HUB_LOCK.lock().unwrap();
+
+        ::lazy_static::initialize(&DB_PATH);
+        KV_STORE.rebuild_from_kv()?;
+
+        // the workers are started when first accessed
+        ::lazy_static::initialize(&MAIN_CHAIN_WORKER);
+        ::lazy_static::initialize(&BUSINESS_WORKER);
+        ::lazy_static::initialize(&FINALIZATION_WORKER);
+        ::lazy_static::initialize(&PUSH_HANDLERS);
+
+        let address = format!("127.0.0.1:{}", get_free_port()?);
+        let server = WsServer::start(&address, |c| {
+            t!(WSS.add_p2p_conn(c, true));
+        })?;
+        info!("in process hub running on ws://{}", address);
+
+        Ok(InProcessHub {
+            address,
+            server: Some(server),
+            _guard: guard,
+        })
+    }
+
+    /// start a hub and post the bootstrap joints (genesis first) to it
+    pub fn with_bootstrap(joints: &[Joint]) -> Result<Self> {
+        let hub = InProcessHub::new()?;
+        let wallet = hub.connect_wallet()?;
+        for joint in joints {
+            wallet.post_joint(joint)?;
+        }
+        Ok(hub)
+    }
+
+    pub fn get_address(&self) -> &str {
+        &self.address
+    }
+
+    /// connect a wallet client to the hub
+    pub fn connect_wallet(&self) -> Result<Arc<WalletConn>> {
+        create_outbound_conn(&self.address)
+    }
+
+    /// stop the server, close all the connections and flush the database
+    /// the workers and the database are process wide and kept for the next hub
+    pub fn teardown(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    // only the first call does the work, the drop after teardown does nothing
+    fn shutdown(&mut self) -> Result<()> {
+        let server = match self.server.take() {
+            Some(server) => server,
+            None => return Ok(()),
+        };
+        server.shutdown();
+
+        WSS.close_all();
+        KV_STORE.flush()
+    }
+}
+
+impl Drop for InProcessHub {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!("failed to teardown in process hub, err={}", e);
+        }
+    }
+}
+
+fn init_db_path() -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let mut path = ::std::env::temp_dir();
+    path.push(format!("sdag_test_{}_{}", ::std::process::id(), nanos));
+    config::CONFIG.write().unwrap().database_path = path.to_string_lossy().into_owned();
+    path
+}
+
+// let the os pick a free port, there is a tiny chance that it's taken again
+fn get_free_port() -> Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    Ok(listener.local_addr()?.port())
+}
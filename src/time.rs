@@ -1,4 +1,15 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(test)]
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    // the base of the monotonic clock, (instant, wall clock ms) when first used
+    static ref STARTUP: (Instant, u64) = (Instant::now(), now());
+}
+
+// simulate the time advancement in tests
+#[cfg(test)]
+static MONOTONIC_OFFSET: AtomicIsize = AtomicIsize::new(0);
 
 /// return milliseconds since unix epoch
 pub fn now() -> u64 {
@@ -8,3 +19,46 @@ pub fn now() -> u64 {
 
     dur.as_secs() * 1000 + u64::from(dur.subsec_nanos()) / 1_000_000
 }
+
+/// return the whole milliseconds of the duration
+pub fn duration_to_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/// return milliseconds since unix epoch that never goes backward
+/// the wall clock adjustments (like NTP) after startup are ignored
+/// use it for timeouts and TTL, use `now()` for the unit timestamp
+pub fn monotonic_now() -> u64 {
+    let (start, startup_wall_ms) = *STARTUP;
+    let ms = startup_wall_ms + duration_to_ms(start.elapsed());
+    apply_monotonic_offset(ms)
+}
+
+#[cfg(test)]
+pub fn set_monotonic_offset(offset_ms: i64) {
+    MONOTONIC_OFFSET.store(offset_ms as isize, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+fn apply_monotonic_offset(ms: u64) -> u64 {
+    (ms as i64 + MONOTONIC_OFFSET.load(Ordering::Relaxed) as i64) as u64
+}
+
+#[cfg(not(test))]
+fn apply_monotonic_offset(ms: u64) -> u64 {
+    ms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_monotonic_now() {
+        let start = monotonic_now();
+        ::std::thread::sleep(Duration::from_millis(15));
+        let end = monotonic_now();
+        assert!(end - start >= 15);
+    }
+}
@@ -1,6 +1,8 @@
-/// AppendListExt is a low-level primitive supporting two safe operations:
-/// `push`, which appends a node to the list, and `iter` which iterates the list
+/// AppendListExt is a low-level primitive supporting three safe operations:
+/// `push`, which appends a node to the list, `iter` which iterates the list
+/// and `iter_rev` which iterates the list backward
 /// The list cannot be shrunk whilst in use.
+use std::marker::PhantomData;
 use std::sync::atomic::{AtomicPtr, Ordering};
 use std::{mem, ptr};
 
@@ -25,6 +27,8 @@ impl<T> IntoRaw<Node<T>> for NodePtr<T> {
 struct Node<T> {
     value: RcuCell<T>,
     next: AppendListExt<T>,
+    // the previous node, null for the first node
+    prev: AtomicPtr<Node<T>>,
 }
 
 #[derive(Debug)]
@@ -51,11 +55,15 @@ impl<T> AppendListExt<T> {
         self.append_list(AppendListExt::new_internal(Some(Box::new(Node {
             value: RcuCell::new(Some(value)),
             next: AppendListExt::new(),
+            prev: AtomicPtr::new(ptr::null_mut()),
         }))));
     }
 
-    unsafe fn append_ptr(&self, p: *mut Node<T>) {
+    // prev is the node that owns self, null if self is the head of the list
+    unsafe fn append_ptr(&self, prev: *mut Node<T>, p: *mut Node<T>) {
         loop {
+            // link back before the node is published by the cas
+            (*p).prev.store(prev, Ordering::Relaxed);
             match self.0.compare_exchange_weak(
                 ptr::null_mut(),
                 p,
@@ -65,7 +73,7 @@ impl<T> AppendListExt<T> {
                 Ok(_) => return,
                 Err(head) => {
                     if !head.is_null() {
-                        return (*head).next.append_ptr(p);
+                        return (*head).next.append_ptr(head, p);
                     }
                 }
             }
@@ -75,13 +83,31 @@ impl<T> AppendListExt<T> {
     pub fn append_list(&self, other: AppendListExt<T>) {
         let p = other.0.load(Ordering::Acquire);
         mem::forget(other);
-        unsafe { self.append_ptr(p) };
+        if !p.is_null() {
+            unsafe { self.append_ptr(ptr::null_mut(), p) };
+        }
     }
 
     pub fn iter(&self) -> AppendListIterator<T> {
         AppendListIterator(&self.0)
     }
 
+    /// iterate from the last node to the first one without allocation
+    /// the nodes appended after the call are not visited
+    pub fn iter_rev(&self) -> AppendListRevIterator<T> {
+        let mut last = ptr::null_mut();
+        let mut p = self.0.load(Ordering::Acquire);
+        while !p.is_null() {
+            last = p;
+            p = unsafe { (*p).next.0.load(Ordering::Acquire) };
+        }
+
+        AppendListRevIterator {
+            node: last,
+            _list: PhantomData,
+        }
+    }
+
     /// Returns true if the AppendListExt contains no data
     pub fn is_empty(&self) -> bool {
         self.iter().next().is_none()
@@ -184,3 +210,49 @@ impl<'a, T: 'a> Iterator for AppendListIterator<'a, T> {
         }
     }
 }
+
+#[derive(Debug)]
+pub struct AppendListRevIterator<'a, T: 'a> {
+    node: *const Node<T>,
+    _list: PhantomData<&'a AppendListExt<T>>,
+}
+
+impl<'a, T: 'a> Iterator for AppendListRevIterator<'a, T> {
+    type Item = RcuReader<T>;
+
+    fn next(&mut self) -> Option<RcuReader<T>> {
+        while !self.node.is_null() {
+            // the nodes are never freed until the list is dropped
+            let value = unsafe {
+                let node = &*self.node;
+                self.node = node.prev.load(Ordering::Acquire);
+                &node.value
+            };
+
+            // skip those removed items
+            if let Some(r) = value.read() {
+                return Some(r);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iter_rev() {
+        let list = (0..10).collect::<AppendListExt<_>>();
+        list.append_list((10..20).collect());
+        list.append_list(AppendListExt::new());
+        list.remove_with(|v| *v == 5);
+
+        let values = list.iter_rev().map(|v| *v).collect::<Vec<_>>();
+        let expected = (0..20).rev().filter(|v| *v != 5).collect::<Vec<_>>();
+        assert_eq!(values, expected);
+
+        assert!(AppendListExt::<usize>::new().iter_rev().next().is_none());
+    }
+}
@@ -0,0 +1,88 @@
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::f64::consts::LN_2;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const WORD_BITS: usize = mem::size_of::<usize>() * 8;
+
+/// a bloom filter that can be updated concurrently without lock
+/// items can't be removed, the false positive rate grows when exceeding the capacity
+pub struct BloomFilter {
+    bits: Vec<AtomicUsize>,
+    num_bits: u64,
+    num_hashes: u64,
+}
+
+impl BloomFilter {
+    /// create a filter sized for the number of items at the false positive rate
+    pub fn with_rate(items: usize, fp_rate: f64) -> Self {
+        let items = cmp::max(items, 1) as f64;
+        let num_bits = (-items * fp_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let num_bits = cmp::max(num_bits, 1);
+        let num_hashes = cmp::max((num_bits as f64 / items * LN_2).round() as u64, 1);
+
+        let words = (num_bits + WORD_BITS - 1) / WORD_BITS;
+        BloomFilter {
+            bits: (0..words).map(|_| AtomicUsize::new(0)).collect(),
+            num_bits: num_bits as u64,
+            num_hashes,
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&self, item: &T) {
+        for idx in self.bit_indexes(item) {
+            self.bits[idx / WORD_BITS].fetch_or(1 << (idx % WORD_BITS), Ordering::Relaxed);
+        }
+    }
+
+    /// false means the item is never inserted, true means it may be inserted
+    pub fn may_contain<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        self.bit_indexes(item).all(|idx| {
+            self.bits[idx / WORD_BITS].load(Ordering::Relaxed) & (1 << (idx % WORD_BITS)) != 0
+        })
+    }
+
+    // double hashing, the i-th index is h1 + i * h2
+    fn bit_indexes<T: Hash + ?Sized>(&self, item: &T) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // continue hashing so that h2 is independent of h1
+        h1.hash(&mut hasher);
+        // an odd h2 never makes all the indexes the same
+        let h2 = hasher.finish() | 1;
+
+        let num_bits = self.num_bits;
+        (0..self.num_hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let filter = BloomFilter::with_rate(1_000, 0.01);
+        for i in 0..1_000 {
+            filter.insert(&format!("unit_{}", i));
+        }
+
+        // no false negative
+        for i in 0..1_000 {
+            assert!(filter.may_contain(&format!("unit_{}", i)));
+        }
+
+        // the false positive rate is near the expected
+        let false_positives = (1_000..11_000)
+            .filter(|i| filter.may_contain(&format!("unit_{}", i)))
+            .count();
+        assert!(
+            false_positives < 300,
+            "false positives = {}",
+            false_positives
+        );
+    }
+}
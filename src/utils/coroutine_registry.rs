@@ -0,0 +1,156 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use error::Result;
+use hashbrown::HashMap;
+use may::coroutine::{self, JoinHandle};
+use may::sync::Mutex;
+use statistics;
+
+const INFLIGHT_GAUGE: &str = "sdag_inflight_coroutines";
+
+lazy_static! {
+    pub static ref COROUTINE_REGISTRY: CoroutineRegistry = CoroutineRegistry::default();
+}
+
+/// identify a coroutine spawned by the registry
+///
+/// the coroutine removes itself from the registry when it finished, so dropping
+/// the handle would not detach the coroutine from `wait_all`
+#[derive(Clone, Copy)]
+pub struct RegistrationHandle {
+    id: usize,
+    registry: &'static CoroutineRegistry,
+}
+
+impl RegistrationHandle {
+    pub fn is_running(&self) -> bool {
+        self.registry.contains(self.id)
+    }
+}
+
+// remove the registered coroutine when dropped at the end of the coroutine
+struct Registration {
+    id: usize,
+    registry: &'static CoroutineRegistry,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.remove(self.id);
+    }
+}
+
+/// track the in-flight coroutines so that they could be waited on shutdown
+#[derive(Default)]
+pub struct CoroutineRegistry {
+    next_id: AtomicUsize,
+    handles: Mutex<HashMap<usize, JoinHandle<()>>>,
+}
+
+impl CoroutineRegistry {
+    /// spawn a coroutine that is tracked until it finished, the error is logged
+    pub fn spawn<F>(&'static self, f: F) -> RegistrationHandle
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let registration = Registration { id, registry: self };
+
+        // hold the lock so that the coroutine can't remove itself before registered
+        let mut handles = self.handles.lock().unwrap();
+        let handle = go!(move || {
+            let _registration = registration;
+            if let Err(e) = f() {
+                error!("coroutine error: {}", e);
+            }
+        });
+        handles.insert(id, handle);
+        statistics::set_gauge(INFLIGHT_GAUGE, handles.len());
+
+        RegistrationHandle { id, registry: self }
+    }
+
+    /// the number of the in-flight coroutines
+    pub fn len(&self) -> usize {
+        self.handles.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// wait all the in-flight coroutines done, the remaining are cancelled after timeout
+    /// return the number of the cancelled coroutines
+    pub fn wait_all(&self, timeout: Duration) -> usize {
+        let start = Instant::now();
+        while !self.is_empty() && start.elapsed() < timeout {
+            coroutine::sleep(Duration::from_millis(10));
+        }
+
+        let remaining = ::std::mem::replace(&mut *self.handles.lock().unwrap(), HashMap::new());
+        statistics::set_gauge(INFLIGHT_GAUGE, 0);
+        if !remaining.is_empty() {
+            warn!("cancel {} coroutines after {:?}", remaining.len(), timeout);
+        }
+
+        let cancelled = remaining.len();
+        for (_, handle) in remaining {
+            unsafe { handle.coroutine().cancel() };
+            handle.join().ok();
+        }
+        cancelled
+    }
+
+    fn contains(&self, id: usize) -> bool {
+        self.handles.lock().unwrap().contains_key(&id)
+    }
+
+    fn remove(&self, id: usize) {
+        let mut handles = self.handles.lock().unwrap();
+        handles.remove(&id);
+        statistics::set_gauge(INFLIGHT_GAUGE, handles.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_all() {
+        lazy_static! {
+            static ref REGISTRY: CoroutineRegistry = CoroutineRegistry::default();
+        }
+
+        let handles = (0..10)
+            .map(|_| {
+                REGISTRY.spawn(|| {
+                    coroutine::sleep(Duration::from_millis(100));
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(REGISTRY.len(), 10);
+
+        let start = Instant::now();
+        assert_eq!(REGISTRY.wait_all(Duration::from_secs(5)), 0);
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(REGISTRY.is_empty());
+        assert!(handles.iter().all(|h| !h.is_running()));
+    }
+
+    #[test]
+    fn test_wait_all_timeout() {
+        lazy_static! {
+            static ref REGISTRY: CoroutineRegistry = CoroutineRegistry::default();
+        }
+
+        REGISTRY.spawn(|| {
+            coroutine::sleep(Duration::from_secs(10));
+            Ok(())
+        });
+        assert_eq!(REGISTRY.wait_all(Duration::from_millis(50)), 1);
+        assert!(REGISTRY.is_empty());
+    }
+}
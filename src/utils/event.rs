@@ -1,4 +1,12 @@
-use may::sync::RwLock;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use may::sync::{mpsc, Mutex, RwLock};
+
+// tokens are unique among all the event types
+static NEXT_HANDLER_TOKEN: AtomicU64 = AtomicU64::new(0);
+// the max pending events for the async handlers of each event type
+const ASYNC_QUEUE_CAPACITY: usize = 1000;
 
 trait FnOps<T>: Send + Sync {
     fn call_box(self: &Self, data: &T) -> ();
@@ -13,31 +21,108 @@ where
     }
 }
 
+/// identify a registered event handler, used to remove the handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HandlerToken(u64);
+
+/// remove the event handler when dropped
+pub struct HandlerGuard {
+    token: HandlerToken,
+    remove: fn(HandlerToken) -> bool,
+}
+
+impl HandlerGuard {
+    pub fn token(&self) -> HandlerToken {
+        self.token
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        (self.remove)(self.token);
+    }
+}
+
 /// event handlers for a given `Event` type
 pub struct EventHandlers<T: Event> {
-    ops: RwLock<Vec<Box<FnOps<T>>>>,
+    name: &'static str,
+    // handlers are called in the order of registration
+    ops: RwLock<BTreeMap<HandlerToken, Box<FnOps<T>>>>,
+    // handlers that are called one by one by the dispatcher coroutine
+    async_ops: RwLock<BTreeMap<HandlerToken, Box<FnOps<T>>>>,
+    // the dispatcher is started by the first async event
+    async_queue: Mutex<Option<mpsc::Sender<T>>>,
+    async_pending: AtomicUsize,
 }
 
-impl<T: Event> Default for EventHandlers<T> {
-    fn default() -> Self {
+impl<T: Event + Send> EventHandlers<T> {
+    pub fn new(name: &'static str) -> Self {
         EventHandlers {
-            ops: RwLock::new(Vec::new()),
+            name,
+            ops: RwLock::new(BTreeMap::new()),
+            async_ops: RwLock::new(BTreeMap::new()),
+            async_queue: Mutex::new(None),
+            async_pending: AtomicUsize::new(0),
         }
     }
-}
 
-impl<T: Event + Send> EventHandlers<T> {
-    fn add_op<F>(&self, f: F)
+    fn add_op<F>(&self, f: F) -> HandlerToken
+    where
+        F: Fn(&T) -> () + Send + Sync + 'static,
+    {
+        let token = HandlerToken(NEXT_HANDLER_TOKEN.fetch_add(1, Ordering::Relaxed));
+        self.ops.write().unwrap().insert(token, Box::new(f));
+        token
+    }
+
+    fn add_async_op<F>(&self, f: F) -> HandlerToken
     where
         F: Fn(&T) -> () + Send + Sync + 'static,
     {
-        self.ops.write().unwrap().push(Box::new(f));
+        let token = HandlerToken(NEXT_HANDLER_TOKEN.fetch_add(1, Ordering::Relaxed));
+        self.async_ops.write().unwrap().insert(token, Box::new(f));
+        token
+    }
+
+    fn remove_op(&self, token: HandlerToken) -> bool {
+        self.ops.write().unwrap().remove(&token).is_some()
+            || self.async_ops.write().unwrap().remove(&token).is_some()
+    }
+
+    // queue the event for the async handlers, drop it if the queue is full
+    fn run_async(&'static self, data: T) {
+        if self.async_ops.read().unwrap().is_empty() {
+            return;
+        }
+
+        if self.async_pending.fetch_add(1, Ordering::AcqRel) >= ASYNC_QUEUE_CAPACITY {
+            self.async_pending.fetch_sub(1, Ordering::AcqRel);
+            warn!("event bus overflow, dropping {}", self.name);
+            return;
+        }
+
+        let mut queue = self.async_queue.lock().unwrap();
+        let tx = queue.get_or_insert_with(|| self.start_dispatcher());
+        if tx.send(data).is_err() {
+            self.async_pending.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    fn start_dispatcher(&'static self) -> mpsc::Sender<T> {
+        let (tx, rx) = mpsc::channel::<T>();
+        go!(move || while let Ok(data) = rx.recv() {
+            self.async_pending.fetch_sub(1, Ordering::AcqRel);
+            for op in self.async_ops.read().unwrap().values() {
+                op.call_box(&data);
+            }
+        });
+        tx
     }
 
     fn run(&'static self, data: T) {
         let g = self.ops.read().unwrap();
         if !g.is_empty() {
-            go!(move || for op in g.iter() {
+            go!(move || for op in g.values() {
                 op.call_box(&data);
             });
         }
@@ -54,6 +139,18 @@ pub trait Event: Sized + Send + 'static {
         Self::get_event_handlers().run(self);
     }
 
+    /// trigger an event without waiting the async handlers
+    /// the async handlers are called in the order of the events, if there are too many
+    /// pending events, the new event is dropped for the async handlers
+    fn trigger_async(self)
+    where
+        Self: Clone,
+    {
+        let handlers = Self::get_event_handlers();
+        handlers.run_async(self.clone());
+        handlers.run(self);
+    }
+
     /// globally register an event handler for the event
     /// you can add any number of event handlers,
     /// each handler take a ref of the event data as parameter
@@ -63,6 +160,39 @@ pub trait Event: Sized + Send + 'static {
     {
         Self::get_event_handlers().add_op(f);
     }
+
+    /// register an event handler that is called by a dedicated dispatcher coroutine
+    /// use it for slow handlers, they only receive the events by `emit_event_async`
+    fn add_async_handler<F>(f: F) -> HandlerToken
+    where
+        F: Fn(&Self) -> () + Send + Sync + 'static,
+    {
+        Self::get_event_handlers().add_async_op(f)
+    }
+
+    /// register an event handler that could be removed by the returned token
+    fn add_handler_with_token<F>(f: F) -> HandlerToken
+    where
+        F: Fn(&Self) -> () + Send + Sync + 'static,
+    {
+        Self::get_event_handlers().add_op(f)
+    }
+
+    /// register an event handler that would be removed when the guard is dropped
+    fn add_handler_guard<F>(f: F) -> HandlerGuard
+    where
+        F: Fn(&Self) -> () + Send + Sync + 'static,
+    {
+        HandlerGuard {
+            token: Self::add_handler_with_token(f),
+            remove: Self::remove_handler,
+        }
+    }
+
+    /// remove the event handler, return false if it's not found
+    fn remove_handler(token: HandlerToken) -> bool {
+        Self::get_event_handlers().remove_op(token)
+    }
 }
 
 /// macro used to implement `Event` trait for a type
@@ -75,7 +205,7 @@ macro_rules! impl_event {
             fn get_event_handlers() -> &'static $crate::utils::event::EventHandlers<Self> {
                 lazy_static! {
                     static ref HANDLERS: $crate::utils::event::EventHandlers<$T> =
-                        $crate::utils::event::EventHandlers::default();
+                        $crate::utils::event::EventHandlers::new(stringify!($T));
                 }
                 &*HANDLERS
             }
@@ -89,6 +219,12 @@ pub fn emit_event<T: Event>(event: T) {
     event.trigger();
 }
 
+/// emit an event to both the normal and the async handlers
+/// the emitter is never blocked by the async handlers
+pub fn emit_event_async<T: Event + Clone>(event: T) {
+    event.trigger_async();
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -100,15 +236,98 @@ mod test {
         }
         impl_event!(MyEvent);
         let s = MyEvent { data: 42 };
-        MyEvent::add_handler(|s| assert_eq!(s.data % 2, 0));
-        MyEvent::add_handler(|s| assert_eq!(s.data, 42));
+        let _g1 = MyEvent::add_handler_guard(|s| assert_eq!(s.data % 2, 0));
+        let _g2 = MyEvent::add_handler_guard(|s| assert_eq!(s.data, 42));
         s.trigger();
     }
 
     #[test]
     fn test_emit_event() {
         impl_event!(u32);
-        <u32 as Event>::add_handler(|v| assert_eq!(*v, 64));
+        let _g = <u32 as Event>::add_handler_guard(|v| assert_eq!(*v, 64));
         emit_event(64);
     }
+
+    #[test]
+    fn test_remove_handler() {
+        use joint::Level;
+        use main_chain::MciStableEvent;
+        use may::sync::{mpsc, Mutex};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let called = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = mpsc::channel();
+        let add = |id: usize| {
+            let called = called.clone();
+            let tx = tx.clone();
+            MciStableEvent::add_handler_guard(move |v| {
+                if v.mci == Level::new(1234) {
+                    called.lock().unwrap().push(id);
+                    tx.send(()).unwrap();
+                }
+            })
+        };
+
+        let g1 = add(1);
+        let g2 = add(2);
+        let g3 = add(3);
+        let token = g2.token();
+        drop(g2);
+        assert_eq!(MciStableEvent::remove_handler(token), false);
+
+        emit_event(MciStableEvent {
+            mci: Level::new(1234),
+        });
+        for _ in 0..2 {
+            rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+
+        assert_eq!(*called.lock().unwrap(), vec![1, 3]);
+        drop(g1);
+        drop(g3);
+    }
+
+    #[test]
+    fn test_emit_event_async() {
+        use may::sync::{mpsc, Semphore};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        #[derive(Clone)]
+        struct SlowEvent(usize);
+        impl_event!(SlowEvent);
+
+        // the handler is blocked until the gate is posted for the event
+        let gate = Arc::new(Semphore::new(0));
+        let (tx, rx) = mpsc::channel();
+        let token = {
+            let gate = gate.clone();
+            SlowEvent::add_async_handler(move |v| {
+                tx.send(v.0).unwrap();
+                gate.wait();
+            })
+        };
+        let recv = || rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // the emitter is not blocked by the handler
+        emit_event_async(SlowEvent(0));
+        assert_eq!(recv(), 0);
+        for i in 1..=ASYNC_QUEUE_CAPACITY {
+            emit_event_async(SlowEvent(i));
+        }
+        // the queue is full, the event is dropped
+        emit_event_async(SlowEvent(ASYNC_QUEUE_CAPACITY + 1));
+
+        // the events are handled in order
+        for i in 1..=ASYNC_QUEUE_CAPACITY {
+            gate.post();
+            assert_eq!(recv(), i);
+        }
+        gate.post();
+        emit_event_async(SlowEvent(0));
+        assert_eq!(recv(), 0);
+        gate.post();
+        assert!(SlowEvent::remove_handler(token));
+    }
 }
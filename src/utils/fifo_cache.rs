@@ -1,18 +1,21 @@
 extern crate indexmap;
 
 use may::sync::RwLock;
+use std::cmp;
 use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct FifoCache<K, V> {
     inner: RwLock<indexmap::IndexMap<K, V>>,
-    capacity: usize,
+    capacity: AtomicUsize,
 }
 
 impl<K: Eq + Hash, V: Clone> FifoCache<K, V> {
     pub fn with_capacity(capacity: usize) -> FifoCache<K, V> {
+        let capacity = cmp::max(capacity, 1);
         FifoCache {
             inner: RwLock::new(indexmap::IndexMap::with_capacity(capacity)),
-            capacity,
+            capacity: AtomicUsize::new(capacity),
         }
     }
 
@@ -21,10 +24,31 @@ impl<K: Eq + Hash, V: Clone> FifoCache<K, V> {
         self.inner.read().unwrap().get(k).cloned()
     }
 
+    #[inline]
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.inner.read().unwrap().contains_key(k)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().is_empty()
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
     #[inline]
     pub fn insert(&self, k: K, v: V) -> Option<V> {
         let mut map = self.inner.write().unwrap();
-        while self.capacity - 1 < map.len() {
+        let capacity = self.capacity();
+        while capacity - 1 < map.len() {
             map.pop();
         }
         map.insert(k, v)
@@ -34,4 +58,46 @@ impl<K: Eq + Hash, V: Clone> FifoCache<K, V> {
     pub fn remove(&self, k: &K) -> Option<V> {
         self.inner.write().unwrap().remove(k)
     }
+
+    /// change the capacity, the oldest entries are dropped when shrinking
+    pub fn resize(&self, new_capacity: usize) {
+        let new_capacity = cmp::max(new_capacity, 1);
+        let mut map = self.inner.write().unwrap();
+        let excess = map.len().saturating_sub(new_capacity);
+        map.drain(..excess);
+        let additional = new_capacity.saturating_sub(map.capacity());
+        map.reserve(additional);
+        self.capacity.store(new_capacity, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_cache_resize() {
+        let cache = FifoCache::with_capacity(3);
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 3);
+
+        // the last one is dropped for the new one
+        cache.insert(3, 3);
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.contains_key(&2));
+
+        // shrinking drops the oldest
+        cache.resize(2);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key(&0));
+        assert_eq!(cache.get(&3), Some(3));
+
+        cache.resize(4);
+        cache.insert(4, 4);
+        cache.insert(5, 5);
+        assert_eq!(cache.len(), 4);
+        assert!(cache.contains_key(&1));
+    }
 }
@@ -71,7 +71,12 @@ impl<T: Clone + Hash + Eq> Inner<T> {
     }
 }
 
-// protect the struct by a RwLock
+/// A lock over a set of keys, a guard holds all the requested keys at once
+///
+/// The keys are always acquired in canonical (sorted) order, so two callers
+/// that request the same keys in different order would never wait on each
+/// other in a cycle. `try_lock` and `lock` sort the keys internally, callers
+/// that already have a sorted key list can use `try_lock_sorted` instead.
 pub struct MapLock<T>(Mutex<Inner<T>>);
 
 impl<T: Hash + Eq> Debug for MapLock<T> {
@@ -94,12 +99,27 @@ impl<T: Clone + Hash + Eq> MapLock<T> {
         }))
     }
 
-    // return how many tasks waiting on the MapLock
+    /// return how many tasks are blocked in `lock` waiting for their keys
+    ///
+    /// the count is read under the same mutex that enqueues and dequeues the
+    /// tasks, a task leaves the queue only when its keys are handed over to it
     pub fn get_waiter_num(&self) -> usize {
         let g = self.0.lock().unwrap();
         g.tasks.len()
     }
 
+    // remove a canceled task from the waiting queue
+    fn cancel_task(&self, blocker: &Arc<Blocker>, keys: &[T]) {
+        let mut g = self.0.lock().unwrap();
+        let len = g.tasks.len();
+        g.tasks.retain(|task| !Arc::ptr_eq(&task.blocker, blocker));
+        if g.tasks.len() == len {
+            // the keys are already handed over to the task, give them back
+            drop(g);
+            self.release_keys(keys);
+        }
+    }
+
     // used internally
     fn release_keys(&self, keys: &[T]) {
         let mut g = self.0.lock().unwrap();
@@ -114,7 +134,24 @@ impl<T: Clone + Hash + Eq> MapLock<T> {
     }
 
     // we must keep the strict order for fairness
-    pub fn try_lock(&self, keys: Vec<T>) -> Option<MapLockGuard<T>> {
+    pub fn try_lock(&self, mut keys: Vec<T>) -> Option<MapLockGuard<T>>
+    where
+        T: Ord,
+    {
+        sort_keys(&mut keys);
+        self.try_lock_inner(keys)
+    }
+
+    /// same as `try_lock`, but the keys must be already sorted and unique
+    pub fn try_lock_sorted(&self, keys: &[T]) -> Option<MapLockGuard<T>>
+    where
+        T: Ord,
+    {
+        debug_assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        self.try_lock_inner(keys.to_vec())
+    }
+
+    fn try_lock_inner(&self, keys: Vec<T>) -> Option<MapLockGuard<T>> {
         let mut g = self.0.lock().unwrap();
 
         // first check if there are other pending task is ok for wakeup
@@ -139,9 +176,13 @@ impl<T: Clone + Hash + Eq> MapLock<T> {
         Some(MapLockGuard { owner: self, keys })
     }
 
-    pub fn lock(&self, keys: Vec<T>) -> MapLockGuard<T> {
+    pub fn lock(&self, mut keys: Vec<T>) -> MapLockGuard<T>
+    where
+        T: Ord,
+    {
         use may::coroutine::{self, ParkError};
 
+        sort_keys(&mut keys);
         let mut g = self.0.lock().unwrap();
 
         // first check if there are other pending task is ok for wakeup
@@ -174,6 +215,8 @@ impl<T: Clone + Hash + Eq> MapLock<T> {
             Ok(_) => {}
             Err(ParkError::Timeout) => unreachable!(),
             Err(ParkError::Canceled) => {
+                // the task would otherwise stay in the queue and be counted as a waiter
+                self.cancel_task(&blocker, &keys);
                 coroutine::trigger_cancel_panic();
             }
         }
@@ -183,6 +226,12 @@ impl<T: Clone + Hash + Eq> MapLock<T> {
     }
 }
 
+// put the keys in canonical order, duplicated keys are only locked once
+fn sort_keys<T: Ord>(keys: &mut Vec<T>) {
+    keys.sort();
+    keys.dedup();
+}
+
 #[derive(Debug)]
 pub struct MapLockGuard<'a, T: Clone + Hash + Eq + 'a> {
     owner: &'a MapLock<T>,
@@ -255,4 +304,93 @@ mod tests {
         j1.join().unwrap();
         j2.join().unwrap();
     }
+
+    #[test]
+    fn test_map_lock_sorted() {
+        let lock = MapLock::new();
+        let g = lock.try_lock(vec!["b", "a", "b"]);
+        assert_eq!(g.as_ref().map(|g| g.keys.clone()), Some(vec!["a", "b"]));
+        assert_eq!(lock.try_lock_sorted(&["a"]).is_some(), false);
+        drop(g);
+        assert_eq!(lock.try_lock_sorted(&["a", "b"]).is_some(), true);
+    }
+
+    #[test]
+    fn test_map_lock_no_deadlock() {
+        let lock = Arc::new(MapLock::new());
+
+        let lock_1 = lock.clone();
+        let j1 = go!(move || {
+            for _ in 0..1000 {
+                let _g = lock_1.lock(vec!["key_a".to_owned(), "key_b".to_owned()]);
+                may::coroutine::yield_now();
+            }
+        });
+
+        let lock_2 = lock.clone();
+        let j2 = go!(move || {
+            for _ in 0..1000 {
+                let _g = lock_2.lock(vec!["key_b".to_owned(), "key_a".to_owned()]);
+                may::coroutine::yield_now();
+            }
+        });
+
+        // both orders wait for the whole key set, a deadlock would hang the joins
+        j1.join().unwrap();
+        j2.join().unwrap();
+        assert_eq!(lock.get_waiter_num(), 0);
+
+        // the reversed keys wait for the holder, and are then locked in sorted order
+        let g = lock.lock(vec!["key_a".to_owned(), "key_b".to_owned()]);
+        let lock_3 = lock.clone();
+        let j3 = go!(move || lock_3
+            .lock(vec!["key_b".to_owned(), "key_a".to_owned()])
+            .keys
+            .clone());
+        while lock.get_waiter_num() == 0 {
+            may::coroutine::yield_now();
+        }
+        assert_eq!(lock.try_lock(vec!["key_b".to_owned()]).is_some(), false);
+        drop(g);
+        assert_eq!(
+            j3.join().unwrap(),
+            vec!["key_a".to_owned(), "key_b".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_map_lock_waiter_num() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Mutex as StdMutex;
+
+        let lock = Arc::new(MapLock::new());
+        let all_waiting = Arc::new(AtomicBool::new(false));
+        let peak = Arc::new(StdMutex::new(0));
+
+        let handlers = (0..50)
+            .map(|_| {
+                let lock = lock.clone();
+                let all_waiting = all_waiting.clone();
+                let peak = peak.clone();
+                go!(move || {
+                    let _g = lock.lock(vec!["test"]);
+                    // the first holder keeps the key until all the others are blocked
+                    while !all_waiting.load(Ordering::Acquire) {
+                        if lock.get_waiter_num() == 49 {
+                            all_waiting.store(true, Ordering::Release);
+                        }
+                        may::coroutine::yield_now();
+                    }
+                    let mut peak = peak.lock().unwrap();
+                    *peak = ::std::cmp::max(*peak, lock.get_waiter_num());
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for j in handlers {
+            j.join().unwrap();
+        }
+        assert_eq!(*peak.lock().unwrap(), 49);
+        assert_eq!(lock.get_waiter_num(), 0);
+    }
 }
@@ -3,18 +3,24 @@ pub mod atomic_lock;
 pub mod event;
 pub mod append_list;
 pub mod append_list_ext;
+pub mod bloom_filter;
+pub mod coroutine_registry;
 pub mod fifo_cache;
 pub mod map_lock;
 pub mod once;
 pub mod once_option;
+pub mod watchdog;
 
 pub use self::append_list::AppendList;
 pub use self::append_list_ext::AppendListExt;
 pub use self::atomic_lock::{AtomicLock, AtomicLockGuard};
+pub use self::bloom_filter::BloomFilter;
+pub use self::coroutine_registry::{CoroutineRegistry, COROUTINE_REGISTRY};
 pub use self::fifo_cache::FifoCache;
 pub use self::map_lock::{MapLock, MapLockGuard};
 pub use self::once::Once;
 pub use self::once_option::OnceOption;
+pub use self::watchdog::Watchdog;
 
 use std::io::{Error, ErrorKind};
 use std::time::{Duration, Instant};
@@ -1,4 +1,11 @@
+use std::cmp;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use may::coroutine;
+
+// how often the waiting coroutine checks the data
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct OnceOption<T> {
     b_init: AtomicBool,
@@ -39,6 +46,27 @@ impl<T> OnceOption<T> {
             None
         }
     }
+
+    /// wait until the data is set or the timeout elapsed, return a clone of the data
+    ///
+    /// the data is not moved out, other coroutines may still hold a reference to it
+    pub fn take_or_wait(&self, timeout: Duration) -> Option<T>
+    where
+        T: Clone,
+    {
+        let start = Instant::now();
+        loop {
+            if let Some(data) = self.get() {
+                return Some(data.clone());
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return None;
+            }
+            coroutine::sleep(cmp::min(timeout - elapsed, WAIT_INTERVAL));
+        }
+    }
 }
 
 impl<T> Default for OnceOption<T> {
@@ -49,3 +77,28 @@ impl<T> Default for OnceOption<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_take_or_wait() {
+        let option = Arc::new(OnceOption::new());
+        assert_eq!(option.take_or_wait(Duration::from_millis(20)), None);
+
+        let option_1 = option.clone();
+        let j = go!(move || {
+            coroutine::sleep(Duration::from_millis(50));
+            option_1.set(42);
+        });
+
+        let start = Instant::now();
+        assert_eq!(option.take_or_wait(Duration::from_secs(1)), Some(42));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // the data is still there for the others
+        assert_eq!(option.get(), Some(&42));
+        j.join().unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::thread;
+use std::time::Duration;
+
+use may::sync::mpsc;
+use statistics;
+use time::{duration_to_ms, monotonic_now};
+
+#[derive(Debug)]
+pub enum WatchdogPing {
+    // the worker begins to process an item
+    Ping,
+    // the worker finished the item
+    Pong,
+}
+
+/// Watchdog that detects a stalled worker
+///
+/// The worker sends a `Ping` before processing an item and a `Pong` after it,
+/// if no `Pong` arrives within `timeout` the stall handler is called once for
+/// the item. An idle worker is never treated as stalled. The watchdog runs on a
+/// dedicated OS thread instead of a coroutine, so a worker that blocks the
+/// coroutine scheduler can't stop the watchdog from firing.
+pub struct Watchdog {
+    tx: mpsc::Sender<WatchdogPing>,
+    timeout: Duration,
+}
+
+impl Watchdog {
+    /// start a watchdog that reports the stalled worker
+    /// the worker may still finish the item, so the process is kept running
+    pub fn start(name: &str, timeout: Duration) -> Self {
+        let worker = name.to_owned();
+        Watchdog::with_handler(name, timeout, move |ms| {
+            error!("CRITICAL {} worker stalled for {}ms", worker, ms);
+            statistics::increase_counter(&format!("sdag_{}_worker_stalled_total", worker));
+        })
+    }
+
+    /// start a watchdog with a customized stall handler
+    /// the handler receives the stalled time in milliseconds
+    pub fn with_handler<F>(name: &str, timeout: Duration, on_stall: F) -> Self
+    where
+        F: Fn(u64) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let check_interval = timeout / 3;
+
+        thread::Builder::new()
+            .name(format!("{}_watchdog", name))
+            .spawn(move || {
+                let mut detector = StallDetector::new(timeout);
+                loop {
+                    match rx.recv_timeout(check_interval) {
+                        Ok(WatchdogPing::Ping) => detector.on_ping(monotonic_now()),
+                        Ok(WatchdogPing::Pong) => detector.on_pong(),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        // the worker is gone
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+
+                    if let Some(ms) = detector.check(monotonic_now()) {
+                        on_stall(ms);
+                    }
+                }
+            })
+            .expect("failed to start watchdog thread");
+
+        Watchdog { tx, timeout }
+    }
+
+    pub fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn ping(&self) {
+        self.tx.send(WatchdogPing::Ping).ok();
+    }
+
+    pub fn pong(&self) {
+        self.tx.send(WatchdogPing::Pong).ok();
+    }
+
+    /// send `Ping` now and `Pong` when the guard is dropped
+    pub fn guard(&self) -> WatchdogGuard {
+        self.ping();
+        WatchdogGuard { owner: self }
+    }
+}
+
+pub struct WatchdogGuard<'a> {
+    owner: &'a Watchdog,
+}
+
+impl<'a> Drop for WatchdogGuard<'a> {
+    fn drop(&mut self) {
+        self.owner.pong();
+    }
+}
+
+// the stall state of the worker, the time in ms is passed in by the caller
+struct StallDetector {
+    timeout_ms: u64,
+    // when the worker began the current item
+    busy_since: Option<u64>,
+    // the current item is already reported as stalled
+    is_reported: bool,
+}
+
+impl StallDetector {
+    fn new(timeout: Duration) -> Self {
+        StallDetector {
+            timeout_ms: duration_to_ms(timeout),
+            busy_since: None,
+            is_reported: false,
+        }
+    }
+
+    fn on_ping(&mut self, now: u64) {
+        self.busy_since.get_or_insert(now);
+    }
+
+    fn on_pong(&mut self) {
+        self.busy_since = None;
+        self.is_reported = false;
+    }
+
+    // return the stalled ms if the current item stalled and is not reported yet
+    fn check(&mut self, now: u64) -> Option<u64> {
+        let stalled_ms = now.saturating_sub(self.busy_since?);
+        if self.is_reported || stalled_ms < self.timeout_ms {
+            return None;
+        }
+        self.is_reported = true;
+        Some(stalled_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall_detector_fire() {
+        let mut detector = StallDetector::new(Duration::from_secs(30));
+
+        // the mock worker stalled on an item
+        detector.on_ping(1_000);
+        assert_eq!(detector.check(1_000 + 29_999), None);
+        assert_eq!(detector.check(1_000 + 30_100), Some(30_100));
+        // the same item is reported only once
+        assert_eq!(detector.check(1_000 + 60_000), None);
+
+        // the next stalled item is reported again
+        detector.on_pong();
+        detector.on_ping(100_000);
+        assert_eq!(detector.check(130_000), Some(30_000));
+    }
+
+    #[test]
+    fn test_stall_detector_not_fire() {
+        let mut detector = StallDetector::new(Duration::from_secs(30));
+
+        // the mock worker keeps going
+        for i in 0..10 {
+            detector.on_ping(i * 20_000);
+            assert_eq!(detector.check(i * 20_000 + 10_000), None);
+            detector.on_pong();
+        }
+
+        // an idle worker is not stalled
+        assert_eq!(detector.check(1_000_000), None);
+
+        // the nested ping keeps the time of the first one
+        detector.on_ping(2_000_000);
+        detector.on_ping(2_020_000);
+        assert_eq!(detector.check(2_030_000), Some(30_000));
+    }
+}
@@ -6,14 +6,15 @@ use cache::{CachedJoint, JointData, SDAG_CACHE};
 use config;
 use error::Result;
 use failure::ResultExt;
-use joint::{Joint, JointSequence};
+use joint::{Joint, JointSequence, Level};
 use main_chain;
+use may::sync::RwLock;
 use rcu_cell::RcuReader;
 use sdag_object_base::object_hash;
 use serde::Deserialize;
 use serde_json::Value;
 use signature;
-use spec::{Definition, Unit};
+use spec::{self, Definition, Unit};
 use statistics;
 
 //---------------------------------------------------------------------------------------
@@ -25,6 +26,162 @@ pub struct NewJointEvent {
 
 impl_event!(NewJointEvent);
 
+lazy_static! {
+    static ref VALIDATION_PIPELINE: RwLock<Arc<ValidationPipeline>> =
+        RwLock::new(Arc::new(ValidationPipeline::default()));
+}
+
+//---------------------------------------------------------------------------------------
+// Validator
+//---------------------------------------------------------------------------------------
+pub trait Validator: Send + Sync {
+    fn validate(&self, joint: &JointData) -> Result<()>;
+    fn name(&self) -> &'static str;
+}
+
+pub struct HashValidator;
+impl Validator for HashValidator {
+    fn validate(&self, joint: &JointData) -> Result<()> {
+        validate_unit_hash(&joint.unit)
+    }
+
+    fn name(&self) -> &'static str {
+        "hash"
+    }
+}
+
+pub struct ParentValidator;
+impl Validator for ParentValidator {
+    fn validate(&self, joint: &JointData) -> Result<()> {
+        if !joint.unit.is_genesis_unit() {
+            validate_parents(joint)?;
+            // validate_ball(joint)?;
+        }
+
+        if !joint.skiplist_units.is_empty() {
+            validate_skip_list(&joint.skiplist_units)?;
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "parent"
+    }
+}
+
+pub struct WitnessValidator;
+impl Validator for WitnessValidator {
+    fn validate(&self, joint: &JointData) -> Result<()> {
+        validate_witnesses(joint)
+    }
+
+    fn name(&self) -> &'static str {
+        "witness"
+    }
+}
+
+pub struct AuthorValidator;
+impl Validator for AuthorValidator {
+    fn validate(&self, joint: &JointData) -> Result<()> {
+        validate_authors(joint)
+    }
+
+    fn name(&self) -> &'static str {
+        "author"
+    }
+}
+
+pub struct BusinessBasicValidator;
+impl Validator for BusinessBasicValidator {
+    fn validate(&self, joint: &JointData) -> Result<()> {
+        // check if include last self unit
+        business::BUSINESS_CACHE.is_include_last_stable_self_joint(joint)
+    }
+
+    fn name(&self) -> &'static str {
+        "business_basic"
+    }
+}
+
+pub struct BusinessCheckValidator;
+impl Validator for BusinessCheckValidator {
+    fn validate(&self, joint: &JointData) -> Result<()> {
+        // check sub businesses
+        business::check_business(joint)
+    }
+
+    fn name(&self) -> &'static str {
+        "business_check"
+    }
+}
+
+//---------------------------------------------------------------------------------------
+// ValidationPipeline
+//---------------------------------------------------------------------------------------
+/// run the validators in order, stop at the first error
+pub struct ValidationPipeline {
+    validators: Vec<Box<dyn Validator>>,
+}
+
+impl Default for ValidationPipeline {
+    fn default() -> Self {
+        ValidationPipeline {
+            validators: vec![
+                Box::new(HashValidator),
+                Box::new(ParentValidator),
+                Box::new(WitnessValidator),
+                Box::new(AuthorValidator),
+                Box::new(BusinessBasicValidator),
+                Box::new(BusinessCheckValidator),
+            ],
+        }
+    }
+}
+
+impl ValidationPipeline {
+    /// create an empty pipeline
+    pub fn new() -> Self {
+        ValidationPipeline {
+            validators: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, validator: Box<dyn Validator>) {
+        self.validators.push(validator);
+    }
+
+    /// insert the validator at the position, panics if index > len
+    pub fn insert(&mut self, index: usize, validator: Box<dyn Validator>) {
+        self.validators.insert(index, validator);
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    pub fn validate_all(&self, joint: &JointData) -> Result<()> {
+        for validator in &self.validators {
+            if let Err(e) = validator.validate(joint) {
+                bail!("{}: {}", validator.name(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// replace the pipeline used to validate the ready joints
+pub fn set_validation_pipeline(pipeline: ValidationPipeline) {
+    *VALIDATION_PIPELINE.write().unwrap() = Arc::new(pipeline);
+}
+
+fn get_validation_pipeline() -> Arc<ValidationPipeline> {
+    VALIDATION_PIPELINE.read().unwrap().clone()
+}
+
 //---------------------------------------------------------------------------------------
 // Global functions
 //---------------------------------------------------------------------------------------
@@ -47,6 +204,7 @@ pub fn validate_unit_hash(unit: &Unit) -> Result<()> {
 pub fn validate_ready_joint(joint: CachedJoint) -> Result<()> {
     // TODO: if validation failed we should sent error message to the corresponding connection
     let joint_data = joint.read()?;
+    ::tracing::debug!(unit_hash = %joint_data.unit.unit, "validate_ready_joint");
 
     // FIXME: what if it failed, should we purge the joint or just leaving it?
     // if we just return the error, the joint would still kept in unhandled, and never
@@ -62,15 +220,17 @@ pub fn validate_ready_joint(joint: CachedJoint) -> Result<()> {
             joint.save_to_db_async()?;
             // save the unhandled joint to normal
             SDAG_CACHE.normalize_joint(joint);
+            ::network::hub::update_peer_reputation(&peer_id, true);
             statistics::increase_stats(peer_id, true, true);
         }
         Err(e) => {
             // validation failed, purge the bad joint
-            error!(
-                "normal_validate, unit={}, err={}",
-                &joint_data.unit.unit,
-                e.to_string()
+            ::tracing::error!(
+                unit_hash = %joint_data.unit.unit,
+                err = %e,
+                "normal_validate failed"
             );
+            // the normal validation depends on the local state, don't blame the peer
             SDAG_CACHE.purge_bad_joint(joint.key, e.to_string());
             statistics::increase_stats(peer_id, true, false);
             return Err(e);
@@ -94,24 +254,9 @@ pub fn validate_ready_joint(joint: CachedJoint) -> Result<()> {
 // validation before move the joint to normal joints
 fn normal_validate(cached_joint: CachedJoint) -> Result<()> {
     let joint = cached_joint.read()?;
+    ::tracing::debug!(unit_hash = %joint.unit.unit, "normal_validate");
 
-    if !joint.unit.is_genesis_unit() {
-        validate_parents(&joint)?;
-        // validate_ball(joint)?;
-    }
-
-    validate_witnesses(&joint)?;
-
-    if !joint.skiplist_units.is_empty() {
-        validate_skip_list(&joint.skiplist_units)?;
-    }
-
-    validate_authors(&joint)?;
-
-    // check if include last self unit
-    business::BUSINESS_CACHE.is_include_last_stable_self_joint(&joint)?;
-    // check sub businesses
-    business::check_business(&joint)?;
+    get_validation_pipeline().validate_all(&joint)?;
 
     // temp validate the business
     if joint.unit.content_hash.is_none() {
@@ -141,6 +286,32 @@ pub fn basic_validate(joint: &JointData) -> Result<()> {
     let unit = &joint.unit;
     info!("basic validating joint identified by unit {}", unit.unit);
 
+    validate_joint_format(joint)?;
+
+    // the check is against the local wall clock, so it only applies to the
+    // fresh joints. joints with a ball are already stable on the sending hub
+    // and were accepted by the witnesses, the catchup and the history sync
+    // must accept them no matter how old they are
+    if joint.ball.is_none() {
+        validate_timestamp(
+            unit,
+            ::time::now() / 1000,
+            config::get_max_joint_age_secs(),
+            config::get_max_joint_future_secs(),
+        )?;
+    }
+
+    // validate authors move here for improving TPS
+    validate_authors(joint)?;
+
+    Ok(())
+}
+
+/// the basic checks that only depend on the joint itself, unlike the timestamp and
+/// the definitions, so every node gets the same result
+pub fn validate_joint_format(joint: &JointData) -> Result<()> {
+    let unit = &joint.unit;
+
     // basic info checks
     if unit.version != config::VERSION {
         bail!("wrong version");
@@ -162,9 +333,6 @@ pub fn basic_validate(joint: &JointData) -> Result<()> {
     // basic ball check
     validate_ball_basic(joint)?;
 
-    // validate authors move here for improving TPS
-    validate_authors(joint)?;
-
     Ok(())
 }
 
@@ -370,6 +538,72 @@ fn validate_message_basic(unit: &Unit) -> Result<()> {
     Ok(())
 }
 
+// reject the replayed old joints and the joints from the future
+// a joint exactly max_future ahead is already rejected
+// the units without timestamp are not checked
+fn validate_timestamp(unit: &Unit, now: u64, max_age: u64, max_future: u64) -> Result<()> {
+    let timestamp = match unit.timestamp {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    if timestamp <= now {
+        let age_secs = now - timestamp;
+        if age_secs > max_age {
+            statistics::increase_counter("sdag_joint_too_old_total");
+            bail!("joint is too old: {}s", age_secs);
+        }
+    } else {
+        let future_secs = timestamp - now;
+        if future_secs >= max_future {
+            statistics::increase_counter("sdag_joint_too_future_total");
+            bail!("joint is too far in the future: {}s", future_secs);
+        }
+    }
+
+    Ok(())
+}
+
+// the grace period for the clock skew between nodes
+const PARENT_TIMESTAMP_GRACE_SECS: u64 = 120;
+
+// the child should not be much earlier than its parents
+fn validate_parent_timestamps(joint: &JointData) -> Result<()> {
+    let mut parent_timestamps = Vec::with_capacity(joint.parents.len());
+    for parent in joint.parents.iter() {
+        parent_timestamps.push(parent.read()?.unit.timestamp);
+    }
+    check_parent_timestamps(joint.unit.timestamp, &parent_timestamps)
+}
+
+// skip the check if the child or any parent has no timestamp
+fn check_parent_timestamps(
+    timestamp: Option<u64>,
+    parent_timestamps: &[Option<u64>],
+) -> Result<()> {
+    let timestamp = match timestamp {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let mut max_parent_timestamp = 0;
+    for parent_timestamp in parent_timestamps {
+        match *parent_timestamp {
+            Some(t) => max_parent_timestamp = ::std::cmp::max(max_parent_timestamp, t),
+            None => return Ok(()),
+        }
+    }
+
+    if timestamp + PARENT_TIMESTAMP_GRACE_SECS < max_parent_timestamp {
+        statistics::increase_counter("sdag_timestamp_monotone_violations_total");
+        bail!(
+            "timestamp not monotone: child {}s before parent",
+            max_parent_timestamp - timestamp
+        );
+    }
+    Ok(())
+}
+
 fn validate_ball_basic(joint: &Joint) -> Result<()> {
     if joint.ball.is_some() {
         let ball = joint.ball.as_ref().unwrap();
@@ -389,8 +623,15 @@ fn validate_ball_basic(joint: &Joint) -> Result<()> {
 // 3)an address(author) can not appear twice in parents
 // 4)last_ball_unit must be stable in view of parents(at least one parent)
 // 5)last_ball_unit must be on_main_chain, joint.last_ball = last_ball from DB
-// 6)max_parent_last_ball_mci <= last_ball_mci (last ball unit should not retreat)
+// 6)last ball unit should not retreat
+// 7)min_parent_level - MAX_LAST_BALL_LOOKBACK <= last_ball_level (last ball is not too old)
 fn validate_parents(joint: &JointData) -> Result<()> {
+    ::tracing::trace!(
+        unit_hash = %joint.unit.unit,
+        parents = joint.parents.len(),
+        "validate_parents"
+    );
+
     // should never happen
     if joint.parents.len() != joint.unit.parent_units.len() {
         bail!("missing parents");
@@ -421,6 +662,11 @@ fn validate_parents(joint: &JointData) -> Result<()> {
         parents.push((parent_joint, parent.clone()));
     }
 
+    // TODO: reject the joint once all the nodes have the clock configured properly
+    if let Err(e) = validate_parent_timestamps(joint) {
+        warn!("unit {}: {}", joint.unit.unit, e);
+    }
+
     // ensure no include relationship between parents
     // descendent sorting
     let mut new_parents = Vec::new();
@@ -440,23 +686,7 @@ fn validate_parents(joint: &JointData) -> Result<()> {
     let last_ball_joint = SDAG_CACHE
         .get_joint(last_ball_unit_hash)
         .context("last ball unit not found")?;
-
-    // check last ball should not retreat
     let last_ball_joint_data = last_ball_joint.read()?;
-    let last_ball_level = last_ball_joint_data.get_level();
-    for parent in new_parents {
-        let parent_joint = parent.read()?;
-        let parent_last_ball = parent_joint.get_last_ball_joint()?;
-        let parent_last_ball_level = parent_last_ball.get_level();
-
-        if parent_last_ball_level > last_ball_level {
-            bail!(
-                "last ball level must not retreat, parent_last_ball_level:{:?}, last_ball_level:{:?}",
-                parent_last_ball_level,
-                last_ball_level
-            );
-        }
-    }
 
     // Check if it is stable in view of the parents
     let best_parent = joint.get_best_parent().read()?;
@@ -501,6 +731,77 @@ fn validate_parents(joint: &JointData) -> Result<()> {
         );
     }
 
+    // check last ball should not retreat
+    let last_ball_level = last_ball_joint_data.get_level();
+    for parent in &new_parents {
+        let parent_joint = parent.read()?;
+        let parent_last_ball = parent_joint.get_last_ball_joint()?;
+        let parent_last_ball_level = parent_last_ball.get_level();
+        if parent_last_ball_level > last_ball_level {
+            bail!(
+                "last ball level must not retreat, parent_last_ball_level:{:?}, last_ball_level:{:?}",
+                parent_last_ball_level,
+                last_ball_level
+            );
+        }
+    }
+
+    // the new rules are only checked after the upgrade mci so that the old joints are still valid
+    // the last ball and the last balls of the parents are stable, levels never change
+    // so all the nodes get the same result no matter when the joint is validated
+    let last_ball_mci = joint.get_last_ball_mci()?;
+    if last_ball_mci < Level::from(spec::LAST_BALL_LOOKBACK_UPGRADE_MCI) {
+        return Ok(());
+    }
+
+    let mut parent_last_ball_mcis = Vec::with_capacity(new_parents.len());
+    let mut parent_levels = Vec::with_capacity(new_parents.len());
+    for parent in new_parents {
+        let parent_joint = parent.read()?;
+        parent_last_ball_mcis.push(parent_joint.get_last_ball_mci()?);
+        parent_levels.push(parent_joint.get_level());
+    }
+    check_last_ball_mci(
+        last_ball_mci,
+        last_ball_level,
+        &parent_last_ball_mcis,
+        &parent_levels,
+        spec::MAX_LAST_BALL_LOOKBACK,
+    )
+}
+
+fn check_last_ball_mci(
+    last_ball_mci: Level,
+    last_ball_level: Level,
+    parent_last_ball_mcis: &[Level],
+    parent_levels: &[Level],
+    max_lookback: usize,
+) -> Result<()> {
+    if !last_ball_mci.is_valid() {
+        bail!("last ball is not stable");
+    }
+
+    for parent_last_ball_mci in parent_last_ball_mcis {
+        if *parent_last_ball_mci > last_ball_mci {
+            bail!(
+                "last ball mci must not retreat, parent_last_ball_mci:{:?}, last_ball_mci:{:?}",
+                parent_last_ball_mci,
+                last_ball_mci
+            );
+        }
+    }
+
+    let min_parent_level = parent_levels.iter().map(|level| level.value()).min();
+    if let Some(min_parent_level) = min_parent_level {
+        if last_ball_level.value() + max_lookback < min_parent_level {
+            bail!(
+                "last ball is too old, min_parent_level:{}, last_ball_level:{:?}",
+                min_parent_level,
+                last_ball_level
+            );
+        }
+    }
+
     Ok(())
 }
 
@@ -620,7 +921,13 @@ fn validate_authors(joint: &JointData) -> Result<()> {
 
             let definition = &author.definition;
             let unit_hash = joint.unit.calc_unit_hash_to_sign();
-            validate_authentifiers(&Value::Null, definition, &unit_hash, &author.authentifiers)?;
+            validate_authentifiers_with(
+                &Value::Null,
+                definition,
+                &unit_hash,
+                &author.authentifiers,
+                &|address| get_definition(address, last_ball_unit),
+            )?;
         } else {
             // get_definitions failed, or definition unit is not stable,
             // basic validate can set validate_authors_state 0x10|0x11
@@ -638,7 +945,13 @@ fn validate_authors(joint: &JointData) -> Result<()> {
                 }
             };
             let unit_hash = joint.unit.calc_unit_hash_to_sign();
-            validate_authentifiers(&Value::Null, &definition, &unit_hash, &author.authentifiers)?;
+            validate_authentifiers_with(
+                &Value::Null,
+                &definition,
+                &unit_hash,
+                &author.authentifiers,
+                &|address| get_definition(address, last_ball_unit),
+            )?;
         };
     }
 
@@ -670,6 +983,13 @@ struct SigValue<'a> {
     pubkey: &'a str,
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RofSetValue {
+    required: usize,
+    set: Vec<Value>,
+}
+
 fn validate_definition(definition: &Value, is_asset: bool) -> Result<()> {
     fn evaluate(
         definition: &Value,
@@ -704,10 +1024,80 @@ fn validate_definition(definition: &Value, is_asset: bool) -> Result<()> {
                     sig_value.pubkey.len() == config::HASH_LENGTH,
                     "wrong pubkey length"
                 );
+                Ok(true)
+            }
+            "and" => {
+                let args = definition
+                    .args
+                    .as_array()
+                    .ok_or_else(|| format_err!("and args must be array"))?;
+                ensure!(args.len() >= 2, "and must have at least 2 options");
+
+                let mut has_sig = false;
+                for arg in args {
+                    has_sig |= evaluate(arg, is_in_negation, is_asset, complexity)?;
+                }
+                Ok(has_sig)
+            }
+            "or" => {
+                let args = definition
+                    .args
+                    .as_array()
+                    .ok_or_else(|| format_err!("or args must be array"))?;
+                ensure!(args.len() >= 2, "or must have at least 2 options");
+
+                // every option must have a sig
+                let mut has_sig = true;
+                for arg in args {
+                    has_sig &= evaluate(arg, is_in_negation, is_asset, complexity)?;
+                }
+                Ok(has_sig)
+            }
+            "r of set" => {
+                let value = RofSetValue::deserialize(definition.args)
+                    .context("can't convert to RofSetValue")?;
+                ensure!(
+                    value.set.len() >= 2,
+                    "r of set must have at least 2 options"
+                );
+                ensure!(
+                    value.required >= 1 && value.required <= value.set.len(),
+                    "invalid required number {}",
+                    value.required
+                );
+
+                let mut count_options_with_sig = 0;
+                for arg in &value.set {
+                    if evaluate(arg, is_in_negation, is_asset, complexity)? {
+                        count_options_with_sig += 1;
+                    }
+                }
+
+                // any combination of the required options must have a sig
+                Ok(count_options_with_sig + value.required > value.set.len())
+            }
+            "address" => {
+                if is_in_negation {
+                    bail!("address cannot be negated");
+                }
+                if is_asset {
+                    bail!("asset condition cannot have address");
+                }
+
+                let address = definition
+                    .args
+                    .as_str()
+                    .ok_or_else(|| format_err!("address args must be string"))?;
+                ensure!(
+                    object_hash::is_chash_valid(address),
+                    "invalid address: {}",
+                    address
+                );
+                // the delegated definition is validated when the authentifiers are verified
+                Ok(true)
             }
-            op => unimplemented!("unsupported op: {}", op),
+            op => bail!("unsupported op: {}", op),
         }
-        Ok(true)
     }
 
     let mut complexity = 0;
@@ -720,19 +1110,51 @@ fn validate_definition(definition: &Value, is_asset: bool) -> Result<()> {
     Ok(())
 }
 
+/// the address op is not supported, since there is no stable view to resolve the delegated address
 pub fn validate_authentifiers<S: std::hash::BuildHasher>(
     asset: &Value,
     definition: &Value,
     unit_hash: &[u8],
     authentifiers: &StdHashMap<String, String, S>,
 ) -> Result<()> {
-    let evaluate = |definition: &Value, path: &str, used_path: &mut Vec<String>| -> Result<()> {
+    validate_authentifiers_with(asset, definition, unit_hash, authentifiers, &|address| {
+        bail!("delegated address {} can't be resolved here", address)
+    })
+}
+
+/// `get_definition` returns the stable definition of a delegated address
+fn validate_authentifiers_with<S, F>(
+    asset: &Value,
+    definition: &Value,
+    unit_hash: &[u8],
+    authentifiers: &StdHashMap<String, String, S>,
+    get_definition: &F,
+) -> Result<()>
+where
+    S: std::hash::BuildHasher,
+    F: Fn(&str) -> Result<Value>,
+{
+    // return false if the authentifier of the branch is not provided
+    fn evaluate<S, F>(
+        definition: &Value,
+        path: &str,
+        unit_hash: &[u8],
+        authentifiers: &StdHashMap<String, String, S>,
+        used_path: &mut Vec<String>,
+        get_definition: &F,
+        depth: usize,
+    ) -> Result<bool>
+    where
+        S: std::hash::BuildHasher,
+        F: Fn(&str) -> Result<Value>,
+    {
         let definition = Definition::from_value(definition)?;
         match definition.op {
             "sig" => {
-                let sig = authentifiers
-                    .get(path)
-                    .ok_or_else(|| format_err!("authentifier path not found: {}", path))?;
+                let sig = match authentifiers.get(path) {
+                    Some(sig) => sig,
+                    None => return Ok(false),
+                };
                 used_path.push(path.to_owned());
 
                 let sig_value =
@@ -740,11 +1162,99 @@ pub fn validate_authentifiers<S: std::hash::BuildHasher>(
 
                 signature::verify(unit_hash, sig, sig_value.pubkey)
                     .context(format!("bad signature at path: {:?}", path))?;
+                Ok(true)
+            }
+            "and" => {
+                let args = definition
+                    .args
+                    .as_array()
+                    .ok_or_else(|| format_err!("and args must be array"))?;
+
+                let mut res = true;
+                for (i, arg) in args.iter().enumerate() {
+                    let path = format!("{}.{}", path, i);
+                    res &= evaluate(
+                        arg,
+                        &path,
+                        unit_hash,
+                        authentifiers,
+                        used_path,
+                        get_definition,
+                        depth,
+                    )?;
+                }
+                Ok(res)
             }
-            op => unimplemented!("unsupported op: {}", op),
+            "or" => {
+                let args = definition
+                    .args
+                    .as_array()
+                    .ok_or_else(|| format_err!("or args must be array"))?;
+
+                // evaluate all the options, so that every passed authentifier is verified
+                let mut res = false;
+                for (i, arg) in args.iter().enumerate() {
+                    let path = format!("{}.{}", path, i);
+                    res |= evaluate(
+                        arg,
+                        &path,
+                        unit_hash,
+                        authentifiers,
+                        used_path,
+                        get_definition,
+                        depth,
+                    )?;
+                }
+                Ok(res)
+            }
+            "r of set" => {
+                let value = RofSetValue::deserialize(definition.args)
+                    .context("can't convert to RofSetValue")?;
+
+                let mut count = 0;
+                for (i, arg) in value.set.iter().enumerate() {
+                    let path = format!("{}.{}", path, i);
+                    if evaluate(
+                        arg,
+                        &path,
+                        unit_hash,
+                        authentifiers,
+                        used_path,
+                        get_definition,
+                        depth,
+                    )? {
+                        count += 1;
+                    }
+                }
+                Ok(count >= value.required)
+            }
+            "address" => {
+                // the delegation chain must be finite
+                if depth >= spec::MAX_ADDRESS_DELEGATION_DEPTH {
+                    bail!("address delegation is too deep at path: {:?}", path);
+                }
+
+                let address = definition
+                    .args
+                    .as_str()
+                    .ok_or_else(|| format_err!("address args must be string"))?;
+                let inner_definition = get_definition(address)?;
+                validate_definition(&inner_definition, false)?;
+
+                // the delegated definition is signed at the same path
+                evaluate(
+                    &inner_definition,
+                    path,
+                    unit_hash,
+                    authentifiers,
+                    used_path,
+                    get_definition,
+                    depth + 1,
+                )
+            }
+            op => bail!("unsupported op: {}", op),
         }
-        Ok(())
-    };
+    }
 
     let is_asset = authentifiers.is_empty();
     if is_asset && !asset.is_null() {
@@ -752,7 +1262,18 @@ pub fn validate_authentifiers<S: std::hash::BuildHasher>(
     }
     validate_definition(definition, is_asset)?;
     let mut used_path = Vec::new();
-    evaluate(definition, "r", &mut used_path)?;
+    let res = evaluate(
+        definition,
+        "r",
+        unit_hash,
+        authentifiers,
+        &mut used_path,
+        get_definition,
+        0,
+    )?;
+    if !is_asset && !res {
+        bail!("authentifier verification failed");
+    }
     if !is_asset && used_path.len() != authentifiers.len() {
         bail!(
             "some authentifiers are not used, used={:?}, passed={:?}",
@@ -789,3 +1310,387 @@ fn validate_messages(joint: CachedJoint) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64;
+    use secp256k1::{key, Secp256k1};
+    use std::cell::RefCell;
+
+    // return (priv_key, b64_pub_key)
+    fn gen_key(seed: u8) -> (Vec<u8>, String) {
+        let secp = Secp256k1::new();
+        let priv_key = vec![seed; 32];
+        let sk = key::SecretKey::from_slice(&priv_key).unwrap();
+        let pk = key::PublicKey::from_secret_key(&secp, &sk);
+        (priv_key, base64::encode(&pk.serialize()[..]))
+    }
+
+    fn multisig_authentifiers(
+        keys: &[(Vec<u8>, String)],
+        signers: &[usize],
+        hash: &[u8],
+    ) -> StdHashMap<String, String> {
+        signers
+            .iter()
+            .map(|&i| {
+                let sig = signature::sign(hash, &keys[i].0).unwrap();
+                (format!("r.{}", i), sig)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_and_definition() {
+        let keys = vec![gen_key(1), gen_key(2)];
+        let definition = json!(["and", [
+            ["sig", {"pubkey": keys[0].1}],
+            ["sig", {"pubkey": keys[1].1}],
+        ]]);
+        let hash = [7u8; 32];
+
+        let authentifiers = multisig_authentifiers(&keys, &[0, 1], &hash);
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_ok());
+
+        let authentifiers = multisig_authentifiers(&keys, &[0], &hash);
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_err());
+    }
+
+    #[test]
+    fn test_validation_pipeline() {
+        use may::sync::Mutex;
+        use spec::UnitBuilder;
+
+        struct SpyValidator(Arc<Mutex<Vec<String>>>);
+        impl Validator for SpyValidator {
+            fn validate(&self, joint: &JointData) -> Result<()> {
+                self.0.lock().unwrap().push(joint.unit.unit.clone());
+                Ok(())
+            }
+
+            fn name(&self) -> &'static str {
+                "spy"
+            }
+        }
+
+        struct FailValidator;
+        impl Validator for FailValidator {
+            fn validate(&self, _joint: &JointData) -> Result<()> {
+                bail!("always fail")
+            }
+
+            fn name(&self) -> &'static str {
+                "fail"
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = ValidationPipeline::new();
+        pipeline.push(Box::new(HashValidator));
+        pipeline.insert(0, Box::new(SpyValidator(log.clone())));
+
+        let mut units = Vec::new();
+        for i in 0..5 {
+            let unit = UnitBuilder::new()
+                .parent(&format!("parent_{}", i))
+                .build()
+                .unwrap();
+            units.push(unit.unit.clone());
+            let joint = Joint {
+                ball: None,
+                skiplist_units: Vec::new(),
+                unit,
+            };
+            let joint = JointData::from_joint(joint, None);
+            assert!(pipeline.validate_all(&joint).is_ok());
+        }
+        assert_eq!(*log.lock().unwrap(), units);
+
+        // the error is prefixed with the validator name
+        pipeline.insert(1, Box::new(FailValidator));
+        let joint = Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit: UnitBuilder::new().build().unwrap(),
+        };
+        let joint = JointData::from_joint(joint, None);
+        let err = pipeline.validate_all(&joint).unwrap_err();
+        assert_eq!(err.to_string(), "fail: always fail");
+        assert_eq!(log.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_validate_timestamp() {
+        use spec::UnitBuilder;
+
+        const HOUR: u64 = 3600;
+        const MAX_AGE: u64 = 24 * HOUR;
+        let now = 1_527_218_469;
+        let check = |timestamp: u64| {
+            let unit = UnitBuilder::new().timestamp(timestamp).build().unwrap();
+            validate_timestamp(&unit, now, MAX_AGE, HOUR)
+        };
+
+        let err = check(now - 25 * HOUR).unwrap_err();
+        assert_eq!(err.to_string(), format!("joint is too old: {}s", 25 * HOUR));
+        assert!(check(now - 23 * HOUR).is_ok());
+        assert!(check(now + HOUR).is_err());
+        assert!(check(now + 50 * 60).is_ok());
+
+        let unit = UnitBuilder::new().build().unwrap();
+        assert!(validate_timestamp(&unit, now, MAX_AGE, HOUR).is_ok());
+    }
+
+    #[test]
+    fn test_check_parent_timestamps() {
+        let parent = 1_527_218_469;
+        let parents = [Some(parent - 10), Some(parent)];
+
+        // within the grace period
+        assert!(check_parent_timestamps(Some(parent - 100), &parents).is_ok());
+
+        let err = check_parent_timestamps(Some(parent - 130), &parents).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "timestamp not monotone: child 130s before parent"
+        );
+
+        // a parent has no timestamp
+        assert!(check_parent_timestamps(Some(parent - 130), &[Some(parent), None]).is_ok());
+        assert!(check_parent_timestamps(None, &parents).is_ok());
+    }
+
+    #[test]
+    fn test_check_last_ball_mci() {
+        let level = Level::new;
+        let parent_last_ball_mcis = [level(80), level(100)];
+        let parent_levels = [level(180), level(150)];
+
+        // equal to the max parent last ball mci
+        assert!(check_last_ball_mci(
+            level(100),
+            level(120),
+            &parent_last_ball_mcis,
+            &parent_levels,
+            100
+        )
+        .is_ok());
+
+        // retreat one mci
+        let err = check_last_ball_mci(
+            level(99),
+            level(120),
+            &parent_last_ball_mcis,
+            &parent_levels,
+            100,
+        );
+        assert!(err.unwrap_err().to_string().contains("must not retreat"));
+
+        // older than the lookback of the min parent level
+        let parent_levels = [level(250), level(201)];
+        assert!(check_last_ball_mci(
+            level(100),
+            level(101),
+            &parent_last_ball_mcis,
+            &parent_levels,
+            100
+        )
+        .is_ok());
+        let err = check_last_ball_mci(
+            level(100),
+            level(100),
+            &parent_last_ball_mcis,
+            &parent_levels,
+            100,
+        );
+        assert!(err.unwrap_err().to_string().contains("too old"));
+
+        assert!(check_last_ball_mci(Level::INVALID, level(0), &[], &[], 100).is_err());
+    }
+
+    #[test]
+    fn test_r_of_set_definition() {
+        let keys = vec![gen_key(1), gen_key(2), gen_key(3)];
+        let definition = json!(["r of set", {
+            "required": 2,
+            "set": [
+                ["sig", {"pubkey": keys[0].1}],
+                ["sig", {"pubkey": keys[1].1}],
+                ["sig", {"pubkey": keys[2].1}],
+            ]
+        }]);
+        let hash = [7u8; 32];
+
+        let authentifiers = multisig_authentifiers(&keys, &[0, 2], &hash);
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_ok());
+
+        let authentifiers = multisig_authentifiers(&keys, &[1], &hash);
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_err());
+    }
+
+    #[test]
+    fn test_or_definition() {
+        let keys = vec![gen_key(1), gen_key(2)];
+        let definition = json!(["or", [
+            ["sig", {"pubkey": keys[0].1}],
+            ["sig", {"pubkey": keys[1].1}],
+        ]]);
+        let hash = [7u8; 32];
+
+        for signers in &[vec![0], vec![1], vec![0, 1]] {
+            let authentifiers = multisig_authentifiers(&keys, signers, &hash);
+            assert!(
+                validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_ok()
+            );
+        }
+
+        let authentifiers = multisig_authentifiers(&keys, &[], &hash);
+        assert!(validate_definition(&definition, false).is_ok());
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_err());
+
+        // a bad signature in any option is rejected
+        let mut authentifiers = multisig_authentifiers(&keys, &[0, 1], &hash);
+        let other_hash = [8u8; 32];
+        authentifiers.insert(
+            "r.1".to_owned(),
+            signature::sign(&other_hash, &keys[1].0).unwrap(),
+        );
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_err());
+
+        let definition = json!(["or", [["sig", {"pubkey": keys[0].1}]]]);
+        assert!(validate_definition(&definition, false).is_err());
+    }
+
+    #[test]
+    fn test_address_definition() {
+        // the stable definitions known to the validator
+        let definitions = RefCell::new(StdHashMap::new());
+        let define = |definition: Value| {
+            let address = object_hash::get_chash(&definition).unwrap();
+            definitions.borrow_mut().insert(address.clone(), definition);
+            address
+        };
+        let get_definition = |address: &str| {
+            definitions
+                .borrow()
+                .get(address)
+                .cloned()
+                .ok_or_else(|| format_err!("definition of {} is not stable", address))
+        };
+        let hash = [7u8; 32];
+        let validate = |definition: &Value, authentifiers: &StdHashMap<String, String>| {
+            validate_authentifiers_with(
+                &Value::Null,
+                definition,
+                &hash,
+                authentifiers,
+                &get_definition,
+            )
+        };
+
+        let key = gen_key(4);
+        let mut authentifiers = StdHashMap::new();
+        authentifiers.insert("r".to_owned(), signature::sign(&hash, &key.0).unwrap());
+
+        // A -> B -> C, C is signed by the key
+        let address_c = define(json!(["sig", {"pubkey": key.1}]));
+        let address_b = define(json!(["address", address_c]));
+        let definition_a = json!(["address", address_b]);
+        assert!(validate_definition(&definition_a, false).is_ok());
+        assert!(validate(&definition_a, &authentifiers).is_ok());
+
+        // the delegated address is signed at the path of the address op
+        let definition = json!(["or", [["address", address_b], ["sig", {"pubkey": key.1}]]]);
+        let mut or_authentifiers = StdHashMap::new();
+        or_authentifiers.insert("r.0".to_owned(), authentifiers["r"].clone());
+        assert!(validate(&definition, &or_authentifiers).is_ok());
+
+        // the delegation chain exceeds the max depth
+        let mut address = address_c;
+        for _ in 0..spec::MAX_ADDRESS_DELEGATION_DEPTH {
+            address = define(json!(["address", address]));
+        }
+        let definition = json!(["address", address]);
+        let err = validate(&definition, &authentifiers);
+        assert!(err.unwrap_err().to_string().contains("too deep"));
+
+        // the delegated address has no stable definition
+        let undefined = object_hash::get_chash(&json!(["sig", {"pubkey": gen_key(5).1}])).unwrap();
+        let definition = json!(["address", undefined]);
+        assert!(validate(&definition, &authentifiers).is_err());
+
+        // there is no stable view to resolve the address without a joint
+        assert!(
+            validate_authentifiers(&Value::Null, &definition_a, &hash, &authentifiers).is_err()
+        );
+
+        assert!(validate_definition(&json!(["address", "not an address"]), false).is_err());
+    }
+
+    #[test]
+    fn test_2_of_3_unit_signature() {
+        use spec::UnitBuilder;
+
+        let keys = vec![gen_key(1), gen_key(2), gen_key(3)];
+        let definition = json!(["or", [
+            ["r of set", {
+                "required": 2,
+                "set": [
+                    ["sig", {"pubkey": keys[0].1}],
+                    ["sig", {"pubkey": keys[1].1}],
+                    ["sig", {"pubkey": keys[2].1}],
+                ]
+            }],
+            ["sig", {"pubkey": keys[2].1}],
+        ]]);
+        let address = object_hash::get_chash(&definition).unwrap();
+
+        let unit = UnitBuilder::new()
+            .author(&address, definition.clone(), StdHashMap::new())
+            .parent("parent")
+            .build()
+            .unwrap();
+        let hash = unit.calc_unit_hash_to_sign();
+
+        // sign the r of set branch with key 0 and key 1
+        let mut authentifiers = StdHashMap::new();
+        for (i, key) in keys.iter().take(2).enumerate() {
+            let sig = signature::sign(&hash, &key.0).unwrap();
+            authentifiers.insert(format!("r.0.{}", i), sig);
+        }
+        let mut unit = unit;
+        unit.authors[0].authentifiers = authentifiers.clone();
+
+        // the authentifiers are not part of the hash to sign
+        assert_eq!(unit.calc_unit_hash_to_sign(), hash);
+        let author = &unit.authors[0];
+        assert!(validate_authentifiers(
+            &Value::Null,
+            &author.definition,
+            &hash,
+            &author.authentifiers
+        )
+        .is_ok());
+
+        // an authentifier of a path that is not in the definition is not used
+        let mut extra = authentifiers.clone();
+        extra.insert(
+            "r.2".to_owned(),
+            signature::sign(&hash, &keys[2].0).unwrap(),
+        );
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &extra).is_err());
+
+        // one signature is not enough for the r of set branch
+        authentifiers.remove("r.0.1");
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_err());
+
+        // the single sig branch
+        let mut authentifiers = StdHashMap::new();
+        authentifiers.insert(
+            "r.1".to_owned(),
+            signature::sign(&hash, &keys[2].0).unwrap(),
+        );
+        assert!(validate_authentifiers(&Value::Null, &definition, &hash, &authentifiers).is_ok());
+    }
+}
@@ -23,7 +23,7 @@ pub struct WalletInfo {
 }
 
 impl WalletInfo {
-    fn from_mnemonic(mnemonic: &str) -> Result<WalletInfo> {
+    pub fn from_mnemonic(mnemonic: &str) -> Result<WalletInfo> {
         let wallet = 0;
         let mnemonic = Mnemonic::from(&mnemonic)?;
         let master_prvk = sdag_wallet_base::master_private_key(&mnemonic, "")?;
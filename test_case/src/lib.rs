@@ -23,6 +23,7 @@ extern crate serde;
 pub mod genesis;
 pub mod local_cmd;
 pub mod net_cmd;
+pub mod stress;
 pub mod transaction;
 pub mod wallet;
 
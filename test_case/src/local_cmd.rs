@@ -17,8 +17,9 @@ pub fn local_cmd(m: &ArgMatches) -> Result<()> {
     }
 
     if let Some(n) = m.subcommand_matches("genesis") {
+        let wallets = value_t!(n.value_of("wallets"), usize).ok();
         match value_t!(n.value_of("n"), u32) {
-            Ok(num) => genesis_init(num)?,
+            Ok(num) => genesis_init(num, wallets)?,
 
             Err(e) => {
                 error!("{}", e);
@@ -31,7 +32,7 @@ pub fn local_cmd(m: &ArgMatches) -> Result<()> {
 
     if let Some(n) = m.subcommand_matches("wallets") {
         match value_t!(n.value_of("n"), usize) {
-            Ok(num) => wallet::gen_wallets(num)?,
+            Ok(num) => wallet::generate_wallet_set(num)?,
 
             Err(e) => e.exit(),
         };
@@ -39,7 +40,7 @@ pub fn local_cmd(m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn genesis_init(witness_counts: u32) -> Result<()> {
+fn genesis_init(witness_counts: u32, test_wallets: Option<usize>) -> Result<()> {
     // TODO: get total amount and msg from args
     let total = 500_000_000_000_000;
     let msg = "hello sdag";
@@ -67,5 +68,11 @@ fn genesis_init(witness_counts: u32) -> Result<()> {
         genesis_joint,
     };
 
-    save_results(&result, "result.json")
+    save_results(&result, "result.json")?;
+
+    // the test wallets could be funded by the `fund` command once the hub is running
+    if let Some(num) = test_wallets {
+        wallet::generate_wallet_set(num)?;
+    }
+    Ok(())
 }
@@ -21,6 +21,16 @@ pub fn net_cmd(m: &ArgMatches, settings: &sdag::config::Settings) -> Result<()>
     let wallet_info = wallet::WalletInfo::from_mnemonic(&settings.get_mnemonic())?;
     //transfer
     if let Some(send) = m.subcommand_matches("send") {
+        // pay all the spendable outputs to one address
+        if let Some(address) = send.value_of("all") {
+            if !object_hash::is_chash_valid(address) {
+                eprintln!("invalid address, please check");
+                return Ok(());
+            }
+            transaction::send_all_payment(&ws, address, &wallet_info)?;
+            return Ok(());
+        }
+
         distribute_coins_and_cocurrency(&ws, &send, &wallet_info, &witnesses)?;
         loop {
             may::coroutine::sleep(std::time::Duration::from_secs(100));
@@ -78,6 +88,38 @@ pub fn net_cmd(m: &ArgMatches, settings: &sdag::config::Settings) -> Result<()>
         transaction::send_payment(&ws, address_amount, &wallet_info, flag)?;
     }
 
+    //fund the wallets generated by genesis
+    if let Some(fund) = m.subcommand_matches("fund") {
+        let amount = value_t!(fund.value_of("AMOUNT"), f64).unwrap_or_else(|e| e.exit());
+        if amount > std::u64::MAX as f64 / 1_000_000.0 || amount < 0.000_001 {
+            eprintln!("invalid amount, please check");
+            return Ok(());
+        }
+
+        let test_wallets = wallet::get_wallets().context("no wallets, run genesis first")?;
+        let amount_each = (amount * 1_000_000.0).round() as u64;
+        // the funding joints need the witnesses to become stable
+        let timeout = std::time::Duration::from_secs(300);
+        for unit in wallet::fund_wallets(&ws, &wallet_info, &test_wallets, amount_each, timeout)? {
+            println!("stable: {}", unit);
+        }
+        return Ok(());
+    }
+
+    //circular payments among the test wallets
+    if let Some(arg) = m.subcommand_matches("stress") {
+        let num = value_t!(arg.value_of("wallets"), usize).unwrap_or_else(|e| e.exit());
+        let tps = value_t!(arg.value_of("tps"), f64).unwrap_or_else(|e| e.exit());
+        let secs = value_t!(arg.value_of("duration"), u64).unwrap_or_else(|e| e.exit());
+
+        let mut test_wallets = wallet::get_wallets().context("no wallets, run genesis first")?;
+        if test_wallets.len() < num {
+            bail!("only {} wallets in wallets.json", test_wallets.len());
+        }
+        test_wallets.truncate(num);
+        return stress::stress(&ws, test_wallets, tps, std::time::Duration::from_secs(secs));
+    }
+
     //balance
     if let Some(arg) = m.subcommand_matches("balance") {
         if let Some(address) = arg.value_of("ADDRESS") {
@@ -226,7 +268,7 @@ fn distribute_coins_and_cocurrency(
 ) -> Result<()> {
     let test_wallets = match wallet::get_wallets() {
         Ok(wallets) => wallets,
-        Err(_) => wallet::gen_wallets(100)?,
+        Err(_) => wallet::generate_wallet_set(100)?,
     };
 
     if witnesses.contains(&wallet_info._00_address) {
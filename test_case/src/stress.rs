@@ -0,0 +1,269 @@
+//! stress test that keeps the wallets paying each other at a target tps
+//!
+//! wallet i always pays wallet i+1 (the last one pays the first one), every payment
+//! takes a ticket from the semaphore of the wallet, the semaphores are released in
+//! turn at the target rate. The confirmation latency is the time from composing a
+//! joint to the hub accepting it.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use may::sync::{Mutex, Semphore};
+
+use sdag::error::Result;
+use sdag::network::wallet::WalletConn;
+use sdag::spec::Output;
+
+use crate::{save_results, transaction, wallet::WalletInfo};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const PAYMENT_AMOUNT: u64 = 1_000;
+// the consolidation joint has to be stable before the merged output is spendable
+const CONSOLIDATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the time source that paces the payments, the tests use a simulated one
+pub trait Clock {
+    fn elapsed(&self) -> Duration;
+    fn sleep(&self, dur: Duration);
+}
+
+/// the wall clock since the stress test started
+pub struct WallClock(Instant);
+
+impl WallClock {
+    pub fn start() -> Self {
+        WallClock(Instant::now())
+    }
+}
+
+impl Clock for WallClock {
+    fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        may::coroutine::sleep(dur);
+    }
+}
+
+#[derive(Default)]
+struct StressStats {
+    good: usize,
+    bad: usize,
+    utxo_exhausted: usize,
+    latencies_ms: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StressReport {
+    pub wallets: usize,
+    pub target_tps: f64,
+    pub elapsed_secs: f64,
+    pub good: usize,
+    pub bad: usize,
+    pub utxo_exhausted: usize,
+    pub tps: f64,
+    pub error_rate: f64,
+    pub latency_p50_ms: u64,
+    pub latency_p95_ms: u64,
+    pub latency_p99_ms: u64,
+}
+
+impl StressReport {
+    fn new(stats: &StressStats, wallets: usize, target_tps: f64, elapsed: Duration) -> Self {
+        let elapsed_secs = elapsed.as_millis() as f64 / 1000.0;
+        let total = stats.good + stats.bad;
+        let mut latencies = stats.latencies_ms.clone();
+        latencies.sort();
+
+        StressReport {
+            wallets,
+            target_tps,
+            elapsed_secs,
+            good: stats.good,
+            bad: stats.bad,
+            utxo_exhausted: stats.utxo_exhausted,
+            tps: if elapsed_secs > 0.0 {
+                stats.good as f64 / elapsed_secs
+            } else {
+                0.0
+            },
+            error_rate: if total > 0 {
+                stats.bad as f64 / total as f64
+            } else {
+                0.0
+            },
+            latency_p50_ms: percentile(&latencies, 50),
+            latency_p95_ms: percentile(&latencies, 95),
+            latency_p99_ms: percentile(&latencies, 99),
+        }
+    }
+
+    pub fn show(&self) {
+        println!(
+            "[{:.0}s] tps: {:.2}/{}, good: {}, bad: {}, error rate: {:.2}%, utxo exhausted: {}",
+            self.elapsed_secs,
+            self.tps,
+            self.target_tps,
+            self.good,
+            self.bad,
+            self.error_rate * 100.0,
+            self.utxo_exhausted
+        );
+        println!(
+            "      latency p50: {}ms, p95: {}ms, p99: {}ms",
+            self.latency_p50_ms, self.latency_p95_ms, self.latency_p99_ms
+        );
+    }
+}
+
+// nearest rank percentile of the sorted values
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (sorted.len() * p + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+/// run the circular payments among the wallets at the target tps for the duration
+pub fn run_stress<C: Clock>(
+    ws: &Arc<WalletConn>,
+    wallets: Vec<WalletInfo>,
+    target_tps: f64,
+    duration: Duration,
+    clock: &C,
+) -> Result<StressReport> {
+    ensure!(wallets.len() >= 2, "stress test needs at least 2 wallets");
+    ensure!(target_tps > 0.0, "target tps must be positive");
+
+    let wallet_num = wallets.len();
+    let wallets = Arc::new(wallets);
+    let tickets = (0..wallet_num)
+        .map(|_| Arc::new(Semphore::new(0)))
+        .collect::<Vec<_>>();
+    let stats = Arc::new(Mutex::new(StressStats::default()));
+    let running = Arc::new(AtomicBool::new(true));
+
+    let mut workers = Vec::with_capacity(wallet_num);
+    for i in 0..wallet_num {
+        let ws = Arc::clone(ws);
+        let wallets = Arc::clone(&wallets);
+        let tickets = Arc::clone(&tickets[i]);
+        let stats = Arc::clone(&stats);
+        let running = Arc::clone(&running);
+        workers.push(may::go!(move || {
+            let from = &wallets[i];
+            let to = &wallets[(i + 1) % wallets.len()];
+            loop {
+                if !tickets.wait_timeout(Duration::from_millis(100)) {
+                    if running.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    break;
+                }
+                pay_once(&ws, from, to, &stats);
+            }
+        }));
+    }
+
+    let interval = Duration::from_nanos((1_000_000_000.0 / target_tps) as u64);
+    let mut next_report = REPORT_INTERVAL;
+    let mut paced = 0;
+    while clock.elapsed() < duration {
+        tickets[paced % wallet_num].post();
+        paced += 1;
+        clock.sleep(interval);
+
+        // report the progress
+        if clock.elapsed() >= next_report {
+            let stats = stats.lock().unwrap();
+            StressReport::new(&stats, wallet_num, target_tps, clock.elapsed()).show();
+            next_report += REPORT_INTERVAL;
+        }
+    }
+    // the workers still take the tickets left before they quit
+    running.store(false, Ordering::Relaxed);
+
+    for worker in workers {
+        worker.join().ok();
+    }
+
+    let stats = stats.lock().unwrap();
+    Ok(StressReport::new(
+        &stats,
+        wallet_num,
+        target_tps,
+        clock.elapsed(),
+    ))
+}
+
+/// run the stress test and save the final report to `stress_test_{timestamp}.json`
+pub fn stress(
+    ws: &Arc<WalletConn>,
+    wallets: Vec<WalletInfo>,
+    target_tps: f64,
+    duration: Duration,
+) -> Result<()> {
+    let report = run_stress(ws, wallets, target_tps, duration, &WallClock::start())?;
+    println!("\nfinal report:");
+    report.show();
+
+    let path = format!("stress_test_{}.json", Local::now().format("%Y%m%d%H%M%S"));
+    save_results(&report, &path)?;
+    println!("report saved to {}", path);
+    Ok(())
+}
+
+fn pay_once(ws: &Arc<WalletConn>, from: &WalletInfo, to: &WalletInfo, stats: &Mutex<StressStats>) {
+    let outputs = vec![Output {
+        address: to._00_address.clone(),
+        amount: PAYMENT_AMOUNT,
+    }];
+
+    let start = Instant::now();
+    let joint = match transaction::compose_payment(ws, outputs, from, false) {
+        Ok(joint) => joint,
+        Err(e) => {
+            if is_utxo_exhausted(&e.to_string()) {
+                stats.lock().unwrap().utxo_exhausted += 1;
+                if let Err(e) = consolidate(ws, from) {
+                    error!("consolidate {} failed, err={}", from._00_address, e);
+                }
+            } else {
+                error!("compose payment failed, err={}", e);
+            }
+            return;
+        }
+    };
+
+    let ret = ws.post_joint(&joint);
+    let latency = start.elapsed().as_millis() as u64;
+
+    let mut stats = stats.lock().unwrap();
+    match ret {
+        Ok(()) => {
+            stats.good += 1;
+            stats.latencies_ms.push(latency);
+        }
+        Err(e) => {
+            error!("post joint {} failed, err={}", joint.unit.unit, e);
+            stats.bad += 1;
+        }
+    }
+}
+
+// the hub can't find enough stable outputs for the payment
+fn is_utxo_exhausted(err: &str) -> bool {
+    err.contains("not enough balance") || err.contains("NOT_ENOUGH_FUNDS")
+}
+
+// merge all the spendable outputs into one and wait it stable
+fn consolidate(ws: &Arc<WalletConn>, wallet: &WalletInfo) -> Result<()> {
+    let joint = transaction::compose_payment(ws, Vec::new(), wallet, true)?;
+    ws.post_joint_and_wait_stable(&joint, CONSOLIDATE_TIMEOUT)?;
+    info!("consolidated outputs of {}", wallet._00_address);
+    Ok(())
+}
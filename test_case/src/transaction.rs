@@ -4,6 +4,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use sdag::error::Result;
+use sdag::joint::Level;
 use sdag::network::wallet::WalletConn;
 use sdag_wallet_base::Base64KeyExt;
 
@@ -12,6 +13,9 @@ use crate::{
     REGISTERED_WALLETS, TRANSANTION_NUM,
 };
 
+// the max time to wait a distributed payment stable
+const WAIT_STABLE_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub fn send_payment(
     ws: &Arc<WalletConn>,
     address_amount: Vec<(String, f64)>,
@@ -46,6 +50,7 @@ pub fn send_payment(
         transaction_amount: total_amount,
         light_props,
         pubk: wallet_info._00_address_pubk.to_base64_key(),
+        send_all: false,
     };
 
     let normal_joint = sdag::composer::compose_joint(compose_info.clone(), wallet_info)?;
@@ -123,6 +128,82 @@ pub fn send_payment(
     Ok(normal_joint.unit.unit)
 }
 
+/// compose a payment joint without posting it
+/// if is_spend_all is set, all the spendable outputs are merged into the change
+pub fn compose_payment(
+    ws: &Arc<WalletConn>,
+    outputs: Vec<sdag::spec::Output>,
+    wallet_info: &WalletInfo,
+    is_spend_all: bool,
+) -> Result<sdag::joint::Joint> {
+    let light_props = ws.get_light_props(&wallet_info._00_address)?;
+    let total_amount = outputs.iter().fold(0, |acc, x| acc + x.amount);
+
+    let inputs = ws.get_inputs_from_hub(
+        &wallet_info._00_address,
+        total_amount + 1000, // we need another 1000 sdg (usually 431 + 197)
+        is_spend_all,
+        &light_props.last_ball_unit,
+    )?;
+
+    let compose_info = sdag::composer::ComposeInfo {
+        paid_address: wallet_info._00_address.clone(),
+        change_address: wallet_info._00_address.clone(),
+        outputs,
+        text_message: None,
+        inputs,
+        transaction_amount: total_amount,
+        light_props,
+        pubk: wallet_info._00_address_pubk.to_base64_key(),
+        send_all: false,
+    };
+
+    sdag::composer::compose_joint(compose_info, wallet_info)
+}
+
+/// pay all the spendable outputs to the address, the commissions are deducted from it
+pub fn send_all_payment(
+    ws: &Arc<WalletConn>,
+    address: &str,
+    wallet_info: &WalletInfo,
+) -> Result<String> {
+    let light_props = ws.get_light_props(&wallet_info._00_address)?;
+
+    let inputs = ws.get_inputs_from_hub(
+        &wallet_info._00_address,
+        1000, // we need 1000 sdg for the commissions at least
+        true, // is_spend_all
+        &light_props.last_ball_unit,
+    )?;
+
+    let compose_info = sdag::composer::ComposeInfo {
+        paid_address: wallet_info._00_address.clone(),
+        change_address: wallet_info._00_address.clone(),
+        outputs: vec![sdag::spec::Output {
+            address: address.to_owned(),
+            amount: 0,
+        }],
+        text_message: None,
+        inputs,
+        transaction_amount: 0,
+        light_props,
+        pubk: wallet_info._00_address_pubk.to_base64_key(),
+        send_all: true,
+    };
+
+    let joint = sdag::composer::compose_joint(compose_info, wallet_info)?;
+    if let Err(e) = ws.post_joint(&joint) {
+        eprintln!("post_joint err={}", e);
+        return Err(e);
+    }
+
+    println!("FROM  : {}", wallet_info._00_address);
+    println!("TO    : {}", address);
+    println!("UNIT  : {}", joint.unit.unit);
+
+    Ok(joint.unit.unit)
+}
+
 // choose a wallet whose index is cur_wallet in test_wallets
 pub fn choose_wallet(
     cur_wallet: usize,
@@ -194,17 +275,15 @@ pub fn distribute_token(
     for _ in 0..count {
         for chunk in address_amount.chunks(sdag::config::MAX_OUTPUTS_PER_PAYMENT_MESSAGE - 1) {
             if let Ok(hash) = send_payment(&ws, chunk.to_vec(), &paid_wallet, "good") {
-                wait_stable(ws, &hash);
+                if let Err(e) = wait_stable(ws, &hash, WAIT_STABLE_TIMEOUT) {
+                    error!("wait {} stable failed, err={}", hash, e);
+                }
             }
         }
     }
 }
 
-fn wait_stable(ws: &Arc<WalletConn>, unit: &str) {
-    while let Ok(resp) = ws.get_joint_by_unit_hash(&unit) {
-        if resp.1.is_stable {
-            break;
-        }
-        may::coroutine::sleep(Duration::from_millis(500));
-    }
+/// wait the posted unit stable, return the mci of the unit
+pub fn wait_stable(ws: &Arc<WalletConn>, unit: &str, timeout: Duration) -> Result<Level> {
+    ws.wait_unit_stable(unit, timeout)
 }
@@ -1,7 +1,12 @@
 use sdag_wallet_base::{ExtendedPrivKey, ExtendedPubKey, Mnemonic};
 use std::fs::File;
+use std::sync::Arc;
+use std::time::Duration;
 
 use sdag::error::Result;
+use sdag::network::wallet::WalletConn;
+
+use crate::transaction;
 
 pub const WALLET_ADDRESSES: &str = "wallets.json";
 
@@ -58,10 +63,12 @@ impl sdag::signature::Signer for WalletInfo {
     }
 }
 
-pub fn gen_wallets(c: usize) -> Result<Vec<WalletInfo>> {
-    let mut wallets_info: Vec<WalletInfo> = Vec::new();
+/// generate n random wallets and save them to the wallets.json
+/// the mnemonic entropy is read from the OsRng
+pub fn generate_wallet_set(n: usize) -> Result<Vec<WalletInfo>> {
+    let mut wallets_info: Vec<WalletInfo> = Vec::with_capacity(n);
 
-    for _ in 0..c {
+    for _ in 0..n {
         wallets_info.push(WalletInfo::from_mnemonic("")?);
     }
 
@@ -73,6 +80,34 @@ pub fn gen_wallets(c: usize) -> Result<Vec<WalletInfo>> {
     Ok(wallets_info)
 }
 
+/// pay amount_each to every target wallet, the outputs are batched in as few joints
+/// as possible, wait all the joints stable within the timeout and return their unit hashes
+pub fn fund_wallets(
+    ws: &Arc<WalletConn>,
+    genesis_wallet: &WalletInfo,
+    targets: &[WalletInfo],
+    amount_each: u64,
+    timeout: Duration,
+) -> Result<Vec<String>> {
+    let amount = amount_each as f64 / 1_000_000.0;
+    let address_amount = targets
+        .iter()
+        .map(|w| (w._00_address.clone(), amount))
+        .collect::<Vec<_>>();
+
+    // the change output takes one place
+    let mut units = Vec::new();
+    for chunk in address_amount.chunks(sdag::config::MAX_OUTPUTS_PER_PAYMENT_MESSAGE - 1) {
+        let unit = transaction::send_payment(ws, chunk.to_vec(), genesis_wallet, "good")?;
+        units.push(unit);
+    }
+
+    for unit in &units {
+        transaction::wait_stable(ws, unit, timeout)?;
+    }
+    Ok(units)
+}
+
 pub fn get_wallets() -> Result<Vec<WalletInfo>> {
     let mut settings_path = ::std::env::current_dir()?;
     settings_path.push(WALLET_ADDRESSES);
@@ -0,0 +1,63 @@
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use sdag::light::{FullHistoryRequest, TxStatus};
+use sdag::spec::Output;
+use sdag::test_utils::InProcessHub;
+use sdag::wallet_info::WalletInfo;
+use tests::{genesis, transaction};
+
+#[test]
+fn test_full_history_pages() {
+    let wallets = genesis::gen_all_wallets(sdag::config::COUNT_WITNESSES as u32).unwrap();
+    let (genesis_joint, _) =
+        genesis::gen_genesis_joint(&wallets, 500_000_000_000_000, "full history test").unwrap();
+    // must be set before the hub first reads the genesis unit
+    env::set_var("SDAG_GENESIS_UNIT", &genesis_joint.unit.unit);
+
+    let hub = InProcessHub::with_bootstrap(&[genesis_joint]).unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // three payments from different witnesses to a fresh address
+    let address = WalletInfo::from_mnemonic("").unwrap()._00_address;
+    let mut units = Vec::new();
+    for witness in &wallets.witnesses[..3] {
+        let outputs = vec![Output {
+            address: address.clone(),
+            amount: 1_000,
+        }];
+        let joint = transaction::compose_payment(&wallet, outputs, witness, false).unwrap();
+        wallet.post_joint(&joint).unwrap();
+        units.push(joint.unit.unit);
+    }
+    // wait the joints to be saved as unstable
+    thread::sleep(Duration::from_millis(500));
+
+    let request = |offset: usize| FullHistoryRequest {
+        address: address.clone(),
+        offset,
+        num: 2,
+    };
+
+    // the newest first
+    let page = wallet.get_full_history(&request(0)).unwrap();
+    let page_units = page
+        .transactions
+        .iter()
+        .map(|tx| tx.unit_hash.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(page_units, vec![units[2].clone(), units[1].clone()]);
+    assert!(page
+        .transactions
+        .iter()
+        .all(|tx| tx.status == TxStatus::Pending && tx.to_addr == address));
+    assert_eq!(page.next_offset, Some(2));
+
+    let page = wallet.get_full_history(&request(2)).unwrap();
+    assert_eq!(page.transactions.len(), 1);
+    assert_eq!(page.transactions[0].unit_hash, units[0]);
+    assert_eq!(page.next_offset, None);
+
+    hub.teardown().unwrap();
+}
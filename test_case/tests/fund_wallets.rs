@@ -0,0 +1,66 @@
+use std::env;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use sdag::spec::Output;
+use sdag::test_utils::InProcessHub;
+use tests::wallet::{self, WalletInfo};
+use tests::{genesis, transaction};
+
+#[test]
+fn test_fund_wallets() {
+    let wallets = genesis::gen_all_wallets(sdag::config::COUNT_WITNESSES as u32).unwrap();
+    let (genesis_joint, _) =
+        genesis::gen_genesis_joint(&wallets, 500_000_000_000_000, "fund wallets test").unwrap();
+    // must be set before the hub first reads the genesis unit
+    env::set_var("SDAG_GENESIS_UNIT", &genesis_joint.unit.unit);
+
+    let hub = InProcessHub::with_bootstrap(&[genesis_joint]).unwrap();
+    let funder = hub.connect_wallet().unwrap();
+    let ws = hub.connect_wallet().unwrap();
+
+    // the error is returned instead of waiting forever
+    let unknown = "-".repeat(sdag::config::HASH_LENGTH);
+    assert!(transaction::wait_stable(&ws, &unknown, Duration::from_secs(1)).is_err());
+
+    let targets = (0..5)
+        .map(|_| WalletInfo::from_mnemonic("").unwrap())
+        .collect::<Vec<_>>();
+    let genesis_wallet = wallets.witnesses[0].clone();
+    let fund_targets = targets.clone();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let timeout = Duration::from_secs(60);
+        let ret = wallet::fund_wallets(&funder, &genesis_wallet, &fund_targets, 1_000_000, timeout);
+        tx.send(ret).unwrap();
+    });
+
+    // the other witnesses keep posting until the funding joint become stable
+    let mut ret = None;
+    for _ in 0..8 {
+        for witness in &wallets.witnesses[1..] {
+            let outputs = vec![Output {
+                address: witness._00_address.clone(),
+                amount: 1_000,
+            }];
+            let joint = transaction::compose_payment(&ws, outputs, witness, false).unwrap();
+            ws.post_joint(&joint).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        if let Ok(r) = rx.recv_timeout(Duration::from_millis(500)) {
+            ret = Some(r);
+            break;
+        }
+    }
+
+    // the 5 outputs are batched in one joint
+    let units = ret.expect("the funding joint is not stable").unwrap();
+    assert_eq!(units.len(), 1);
+    for target in &targets {
+        assert!(ws.get_balance(&target._00_address).unwrap() >= 1_000_000);
+    }
+
+    hub.teardown().unwrap();
+}
@@ -0,0 +1,62 @@
+use std::env;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use sdag::composer;
+use sdag::spec::Output;
+use sdag::test_utils::InProcessHub;
+use sdag::wallet_info::WalletInfo;
+use tests::{genesis, transaction};
+
+#[test]
+fn test_pay_and_wait_stable() {
+    let wallets = genesis::gen_all_wallets(sdag::config::COUNT_WITNESSES as u32).unwrap();
+    let (genesis_joint, _) =
+        genesis::gen_genesis_joint(&wallets, 500_000_000_000_000, "pay and wait test").unwrap();
+    // must be set before the hub first reads the genesis unit
+    env::set_var("SDAG_GENESIS_UNIT", &genesis_joint.unit.unit);
+
+    let hub = InProcessHub::with_bootstrap(&[genesis_joint]).unwrap();
+    let payer = hub.connect_wallet().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // the first witness pays, the composer signs with the wallet of the library
+    let payer_info = WalletInfo::from_mnemonic(&wallets.witnesses[0].mnemonic).unwrap();
+    let outputs = vec![Output {
+        address: wallets.sdag_org._00_address.clone(),
+        amount: 1_000,
+    }];
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let timeout = Duration::from_secs(60);
+        let ret = composer::pay_and_wait(&payer, &payer_info, outputs, None, timeout);
+        tx.send(ret).unwrap();
+    });
+
+    // the other witnesses keep posting until the payment become stable
+    let mut ret = None;
+    for _ in 0..8 {
+        for witness in &wallets.witnesses[1..] {
+            let outputs = vec![Output {
+                address: witness._00_address.clone(),
+                amount: 1_000,
+            }];
+            let joint = transaction::compose_payment(&wallet, outputs, witness, false).unwrap();
+            wallet.post_joint(&joint).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        if let Ok(r) = rx.recv_timeout(Duration::from_millis(500)) {
+            ret = Some(r);
+            break;
+        }
+    }
+
+    let (unit, mci) = ret.expect("the payment is not stable").unwrap();
+    let (_, property) = wallet.get_joint_by_unit_hash(&unit).unwrap();
+    assert!(property.is_stable);
+    assert_eq!(property.mci, mci);
+
+    hub.teardown().unwrap();
+}
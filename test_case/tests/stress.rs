@@ -0,0 +1,47 @@
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sdag::test_utils::InProcessHub;
+use tests::{genesis, stress};
+
+// the time only advances when the pacer sleeps
+#[derive(Default)]
+struct SimClock(Mutex<Duration>);
+
+impl stress::Clock for SimClock {
+    fn elapsed(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        *self.0.lock().unwrap() += dur;
+    }
+}
+
+#[test]
+fn test_stress_at_one_tps() {
+    let wallets = genesis::gen_all_wallets(sdag::config::COUNT_WITNESSES as u32).unwrap();
+    let (genesis_joint, _) =
+        genesis::gen_genesis_joint(&wallets, 500_000_000_000_000, "stress test").unwrap();
+    // must be set before the hub first reads the genesis unit
+    env::set_var("SDAG_GENESIS_UNIT", &genesis_joint.unit.unit);
+
+    let hub = InProcessHub::with_bootstrap(&[genesis_joint]).unwrap();
+    let ws = hub.connect_wallet().unwrap();
+
+    // each witness owns 8 stable outputs from the genesis, enough for the test
+    let test_wallets = wallets.witnesses[..2].to_vec();
+    let clock = SimClock::default();
+    let report =
+        stress::run_stress(&ws, test_wallets, 1.0, Duration::from_secs(10), &clock).unwrap();
+
+    // 10 payments are paced in the 10 simulated seconds, 5 for each wallet
+    assert_eq!(report.good, 10);
+    assert!((report.tps - 1.0).abs() <= 0.1, "tps = {}", report.tps);
+    assert_eq!(report.bad, 0);
+    assert_eq!(report.error_rate, 0.0);
+    assert!(report.latency_p99_ms < 5_000);
+
+    hub.teardown().unwrap();
+}
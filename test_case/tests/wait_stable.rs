@@ -0,0 +1,74 @@
+use std::env;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use sdag::spec::Output;
+use sdag::test_utils::InProcessHub;
+use tests::{genesis, transaction};
+
+#[test]
+fn test_post_joint_and_wait_stable() {
+    let wallets = genesis::gen_all_wallets(sdag::config::COUNT_WITNESSES as u32).unwrap();
+    let (genesis_joint, _) =
+        genesis::gen_genesis_joint(&wallets, 500_000_000_000_000, "wait stable test").unwrap();
+    // must be set before the hub first reads the genesis unit
+    env::set_var("SDAG_GENESIS_UNIT", &genesis_joint.unit.unit);
+
+    let hub = InProcessHub::with_bootstrap(&[genesis_joint]).unwrap();
+    let poster = hub.connect_wallet().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // an unknown unit never becomes stable
+    let unknown = "-".repeat(sdag::config::HASH_LENGTH);
+    assert!(wallet
+        .wait_unit_stable(&unknown, Duration::from_secs(1))
+        .is_err());
+
+    let outputs = vec![Output {
+        address: wallets.sdag_org._00_address.clone(),
+        amount: 1_000,
+    }];
+    let joint =
+        transaction::compose_payment(&poster, outputs, &wallets.witnesses[0], false).unwrap();
+    let unit = joint.unit.unit.clone();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let ret = poster.post_joint_and_wait_stable(&joint, Duration::from_secs(60));
+        tx.send(ret).unwrap();
+    });
+
+    // the other witnesses keep posting until the joint become stable
+    let mut ret = None;
+    for _ in 0..8 {
+        for witness in &wallets.witnesses[1..] {
+            let outputs = vec![Output {
+                address: witness._00_address.clone(),
+                amount: 1_000,
+            }];
+            let joint = transaction::compose_payment(&wallet, outputs, witness, false).unwrap();
+            wallet.post_joint(&joint).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        if let Ok(r) = rx.recv_timeout(Duration::from_millis(500)) {
+            ret = Some(r);
+            break;
+        }
+    }
+
+    let mci = ret.expect("the joint is not stable").unwrap();
+    let (_, property) = wallet.get_joint_by_unit_hash(&unit).unwrap();
+    assert!(property.is_stable);
+    assert_eq!(property.mci, mci);
+
+    // the stable unit returns at once
+    assert_eq!(
+        wallet
+            .wait_unit_stable(&unit, Duration::from_secs(1))
+            .unwrap(),
+        mci
+    );
+
+    hub.teardown().unwrap();
+}
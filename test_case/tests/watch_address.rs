@@ -0,0 +1,49 @@
+use std::env;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sdag::spec::Output;
+use sdag::test_utils::InProcessHub;
+use tests::{genesis, transaction};
+
+#[test]
+fn test_watch_address_push() {
+    let wallets = genesis::gen_all_wallets(sdag::config::COUNT_WITNESSES as u32).unwrap();
+    let (genesis_joint, _) =
+        genesis::gen_genesis_joint(&wallets, 500_000_000_000_000, "watch address test").unwrap();
+    // must be set before the hub first reads the genesis unit
+    env::set_var("SDAG_GENESIS_UNIT", &genesis_joint.unit.unit);
+
+    let hub = InProcessHub::with_bootstrap(&[genesis_joint]).unwrap();
+    let watcher = hub.connect_wallet().unwrap();
+    let payer = hub.connect_wallet().unwrap();
+
+    let watched = wallets.witnesses[1]._00_address.clone();
+    watcher.watch_address(&watched).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let _handle = watcher.add_live_handler(None, move |joint| {
+        tx.lock().unwrap().send(joint.unit.unit).unwrap();
+    });
+
+    let pay_to = |to: &str| {
+        let outputs = vec![Output {
+            address: to.to_owned(),
+            amount: 1_000,
+        }];
+        let joint =
+            transaction::compose_payment(&payer, outputs, &wallets.witnesses[0], false).unwrap();
+        payer.post_joint(&joint).unwrap();
+        joint.unit.unit
+    };
+
+    // only the joint paying to the watched address is pushed to the watcher
+    pay_to(&wallets.sdag_org._00_address);
+    let related = pay_to(&watched);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(3)).unwrap(), related);
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    hub.teardown().unwrap();
+}
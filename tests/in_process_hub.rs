@@ -0,0 +1,353 @@
+extern crate sdag;
+#[macro_use]
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sdag::composer;
+use sdag::config;
+use sdag::joint::{Joint, Level};
+use sdag::light::FullHistoryRequest;
+use sdag::main_chain::MciStableEvent;
+use sdag::network::hub::notify_watchers_about_stable_joint;
+use sdag::spec::{Output, Payload, Payment, UnitBuilder};
+use sdag::test_utils::InProcessHub;
+use sdag::utils::event::emit_event_async;
+use sdag::wallet_info::WalletInfo;
+
+// all the cases share the process wide hub state, so they run one by one
+// guarded by the lock inside `InProcessHub`
+
+#[test]
+fn test_hub_start_and_connect() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    let info = wallet.get_joints_info().unwrap();
+    assert_eq!(info.temp_bad, 0);
+    assert_eq!(info.unhandled, 0);
+    assert_eq!(wallet.get_pending_count().unwrap(), 0);
+
+    // the listener is closed by the shutdown
+    let address = hub.get_address().to_owned();
+    hub.teardown().unwrap();
+    assert!(TcpStream::connect(&address).is_err());
+}
+
+#[test]
+fn test_hub_reject_invalid_joint() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // a joint with no authors and no parents can never be accepted
+    let joint = Joint {
+        ball: None,
+        skiplist_units: Vec::new(),
+        unit: UnitBuilder::new().build().unwrap(),
+    };
+    assert!(wallet.post_joint(&joint).is_err());
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_http_get_info() {
+    let hub = InProcessHub::new().unwrap();
+
+    let mut stream = TcpStream::connect(hub.get_address()).unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap();
+    let info: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(info["version"], "1.0");
+    assert!(info["peers"].is_u64());
+    assert!(info["is_synced"].is_boolean());
+
+    // the websocket upgrade still works on the same port
+    let wallet = hub.connect_wallet().unwrap();
+    assert_eq!(wallet.get_pending_count().unwrap(), 0);
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_get_network_health() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // the wallet is not a hub peer, a hub without peers is not synced
+    let health = wallet.get_network_health().unwrap();
+    assert_eq!(health.peer_count, 0);
+    assert!(!health.is_synced);
+    assert_eq!(health.missing_joints, 0);
+
+    let mut stream = TcpStream::connect(hub.get_address()).unwrap();
+    stream.write_all(b"GET /health HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap();
+    let report: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(report["is_synced"], json!(false));
+    assert!(report["uptime_secs"].is_u64());
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_pipelined_requests() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // all the requests are in flight before any response is waited
+    let pending = (0..10)
+        .map(|_| {
+            wallet
+                .send_request_pipelined("light/get_last_stable", &serde_json::Value::Null)
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    for request in pending {
+        assert!(request.wait().unwrap()["last_stable_mci"].is_u64());
+    }
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_pay_and_wait_without_funds() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // no genesis is posted, the new wallet has nothing to spend
+    let wallet_info = WalletInfo::from_mnemonic("").unwrap();
+    let outputs = vec![Output {
+        address: wallet_info._00_address.clone(),
+        amount: 1_000,
+    }];
+
+    let start = Instant::now();
+    let timeout = Duration::from_secs(3);
+    let ret = composer::pay_and_wait(&wallet, &wallet_info, outputs, None, timeout);
+    assert!(ret.is_err());
+    assert!(start.elapsed() < timeout);
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_subscribe_mci_stable() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // the already stable mci returns directly
+    let last_stable_mci = wallet.get_last_stable_mci().unwrap();
+    let start = Instant::now();
+    wallet
+        .subscribe_mci_stable(last_stable_mci as u32, Duration::from_secs(2))
+        .unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    // the hub has no witnesses to stabilize a joint, emit the stable event like the
+    // main chain does when the next mci become stable
+    let target_mci = last_stable_mci + 1;
+    let stabilized = Instant::now() + Duration::from_millis(500);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        emit_event_async(MciStableEvent {
+            mci: Level::new(target_mci),
+        });
+    });
+    wallet
+        .subscribe_mci_stable(target_mci as u32, Duration::from_secs(5))
+        .unwrap();
+    assert!(Instant::now() < stabilized + Duration::from_secs(2));
+
+    // no one stabilize the mci
+    let start = Instant::now();
+    let timeout = Duration::from_secs(1);
+    assert!(wallet
+        .subscribe_mci_stable(target_mci as u32 + 10, timeout)
+        .is_err());
+    assert!(start.elapsed() >= timeout);
+
+    // no polling coroutine per subscription, the pending ones are bounded per connection
+    let wallet = hub.connect_wallet().unwrap();
+    let subscribe =
+        |mci: usize| wallet.send_request("light/subscribe_mci", &json!({ "target_mci": mci }));
+    // 100 is the MAX_PENDING_MCI_SUBS of the hub
+    assert!((0..100).all(|i| subscribe(target_mci + 100 + i).is_ok()));
+    assert!(subscribe(target_mci + 1000).is_err());
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_subscribe_to_mci() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    let target_mci = wallet.get_last_stable_mci().unwrap() + 1;
+    let ack = wallet
+        .send_request(
+            "subscribe_to_mci",
+            &json!({ "mci": target_mci, "id": "sub" }),
+        )
+        .unwrap();
+    assert_eq!(ack, json!("ack"));
+
+    // the pushed "mci_stable" is taken by the wallet as the reached mci
+    emit_event_async(MciStableEvent {
+        mci: Level::new(target_mci),
+    });
+    wallet
+        .subscribe_mci_stable(target_mci as u32, Duration::from_secs(5))
+        .unwrap();
+
+    // the pending subscriptions of a connection are bounded
+    let wallet = hub.connect_wallet().unwrap();
+    let subscribe = |i: usize| {
+        wallet.send_request(
+            "subscribe_to_mci",
+            &json!({ "mci": target_mci + 1000, "id": i.to_string() }),
+        )
+    };
+    // 100 is the MAX_PENDING_MCI_SUBS of the hub
+    assert!((0..100).all(|i| subscribe(i).is_ok()));
+    assert!(subscribe(100).is_err());
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_get_link_proofs() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    assert!(wallet.get_link_proofs(&[]).unwrap().is_empty());
+
+    // the hub has no genesis, no unit could be proved
+    let unknown = vec!["bkdAIzhxgpXQ1+ZzcTqJZGaQ0B3YZmUhjhgyiSqfU5s=".to_owned()];
+    assert!(wallet.get_link_proofs(&unknown).is_err());
+
+    // the number of proofs in a request is limited
+    let too_many = vec![unknown[0].clone(); config::MAX_LINK_PROOFS_PER_REQUEST + 1];
+    let err = wallet.get_link_proofs(&too_many).unwrap_err();
+    assert!(err.to_string().contains("too many units"));
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_get_joint_proof() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    // the hub has no genesis, no unit could be proved
+    let err = wallet.get_joint_proof("genesis", None).unwrap_err();
+    assert!(err.to_string().contains("get_joint_proof err"));
+    assert!(wallet.get_joint_proof("genesis", Some(0)).is_err());
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_watch_address_stable_push() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    let address = WalletInfo::from_mnemonic("").unwrap()._00_address;
+    assert!(wallet.watch_address("not an address").is_err());
+    wallet.watch_address(&address).unwrap();
+
+    let related = {
+        let payment = Payment {
+            address: None,
+            asset: None,
+            definition_chash: None,
+            denomination: None,
+            inputs: Vec::new(),
+            outputs: vec![Output {
+                address: address.clone(),
+                amount: 1_000,
+            }],
+        };
+        Joint {
+            ball: None,
+            skiplist_units: Vec::new(),
+            unit: UnitBuilder::new()
+                .author("SOME_AUTHOR", serde_json::Value::Null, HashMap::new())
+                .message("payment", Payload::Payment(payment))
+                .build()
+                .unwrap(),
+        }
+    };
+
+    // the hub has no witnesses to stabilize a joint, push like the stable handler does
+    // the new joints push is tested with a genesis in test_case
+    notify_watchers_about_stable_joint(&related, Level::new(5));
+    let mci = wallet
+        .wait_unit_stable(&related.unit.unit, Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(mci, Level::new(5));
+
+    // the watched addresses of a connection are bounded
+    let wallet = hub.connect_wallet().unwrap();
+    let watch_new = || wallet.watch_address(&WalletInfo::from_mnemonic("").unwrap()._00_address);
+    // 100 is the MAX_WATCHED_ADDRESSES of the hub
+    assert!((0..100).all(|_| watch_new().is_ok()));
+    assert!(watch_new().is_err());
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_get_full_history() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    let request = |address: &str, num: usize| FullHistoryRequest {
+        address: address.to_owned(),
+        offset: 0,
+        num,
+    };
+    assert!(wallet
+        .get_full_history(&request("not an address", 0))
+        .is_err());
+
+    // the page size is capped
+    let address = WalletInfo::from_mnemonic("").unwrap()._00_address;
+    let too_many = config::MAX_HISTORY_TRANSACTIONS + 1;
+    assert!(wallet
+        .get_full_history(&request(&address, too_many))
+        .is_err());
+
+    // no joint is posted, neither pending nor stable transactions
+    let history = wallet.get_full_history(&request(&address, 0)).unwrap();
+    assert!(history.transactions.is_empty());
+    assert_eq!(history.next_offset, None);
+
+    hub.teardown().unwrap();
+}
+
+#[test]
+fn test_get_unconfirmed_inputs() {
+    let hub = InProcessHub::new().unwrap();
+    let wallet = hub.connect_wallet().unwrap();
+
+    assert!(wallet.get_unconfirmed_inputs("not an address").is_err());
+
+    // no joint is posted, nothing is spent by pending joints
+    let address = WalletInfo::from_mnemonic("").unwrap()._00_address;
+    assert!(wallet.get_unconfirmed_inputs(&address).unwrap().is_empty());
+
+    hub.teardown().unwrap();
+}
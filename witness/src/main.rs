@@ -10,7 +10,6 @@ extern crate sdag;
 extern crate lazy_static;
 
 extern crate chrono;
-extern crate env_logger;
 extern crate hashbrown;
 extern crate may_signal;
 extern crate num_cpus;
@@ -18,7 +17,9 @@ extern crate rand;
 extern crate rcu_cell;
 extern crate sdag_object_base;
 extern crate sdag_wallet_base;
+extern crate tracing_subscriber;
 
+mod slash_detection;
 mod timer;
 mod witness;
 
@@ -30,10 +31,12 @@ use sdag::wallet_info::MY_WALLET;
 fn log_init() {
     // TODO: need to implement async logs
     let log_lvl = sdag::config::get_log_level();
-    let mut builder = env_logger::Builder::from_default_env();
 
-    builder.filter_module("pagecache", log::LevelFilter::Error);
-    builder.filter(None, log_lvl).init();
+    // RUST_LOG overrides the configured log level, the log records are bridged to tracing
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(log_lvl.to_string().to_lowercase()))
+        .add_directive("pagecache=error".parse().expect("invalid log directive"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
     info!("log init done!");
 }
@@ -101,13 +104,47 @@ fn run_hub_server() -> Result<()> {
     Ok(())
 }
 
+// the witness must have paid the stake to the stake address
+fn check_witness_stake() -> Result<()> {
+    let stake_amount = sdag::config::get_witness_stake_amount();
+    if stake_amount == 0 {
+        return Ok(());
+    }
+
+    let stake_address = sdag::config::get_witness_stake_address();
+    let details = sdag::business::BUSINESS_CACHE.get_balance_details(&stake_address);
+    for utxo in details.stable_utxos {
+        if utxo.amount < stake_amount {
+            continue;
+        }
+        let joint = sdag::cache::SDAG_CACHE.get_joint(&utxo.unit)?.read()?;
+        if joint
+            .unit
+            .authors
+            .iter()
+            .any(|a| a.address == MY_WALLET._00_address)
+        {
+            return Ok(());
+        }
+    }
+
+    bail!(
+        "witness stake requirement not met, need {} paid to {}",
+        stake_amount,
+        stake_address
+    );
+}
+
 fn main() -> Result<()> {
     init()?;
-    run_hub_server()?;
 
+    // check before joining the network, so no joint is composed or reported
     if !sdag::my_witness::MY_WITNESSES.contains(&MY_WALLET._00_address) {
         bail!("address {} is not witness");
     }
+    check_witness_stake()?;
+
+    run_hub_server()?;
 
     // wait user input a ctrl_c to exit
     may_signal::ctrl_c().recv().unwrap();
@@ -0,0 +1,144 @@
+use hashbrown::HashSet;
+use may::sync::Mutex;
+use sdag::cache::{JointData, SDAG_CACHE};
+use sdag::error::Result;
+use sdag::joint::JointSequence;
+use sdag::my_witness::MY_WITNESSES;
+use sdag::network::hub::WSS;
+use sdag::utils::event::emit_event;
+
+lazy_static! {
+    // the reported unit pairs, each pair is only reported once
+    static ref REPORTED: Mutex<HashSet<(String, String)>> = Mutex::new(HashSet::new());
+}
+
+//---------------------------------------------------------------------------------------
+// WitnessDoubleSignEvent
+//---------------------------------------------------------------------------------------
+pub struct WitnessDoubleSignEvent {
+    pub address: String,
+    pub unit_a: String,
+    pub unit_b: String,
+}
+impl_event!(WitnessDoubleSignEvent);
+
+/// return true if the two joints are a potential double-sign of a witness
+pub fn check_double_sign(joint_a: &JointData, joint_b: &JointData) -> bool {
+    is_double_sign(joint_a, joint_b, |address| MY_WITNESSES.contains(address))
+}
+
+fn is_double_sign<F>(joint_a: &JointData, joint_b: &JointData, is_witness: F) -> bool
+where
+    F: Fn(&String) -> bool,
+{
+    if joint_a.unit.unit == joint_b.unit.unit {
+        return false;
+    }
+
+    // witnesses always compose single author joints
+    if joint_a.unit.authors.len() != 1 || joint_b.unit.authors.len() != 1 {
+        return false;
+    }
+
+    let address = &joint_a.unit.authors[0].address;
+    if address != &joint_b.unit.authors[0].address || !is_witness(address) {
+        return false;
+    }
+
+    // a serial author always includes its previous joint
+    joint_a.partial_cmp(joint_b).is_none()
+}
+
+/// check the nonserial joints against the other unstable joints of the same author
+pub fn detect_double_sign() -> Result<()> {
+    let mut joints = Vec::new();
+    for joint in SDAG_CACHE.get_unstable_joints()? {
+        joints.push(joint.read()?);
+    }
+
+    let nonserial_joints = joints
+        .iter()
+        .filter(|j| j.get_sequence() == JointSequence::NonserialBad);
+    for joint_a in nonserial_joints {
+        for joint_b in &joints {
+            if check_double_sign(joint_a, joint_b) {
+                report_double_sign(joint_a, joint_b);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn report_double_sign(joint_a: &JointData, joint_b: &JointData) {
+    let address = joint_a.unit.authors[0].address.clone();
+    let (unit_a, unit_b) = if joint_a.unit.unit < joint_b.unit.unit {
+        (joint_a.unit.unit.clone(), joint_b.unit.unit.clone())
+    } else {
+        (joint_b.unit.unit.clone(), joint_a.unit.unit.clone())
+    };
+
+    if !REPORTED
+        .lock()
+        .unwrap()
+        .insert((unit_a.clone(), unit_b.clone()))
+    {
+        return;
+    }
+
+    error!(
+        "witness {} double-sign detected, unit_a={}, unit_b={}",
+        address, unit_a, unit_b
+    );
+    WSS.report_double_sign(&address, &unit_a, &unit_b);
+    emit_event(WitnessDoubleSignEvent {
+        address,
+        unit_a,
+        unit_b,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdag::joint::Joint;
+    use sdag::spec::{Payload, UnitBuilder};
+
+    const WITNESS: &str = "D27P6DGHLPO5A7MSOZABHOOWQ3BJ56ZI";
+    const OTHER: &str = "LWFAESN3EB5E5VFXJ7JWIJB7K5MDQCZE";
+
+    fn new_joint(author: &str, text: &str) -> JointData {
+        let unit = UnitBuilder::new()
+            .author(author, Default::default(), Default::default())
+            .message("text", Payload::Text(text.to_owned()))
+            .parent("parent")
+            .build()
+            .unwrap();
+        JointData::from_joint(
+            Joint {
+                ball: None,
+                skiplist_units: Vec::new(),
+                unit,
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn test_check_double_sign() {
+        let is_witness = |address: &String| address == WITNESS;
+
+        // the mci of unstable joints is not known yet, the conflict is still detected
+        let joint_a = new_joint(WITNESS, "a");
+        let joint_b = new_joint(WITNESS, "b");
+        assert!(is_double_sign(&joint_a, &joint_b, is_witness));
+        assert!(!is_double_sign(&joint_a, &joint_a, is_witness));
+
+        let joint_e = new_joint(OTHER, "e");
+        assert!(!is_double_sign(&joint_a, &joint_e, is_witness));
+        assert!(!is_double_sign(
+            &joint_e,
+            &new_joint(OTHER, "f"),
+            is_witness
+        ));
+    }
+}
@@ -4,6 +4,7 @@ use may::coroutine;
 use sdag::network::hub;
 use sdag::statistics;
 
+use slash_detection;
 use witness;
 
 pub fn start_global_timers() {
@@ -60,6 +61,12 @@ pub fn start_global_timers() {
         coroutine::sleep(dur);
     });
 
+    // detect the witnesses that sign conflicting joints
+    go!(move || loop {
+        coroutine::sleep(Duration::from_secs(10));
+        t!(slash_detection::detect_double_sign());
+    });
+
     // Run cache gc
     if !cfg!(feature = "kv_store_none") {
         go!(move || loop {
@@ -262,6 +262,7 @@ fn compose_and_normalize() -> Result<()> {
         text_message: None,
         light_props,
         pubk: WALLET_PUBK.clone(),
+        send_all: false,
     };
 
     // if sdag::config::get_need_post_timestamp() {